@@ -1,22 +1,27 @@
 #!/usr/bin/python3
+import json
+import os
 import socket
 import sys
 
 def main():
     client = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
 
-    client.connect("/tmp/rust-vmm.sock")
+    client.connect(os.environ.get("VMM_API_SOCK", "/tmp/rust-vmm.sock"))
     # 1024M: reclaim 1G
     # 0M: deflate, give back all
     M = int(sys.argv[1])
     pages = M * 256
 
+    request = {"version": 1, "command": "balloon", "num_pages": pages}
+    client.sendall((json.dumps(request) + "\n").encode('utf-8'))
 
-    message = f"balloon {pages}"
-
-    client.sendall(message.encode('utf-8'))
+    response = json.loads(client.makefile().readline())
+    print(response)
 
     client.close()
+    if response["status"] != "ok":
+        sys.exit(1)
 
 if __name__ == "__main__":
     main()
@@ -0,0 +1,23 @@
+#!/usr/bin/python3
+import json
+import os
+import socket
+import sys
+
+def main():
+    client = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+
+    client.connect(os.environ.get("VMM_API_SOCK", "/tmp/rust-vmm.sock"))
+
+    request = {"version": 1, "command": "balloon_size"}
+    client.sendall((json.dumps(request) + "\n").encode('utf-8'))
+
+    response = json.loads(client.makefile().readline())
+    print(response)
+
+    client.close()
+    if response["status"] != "ok":
+        sys.exit(1)
+
+if __name__ == "__main__":
+    main()
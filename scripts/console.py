@@ -0,0 +1,73 @@
+#!/usr/bin/python3
+import json
+import os
+import select
+import socket
+import sys
+import termios
+import tty
+
+# Ctrl-], like telnet.
+ESCAPE = b"\x1d"
+
+def read_response(client):
+    # Byte by byte, the console output follows the response of `attach_console`.
+    line = b""
+    while not line.endswith(b"\n"):
+        byte = client.recv(1)
+        if not byte:
+            break
+        line += byte
+    return json.loads(line)
+
+def attach(client):
+    stdin = sys.stdin.fileno()
+    saved = termios.tcgetattr(stdin) if os.isatty(stdin) else None
+    if saved is not None:
+        tty.setraw(stdin)
+    try:
+        while True:
+            readable, _, _ = select.select([client, stdin], [], [])
+            if client in readable:
+                data = client.recv(4096)
+                if not data:
+                    break
+                os.write(sys.stdout.fileno(), data)
+            if stdin in readable:
+                data = os.read(stdin, 4096)
+                if not data or ESCAPE in data:
+                    break
+                client.sendall(data)
+    finally:
+        if saved is not None:
+            termios.tcsetattr(stdin, termios.TCSADRAIN, saved)
+
+def main():
+    if len(sys.argv) != 2 or sys.argv[1] not in ("attach", "detach", "log"):
+        print("usage: {} attach | detach | log".format(sys.argv[0]))
+        sys.exit(1)
+
+    client = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+
+    client.connect(os.environ.get("VMM_API_SOCK", "/tmp/rust-vmm.sock"))
+
+    command = {"attach": "attach_console", "detach": "detach_console", "log": "console_log"}
+    request = {"version": 1, "command": command[sys.argv[1]]}
+    client.sendall((json.dumps(request) + "\n").encode('utf-8'))
+
+    response = read_response(client)
+    if response["status"] != "ok":
+        print(response)
+        client.close()
+        sys.exit(1)
+
+    if sys.argv[1] == "attach":
+        print("Attached to the serial console, Ctrl-] to quit.\r")
+        attach(client)
+    elif sys.argv[1] == "log":
+        sys.stdout.write(response["data"]["output"])
+
+    client.close()
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,19 @@
+#!/usr/bin/python3
+import json
+import os
+import socket
+
+def main():
+    client = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+
+    client.connect(os.environ.get("VMM_API_SOCK", "/tmp/rust-vmm.sock"))
+
+    request = {"version": 1, "command": "describe"}
+    client.sendall((json.dumps(request) + "\n").encode('utf-8'))
+
+    print(json.loads(client.makefile().readline()))
+
+    client.close()
+
+if __name__ == "__main__":
+    main()
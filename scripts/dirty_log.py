@@ -0,0 +1,44 @@
+#!/usr/bin/python3
+import json
+import os
+import socket
+import sys
+
+def main():
+    if len(sys.argv) < 2 or sys.argv[1] not in ("start", "stop", "fetch"):
+        print("usage: {} start [<slot>...] | stop | fetch".format(sys.argv[0]))
+        sys.exit(1)
+
+    client = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+
+    client.connect(os.environ.get("VMM_API_SOCK", "/tmp/rust-vmm.sock"))
+
+    if sys.argv[1] == "start":
+        request = {"version": 1, "command": "start_dirty_log"}
+        if len(sys.argv) > 2:
+            request["slots"] = [int(slot) for slot in sys.argv[2:]]
+    elif sys.argv[1] == "stop":
+        request = {"version": 1, "command": "stop_dirty_log"}
+    else:
+        request = {"version": 1, "command": "dirty_log"}
+    client.sendall((json.dumps(request) + "\n").encode('utf-8'))
+
+    response = json.loads(client.makefile().readline())
+    if response["status"] == "ok" and "data" in response:
+        # The bitmaps are too long to be worth printing.
+        data = response["data"]
+        print("{} dirty pages".format(data["dirty_pages"]))
+        for slot in data["slots"]:
+            count = sum(bin(word).count("1") for word in slot["bitmap"])
+            print("slot {}: {:#x}+{:#x}, {} dirty pages".format(
+                slot["slot"], slot["guest_phys_addr"], slot["memory_size"], count))
+    else:
+        print(response)
+
+    client.close()
+
+    if response["status"] != "ok":
+        sys.exit(1)
+
+if __name__ == "__main__":
+    main()
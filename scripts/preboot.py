@@ -0,0 +1,51 @@
+#!/usr/bin/python3
+import json
+import os
+import socket
+import sys
+
+def requests(config):
+    memory = config.get("memory", {})
+    vcpu = config.get("vcpu", {})
+    yield {
+        "command": "put_machine_config",
+        "size_mib": memory.get("size_mib"),
+        "num_vcpus": vcpu.get("num"),
+    }
+    yield dict(config["kernel"], command="put_kernel")
+    for i, block in enumerate(config.get("block", [])):
+        yield {"command": "put_drive", "drive_id": "drive{}".format(i), "path": block["path"]}
+    for i, net in enumerate(config.get("net", [])):
+        yield {"command": "put_network", "iface_id": "eth{}".format(i), "tap": net["tap"]}
+    if config.get("balloon"):
+        yield dict(config["balloon"][0], command="put_balloon")
+    yield {"command": "start"}
+
+def main():
+    if len(sys.argv) != 2:
+        print("usage: {} <config.json>".format(sys.argv[0]))
+        sys.exit(1)
+
+    with open(sys.argv[1]) as f:
+        config = json.load(f)
+
+    client = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+
+    client.connect(os.environ.get("VMM_API_SOCK", "/tmp/rust-vmm.sock"))
+    reader = client.makefile()
+
+    for request in requests(config):
+        request["version"] = 1
+        client.sendall((json.dumps(request) + "\n").encode('utf-8'))
+
+        response = json.loads(reader.readline())
+        print(request["command"], response)
+
+        if response["status"] != "ok":
+            client.close()
+            sys.exit(1)
+
+    client.close()
+
+if __name__ == "__main__":
+    main()
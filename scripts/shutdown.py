@@ -1,13 +1,17 @@
 #!/usr/bin/python3
+import json
+import os
 import socket
 
 def main():
     client = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
 
-    client.connect("/tmp/rust-vmm.sock")
+    client.connect(os.environ.get("VMM_API_SOCK", "/tmp/rust-vmm.sock"))
 
-    message = "shutdown 0"
-    client.sendall(message.encode('utf-8'))
+    request = {"version": 1, "command": "shutdown"}
+    client.sendall((json.dumps(request) + "\n").encode('utf-8'))
+
+    print(json.loads(client.makefile().readline()))
 
     client.close()
 
@@ -0,0 +1,38 @@
+#!/usr/bin/python3
+import json
+import os
+import socket
+import sys
+
+def main():
+    args = sys.argv[1:]
+    diff = "--diff" in args
+    if diff:
+        args.remove("--diff")
+    if len(args) != 2:
+        print("usage: {} [--diff] <state_path> <mem_path>".format(sys.argv[0]))
+        sys.exit(1)
+
+    client = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+
+    client.connect(os.environ.get("VMM_API_SOCK", "/tmp/rust-vmm.sock"))
+
+    request = {
+        "version": 1,
+        "command": "snapshot",
+        "state_path": args[0],
+        "mem_path": args[1],
+        "diff": diff,
+    }
+    client.sendall((json.dumps(request) + "\n").encode('utf-8'))
+
+    response = json.loads(client.makefile().readline())
+    print(response)
+
+    client.close()
+
+    if response["status"] != "ok":
+        sys.exit(1)
+
+if __name__ == "__main__":
+    main()
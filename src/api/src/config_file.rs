@@ -0,0 +1,615 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! VM configuration file.
+//!
+//! The file describes the whole VM in one JSON document, or in TOML when its name ends with
+//! `.toml`. Every section is optional, and the devices are lists, so that a VM can have any
+//! number of them. The first block device is the root device.
+//!
+//! ```json
+//! {
+//!     "memory": { "size_mib": 1024 },
+//!     "vcpu": { "num": 2 },
+//!     "kernel": { "path": "/path/to/bzImage", "cmdline": "console=ttyS0 panic=1 pci=off" },
+//!     "block": [{ "path": "/path/to/rootfs.ext4" }, { "path": "/path/to/data.ext4" }],
+//!     "net": [{ "tap": "tap0" }],
+//!     "balloon": [{ "amount_mib": 0, "stats_polling_interval_s": 1 }],
+//!     "serial": { "backend": "unix", "path": "/tmp/rust-vmm.console" },
+//!     "reboot": "restart",
+//!     "debug_exit": false,
+//!     "api": { "socket_path": "/tmp/rust-vmm.sock", "enabled": true },
+//!     "metrics": { "path": "/tmp/rust-vmm.metrics", "flush_interval_s": 60 },
+//!     "log": { "path": "/tmp/rust-vmm.log", "level": "info,devices::virtio=debug" }
+//! }
+//! ```
+//!
+//! The sections are converted with the same rules, defaults and [`ConversionError`]s as the
+//! command line `key=value` strings.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use vmm::{
+    ApiConfig, BalloonConfig, BlockConfig, ConversionError, KernelConfig, MemoryConfig,
+    MetricsConfig, NetConfig, RebootPolicy, SerialConfig, VMMConfig, VcpuConfig,
+    DEFAULT_KERNEL_CMDLINE, DEFAULT_KERNEL_LOAD_ADDR, DEFAULT_METRICS_FLUSH_INTERVAL_S,
+};
+
+/// Errors encountered loading a configuration file.
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to read the file.
+    Read(io::Error),
+    /// The file is not a valid JSON configuration.
+    Json(serde_json::Error),
+    /// The file is not a valid TOML configuration.
+    Toml(toml::de::Error),
+    /// The file describes an invalid VM.
+    Config(ConversionError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Read(e) => write!(f, "cannot read the file: {}", e),
+            Error::Json(e) => write!(f, "invalid JSON: {}", e),
+            Error::Toml(e) => write!(f, "invalid TOML: {}", e),
+            Error::Config(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Guest memory section.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct MemorySection {
+    /// Guest memory size in MiB. Defaults to 256.
+    pub size_mib: Option<u32>,
+}
+
+/// vCPU section.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct VcpuSection {
+    /// Number of vCPUs. Defaults to 1.
+    pub num: Option<u8>,
+}
+
+/// Guest kernel section.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct KernelSection {
+    /// Path to the kernel image. Required.
+    pub path: Option<PathBuf>,
+    /// Kernel command line. Defaults to [`DEFAULT_KERNEL_CMDLINE`].
+    pub cmdline: Option<String>,
+    /// Address where the kernel is loaded. Defaults to [`DEFAULT_KERNEL_LOAD_ADDR`].
+    pub kernel_load_addr: Option<u64>,
+}
+
+/// Block device section.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct BlockSection {
+    /// Path to the block device backend. Required.
+    pub path: Option<PathBuf>,
+}
+
+/// Network device section.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct NetSection {
+    /// Name of the tap device. Required.
+    pub tap: Option<String>,
+}
+
+/// Balloon device section.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct BalloonSection {
+    /// Memory the guest gives back to the host once it boots, in MiB. Defaults to 0.
+    pub amount_mib: Option<u32>,
+    /// Whether the guest takes memory back from the balloon when it runs out of it. Defaults
+    /// to `false`.
+    pub deflate_on_oom: Option<bool>,
+    /// Seconds between two requests for the memory statistics of the guest. Defaults to
+    /// [`vmm::DEFAULT_BALLOON_STATS_POLLING_INTERVAL_S`].
+    pub stats_polling_interval_s: Option<u64>,
+}
+
+/// Serial console section.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct SerialSection {
+    /// One of `stdio`, `file`, `unix` or `pty`. Defaults to `stdio`.
+    pub backend: Option<String>,
+    /// Path of the file or of the socket. Required for the `file` and `unix` backends.
+    pub path: Option<PathBuf>,
+}
+
+/// Control API section.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ApiSection {
+    /// Path of the control socket. Defaults to [`vmm::DEFAULT_API_SOCKET_PATH`].
+    pub socket_path: Option<PathBuf>,
+    /// Whether to run the control server. Defaults to `true`.
+    pub enabled: Option<bool>,
+}
+
+/// Metrics section.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsSection {
+    /// Path of the file the metrics are appended to. Required.
+    pub path: Option<PathBuf>,
+    /// Seconds between two writes. Defaults to [`DEFAULT_METRICS_FLUSH_INTERVAL_S`].
+    pub flush_interval_s: Option<u64>,
+}
+
+/// Logger section.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct LogSection {
+    /// Path of the file the logs are appended to. Defaults to stderr.
+    pub path: Option<PathBuf>,
+    /// Levels of the logs, as `<level>[,<module>=<level>...]`. Defaults to `info`.
+    pub level: Option<String>,
+}
+
+/// Contents of a configuration file.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    /// Guest memory.
+    pub memory: Option<MemorySection>,
+    /// vCPUs.
+    pub vcpu: Option<VcpuSection>,
+    /// Guest kernel.
+    pub kernel: Option<KernelSection>,
+    /// Block devices.
+    #[serde(default)]
+    pub block: Vec<BlockSection>,
+    /// Network devices.
+    #[serde(default)]
+    pub net: Vec<NetSection>,
+    /// Balloon devices.
+    #[serde(default)]
+    pub balloon: Vec<BalloonSection>,
+    /// Serial console.
+    pub serial: Option<SerialSection>,
+    /// What to do when the guest reboots: `exit` or `restart`. Defaults to `exit`.
+    pub reboot: Option<String>,
+    /// Whether the guest gets a device it ends the VMM with, choosing the exit code. Defaults
+    /// to `false`.
+    pub debug_exit: Option<bool>,
+    /// Control API.
+    pub api: Option<ApiSection>,
+    /// Metrics file.
+    pub metrics: Option<MetricsSection>,
+    /// Logger.
+    pub log: Option<LogSection>,
+}
+
+impl ConfigFile {
+    /// Reads the configuration file at `path`.
+    ///
+    /// The file is parsed as TOML if its extension is `.toml`, and as JSON otherwise.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path).map_err(Error::Read)?;
+        if path.extension().map_or(false, |ext| ext == "toml") {
+            Self::from_toml(&content)
+        } else {
+            Self::from_json(&content)
+        }
+    }
+
+    /// Parses a JSON configuration.
+    pub fn from_json(content: &str) -> Result<Self, Error> {
+        serde_json::from_str(content).map_err(Error::Json)
+    }
+
+    /// Parses a TOML configuration.
+    pub fn from_toml(content: &str) -> Result<Self, Error> {
+        toml::from_str(content).map_err(Error::Toml)
+    }
+
+    /// Reads the configuration file at `path` and converts it into a `VMMConfig`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<VMMConfig, Error> {
+        let file = Self::from_path(path)?;
+        VMMConfig::try_from(&file).map_err(Error::Config)
+    }
+}
+
+impl TryFrom<&ConfigFile> for VMMConfig {
+    type Error = ConversionError;
+
+    fn try_from(file: &ConfigFile) -> Result<Self, Self::Error> {
+        let mut builder = VMMConfig::builder()
+            .memory_config(file.memory.as_ref())
+            .vcpu_config(file.vcpu.as_ref())
+            .kernel_config(file.kernel.as_ref())
+            .serial_config(file.serial.as_ref())
+            .reboot_policy(file.reboot.as_deref())
+            .debug_exit(file.debug_exit.unwrap_or(false))
+            .api_config(file.api.as_ref())
+            .metrics_config(file.metrics.as_ref())
+            .log_path(file.log.as_ref().and_then(|log| log.path.as_ref()))
+            .log_level(file.log.as_ref().and_then(|log| log.level.as_deref()));
+        for block in file.block.iter() {
+            builder = builder.block_config(Some(block));
+        }
+        for net in file.net.iter() {
+            builder = builder.net_config(Some(net));
+        }
+        for balloon in file.balloon.iter() {
+            builder = builder.balloon_config(Some(balloon));
+        }
+        builder.build()
+    }
+}
+
+impl TryFrom<&MemorySection> for MemoryConfig {
+    type Error = ConversionError;
+
+    fn try_from(section: &MemorySection) -> Result<Self, Self::Error> {
+        Ok(MemoryConfig {
+            size_mib: section.size_mib.unwrap_or(256),
+        })
+    }
+}
+
+impl TryFrom<&VcpuSection> for VcpuConfig {
+    type Error = ConversionError;
+
+    fn try_from(section: &VcpuSection) -> Result<Self, Self::Error> {
+        let num = section.num.unwrap_or(1);
+        if num == 0 {
+            return Err(ConversionError::ParseVcpus(
+                "Param 'num' must be greater than 0".to_string(),
+            ));
+        }
+        Ok(VcpuConfig { num })
+    }
+}
+
+impl TryFrom<&KernelSection> for KernelConfig {
+    type Error = ConversionError;
+
+    fn try_from(section: &KernelSection) -> Result<Self, Self::Error> {
+        let cmdline = KernelConfig::parse_cmdline(
+            section.cmdline.as_deref().unwrap_or(DEFAULT_KERNEL_CMDLINE),
+        )?;
+        let path = non_empty_path(section.path.as_ref()).ok_or_else(|| {
+            ConversionError::ParseKernel("Missing required argument: path".to_string())
+        })?;
+        Ok(KernelConfig {
+            cmdline,
+            path,
+            load_addr: section.kernel_load_addr.unwrap_or(DEFAULT_KERNEL_LOAD_ADDR),
+        })
+    }
+}
+
+impl TryFrom<&BlockSection> for BlockConfig {
+    type Error = ConversionError;
+
+    fn try_from(section: &BlockSection) -> Result<Self, Self::Error> {
+        let path = non_empty_path(section.path.as_ref()).ok_or_else(|| {
+            ConversionError::ParseBlock("Missing required argument: path".to_string())
+        })?;
+        Ok(BlockConfig { path })
+    }
+}
+
+impl TryFrom<&NetSection> for NetConfig {
+    type Error = ConversionError;
+
+    fn try_from(section: &NetSection) -> Result<Self, Self::Error> {
+        let tap_name = section
+            .tap
+            .as_ref()
+            .filter(|tap| !tap.is_empty())
+            .cloned()
+            .ok_or_else(|| {
+                ConversionError::ParseNet("Missing required argument: tap".to_string())
+            })?;
+        Ok(NetConfig { tap_name })
+    }
+}
+
+impl TryFrom<&BalloonSection> for BalloonConfig {
+    type Error = ConversionError;
+
+    fn try_from(section: &BalloonSection) -> Result<Self, Self::Error> {
+        let default = BalloonConfig::default();
+        let amount_mib = section.amount_mib.unwrap_or(default.amount_mib);
+        BalloonConfig::check_amount(amount_mib)?;
+        Ok(BalloonConfig {
+            amount_mib,
+            deflate_on_oom: section.deflate_on_oom.unwrap_or(default.deflate_on_oom),
+            stats_polling_interval_s: section
+                .stats_polling_interval_s
+                .unwrap_or(default.stats_polling_interval_s),
+        })
+    }
+}
+
+impl TryFrom<&ApiSection> for ApiConfig {
+    type Error = ConversionError;
+
+    fn try_from(section: &ApiSection) -> Result<Self, Self::Error> {
+        let mut config = match section.socket_path.as_ref() {
+            Some(path) if path.as_os_str().is_empty() => {
+                return Err(ConversionError::ParseApi("Empty socket path".to_string()))
+            }
+            Some(path) => ApiConfig {
+                socket_path: path.clone(),
+                ..Default::default()
+            },
+            None => ApiConfig::default(),
+        };
+        config.enabled = section.enabled.unwrap_or(true);
+        Ok(config)
+    }
+}
+
+impl TryFrom<&SerialSection> for SerialConfig {
+    type Error = ConversionError;
+
+    fn try_from(section: &SerialSection) -> Result<Self, Self::Error> {
+        let path = || {
+            non_empty_path(section.path.as_ref()).ok_or_else(|| {
+                ConversionError::ParseSerial("Missing required argument: path".to_string())
+            })
+        };
+        match section.backend.as_deref().unwrap_or("stdio") {
+            "stdio" => Ok(SerialConfig::Stdio),
+            "file" => Ok(SerialConfig::File(path()?)),
+            "unix" => Ok(SerialConfig::Socket(path()?)),
+            "pty" => Ok(SerialConfig::Pty),
+            backend => Err(ConversionError::ParseSerial(format!(
+                "Expected stdio, file, unix or pty, got {}",
+                backend
+            ))),
+        }
+    }
+}
+
+impl TryFrom<&MetricsSection> for MetricsConfig {
+    type Error = ConversionError;
+
+    fn try_from(section: &MetricsSection) -> Result<Self, Self::Error> {
+        let path = non_empty_path(section.path.as_ref()).ok_or_else(|| {
+            ConversionError::ParseMetrics("Missing required argument: path".to_string())
+        })?;
+        let flush_interval_s = section
+            .flush_interval_s
+            .unwrap_or(DEFAULT_METRICS_FLUSH_INTERVAL_S);
+        if flush_interval_s == 0 {
+            return Err(ConversionError::ParseMetrics(
+                "Param 'flush_interval_s' must be greater than 0".to_string(),
+            ));
+        }
+        Ok(MetricsConfig {
+            path,
+            flush_interval_s,
+        })
+    }
+}
+
+// An empty path is as good as a missing one, like in the command line strings.
+fn non_empty_path(path: Option<&PathBuf>) -> Option<PathBuf> {
+    path.filter(|path| !path.as_os_str().is_empty()).cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use linux_loader::cmdline::Cmdline;
+    use vmm::{LogConfig, DEFAULT_BALLOON_STATS_POLLING_INTERVAL_S};
+
+    #[test]
+    fn test_json_config() {
+        let file = ConfigFile::from_json(
+            r#"{
+                "memory": { "size_mib": 1024 },
+                "vcpu": { "num": 2 },
+                "kernel": { "path": "/foo/bzImage", "cmdline": "foo=bar bar=foo" },
+                "block": [{ "path": "/foo/rootfs" }, { "path": "/foo/data" }],
+                "net": [{ "tap": "tap0" }, { "tap": "tap1" }],
+                "balloon": [{ "amount_mib": 512, "deflate_on_oom": true }],
+                "serial": { "backend": "file", "path": "/tmp/foo.console" },
+                "reboot": "restart",
+                "debug_exit": true,
+                "api": { "socket_path": "/tmp/foo.sock" },
+                "metrics": { "path": "/tmp/foo.metrics" },
+                "log": { "path": "/tmp/foo.log", "level": "debug" }
+            }"#,
+        )
+        .unwrap();
+
+        let mut cmdline = Cmdline::new(4096);
+        cmdline.insert_str("foo=bar bar=foo").unwrap();
+        assert_eq!(
+            VMMConfig::try_from(&file).unwrap(),
+            VMMConfig {
+                memory_config: MemoryConfig { size_mib: 1024 },
+                vcpu_config: VcpuConfig { num: 2 },
+                kernel_config: KernelConfig {
+                    cmdline,
+                    path: PathBuf::from("/foo/bzImage"),
+                    load_addr: DEFAULT_KERNEL_LOAD_ADDR,
+                },
+                block_config: vec![
+                    BlockConfig {
+                        path: PathBuf::from("/foo/rootfs"),
+                    },
+                    BlockConfig {
+                        path: PathBuf::from("/foo/data"),
+                    },
+                ],
+                net_config: vec![
+                    NetConfig {
+                        tap_name: "tap0".to_string(),
+                    },
+                    NetConfig {
+                        tap_name: "tap1".to_string(),
+                    },
+                ],
+                balloon_config: vec![BalloonConfig {
+                    amount_mib: 512,
+                    deflate_on_oom: true,
+                    stats_polling_interval_s: DEFAULT_BALLOON_STATS_POLLING_INTERVAL_S,
+                }],
+                restore_config: None,
+                incoming_config: None,
+                api_config: ApiConfig {
+                    socket_path: PathBuf::from("/tmp/foo.sock"),
+                    enabled: true,
+                },
+                metrics_config: Some(MetricsConfig {
+                    path: PathBuf::from("/tmp/foo.metrics"),
+                    flush_interval_s: DEFAULT_METRICS_FLUSH_INTERVAL_S,
+                }),
+                log_config: LogConfig {
+                    path: Some(PathBuf::from("/tmp/foo.log")),
+                    filter: "debug".parse().unwrap(),
+                },
+                serial_config: SerialConfig::File(PathBuf::from("/tmp/foo.console")),
+                reboot_policy: RebootPolicy::Restart,
+                debug_exit: true,
+                preboot: false,
+            }
+        );
+
+        // Only the kernel path is required, everything else has a default.
+        let file = ConfigFile::from_json(r#"{ "kernel": { "path": "/foo/bzImage" } }"#).unwrap();
+        let config = VMMConfig::try_from(&file).unwrap();
+        assert_eq!(config.memory_config, MemoryConfig::default());
+        assert_eq!(config.vcpu_config, VcpuConfig::default());
+        assert_eq!(
+            config.kernel_config.cmdline,
+            KernelConfig::default_cmdline()
+        );
+        assert!(config.block_config.is_empty());
+        assert_eq!(config.api_config, ApiConfig::default());
+    }
+
+    #[test]
+    fn test_toml_config() {
+        let file = ConfigFile::from_toml(
+            r#"
+            [memory]
+            size_mib = 512
+
+            [kernel]
+            path = "/foo/bzImage"
+            kernel_load_addr = 42
+
+            [[block]]
+            path = "/foo/rootfs"
+
+            [[balloon]]
+
+            [api]
+            enabled = false
+            "#,
+        )
+        .unwrap();
+        let config = VMMConfig::try_from(&file).unwrap();
+        assert_eq!(config.memory_config, MemoryConfig { size_mib: 512 });
+        assert_eq!(config.kernel_config.load_addr, 42);
+        assert_eq!(
+            config.block_config,
+            vec![BlockConfig {
+                path: PathBuf::from("/foo/rootfs")
+            }]
+        );
+        assert!(config.net_config.is_empty());
+        assert_eq!(config.balloon_config, vec![BalloonConfig::default()]);
+        assert!(!config.api_config.enabled);
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        // Unknown sections and fields are rejected.
+        assert!(matches!(
+            ConfigFile::from_json(r#"{ "kernel": { "path": "/foo" }, "foo": {} }"#),
+            Err(Error::Json(_))
+        ));
+        assert!(matches!(
+            ConfigFile::from_toml("[kernel]\npath = \"/foo\"\nfoo = 1\n"),
+            Err(Error::Toml(_))
+        ));
+
+        let convert = |json: &str| VMMConfig::try_from(&ConfigFile::from_json(json).unwrap());
+
+        // The conversion errors are the ones of the command line strings.
+        assert_eq!(
+            convert(r#"{ "kernel": { "cmdline": "foo" } }"#).unwrap_err(),
+            ConversionError::ParseKernel("Missing required argument: path".to_string())
+        );
+        assert_eq!(
+            convert(r#"{ "kernel": { "path": "/foo" }, "block": [{ "path": "" }] }"#).unwrap_err(),
+            ConversionError::ParseBlock("Missing required argument: path".to_string())
+        );
+        assert_eq!(
+            convert(r#"{ "kernel": { "path": "/foo" }, "net": [{}] }"#).unwrap_err(),
+            ConversionError::ParseNet("Missing required argument: tap".to_string())
+        );
+        assert_eq!(
+            convert(r#"{ "kernel": { "path": "/foo" }, "balloon": [{ "amount_mib": 16777216 }] }"#)
+                .unwrap_err(),
+            ConversionError::ParseBalloon("Param 'amount_mib' is too large".to_string())
+        );
+        assert!(matches!(
+            convert(r#"{ "kernel": { "path": "/foo" }, "vcpu": { "num": 0 } }"#),
+            Err(ConversionError::ParseVcpus(_))
+        ));
+        assert!(matches!(
+            convert(r#"{ "kernel": { "path": "/foo" }, "api": { "socket_path": "" } }"#),
+            Err(ConversionError::ParseApi(_))
+        ));
+        assert!(matches!(
+            convert(r#"{ "kernel": { "path": "/foo" }, "metrics": { "flush_interval_s": 0 } }"#),
+            Err(ConversionError::ParseMetrics(_))
+        ));
+        assert!(matches!(
+            convert(r#"{ "kernel": { "path": "/foo" }, "log": { "level": "loud" } }"#),
+            Err(ConversionError::ParseLog(_))
+        ));
+        assert_eq!(
+            convert(r#"{ "kernel": { "path": "/foo" }, "serial": { "backend": "unix" } }"#)
+                .unwrap_err(),
+            ConversionError::ParseSerial("Missing required argument: path".to_string())
+        );
+        assert!(matches!(
+            convert(r#"{ "kernel": { "path": "/foo" }, "serial": { "backend": "tty" } }"#),
+            Err(ConversionError::ParseSerial(_))
+        ));
+        assert!(matches!(
+            convert(r#"{ "kernel": { "path": "/foo" }, "reboot": "halt" }"#),
+            Err(ConversionError::ParseReboot(_))
+        ));
+        let long_cmdline = format!(
+            r#"{{ "kernel": {{ "path": "/foo", "cmdline": "{}" }} }}"#,
+            "a".repeat(5000)
+        );
+        assert_eq!(
+            convert(&long_cmdline).unwrap_err(),
+            ConversionError::ParseKernel("Kernel cmdline capacity error".to_string())
+        );
+
+        // No kernel at all.
+        assert!(convert("{}").is_err());
+    }
+}
@@ -0,0 +1,493 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Control socket of a running VMM.
+//!
+//! The server listens on a Unix domain socket. Clients send newline delimited JSON requests
+//! (see [`protocol`]) and get one JSON response line back for each request. A connection can
+//! carry any number of requests, until it is attached to the serial console.
+//!
+//! In pre-boot mode, the server first only takes the commands describing the VM, until a
+//! `start` command boots it.
+
+mod preboot;
+pub mod protocol;
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex, Once};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use log::{error, warn};
+use serde_json::{json, Value};
+use vmm::{MigrationAddress, VMMConfig, Vmm};
+
+use preboot::PrebootConfig;
+use protocol::{Command, Error, ErrorCode, Request, Response};
+
+/// Result of running a control command.
+pub type CommandResult = std::result::Result<Option<Value>, Error>;
+
+// Sockets of the servers still alive, removed if the process panics.
+static SOCKETS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+static PANIC_HOOK: Once = Once::new();
+
+/// Control socket server.
+pub struct ControlServer {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl ControlServer {
+    /// Binds the control socket at `path`.
+    ///
+    /// A socket left behind by a VMM that did not exit cleanly is replaced, but binding fails
+    /// if another VMM still listens on `path`, or if `path` is not a socket. The socket is
+    /// removed if the process panics while the server is alive; the other ways out of the VMM
+    /// remove it with [`remove_socket`].
+    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        remove_stale_socket(&path)?;
+        let listener = UnixListener::bind(&path)?;
+        remove_on_panic(&path);
+        Ok(ControlServer { listener, path })
+    }
+
+    /// Serves the pre-boot commands until a `start` command boots the VM, and returns it.
+    ///
+    /// The VM is described by the `put_*` commands, starting from `config`, then built by
+    /// `boot`. If the configuration is incomplete, the error goes back to the client, which
+    /// can fix it and try again. Connections are served one after the other, and the one that
+    /// started the VM keeps being served against it.
+    pub fn configure<F>(&self, config: VMMConfig, mut boot: F) -> io::Result<Arc<Mutex<Vmm>>>
+    where
+        F: FnMut(VMMConfig) -> std::result::Result<Vmm, String>,
+    {
+        let mut preboot = PrebootConfig::new(config);
+        for stream in self.listener.incoming() {
+            let mut reader = match stream {
+                Ok(stream) => BufReader::new(stream),
+                Err(e) => {
+                    error!("Failed to accept control connection: {}", e);
+                    continue;
+                }
+            };
+            match handle_preboot_connection(&mut reader, &mut preboot, &mut boot, &self.path) {
+                Ok(Some(vmm)) => {
+                    let vmm = Arc::new(Mutex::new(vmm));
+                    let connection_vmm = vmm.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = handle_connection(reader, &connection_vmm) {
+                            warn!("Control connection failed: {}", e);
+                        }
+                    });
+                    return Ok(vmm);
+                }
+                Ok(None) => {}
+                Err(e) => warn!("Control connection failed: {}", e),
+            }
+        }
+        // `incoming` never runs out.
+        unreachable!()
+    }
+
+    /// Starts accepting connections on a new thread.
+    ///
+    /// Each connection is served by its own thread, and commands are run against `vmm`.
+    pub fn start(self, vmm: Arc<Mutex<Vmm>>) -> io::Result<JoinHandle<()>> {
+        thread::Builder::new()
+            .name("control".to_string())
+            .spawn(move || {
+                for stream in self.listener.incoming() {
+                    match stream {
+                        Ok(stream) => {
+                            let vmm = vmm.clone();
+                            thread::spawn(move || {
+                                let reader = BufReader::new(stream);
+                                if let Err(e) = handle_connection(reader, &vmm) {
+                                    warn!("Control connection failed: {}", e);
+                                }
+                            });
+                        }
+                        Err(e) => error!("Failed to accept control connection: {}", e),
+                    }
+                }
+            })
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        let mut sockets = SOCKETS.lock().unwrap();
+        if let Some(index) = sockets.iter().position(|path| *path == self.path) {
+            sockets.remove(index);
+        }
+    }
+}
+
+// Panics abort the process, so nothing else gets the chance to clean up. A single hook, installed
+// along with the first server, removes the sockets of all the servers still alive.
+fn remove_on_panic(path: &Path) {
+    PANIC_HOOK.call_once(|| {
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            // Do not wait on, or panic again because of, a server being bound or dropped.
+            if let Ok(sockets) = SOCKETS.try_lock() {
+                sockets.iter().for_each(remove_socket);
+            }
+            default_hook(info);
+        }));
+    });
+    SOCKETS.lock().unwrap().push(path.to_path_buf());
+}
+
+// Serves pre-boot commands on one connection. Returns the VM once started, with `reader`
+// positioned after the `start` command.
+fn handle_preboot_connection<F>(
+    reader: &mut BufReader<UnixStream>,
+    preboot: &mut PrebootConfig,
+    boot: &mut F,
+    path: &Path,
+) -> io::Result<Option<Vmm>>
+where
+    F: FnMut(VMMConfig) -> std::result::Result<Vmm, String>,
+{
+    let mut writer = reader.get_ref().try_clone()?;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let mut vmm = None;
+        let mut exit_code = None;
+        let response = match Request::parse(&line) {
+            Ok(request) => match request.command {
+                Command::Start => match preboot.build() {
+                    Ok(config) => match boot(config) {
+                        Ok(started) => {
+                            vmm = Some(started);
+                            Response::ok(None)
+                        }
+                        // Building the VM registers its devices with the event manager, so a
+                        // failure leaves nothing to retry with.
+                        Err(e) => {
+                            exit_code = Some(1);
+                            Response::error(Error::new(ErrorCode::Internal, e))
+                        }
+                    },
+                    Err(e) => Response::error(e),
+                },
+                Command::Shutdown => {
+                    exit_code = Some(0);
+                    Response::ok(None)
+                }
+                command => Response::from(preboot.handle_command(command)),
+            },
+            Err(e) => Response::error(e),
+        };
+        send_response(&mut writer, &response)?;
+
+        if let Some(code) = exit_code {
+            remove_socket(path);
+            process::exit(code);
+        }
+        if vmm.is_some() {
+            return Ok(vmm);
+        }
+    }
+}
+
+fn handle_connection(reader: BufReader<UnixStream>, vmm: &Mutex<Vmm>) -> io::Result<()> {
+    let mut writer = reader.get_ref().try_clone()?;
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let (response, shutdown) = match Request::parse(&line) {
+            Ok(request) if request.command == Command::AttachConsole => {
+                send_response(&mut writer, &Response::ok(None))?;
+                // The connection carries the console from now on. Input sent before the
+                // response arrived may be lost.
+                return vmm
+                    .lock()
+                    .unwrap()
+                    .attach_console(writer)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)));
+            }
+            Ok(request) => {
+                let command = request.command;
+                let shutdown = command == Command::Shutdown;
+                // A migrated VM runs on the destination from now on.
+                let migrate = matches!(command, Command::Migrate { .. });
+                let result = handle_command(vmm, command);
+                let shutdown = shutdown || (migrate && result.is_ok());
+                (Response::from(result), shutdown)
+            }
+            Err(e) => (Response::error(e), false),
+        };
+
+        send_response(&mut writer, &response)?;
+
+        if shutdown {
+            // The reply is already out, so the client knows the VMM is going away on purpose.
+            // The main thread tears it down once the event loop exits.
+            return vmm
+                .lock()
+                .unwrap()
+                .request_exit(0)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)));
+        }
+    }
+    Ok(())
+}
+
+/// Removes the control socket at `path`, if any.
+pub fn remove_socket<P: AsRef<Path>>(path: P) {
+    let _ = fs::remove_file(path);
+}
+
+// Removes the socket at `path` if no VMM listens on it anymore.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        ));
+    }
+    match UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("Another VMM listens on {}", path.display()),
+        )),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path),
+        Err(e) => Err(e),
+    }
+}
+
+fn send_response(writer: &mut UnixStream, response: &Response) -> io::Result<()> {
+    let mut reply =
+        serde_json::to_vec(response).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    reply.push(b'\n');
+    writer.write_all(&reply)?;
+    writer.flush()
+}
+
+fn handle_command(vmm: &Mutex<Vmm>, command: Command) -> CommandResult {
+    match command {
+        Command::Balloon { num_pages } => {
+            let num_pages = u32::try_from(num_pages).map_err(|_| {
+                Error::new(ErrorCode::InvalidRequest, "num_pages must fit in 32 bits")
+            })?;
+            if vmm.lock().unwrap().change_balloon_config(num_pages) {
+                Ok(None)
+            } else {
+                Err(Error::new(
+                    ErrorCode::DeviceNotFound,
+                    "The VM does not have a balloon device",
+                ))
+            }
+        }
+        Command::BalloonSize => {
+            let size = vmm.lock().unwrap().balloon_size().ok_or_else(|| {
+                Error::new(
+                    ErrorCode::DeviceNotFound,
+                    "The VM does not have a balloon device",
+                )
+            })?;
+            // 256 pages of 4 KiB make a MiB.
+            Ok(Some(json!({
+                "target_mib": size.target_pages >> 8,
+                "actual_mib": size.actual_pages >> 8,
+                "target_pages": size.target_pages,
+                "actual_pages": size.actual_pages,
+            })))
+        }
+        Command::BalloonStats => {
+            let sample = vmm.lock().unwrap().balloon_stats().ok_or_else(|| {
+                Error::new(
+                    ErrorCode::DeviceNotFound,
+                    "The VM does not have a balloon device",
+                )
+            })?;
+            let sample = sample.ok_or_else(|| {
+                Error::new(
+                    ErrorCode::InvalidState,
+                    "The guest did not report memory statistics yet",
+                )
+            })?;
+            Ok(Some(json!({
+                "stats": sample.stats,
+                "age_ms": sample.age().as_millis() as u64,
+            })))
+        }
+        Command::Pause => vmm
+            .lock()
+            .unwrap()
+            .pause()
+            .map(|_| None)
+            .map_err(internal_error),
+        Command::Resume => vmm
+            .lock()
+            .unwrap()
+            .resume()
+            .map(|_| None)
+            .map_err(internal_error),
+        Command::Snapshot {
+            state_path,
+            mem_path,
+            diff,
+        } => vmm
+            .lock()
+            .unwrap()
+            .snapshot(&state_path, &mem_path, diff)
+            .map(|_| None)
+            .map_err(internal_error),
+        Command::Metrics => Ok(Some(json!(vmm.lock().unwrap().metrics()))),
+        Command::Describe => Ok(Some(json!(vmm.lock().unwrap().describe()))),
+        Command::StartDirtyLog { slots } => vmm
+            .lock()
+            .unwrap()
+            .start_dirty_log(slots.as_deref())
+            .map(|_| None)
+            .map_err(|e| match e {
+                vmm::Error::InvalidMemorySlot(slot) => Error::new(
+                    ErrorCode::InvalidRequest,
+                    format!("Invalid memory slot {}", slot),
+                ),
+                e => internal_error(e),
+            }),
+        Command::StopDirtyLog => vmm
+            .lock()
+            .unwrap()
+            .stop_dirty_log()
+            .map(|_| None)
+            .map_err(internal_error),
+        Command::DirtyLog => {
+            let log = vmm.lock().unwrap().dirty_log().map_err(internal_error)?;
+            let dirty_pages: usize = log.iter().map(|slot| slot.dirty_pages().count()).sum();
+            // Bit `n` of the bitmap of a slot stands for the page at `guest_phys_addr` plus
+            // `n` pages.
+            Ok(Some(json!({
+                "page_size": vmm::DIRTY_PAGE_SIZE,
+                "dirty_pages": dirty_pages,
+                "slots": log,
+            })))
+        }
+        Command::Migrate { destination } => {
+            let addr = MigrationAddress::try_from(destination.as_str())
+                .map_err(|e| Error::new(ErrorCode::InvalidRequest, e))?;
+            vmm.lock()
+                .unwrap()
+                .migrate(&addr)
+                .map(|_| None)
+                .map_err(internal_error)
+        }
+        Command::DetachConsole => vmm
+            .lock()
+            .unwrap()
+            .detach_console()
+            .map(|_| None)
+            .map_err(internal_error),
+        Command::ConsoleLog => {
+            let output = vmm.lock().unwrap().console_log();
+            Ok(Some(json!({ "output": String::from_utf8_lossy(&output) })))
+        }
+        Command::SendCtrlAltDel { timeout_s } => {
+            let timeout = match timeout_s {
+                Some(0) => {
+                    return Err(Error::new(
+                        ErrorCode::InvalidRequest,
+                        "timeout_s must be at least 1",
+                    ))
+                }
+                timeout_s => timeout_s.map(Duration::from_secs),
+            };
+            if vmm
+                .lock()
+                .unwrap()
+                .send_ctrl_alt_del(timeout)
+                .map_err(internal_error)?
+            {
+                Ok(None)
+            } else {
+                Err(Error::new(
+                    ErrorCode::DeviceNotFound,
+                    "The VM does not have a keyboard to press Ctrl-Alt-Del on",
+                ))
+            }
+        }
+        // Carried out by the connection handler once the reply is sent.
+        Command::AttachConsole | Command::Shutdown => Ok(None),
+        Command::PutMachineConfig { .. }
+        | Command::PutKernel { .. }
+        | Command::PutDrive { .. }
+        | Command::PutNetwork { .. }
+        | Command::PutBalloon { .. }
+        | Command::Start => Err(Error::new(
+            ErrorCode::InvalidState,
+            "The VM is already started",
+        )),
+    }
+}
+
+// `vmm::Error` only implements `Debug`.
+fn internal_error<E: fmt::Debug>(e: E) -> Error {
+    Error::new(ErrorCode::Internal, format!("{:?}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+
+    #[test]
+    fn test_bind_stale_socket() {
+        let path = env::temp_dir().join(format!("control_{}.sock", process::id()));
+        remove_socket(&path);
+
+        let server = ControlServer::bind(&path).unwrap();
+        // Someone listens on the socket.
+        assert_eq!(
+            ControlServer::bind(&path).err().unwrap().kind(),
+            io::ErrorKind::AddrInUse
+        );
+
+        // The socket file stays behind once nobody listens on it anymore.
+        drop(server);
+        assert!(path.exists());
+        let server = ControlServer::bind(&path).unwrap();
+        assert!(SOCKETS.lock().unwrap().contains(&path));
+        drop(server);
+        assert!(!SOCKETS.lock().unwrap().contains(&path));
+
+        // Other files are left alone.
+        remove_socket(&path);
+        fs::write(&path, "foo").unwrap();
+        assert_eq!(
+            ControlServer::bind(&path).err().unwrap().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        remove_socket(&path);
+    }
+}
@@ -0,0 +1,260 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Configuration of a VM put together over the control socket before it starts.
+
+use std::convert::TryFrom;
+use std::iter;
+
+use vmm::{
+    BalloonConfig, BlockConfig, ConversionError, KernelConfig, MemoryConfig, NetConfig, VMMConfig,
+    VcpuConfig,
+};
+
+use crate::config_file::{
+    BalloonSection, BlockSection, KernelSection, MemorySection, NetSection, VcpuSection,
+};
+
+use super::protocol::{Command, Error, ErrorCode};
+use super::CommandResult;
+
+/// Configuration of a VM that is not started yet.
+pub(super) struct PrebootConfig {
+    config: VMMConfig,
+    // Ids of the block and network devices, in the order of their configurations.
+    drive_ids: Vec<String>,
+    iface_ids: Vec<String>,
+}
+
+impl PrebootConfig {
+    /// Starts from `config`, which has the default VM and the control API configuration.
+    pub(super) fn new(config: VMMConfig) -> Self {
+        PrebootConfig {
+            config,
+            drive_ids: Vec::new(),
+            iface_ids: Vec::new(),
+        }
+    }
+
+    /// Runs a command updating the configuration.
+    ///
+    /// The arguments are checked with the same rules as the configuration file.
+    pub(super) fn handle_command(&mut self, command: Command) -> CommandResult {
+        match command {
+            Command::PutMachineConfig {
+                size_mib,
+                num_vcpus,
+            } => {
+                let memory =
+                    MemoryConfig::try_from(&MemorySection { size_mib }).map_err(invalid_config)?;
+                let vcpu = VcpuConfig::try_from(&VcpuSection { num: num_vcpus })
+                    .map_err(invalid_config)?;
+                self.config.memory_config = memory;
+                self.config.vcpu_config = vcpu;
+            }
+            Command::PutKernel {
+                path,
+                cmdline,
+                kernel_load_addr,
+            } => {
+                let section = KernelSection {
+                    path: Some(path),
+                    cmdline,
+                    kernel_load_addr,
+                };
+                self.config.kernel_config =
+                    KernelConfig::try_from(&section).map_err(invalid_config)?;
+            }
+            Command::PutDrive { drive_id, path } => {
+                let block = BlockConfig::try_from(&BlockSection { path: Some(path) })
+                    .map_err(invalid_config)?;
+                put_device(
+                    &mut self.config.block_config,
+                    &mut self.drive_ids,
+                    drive_id,
+                    block,
+                );
+            }
+            Command::PutNetwork { iface_id, tap } => {
+                let net =
+                    NetConfig::try_from(&NetSection { tap: Some(tap) }).map_err(invalid_config)?;
+                put_device(
+                    &mut self.config.net_config,
+                    &mut self.iface_ids,
+                    iface_id,
+                    net,
+                );
+            }
+            Command::PutBalloon {
+                amount_mib,
+                deflate_on_oom,
+                stats_polling_interval_s,
+            } => {
+                let section = BalloonSection {
+                    amount_mib,
+                    deflate_on_oom,
+                    stats_polling_interval_s,
+                };
+                self.config.balloon_config =
+                    vec![BalloonConfig::try_from(&section).map_err(invalid_config)?];
+            }
+            _ => {
+                return Err(Error::new(
+                    ErrorCode::InvalidState,
+                    "The VM is not started yet",
+                ))
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the configuration to start the VM with.
+    pub(super) fn build(&self) -> Result<VMMConfig, Error> {
+        if self.config.kernel_config.path.as_os_str().is_empty() {
+            return Err(Error::new(ErrorCode::InvalidRequest, "Missing kernel"));
+        }
+
+        // Devices are registered with the event manager as the VM is built, and cannot be
+        // taken back if a later one fails. Catch the most likely failure beforehand.
+        let paths = iter::once(&self.config.kernel_config.path)
+            .chain(self.config.block_config.iter().map(|block| &block.path));
+        for path in paths {
+            if !path.exists() {
+                return Err(Error::new(
+                    ErrorCode::InvalidRequest,
+                    format!("{} does not exist", path.display()),
+                ));
+            }
+        }
+
+        Ok(VMMConfig {
+            preboot: false,
+            ..self.config.clone()
+        })
+    }
+}
+
+// Replaces the device with the given `id`, or adds it after the others.
+fn put_device<T>(devices: &mut Vec<T>, ids: &mut Vec<String>, id: String, device: T) {
+    match ids.iter().position(|other| *other == id) {
+        Some(index) => devices[index] = device,
+        None => {
+            ids.push(id);
+            devices.push(device);
+        }
+    }
+}
+
+fn invalid_config(e: ConversionError) -> Error {
+    Error::new(ErrorCode::InvalidRequest, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_preboot_config() {
+        let mut preboot = PrebootConfig::new(VMMConfig {
+            preboot: true,
+            ..Default::default()
+        });
+
+        // The kernel is required.
+        assert_eq!(preboot.build().unwrap_err().code, ErrorCode::InvalidRequest);
+
+        // Any existing file does as a kernel here.
+        let kernel = env::current_exe().unwrap();
+        preboot
+            .handle_command(Command::PutKernel {
+                path: kernel.clone(),
+                cmdline: None,
+                kernel_load_addr: None,
+            })
+            .unwrap();
+        preboot
+            .handle_command(Command::PutMachineConfig {
+                size_mib: Some(1024),
+                num_vcpus: Some(2),
+            })
+            .unwrap();
+        preboot
+            .handle_command(Command::PutNetwork {
+                iface_id: "eth0".to_string(),
+                tap: "tap0".to_string(),
+            })
+            .unwrap();
+        preboot
+            .handle_command(Command::PutNetwork {
+                iface_id: "eth1".to_string(),
+                tap: "tap1".to_string(),
+            })
+            .unwrap();
+        // Putting a device again replaces it.
+        preboot
+            .handle_command(Command::PutNetwork {
+                iface_id: "eth0".to_string(),
+                tap: "tap2".to_string(),
+            })
+            .unwrap();
+        preboot
+            .handle_command(Command::PutBalloon {
+                amount_mib: Some(128),
+                deflate_on_oom: None,
+                stats_polling_interval_s: None,
+            })
+            .unwrap();
+
+        let config = preboot.build().unwrap();
+        assert!(!config.preboot);
+        assert_eq!(config.kernel_config.path, kernel);
+        assert_eq!(config.memory_config, MemoryConfig { size_mib: 1024 });
+        assert_eq!(config.vcpu_config, VcpuConfig { num: 2 });
+        assert_eq!(
+            config
+                .net_config
+                .iter()
+                .map(|net| net.tap_name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["tap2", "tap1"]
+        );
+        assert_eq!(
+            config.balloon_config,
+            vec![BalloonConfig {
+                amount_mib: 128,
+                ..Default::default()
+            }]
+        );
+
+        // Invalid values are rejected and leave the configuration as it was.
+        assert_eq!(
+            preboot
+                .handle_command(Command::PutMachineConfig {
+                    size_mib: None,
+                    num_vcpus: Some(0),
+                })
+                .unwrap_err()
+                .code,
+            ErrorCode::InvalidRequest
+        );
+        assert_eq!(preboot.build().unwrap().vcpu_config, VcpuConfig { num: 2 });
+
+        // Missing block device backends are caught before the VM is built.
+        preboot
+            .handle_command(Command::PutDrive {
+                drive_id: "root".to_string(),
+                path: PathBuf::from("/foo/rootfs"),
+            })
+            .unwrap();
+        assert_eq!(preboot.build().unwrap_err().code, ErrorCode::InvalidRequest);
+
+        // Commands for a running VM.
+        assert_eq!(
+            preboot.handle_command(Command::Pause).unwrap_err().code,
+            ErrorCode::InvalidState
+        );
+    }
+}
@@ -0,0 +1,505 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Wire format of the control socket.
+//!
+//! Requests and responses are single JSON objects, each terminated by a newline. A request
+//! carries the protocol `version`, the `command` to run and the command arguments as sibling
+//! fields:
+//!
+//! ```text
+//! {"version": 1, "command": "balloon", "num_pages": 262144}
+//! ```
+//!
+//! Every request gets exactly one response, which reports whether the command succeeded:
+//!
+//! ```text
+//! {"version": 1, "status": "ok"}
+//! {"version": 1, "status": "error", "code": "device_not_found", "message": "..."}
+//! ```
+
+use std::fmt;
+use std::path::PathBuf;
+use std::result;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Version of the control protocol implemented by this VMM.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Commands accepted on the control socket.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Command {
+    /// Set the balloon target, expressed in 4 KiB pages.
+    Balloon {
+        /// Number of pages the guest should give back to the host.
+        num_pages: u64,
+    },
+    /// Report the target size of the balloon and the size the guest reached, in MiB and in
+    /// 4 KiB pages.
+    BalloonSize,
+    /// Report the memory statistics the guest last sent through the balloon device, and how
+    /// long ago it sent them.
+    BalloonStats,
+    /// Park all vCPUs. Replies once none of them runs guest code anymore.
+    Pause,
+    /// Let the vCPUs of a paused VM run again.
+    Resume,
+    /// Pause the VM and save its state. The VM stays paused afterwards.
+    Snapshot {
+        /// Path of the file receiving the VM and device state.
+        state_path: PathBuf,
+        /// Path of the file receiving the contents of guest memory.
+        mem_path: PathBuf,
+        /// Only save the guest pages written since the previous snapshot, which needs dirty
+        /// page logging to be enabled on all memory slots since then.
+        #[serde(default)]
+        diff: bool,
+    },
+    /// Report the VMM metrics.
+    Metrics,
+    /// Report the vCPUs, guest memory regions and devices of the VM, the final kernel command
+    /// line and the run state.
+    Describe,
+    /// Start logging the guest pages written by the vCPUs and by the devices.
+    StartDirtyLog {
+        /// Memory slots to log. All of them when missing.
+        #[serde(default)]
+        slots: Option<Vec<u32>>,
+    },
+    /// Stop logging written guest pages, on all memory slots.
+    StopDirtyLog,
+    /// Report the guest pages written since logging started or since the previous report,
+    /// and clear them.
+    DirtyLog,
+    /// Attach the connection to the serial console, in place of the current client. After
+    /// the response, the connection carries the recent output of the guest followed by the
+    /// console output, and what the client sends is the console input. It ends when the client
+    /// disconnects, or when another client attaches or detaches the console.
+    AttachConsole,
+    /// Detach the current client of the serial console, if any.
+    DetachConsole,
+    /// Report the recent output of the guest on the serial console, up to 64 KiB.
+    ConsoleLog,
+    /// Move the VM to another VMM started with `--incoming`, then exit.
+    Migrate {
+        /// Address the destination VMM listens on, as `unix:<path>` or `tcp:<ip>:<port>`.
+        destination: String,
+    },
+    /// Set the guest memory size and the number of vCPUs of a VM that is not started yet.
+    /// Missing fields get their default value.
+    PutMachineConfig {
+        /// Guest memory size in MiB.
+        #[serde(default)]
+        size_mib: Option<u32>,
+        /// Number of vCPUs.
+        #[serde(default)]
+        num_vcpus: Option<u8>,
+    },
+    /// Set the guest kernel of a VM that is not started yet.
+    PutKernel {
+        /// Path to the kernel image.
+        path: PathBuf,
+        /// Kernel command line. The default one when missing.
+        #[serde(default)]
+        cmdline: Option<String>,
+        /// Address where the kernel is loaded. The default one when missing.
+        #[serde(default)]
+        kernel_load_addr: Option<u64>,
+    },
+    /// Add a block device to a VM that is not started yet, or replace the one with the same
+    /// id. The first block device added is the root device.
+    PutDrive {
+        /// Id of the block device, only used to replace it.
+        drive_id: String,
+        /// Path to the block device backend.
+        path: PathBuf,
+    },
+    /// Add a network device to a VM that is not started yet, or replace the one with the
+    /// same id.
+    PutNetwork {
+        /// Id of the network device, only used to replace it.
+        iface_id: String,
+        /// Name of the tap device.
+        tap: String,
+    },
+    /// Give a balloon device to a VM that is not started yet. Missing fields get their
+    /// default value.
+    PutBalloon {
+        /// Memory the guest gives back to the host once it boots, in MiB.
+        #[serde(default)]
+        amount_mib: Option<u32>,
+        /// Whether the guest takes memory back from the balloon when it runs out of it.
+        #[serde(default)]
+        deflate_on_oom: Option<bool>,
+        /// Seconds between two requests for the memory statistics of the guest.
+        #[serde(default)]
+        stats_polling_interval_s: Option<u64>,
+    },
+    /// Start the VM configured with the `put_*` commands.
+    Start,
+    /// Stop the vCPUs and exit the VMM.
+    Shutdown,
+    /// Ask the guest to shut down by pressing Ctrl-Alt-Del (x86_64 only). The VMM exits once
+    /// the guest shut down or rebooted.
+    SendCtrlAltDel {
+        /// Seconds the guest gets to shut down before its vCPUs are stopped. It is never
+        /// stopped when missing.
+        #[serde(default)]
+        timeout_s: Option<u64>,
+    },
+}
+
+/// A request received on the control socket.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct Request {
+    /// Protocol version spoken by the client.
+    pub version: u32,
+    /// Command to run.
+    #[serde(flatten)]
+    pub command: Command,
+}
+
+impl Request {
+    /// Creates a request for `command` using the current protocol version.
+    pub fn new(command: Command) -> Self {
+        Request {
+            version: PROTOCOL_VERSION,
+            command,
+        }
+    }
+
+    /// Parses one line received on the control socket.
+    ///
+    /// The version is checked before the command, so that clients speaking a newer protocol
+    /// get `UnsupportedVersion` instead of a complaint about a command they did not misspell.
+    pub fn parse(input: &str) -> result::Result<Self, Error> {
+        let value: Value =
+            serde_json::from_str(input).map_err(|e| Error::new(ErrorCode::InvalidRequest, e))?;
+
+        let version = value
+            .get("version")
+            .and_then(Value::as_u64)
+            .ok_or_else(|| Error::new(ErrorCode::InvalidRequest, "Missing protocol version"))?;
+        if version != u64::from(PROTOCOL_VERSION) {
+            return Err(Error::new(
+                ErrorCode::UnsupportedVersion,
+                format!(
+                    "Unsupported protocol version {}, expected {}",
+                    version, PROTOCOL_VERSION
+                ),
+            ));
+        }
+
+        serde_json::from_value(value).map_err(|e| Error::new(ErrorCode::InvalidRequest, e))
+    }
+}
+
+/// Machine readable reason for a failed request.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The request is not valid JSON, or does not describe a known command.
+    InvalidRequest,
+    /// The request uses a protocol version this VMM does not implement.
+    UnsupportedVersion,
+    /// The command targets a device the VM does not have.
+    DeviceNotFound,
+    /// The command cannot run before the VM starts, or after it did.
+    InvalidState,
+    /// The command is valid, but the VMM failed to carry it out.
+    Internal,
+}
+
+/// Error reported back to the client.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct Error {
+    /// Category of the failure.
+    pub code: ErrorCode,
+    /// Human readable description of the failure.
+    pub message: String,
+}
+
+impl Error {
+    /// Creates an error with the given `code` and `message`.
+    pub fn new<T: fmt::Display>(code: ErrorCode, message: T) -> Self {
+        Error {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+
+/// Outcome of a request.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Status {
+    /// The command succeeded. Commands that return information place it in `data`.
+    Ok {
+        /// Command specific reply.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        data: Option<Value>,
+    },
+    /// The command failed.
+    Error(Error),
+}
+
+/// A response sent on the control socket.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct Response {
+    /// Protocol version spoken by the VMM.
+    pub version: u32,
+    /// Outcome of the request.
+    #[serde(flatten)]
+    pub status: Status,
+}
+
+impl Response {
+    /// Creates a successful response carrying the optional `data`.
+    pub fn ok(data: Option<Value>) -> Self {
+        Response {
+            version: PROTOCOL_VERSION,
+            status: Status::Ok { data },
+        }
+    }
+
+    /// Creates a response reporting `error`.
+    pub fn error(error: Error) -> Self {
+        Response {
+            version: PROTOCOL_VERSION,
+            status: Status::Error(error),
+        }
+    }
+}
+
+impl From<result::Result<Option<Value>, Error>> for Response {
+    fn from(res: result::Result<Option<Value>, Error>) -> Self {
+        match res {
+            Ok(data) => Response::ok(data),
+            Err(e) => Response::error(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_request() {
+        assert_eq!(
+            Request::parse(r#"{"version": 1, "command": "balloon", "num_pages": 42}"#).unwrap(),
+            Request::new(Command::Balloon { num_pages: 42 })
+        );
+        assert_eq!(
+            Request::parse(r#"{"version": 1, "command": "balloon_stats"}"#).unwrap(),
+            Request::new(Command::BalloonStats)
+        );
+        assert_eq!(
+            Request::parse(r#"{"version": 1, "command": "balloon_size"}"#).unwrap(),
+            Request::new(Command::BalloonSize)
+        );
+        assert_eq!(
+            Request::parse(r#"{"version": 1, "command": "put_balloon", "amount_mib": 64}"#)
+                .unwrap(),
+            Request::new(Command::PutBalloon {
+                amount_mib: Some(64),
+                deflate_on_oom: None,
+                stats_polling_interval_s: None,
+            })
+        );
+        assert_eq!(
+            Request::parse(r#"{"version": 1, "command": "shutdown"}"#).unwrap(),
+            Request::new(Command::Shutdown)
+        );
+        assert_eq!(
+            Request::parse(r#"{"version": 1, "command": "pause"}"#).unwrap(),
+            Request::new(Command::Pause)
+        );
+        assert_eq!(
+            Request::parse(r#"{"version": 1, "command": "resume"}"#).unwrap(),
+            Request::new(Command::Resume)
+        );
+        assert_eq!(
+            Request::parse(r#"{"version": 1, "command": "metrics"}"#).unwrap(),
+            Request::new(Command::Metrics)
+        );
+        assert_eq!(
+            Request::parse(r#"{"version": 1, "command": "describe"}"#).unwrap(),
+            Request::new(Command::Describe)
+        );
+        assert_eq!(
+            Request::parse(r#"{"version": 1, "command": "start_dirty_log"}"#).unwrap(),
+            Request::new(Command::StartDirtyLog { slots: None })
+        );
+        assert_eq!(
+            Request::parse(r#"{"version": 1, "command": "start_dirty_log", "slots": [1]}"#)
+                .unwrap(),
+            Request::new(Command::StartDirtyLog {
+                slots: Some(vec![1])
+            })
+        );
+        assert_eq!(
+            Request::parse(r#"{"version": 1, "command": "dirty_log"}"#).unwrap(),
+            Request::new(Command::DirtyLog)
+        );
+        assert_eq!(
+            Request::parse(
+                r#"{"version": 1, "command": "snapshot", "state_path": "/tmp/vm.state", "mem_path": "/tmp/vm.mem"}"#
+            )
+            .unwrap(),
+            Request::new(Command::Snapshot {
+                state_path: PathBuf::from("/tmp/vm.state"),
+                mem_path: PathBuf::from("/tmp/vm.mem"),
+                diff: false,
+            })
+        );
+        assert_eq!(
+            Request::parse(
+                r#"{"version": 1, "command": "snapshot", "state_path": "/tmp/vm.state", "mem_path": "/tmp/vm.mem", "diff": true}"#
+            )
+            .unwrap(),
+            Request::new(Command::Snapshot {
+                state_path: PathBuf::from("/tmp/vm.state"),
+                mem_path: PathBuf::from("/tmp/vm.mem"),
+                diff: true,
+            })
+        );
+        assert_eq!(
+            Request::parse(r#"{"version": 1, "command": "attach_console"}"#).unwrap(),
+            Request::new(Command::AttachConsole)
+        );
+        assert_eq!(
+            Request::parse(r#"{"version": 1, "command": "detach_console"}"#).unwrap(),
+            Request::new(Command::DetachConsole)
+        );
+        assert_eq!(
+            Request::parse(r#"{"version": 1, "command": "console_log"}"#).unwrap(),
+            Request::new(Command::ConsoleLog)
+        );
+        assert_eq!(
+            Request::parse(
+                r#"{"version": 1, "command": "migrate", "destination": "unix:/tmp/migrate.sock"}"#
+            )
+            .unwrap(),
+            Request::new(Command::Migrate {
+                destination: "unix:/tmp/migrate.sock".to_string(),
+            })
+        );
+        assert_eq!(
+            Request::parse(r#"{"version": 1, "command": "send_ctrl_alt_del"}"#).unwrap(),
+            Request::new(Command::SendCtrlAltDel { timeout_s: None })
+        );
+        assert_eq!(
+            Request::parse(r#"{"version": 1, "command": "send_ctrl_alt_del", "timeout_s": 30}"#)
+                .unwrap(),
+            Request::new(Command::SendCtrlAltDel {
+                timeout_s: Some(30)
+            })
+        );
+
+        assert_eq!(
+            Request::parse(r#"{"version": 1, "command": "put_machine_config", "num_vcpus": 2}"#)
+                .unwrap(),
+            Request::new(Command::PutMachineConfig {
+                size_mib: None,
+                num_vcpus: Some(2),
+            })
+        );
+        assert_eq!(
+            Request::parse(r#"{"version": 1, "command": "put_kernel", "path": "/foo/bzImage"}"#)
+                .unwrap(),
+            Request::new(Command::PutKernel {
+                path: PathBuf::from("/foo/bzImage"),
+                cmdline: None,
+                kernel_load_addr: None,
+            })
+        );
+        assert_eq!(
+            Request::parse(
+                r#"{"version": 1, "command": "put_drive", "drive_id": "root", "path": "/foo/rootfs"}"#
+            )
+            .unwrap(),
+            Request::new(Command::PutDrive {
+                drive_id: "root".to_string(),
+                path: PathBuf::from("/foo/rootfs"),
+            })
+        );
+        assert_eq!(
+            Request::parse(r#"{"version": 1, "command": "start"}"#).unwrap(),
+            Request::new(Command::Start)
+        );
+
+        // Not JSON at all, e.g. the old `balloon <pages>` text protocol.
+        let err = Request::parse("balloon 42").unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidRequest);
+
+        // Missing version.
+        let err = Request::parse(r#"{"command": "shutdown"}"#).unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidRequest);
+
+        // The version is checked before the command.
+        let err = Request::parse(r#"{"version": 2, "command": "foo"}"#).unwrap_err();
+        assert_eq!(err.code, ErrorCode::UnsupportedVersion);
+
+        // Unknown command.
+        let err = Request::parse(r#"{"version": 1, "command": "foo"}"#).unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidRequest);
+
+        // Missing or malformed arguments.
+        let err = Request::parse(r#"{"version": 1, "command": "balloon"}"#).unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidRequest);
+        let err =
+            Request::parse(r#"{"version": 1, "command": "balloon", "num_pages": -1}"#).unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidRequest);
+        let err = Request::parse(r#"{"version": 1, "command": "snapshot", "state_path": "foo"}"#)
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidRequest);
+        let err = Request::parse(r#"{"version": 1, "command": "put_network", "iface_id": "eth0"}"#)
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidRequest);
+    }
+
+    #[test]
+    fn test_serialize_response() {
+        assert_eq!(
+            serde_json::to_value(Response::ok(None)).unwrap(),
+            json!({"version": 1, "status": "ok"})
+        );
+        assert_eq!(
+            serde_json::to_value(Response::ok(Some(json!({"foo": 42})))).unwrap(),
+            json!({"version": 1, "status": "ok", "data": {"foo": 42}})
+        );
+        assert_eq!(
+            serde_json::to_value(Response::error(Error::new(
+                ErrorCode::DeviceNotFound,
+                "No balloon device"
+            )))
+            .unwrap(),
+            json!({
+                "version": 1,
+                "status": "error",
+                "code": "device_not_found",
+                "message": "No balloon device"
+            })
+        );
+
+        // Clients use the same types to decode responses.
+        let response = Response::error(Error::new(ErrorCode::Internal, "foo"));
+        let encoded = serde_json::to_string(&response).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Response>(&encoded).unwrap(),
+            response
+        );
+    }
+}
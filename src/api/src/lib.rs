@@ -8,6 +8,11 @@ use std::result;
 use clap::{App, Arg};
 use vmm::VMMConfig;
 
+use config_file::ConfigFile;
+
+pub mod config_file;
+pub mod control;
+
 /// Command line parser.
 pub struct Cli;
 
@@ -34,29 +39,128 @@ impl Cli {
             .arg(
                 Arg::with_name("kernel")
                     .long("kernel")
-                    .required(true)
+                    .required_unless_present_any(&["restore", "incoming", "config-file", "preboot"])
                     .takes_value(true)
                     .help("Kernel configuration.\n\tFormat: \"path=<string>[,cmdline=<string>,kernel_load_addr=<u64>]\""),
             )
             .arg(
                 Arg::with_name("net")
                     .long("net")
+                    .multiple_occurrences(true)
                     .takes_value(true)
-                    .help("Network device configuration. \n\tFormat: \"tap=<string>\"")
+                    .help("Network device configuration, can be repeated. \n\tFormat: \"tap=<string>\"")
             )
             .arg(
                 Arg::with_name("block")
                     .long("block")
+                    .multiple_occurrences(true)
                     .required(false)
                     .takes_value(true)
-                    .help("Block device configuration. \n\tFormat: \"path=<string>\"")
+                    .help("Block device configuration, can be repeated. The first one is the root device. \n\tFormat: \"path=<string>\"")
             )
             .arg(
                 Arg::with_name("balloon")
                     .long("balloon")
+                    .multiple_occurrences(true)
+                    .required(false)
+                    .takes_value(true)
+                    .help("Balloon device configuration. \n\tFormat: \"amount_mib=<u32>,deflate_on_oom=<bool>,stats_polling_interval_s=<u64>\"")
+            )
+            .arg(
+                Arg::with_name("restore")
+                    .long("restore")
+                    .required(false)
+                    .takes_value(true)
+                    .conflicts_with_all(&["memory", "vcpu", "kernel", "net", "block", "balloon"])
+                    .help("Restore the VM from a snapshot instead of booting a kernel. \n\tFormat: \"state_path=<string>,mem_path=<string>[,lazy=<bool>]\"")
+            )
+            .arg(
+                Arg::with_name("incoming")
+                    .long("incoming")
+                    .required(false)
+                    .takes_value(true)
+                    .conflicts_with_all(&["memory", "vcpu", "kernel", "net", "block", "balloon", "restore"])
+                    .help("Wait for a VM migrated from another VMM instead of booting a kernel. \n\tFormat: \"unix:<path>\" or \"tcp:<ip>:<port>\"")
+            )
+            .arg(
+                Arg::with_name("config-file")
+                    .long("config-file")
+                    .required(false)
+                    .takes_value(true)
+                    .conflicts_with_all(&["memory", "vcpu", "kernel", "net", "block", "balloon", "restore", "incoming"])
+                    .help("Read the whole VM configuration from a JSON file, or a TOML file if its name ends with \".toml\".")
+            )
+            .arg(
+                Arg::with_name("preboot")
+                    .long("preboot")
+                    .required(false)
+                    .takes_value(false)
+                    .conflicts_with_all(&["memory", "vcpu", "kernel", "net", "block", "balloon", "restore", "incoming", "config-file"])
+                    .help("Start with only the control socket, and wait for the VM configuration and a start command on it.")
+            )
+            .arg(
+                Arg::with_name("serial")
+                    .long("serial")
+                    .required(false)
+                    .takes_value(true)
+                    .conflicts_with("config-file")
+                    .help("Backend of the serial console: the terminal, a log file, a Unix socket clients attach to, or a new PTY. \n\tFormat: \"stdio\", \"file:<path>\", \"unix:<path>\" or \"pty\"\n\tDefault: \"stdio\"")
+            )
+            .arg(
+                Arg::with_name("reboot")
+                    .long("reboot")
+                    .required(false)
+                    .takes_value(true)
+                    .conflicts_with("config-file")
+                    .help("What to do when the guest reboots: exit, or boot the kernel again with the same devices. \n\tFormat: \"exit\" or \"restart\"\n\tDefault: \"exit\"")
+            )
+            .arg(
+                Arg::with_name("debug-exit")
+                    .long("debug-exit")
+                    .required(false)
+                    .takes_value(false)
+                    .conflicts_with("config-file")
+                    .help("Give the guest a device it exits the VMM with: writing a value to port 0xf4 (x86_64) or to the MMIO page of the device (aarch64) exits with (value << 1) | 1.")
+            )
+            .arg(
+                Arg::with_name("api-sock")
+                    .long("api-sock")
+                    .required(false)
+                    .takes_value(true)
+                    .conflicts_with("config-file")
+                    .help("Path of the control socket. \n\tDefault: \"/tmp/rust-vmm.sock\"")
+            )
+            .arg(
+                Arg::with_name("no-api")
+                    .long("no-api")
+                    .required(false)
+                    .takes_value(false)
+                    .conflicts_with_all(&["api-sock", "preboot", "config-file"])
+                    .help("Run without the control socket.")
+            )
+            .arg(
+                Arg::with_name("metrics")
+                    .long("metrics")
+                    .required(false)
+                    .takes_value(true)
+                    .conflicts_with("config-file")
+                    .help("Append the metrics to a file as JSON lines. \n\tFormat: \"path=<string>[,flush_interval_s=<u64>]\"")
+            )
+            .arg(
+                Arg::with_name("log-path")
+                    .long("log-path")
+                    .required(false)
+                    .takes_value(true)
+                    .conflicts_with("config-file")
+                    .help("Append the logs to a file instead of writing them to stderr.")
+            )
+            .arg(
+                Arg::with_name("log-level")
+                    .long("log-level")
                     .required(false)
                     .takes_value(true)
-                    .help("Balloon device configuration. \n\tFormat: \"path=<string>\"")
+                    .conflicts_with("config-file")
+                    .help("Levels of the logs, for all modules or by module. The levels are off, error, warn, info, debug and trace. \n\tFormat: \"<level>[,<module>=<level>...]\"\n\tDefault: \"info\"")
             );
 
         // Save the usage beforehand as a string, because `get_matches` consumes the `App`.
@@ -70,15 +174,36 @@ impl Cli {
             format!("Invalid command line arguments: {}", e)
         })?;
 
-        VMMConfig::builder()
+        if let Some(path) = matches.value_of("config-file") {
+            return ConfigFile::load(path)
+                .map_err(|e| format!("Invalid configuration file {}: {}", path, e));
+        }
+
+        let mut builder = VMMConfig::builder()
             .memory_config(matches.value_of("memory"))
             .kernel_config(matches.value_of("kernel"))
             .vcpu_config(matches.value_of("vcpu"))
-            .net_config(matches.value_of("net"))
-            .block_config(matches.value_of("block"))
-            .balloon_config(matches.value_of("balloon"))
-            .build()
-            .map_err(|e| format!("{:?}", e))
+            .restore_config(matches.value_of("restore"))
+            .incoming_config(matches.value_of("incoming"))
+            .serial_config(matches.value_of("serial"))
+            .reboot_policy(matches.value_of("reboot"))
+            .debug_exit(matches.is_present("debug-exit"))
+            .api_config(matches.value_of("api-sock"))
+            .disable_api(matches.is_present("no-api"))
+            .metrics_config(matches.value_of("metrics"))
+            .log_path(matches.value_of("log-path"))
+            .log_level(matches.value_of("log-level"))
+            .preboot(matches.is_present("preboot"));
+        for net in matches.values_of("net").into_iter().flatten() {
+            builder = builder.net_config(Some(net));
+        }
+        for block in matches.values_of("block").into_iter().flatten() {
+            builder = builder.block_config(Some(block));
+        }
+        for balloon in matches.values_of("balloon").into_iter().flatten() {
+            builder = builder.balloon_config(Some(balloon));
+        }
+        builder.build().map_err(|e| format!("{:?}", e))
     }
 }
 
@@ -86,11 +211,18 @@ impl Cli {
 mod tests {
     use super::*;
 
+    use std::env;
+    use std::fs;
     use std::path::PathBuf;
+    use std::process;
 
     use linux_loader::cmdline::Cmdline;
 
-    use vmm::{KernelConfig, MemoryConfig, VcpuConfig, DEFAULT_KERNEL_LOAD_ADDR};
+    use vmm::{
+        ApiConfig, BalloonConfig, BlockConfig, KernelConfig, LogConfig, MemoryConfig,
+        MetricsConfig, MigrationAddress, NetConfig, RebootPolicy, RestoreConfig, SerialConfig,
+        VcpuConfig, DEFAULT_BALLOON_STATS_POLLING_INTERVAL_S, DEFAULT_KERNEL_LOAD_ADDR,
+    };
 
     #[test]
     fn test_launch() {
@@ -242,8 +374,18 @@ mod tests {
                 },
                 memory_config: MemoryConfig { size_mib: 128 },
                 vcpu_config: VcpuConfig { num: 1 },
-                block_config: None,
-                net_config: None,
+                block_config: vec![],
+                net_config: vec![],
+                balloon_config: vec![],
+                restore_config: None,
+                incoming_config: None,
+                api_config: ApiConfig::default(),
+                metrics_config: None,
+                log_config: LogConfig::default(),
+                serial_config: SerialConfig::Stdio,
+                reboot_policy: RebootPolicy::Exit,
+                debug_exit: false,
+                preboot: false,
             }
         );
 
@@ -258,9 +400,327 @@ mod tests {
                 },
                 memory_config: MemoryConfig { size_mib: 256 },
                 vcpu_config: VcpuConfig { num: 1 },
-                block_config: None,
-                net_config: None,
+                block_config: vec![],
+                net_config: vec![],
+                balloon_config: vec![],
+                restore_config: None,
+                incoming_config: None,
+                api_config: ApiConfig::default(),
+                metrics_config: None,
+                log_config: LogConfig::default(),
+                serial_config: SerialConfig::Stdio,
+                reboot_policy: RebootPolicy::Exit,
+                debug_exit: false,
+                preboot: false,
+            }
+        );
+
+        // Restore from a snapshot, no kernel needed.
+        assert_eq!(
+            Cli::launch(vec![
+                "foobar",
+                "--restore",
+                "state_path=/foo/state,mem_path=/foo/mem",
+            ])
+            .unwrap()
+            .restore_config,
+            Some(RestoreConfig {
+                state_path: PathBuf::from("/foo/state"),
+                mem_path: PathBuf::from("/foo/mem"),
+                lazy: false,
+            })
+        );
+
+        // The VM layout comes from the snapshot.
+        assert!(Cli::launch(vec![
+            "foobar",
+            "--restore",
+            "state_path=/foo/state,mem_path=/foo/mem",
+            "--memory",
+            "size_mib=128",
+        ])
+        .is_err());
+
+        // Receive a migrated VM, no kernel needed either.
+        assert_eq!(
+            Cli::launch(vec!["foobar", "--incoming", "unix:/tmp/migrate.sock"])
+                .unwrap()
+                .incoming_config,
+            Some(MigrationAddress::Unix(PathBuf::from("/tmp/migrate.sock")))
+        );
+        assert!(Cli::launch(vec![
+            "foobar",
+            "--incoming",
+            "tcp:127.0.0.1:4444",
+            "--restore",
+            "state_path=/foo/state,mem_path=/foo/mem",
+        ])
+        .is_err());
+
+        // Several devices of the same kind.
+        let config = Cli::launch(vec![
+            "foobar",
+            "--kernel",
+            "path=/foo/bar",
+            "--block",
+            "path=/foo/rootfs",
+            "--block",
+            "path=/foo/data",
+            "--net",
+            "tap=tap0",
+        ])
+        .unwrap();
+        assert_eq!(
+            config.block_config,
+            vec![
+                BlockConfig {
+                    path: PathBuf::from("/foo/rootfs")
+                },
+                BlockConfig {
+                    path: PathBuf::from("/foo/data")
+                }
+            ]
+        );
+        assert_eq!(
+            config.net_config,
+            vec![NetConfig {
+                tap_name: "tap0".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_launch_preboot() {
+        // The VM is configured over the control socket later.
+        let config = Cli::launch(vec!["foobar", "--preboot"]).unwrap();
+        assert!(config.preboot);
+        assert!(config.kernel_config.path.as_os_str().is_empty());
+
+        assert!(Cli::launch(vec!["foobar", "--preboot", "--kernel", "path=/foo/bar"]).is_err());
+    }
+
+    #[test]
+    fn test_launch_api() {
+        let config = Cli::launch(vec![
+            "foobar",
+            "--kernel",
+            "path=/foo/bar",
+            "--api-sock",
+            "/tmp/foo.sock",
+        ])
+        .unwrap();
+        assert_eq!(
+            config.api_config,
+            ApiConfig {
+                socket_path: PathBuf::from("/tmp/foo.sock"),
+                enabled: true,
+            }
+        );
+
+        let config = Cli::launch(vec!["foobar", "--kernel", "path=/foo/bar", "--no-api"]).unwrap();
+        assert!(!config.api_config.enabled);
+
+        // A pre-boot VM is configured through the API.
+        assert!(Cli::launch(vec!["foobar", "--preboot", "--no-api"]).is_err());
+        assert!(Cli::launch(vec![
+            "foobar",
+            "--kernel",
+            "path=/foo/bar",
+            "--api-sock",
+            "/tmp/foo.sock",
+            "--no-api",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_launch_metrics() {
+        let config = Cli::launch(vec![
+            "foobar",
+            "--kernel",
+            "path=/foo/bar",
+            "--metrics",
+            "path=/tmp/metrics.json,flush_interval_s=10",
+        ])
+        .unwrap();
+        assert_eq!(
+            config.metrics_config,
+            Some(MetricsConfig {
+                path: PathBuf::from("/tmp/metrics.json"),
+                flush_interval_s: 10,
+            })
+        );
+
+        assert!(Cli::launch(vec![
+            "foobar",
+            "--kernel",
+            "path=/foo/bar",
+            "--metrics",
+            "flush_interval_s=10",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_launch_serial() {
+        let config = Cli::launch(vec![
+            "foobar",
+            "--kernel",
+            "path=/foo/bar",
+            "--serial",
+            "file:/tmp/console.log",
+        ])
+        .unwrap();
+        assert_eq!(
+            config.serial_config,
+            SerialConfig::File(PathBuf::from("/tmp/console.log"))
+        );
+
+        // The console of a restored VM is not part of the snapshot.
+        let config = Cli::launch(vec![
+            "foobar",
+            "--restore",
+            "state_path=/foo/state,mem_path=/foo/mem",
+            "--serial",
+            "pty",
+        ])
+        .unwrap();
+        assert_eq!(config.serial_config, SerialConfig::Pty);
+
+        assert!(Cli::launch(vec![
+            "foobar",
+            "--kernel",
+            "path=/foo/bar",
+            "--serial",
+            "unix:",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_launch_reboot() {
+        let config = Cli::launch(vec![
+            "foobar",
+            "--kernel",
+            "path=/foo/bar",
+            "--reboot",
+            "restart",
+        ])
+        .unwrap();
+        assert_eq!(config.reboot_policy, RebootPolicy::Restart);
+
+        assert!(Cli::launch(vec![
+            "foobar",
+            "--kernel",
+            "path=/foo/bar",
+            "--reboot",
+            "poweroff",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_launch_balloon() {
+        let config = Cli::launch(vec![
+            "foobar",
+            "--kernel",
+            "path=/foo/bar",
+            "--balloon",
+            "amount_mib=1024,deflate_on_oom=true",
+        ])
+        .unwrap();
+        assert_eq!(
+            config.balloon_config,
+            vec![BalloonConfig {
+                amount_mib: 1024,
+                deflate_on_oom: true,
+                stats_polling_interval_s: DEFAULT_BALLOON_STATS_POLLING_INTERVAL_S,
+            }]
+        );
+
+        assert!(Cli::launch(vec![
+            "foobar",
+            "--kernel",
+            "path=/foo/bar",
+            "--balloon",
+            "0",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_launch_debug_exit() {
+        let config = Cli::launch(vec!["foobar", "--kernel", "path=/foo/bar"]).unwrap();
+        assert!(!config.debug_exit);
+
+        let config =
+            Cli::launch(vec!["foobar", "--kernel", "path=/foo/bar", "--debug-exit"]).unwrap();
+        assert!(config.debug_exit);
+    }
+
+    #[test]
+    fn test_launch_log() {
+        let config = Cli::launch(vec![
+            "foobar",
+            "--kernel",
+            "path=/foo/bar",
+            "--log-path",
+            "/tmp/vmm.log",
+            "--log-level",
+            "error,vm_vcpu=debug",
+        ])
+        .unwrap();
+        assert_eq!(
+            config.log_config,
+            LogConfig {
+                path: Some(PathBuf::from("/tmp/vmm.log")),
+                filter: "error,vm_vcpu=debug".parse().unwrap(),
             }
         );
+
+        assert!(Cli::launch(vec![
+            "foobar",
+            "--kernel",
+            "path=/foo/bar",
+            "--log-level",
+            "vm_vcpu=loud",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_launch_config_file() {
+        let path = env::temp_dir().join(format!("vm_config_{}.toml", process::id()));
+        fs::write(
+            &path,
+            "[kernel]\npath = \"/foo/bar\"\n\n[[block]]\npath = \"/foo/rootfs\"\n",
+        )
+        .unwrap();
+        let path_str = path.to_str().unwrap();
+
+        let config = Cli::launch(vec!["foobar", "--config-file", path_str]).unwrap();
+        assert_eq!(config.kernel_config.path, PathBuf::from("/foo/bar"));
+        assert_eq!(
+            config.block_config,
+            vec![BlockConfig {
+                path: PathBuf::from("/foo/rootfs")
+            }]
+        );
+
+        // The file describes the whole VM.
+        assert!(Cli::launch(vec![
+            "foobar",
+            "--config-file",
+            path_str,
+            "--memory",
+            "size_mib=128",
+        ])
+        .is_err());
+
+        fs::write(&path, "[kernel]\ncmdline = \"foo\"\n").unwrap();
+        assert!(Cli::launch(vec!["foobar", "--config-file", path_str]).is_err());
+
+        fs::remove_file(&path).unwrap();
+        assert!(Cli::launch(vec!["foobar", "--config-file", path_str]).is_err());
     }
 }
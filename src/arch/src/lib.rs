@@ -76,6 +76,7 @@ pub struct FdtBuilder {
     num_vcpus: Option<u32>,
     serial_console: Option<(u64, u64)>,
     rtc: Option<(u64, u64)>,
+    debug_exit: Option<(u64, u64)>,
     virtio_devices: Vec<DeviceInfo>,
 }
 
@@ -109,6 +110,11 @@ impl FdtBuilder {
         self
     }
 
+    pub fn with_debug_exit(&mut self, addr: u64, size: u64) -> &mut Self {
+        self.debug_exit = Some((addr, size));
+        self
+    }
+
     pub fn add_virtio_device(&mut self, addr: u64, size: u64, irq: u32) -> &mut Self {
         self.virtio_devices.push(DeviceInfo { addr, size, irq });
         self
@@ -150,6 +156,9 @@ impl FdtBuilder {
         if let Some(rtc) = self.rtc {
             create_rtc_node(&mut fdt, rtc.0, rtc.1)?;
         }
+        if let Some(debug_exit) = self.debug_exit {
+            create_debug_exit_node(&mut fdt, debug_exit.0, debug_exit.1)?;
+        }
         create_timer_node(&mut fdt, num_vcpus)?;
         create_psci_node(&mut fdt)?;
         create_pmu_node(&mut fdt, num_vcpus)?;
@@ -338,6 +347,15 @@ fn create_rtc_node(fdt: &mut FdtWriter, rtc_addr: u64, size: u64) -> Result<()>
     Ok(())
 }
 
+// There is no binding for this device, the guest looks the node up by its compatible string.
+fn create_debug_exit_node(fdt: &mut FdtWriter, addr: u64, size: u64) -> Result<()> {
+    let debug_exit_node = fdt.begin_node(&format!("debug-exit@{:x}", addr))?;
+    fdt.property_string("compatible", "rust-vmm,debug-exit")?;
+    fdt.property_array_u64("reg", &[addr, size])?;
+    fdt.end_node(debug_exit_node)?;
+    Ok(())
+}
+
 fn create_virtio_node(fdt: &mut FdtWriter, addr: u64, size: u64, irq: u32) -> Result<()> {
     let virtio_mmio = fdt.begin_node(&format!("virtio_mmio@{:x}", addr))?;
     fdt.property_string("compatible", "virtio,mmio")?;
@@ -371,6 +389,7 @@ mod tests {
             .with_mem_size(4096)
             .with_serial_console(0x40000000, 0x1000)
             .with_rtc(0x40001000, 0x1000)
+            .with_debug_exit(0x40002000, 0x1000)
             .add_virtio_device(0x1000, 1000, 5)
             .create_fdt();
         assert!(fdt_ok.is_ok());
@@ -0,0 +1,463 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+// Backends of the serial console: where the output of the guest goes, and where its input
+// comes from. On top of its backend, a console can have one client attached, either over its
+// socket or handed over by the control API, and keeps the recent output for the clients which
+// attach later on.
+
+use std::collections::VecDeque;
+use std::ffi::{CStr, OsStr};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, stdin, stdout, ErrorKind, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, OpenOptionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+use log::info;
+use vmm_sys_util::eventfd::EventFd;
+
+/// Number of bytes of guest output a console keeps.
+pub const SCROLLBACK_SIZE: usize = 64 << 10;
+
+enum Backend {
+    Stdio,
+    File {
+        file: File,
+        path: PathBuf,
+    },
+    Socket {
+        listener: UnixListener,
+        path: PathBuf,
+        // Identifies the socket file we bound, in case another one replaced it since.
+        inode: u64,
+    },
+    Pty {
+        master: File,
+        path: PathBuf,
+        // Kept open so that the master does not hang up when the last client closes the PTY.
+        _slave: File,
+    },
+}
+
+// Change of client requested from outside the event loop.
+enum Handover {
+    Attach(UnixStream),
+    Detach,
+}
+
+/// Output and input of a serial console.
+pub struct Console {
+    backend: Backend,
+    // Gets a copy of the output, and sends input as well.
+    client: Option<UnixStream>,
+    // The client only changes on the event loop, which polls it. `handover_evt` wakes the loop
+    // up to carry out `handover`.
+    handover: Option<Handover>,
+    handover_evt: EventFd,
+    scrollback: VecDeque<u8>,
+}
+
+impl Console {
+    fn new(backend: Backend) -> io::Result<Self> {
+        Ok(Console {
+            backend,
+            client: None,
+            handover: None,
+            handover_evt: EventFd::new(libc::EFD_NONBLOCK)?,
+            scrollback: VecDeque::with_capacity(SCROLLBACK_SIZE),
+        })
+    }
+
+    /// Writes to stdout and reads from stdin.
+    pub fn stdio() -> io::Result<Self> {
+        Console::new(Backend::Stdio)
+    }
+
+    /// Appends to the file at `path`, which is created if needed. The guest only gets input
+    /// from an attached client.
+    pub fn file(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Console::new(Backend::File {
+            file,
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Listens on a Unix socket at `path`.
+    ///
+    /// A client which connects gets the output and sends the input, until it disconnects.
+    /// A new client replaces the previous one, and the output is dropped while no client is
+    /// attached.
+    pub fn socket(path: &Path) -> io::Result<Self> {
+        // A socket nobody listens on was left behind by a VMM which did not exit cleanly.
+        let is_socket = fs::symlink_metadata(path).map_or(false, |m| m.file_type().is_socket());
+        if is_socket && UnixStream::connect(path).is_err() {
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        let inode = fs::symlink_metadata(path)?.ino();
+        info!("Serial console listening on {}", path.display());
+        Console::new(Backend::Socket {
+            listener,
+            path: path.to_path_buf(),
+            inode,
+        })
+    }
+
+    /// Allocates a new PTY, whose path is logged and returned by [`Console::path`].
+    pub fn pty() -> io::Result<Self> {
+        // Safe because the call does not touch memory, and we check the return value.
+        let fd = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because we just opened the fd, and nothing else owns it.
+        let master = unsafe { File::from_raw_fd(fd) };
+        // Safe because the calls only use the fd, which is valid, and we check the return
+        // values.
+        if unsafe { libc::grantpt(fd) } < 0 || unsafe { libc::unlockpt(fd) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut name = [0 as libc::c_char; 64];
+        // Safe because `name` outlives the call, which writes at most `name.len()` bytes.
+        let ret = unsafe { libc::ptsname_r(fd, name.as_mut_ptr(), name.len()) };
+        if ret != 0 {
+            return Err(io::Error::from_raw_os_error(ret));
+        }
+        // Safe because `ptsname_r` succeeded, so `name` holds a nul-terminated string.
+        let name = unsafe { CStr::from_ptr(name.as_ptr()) };
+        let path = PathBuf::from(OsStr::from_bytes(name.to_bytes()));
+
+        let slave = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(&path)?;
+        set_raw_mode(&slave)?;
+        info!("Serial console on {}", path.display());
+        Console::new(Backend::Pty {
+            master,
+            path,
+            _slave: slave,
+        })
+    }
+
+    /// Returns the path of the file, socket or PTY of the console, or `None` for stdio.
+    pub fn path(&self) -> Option<&Path> {
+        match &self.backend {
+            Backend::Stdio => None,
+            Backend::File { path, .. }
+            | Backend::Socket { path, .. }
+            | Backend::Pty { path, .. } => Some(path),
+        }
+    }
+
+    /// Whether the console is the terminal of the VMM.
+    pub fn is_stdio(&self) -> bool {
+        matches!(self.backend, Backend::Stdio)
+    }
+
+    /// Returns the most recent output of the guest, up to [`SCROLLBACK_SIZE`] bytes.
+    pub fn scrollback(&self) -> Vec<u8> {
+        self.scrollback.iter().copied().collect()
+    }
+
+    /// Attaches `client` in place of the current one, from outside the event loop. The client
+    /// first gets the scrollback, then the output as it comes.
+    pub fn hand_over(&mut self, client: UnixStream) -> io::Result<()> {
+        client.set_nonblocking(true)?;
+        self.handover = Some(Handover::Attach(client));
+        self.handover_evt.write(1)
+    }
+
+    /// Detaches the current client, if any, from outside the event loop.
+    pub fn detach(&mut self) -> io::Result<()> {
+        self.handover = Some(Handover::Detach);
+        self.handover_evt.write(1)
+    }
+
+    // Returns the fd of the input of the backend, if it has one.
+    pub(crate) fn input_fd(&self) -> Option<RawFd> {
+        match &self.backend {
+            Backend::Stdio => Some(stdin().as_raw_fd()),
+            Backend::Pty { master, .. } => Some(master.as_raw_fd()),
+            Backend::File { .. } | Backend::Socket { .. } => None,
+        }
+    }
+
+    // Returns the fd which is readable when a client connects, for a socket console.
+    pub(crate) fn listener_fd(&self) -> Option<RawFd> {
+        match &self.backend {
+            Backend::Socket { listener, .. } => Some(listener.as_raw_fd()),
+            _ => None,
+        }
+    }
+
+    // Returns the fd which is readable when a client is handed over.
+    pub(crate) fn handover_fd(&self) -> RawFd {
+        self.handover_evt.as_raw_fd()
+    }
+
+    pub(crate) fn client_fd(&self) -> Option<RawFd> {
+        self.client.as_ref().map(AsRawFd::as_raw_fd)
+    }
+
+    // Accepts a client on the socket. It is only attached with `attach`, so that the caller
+    // can stop polling the previous one before it is closed.
+    pub(crate) fn accept(&self) -> io::Result<UnixStream> {
+        match &self.backend {
+            Backend::Socket { listener, .. } => {
+                let (client, _) = listener.accept()?;
+                client.set_nonblocking(true)?;
+                Ok(client)
+            }
+            _ => Err(io::Error::from(ErrorKind::InvalidInput)),
+        }
+    }
+
+    // Takes the client handed over since the last call: `Some(None)` when the current client
+    // is to be detached, `None` when there is nothing to do.
+    pub(crate) fn take_handover(&mut self) -> Option<Option<UnixStream>> {
+        let _ = self.handover_evt.read();
+        self.handover.take().map(|handover| match handover {
+            Handover::Attach(client) => Some(client),
+            Handover::Detach => None,
+        })
+    }
+
+    // Replaces the client, or detaches it when `client` is `None`. A new client first gets
+    // the scrollback.
+    pub(crate) fn attach(&mut self, client: Option<UnixStream>) {
+        self.client = client;
+        if let Some(client) = self.client.as_mut() {
+            let (front, back) = self.scrollback.as_slices();
+            // Best effort, like the rest of the output.
+            let _ = client.write_all(front).and_then(|_| client.write_all(back));
+        }
+    }
+
+    // Reads the input of the backend. Returns 0 when the input is closed.
+    pub(crate) fn read_input(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.backend {
+            Backend::Stdio => stdin().read(buf),
+            Backend::Pty { master, .. } => master.read(buf),
+            Backend::File { .. } | Backend::Socket { .. } => Ok(0),
+        }
+    }
+
+    // Reads the input sent by the client. Returns 0 when it disconnected.
+    pub(crate) fn read_client(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.client.as_mut() {
+            Some(client) => client.read(buf),
+            None => Ok(0),
+        }
+    }
+}
+
+impl Write for Console {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = match &mut self.backend {
+            Backend::Stdio => stdout().write(buf),
+            Backend::File { file, .. } => file.write(buf),
+            // Only the client gets the output.
+            Backend::Socket { .. } => Ok(buf.len()),
+            // Dropped when the PTY is full because nobody reads it.
+            Backend::Pty { master, .. } => match master.write(buf) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(buf.len()),
+                res => res,
+            },
+        }?;
+        let buf = &buf[..written];
+
+        // A client which does not keep up, or went away, does not block the guest. A client
+        // which went away is detached when its end of the socket is polled.
+        if let Some(client) = self.client.as_mut() {
+            let _ = client.write(buf);
+        }
+
+        let overflow = (self.scrollback.len() + buf.len()).saturating_sub(SCROLLBACK_SIZE);
+        self.scrollback.drain(..overflow.min(self.scrollback.len()));
+        let skip = buf.len().saturating_sub(SCROLLBACK_SIZE);
+        self.scrollback.extend(&buf[skip..]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.backend {
+            Backend::Stdio => stdout().flush(),
+            Backend::File { file, .. } => file.flush(),
+            Backend::Socket { .. } | Backend::Pty { .. } => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for Console {
+    // Uses the format of the `--serial` option.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.backend {
+            Backend::Stdio => write!(f, "stdio"),
+            Backend::File { path, .. } => write!(f, "file:{}", path.display()),
+            Backend::Socket { path, .. } => write!(f, "unix:{}", path.display()),
+            Backend::Pty { path, .. } => write!(f, "pty:{}", path.display()),
+        }
+    }
+}
+
+impl Drop for Console {
+    fn drop(&mut self) {
+        if let Backend::Socket { path, inode, .. } = &self.backend {
+            if fs::symlink_metadata(path).map_or(false, |m| m.ino() == *inode) {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+}
+
+fn set_raw_mode(tty: &File) -> io::Result<()> {
+    // Safe because the termios struct is plain data, which `tcgetattr` fills.
+    let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+    // Safe because `termios` outlives the call, and we check the return value.
+    if unsafe { libc::tcgetattr(tty.as_raw_fd(), &mut termios) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because `termios` is valid, `cfmakeraw` only changes its fields.
+    unsafe { libc::cfmakeraw(&mut termios) };
+    // Safe because `termios` outlives the call, and we check the return value.
+    if unsafe { libc::tcsetattr(tty.as_raw_fd(), libc::TCSANOW, &termios) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn test_file_console() {
+        let path = env::temp_dir().join(format!("serial_console_{}.log", process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut console = Console::file(&path).unwrap();
+        console.write_all(b"foo").unwrap();
+        drop(console);
+        let mut console = Console::file(&path).unwrap();
+        console.write_all(b"bar").unwrap();
+        assert!(console.input_fd().is_none());
+        assert_eq!(console.read_input(&mut [0u8; 8]).unwrap(), 0);
+
+        assert_eq!(fs::read(&path).unwrap(), b"foobar");
+        assert_eq!(console.scrollback(), b"bar");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_socket_console() {
+        let path = env::temp_dir().join(format!("serial_console_{}.sock", process::id()));
+        // A socket left behind is replaced.
+        drop(UnixListener::bind(&path));
+
+        let mut console = Console::socket(&path).unwrap();
+        assert_eq!(console.to_string(), format!("unix:{}", path.display()));
+        assert!(console.client_fd().is_none());
+        // Only kept in the scrollback, nobody is attached.
+        console.write_all(b"boot ").unwrap();
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        let stream = console.accept().unwrap();
+        console.attach(Some(stream));
+        assert!(console.client_fd().is_some());
+
+        console.write_all(b"out").unwrap();
+        let mut buf = [0u8; 8];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"boot out");
+
+        client.write_all(b"in").unwrap();
+        assert_eq!(console.read_client(&mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"in");
+
+        // The output of a client which went away is dropped, until it is detached.
+        drop(client);
+        assert_eq!(console.read_client(&mut buf).unwrap(), 0);
+        console.write_all(b"lost").unwrap();
+        console.attach(None);
+        assert!(console.client_fd().is_none());
+
+        // The socket is removed with the console.
+        drop(console);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_pty_console() {
+        let mut console = Console::pty().unwrap();
+        let path = console.path().unwrap().to_path_buf();
+        assert!(path.starts_with("/dev/pts"));
+
+        let mut client = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(&path)
+            .unwrap();
+        console.write_all(b"out").unwrap();
+        let mut buf = [0u8; 3];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"out");
+    }
+
+    #[test]
+    fn test_hand_over() {
+        let path = env::temp_dir().join(format!("serial_handover_{}.log", process::id()));
+        let mut console = Console::file(&path).unwrap();
+        assert!(console.take_handover().is_none());
+
+        let (stream, mut client) = UnixStream::pair().unwrap();
+        console.hand_over(stream).unwrap();
+        // Not attached until the event loop takes it.
+        assert!(console.client_fd().is_none());
+        let handover = console.take_handover().unwrap();
+        assert!(handover.is_some());
+        console.attach(handover);
+        console.write_all(b"out").unwrap();
+        let mut buf = [0u8; 3];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"out");
+
+        console.detach().unwrap();
+        assert!(console.take_handover().unwrap().is_none());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_scrollback() {
+        let path = env::temp_dir().join(format!("serial_scrollback_{}.log", process::id()));
+        let mut console = Console::file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let line = [b'a'; 1000];
+        for _ in 0..SCROLLBACK_SIZE / line.len() {
+            console.write_all(&line).unwrap();
+        }
+        console.write_all(b"end").unwrap();
+        let scrollback = console.scrollback();
+        assert_eq!(scrollback.len(), SCROLLBACK_SIZE);
+        assert!(scrollback.ends_with(b"aend"));
+
+        // Larger than the whole scrollback at once.
+        let output = (0..SCROLLBACK_SIZE + 10)
+            .map(|i| i as u8)
+            .collect::<Vec<_>>();
+        console.write_all(&output).unwrap();
+        assert_eq!(console.scrollback(), &output[10..]);
+    }
+}
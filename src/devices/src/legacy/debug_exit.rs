@@ -0,0 +1,126 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use std::io;
+
+use log::{error, info};
+use vm_device::bus::{MmioAddress, PioAddress, PioAddressOffset};
+use vm_device::{MutDeviceMmio, MutDevicePio};
+
+use utils::debug;
+
+/// Port of the device on x86_64, as for the QEMU `isa-debug-exit` device.
+pub const DEBUG_EXIT_PORT: u16 = 0xf4;
+
+/// Size of the register of the device, in bytes.
+pub const DEBUG_EXIT_SIZE: u16 = 4;
+
+/// Ends the VMM on behalf of the guest.
+pub trait GuestExit {
+    /// Exits the VMM with `code`.
+    fn exit(&self, code: i32) -> io::Result<()>;
+}
+
+/// A write-only register which ends the VMM with an exit code chosen by the guest.
+///
+/// As with the QEMU `isa-debug-exit` device, writing `value` exits with `(value << 1) | 1`,
+/// so the guest cannot be mistaken for having exited cleanly. Writes of up to 4 bytes are
+/// accepted, and reads return 0.
+pub struct DebugExit<E: GuestExit> {
+    exit: E,
+}
+
+impl<E: GuestExit> DebugExit<E> {
+    /// Creates the device, which calls `exit` when the guest writes to it.
+    pub fn new(exit: E) -> Self {
+        DebugExit { exit }
+    }
+
+    fn read(&self, offset: u64, data: &mut [u8]) {
+        if offset != 0 || data.len() > DEBUG_EXIT_SIZE as usize {
+            debug!(
+                "Invalid debug exit read at {}: {} bytes",
+                offset,
+                data.len()
+            );
+        }
+        data.iter_mut().for_each(|byte| *byte = 0);
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) {
+        if offset != 0 || data.len() > DEBUG_EXIT_SIZE as usize {
+            debug!(
+                "Invalid debug exit write at {}: {} bytes",
+                offset,
+                data.len()
+            );
+            return;
+        }
+        let mut value = [0u8; 4];
+        value[..data.len()].copy_from_slice(data);
+        let code = ((u32::from_le_bytes(value) << 1) | 1) as i32;
+        info!("The guest exits with code {}.", code);
+        if let Err(e) = self.exit.exit(code) {
+            error!("Failed to exit on behalf of the guest: {}", e);
+        }
+    }
+}
+
+impl<E: GuestExit> MutDevicePio for DebugExit<E> {
+    fn pio_read(&mut self, _base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) {
+        self.read(offset.into(), data);
+    }
+
+    fn pio_write(&mut self, _base: PioAddress, offset: PioAddressOffset, data: &[u8]) {
+        self.write(offset.into(), data);
+    }
+}
+
+impl<E: GuestExit> MutDeviceMmio for DebugExit<E> {
+    fn mmio_read(&mut self, _base: MmioAddress, offset: u64, data: &mut [u8]) {
+        self.read(offset, data);
+    }
+
+    fn mmio_write(&mut self, _base: MmioAddress, offset: u64, data: &[u8]) {
+        self.write(offset, data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Codes(Arc<Mutex<Vec<i32>>>);
+
+    impl GuestExit for Codes {
+        fn exit(&self, code: i32) -> io::Result<()> {
+            self.0.lock().unwrap().push(code);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_debug_exit() {
+        let codes = Codes::default();
+        let mut device = DebugExit::new(codes.clone());
+        let port = PioAddress(DEBUG_EXIT_PORT);
+
+        device.pio_write(port, 0, &[0]);
+        device.pio_write(port, 0, &[0x10]);
+        device.pio_write(port, 0, &0x100u32.to_le_bytes());
+        device.mmio_write(MmioAddress(0), 0, &[1, 0]);
+        assert_eq!(*codes.0.lock().unwrap(), vec![1, 0x21, 0x201, 3]);
+
+        // Writes out of the register are ignored.
+        device.pio_write(port, 1, &[0]);
+        device.pio_write(port, 0, &[0; 8]);
+        assert_eq!(codes.0.lock().unwrap().len(), 4);
+
+        let mut data = [0xff; 4];
+        device.pio_read(port, 0, &mut data);
+        assert_eq!(data, [0; 4]);
+    }
+}
@@ -1,15 +1,159 @@
 // Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
+use std::collections::VecDeque;
 use std::convert::TryInto;
+use std::io;
+
 use vm_device::bus::{PioAddress, PioAddressOffset};
 use vm_device::MutDevicePio;
-use vm_superio::I8042Device;
+use vm_superio::{I8042Device, Trigger};
 
 use utils::debug;
 
 use super::EventFdTrigger;
-pub struct I8042Wrapper(pub I8042Device<EventFdTrigger>);
+
+// Offsets of the data and of the status/command ports, from port 0x60.
+const OFS_DATA: u8 = 0;
+const OFS_STATUS: u8 = 4;
+
+// Status register bits.
+const SB_OUT_DATA_AVAIL: u8 = 0x01;
+const SB_I8042_CMD_DATA: u8 = 0x08;
+const SB_KBD_ENABLED: u8 = 0x10;
+
+// Controller command byte bits.
+const CB_KBD_INT: u8 = 0x01;
+const CB_POST_OK: u8 = 0x04;
+
+// Controller commands.
+const CMD_READ_CTR: u8 = 0x20;
+const CMD_WRITE_CTR: u8 = 0x60;
+const CMD_READ_OUTP: u8 = 0xD0;
+const CMD_WRITE_OUTP: u8 = 0xD1;
+const CMD_RESET_CPU: u8 = 0xFE;
+
+// Reply of the keyboard to its commands.
+const KBD_ACK: u8 = 0xFA;
+
+// Make codes of scancode set 2, with the 0xE0 prefix in the high byte for extended keys.
+const KEY_CTRL: u16 = 0x0014;
+const KEY_ALT: u16 = 0x0011;
+const KEY_DEL: u16 = 0xE071;
+
+// Bytes the guest did not read yet beyond which keystrokes are dropped.
+const BUF_SIZE: usize = 16;
+
+/// A PS/2 controller with a keyboard, on ports 0x60 to 0x64.
+///
+/// It only emulates what a Linux guest needs to get keystrokes, with `i8042.noaux
+/// i8042.nomux i8042.nopnp i8042.dumbkbd` on its command line, which the VMM adds along with
+/// the device: the controller command byte, and a keyboard which acknowledges all commands. The CPU reset command is handled by the
+/// `vm_superio` device.
+pub struct I8042Wrapper {
+    device: I8042Device<EventFdTrigger>,
+    kbd_evt: EventFdTrigger,
+    status: u8,
+    control: u8,
+    outp: u8,
+    // Controller command waiting for its argument on the data port.
+    cmd: u8,
+    buf: VecDeque<u8>,
+}
+
+impl I8042Wrapper {
+    /// Creates the controller. `reset_evt` is triggered when the guest resets the CPU, and
+    /// `kbd_evt` for the keyboard interrupt.
+    pub fn new(reset_evt: EventFdTrigger, kbd_evt: EventFdTrigger) -> Self {
+        I8042Wrapper {
+            device: I8042Device::new(reset_evt),
+            kbd_evt,
+            status: SB_KBD_ENABLED,
+            control: CB_POST_OK | CB_KBD_INT,
+            outp: 0,
+            cmd: 0,
+            buf: VecDeque::with_capacity(BUF_SIZE),
+        }
+    }
+
+    /// Presses Ctrl-Alt-Del on the keyboard, which asks the guest to reboot or, depending on
+    /// its init, to shut down.
+    pub fn trigger_ctrl_alt_del(&mut self) -> io::Result<()> {
+        for key in [KEY_CTRL, KEY_ALT, KEY_DEL].iter() {
+            if key & 0xff00 != 0 {
+                self.push((key >> 8) as u8);
+            }
+            self.push(*key as u8);
+        }
+        self.trigger_kbd_interrupt()
+    }
+
+    fn trigger_kbd_interrupt(&self) -> io::Result<()> {
+        if self.control & CB_KBD_INT == 0 {
+            return Ok(());
+        }
+        self.kbd_evt.trigger()
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.buf.len() == BUF_SIZE {
+            debug!("I8042 output buffer full, dropping {:#x}", byte);
+            return;
+        }
+        self.buf.push_back(byte);
+        self.status |= SB_OUT_DATA_AVAIL;
+    }
+
+    fn read(&mut self, offset: u8) -> u8 {
+        match offset {
+            OFS_STATUS => self.status,
+            OFS_DATA => {
+                let byte = self.buf.pop_front().unwrap_or(0);
+                if self.buf.is_empty() {
+                    self.status &= !SB_OUT_DATA_AVAIL;
+                } else if self.trigger_kbd_interrupt().is_err() {
+                    debug!("Failed to trigger the I8042 keyboard interrupt.");
+                }
+                byte
+            }
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u8, value: u8) -> io::Result<()> {
+        match (offset, value) {
+            (OFS_STATUS, CMD_RESET_CPU) => self.device.write(offset, value)?,
+            (OFS_STATUS, CMD_READ_CTR) => {
+                self.buf.clear();
+                self.push(self.control);
+            }
+            (OFS_STATUS, CMD_READ_OUTP) => {
+                self.buf.clear();
+                self.push(self.outp);
+            }
+            (OFS_STATUS, CMD_WRITE_CTR) | (OFS_STATUS, CMD_WRITE_OUTP) => {
+                self.status |= SB_I8042_CMD_DATA;
+                self.cmd = value;
+            }
+            (OFS_STATUS, _) => debug!("Unsupported I8042 command {:#x}", value),
+            (OFS_DATA, _) if self.status & SB_I8042_CMD_DATA != 0 => {
+                match self.cmd {
+                    CMD_WRITE_CTR => self.control = value,
+                    _ => self.outp = value,
+                }
+                self.status &= !SB_I8042_CMD_DATA;
+            }
+            (OFS_DATA, _) => {
+                // A command for the keyboard.
+                self.buf.clear();
+                self.push(KBD_ACK);
+                self.trigger_kbd_interrupt()?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
 
 impl MutDevicePio for I8042Wrapper {
     fn pio_read(&mut self, _base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) {
@@ -18,9 +162,7 @@ impl MutDevicePio for I8042Wrapper {
             return;
         }
         match offset.try_into() {
-            Ok(offset) => {
-                self.0.read(offset);
-            }
+            Ok(offset) => data[0] = self.read(offset),
             Err(_) => debug!("Invalid I8042 read offset."),
         }
     }
@@ -32,7 +174,7 @@ impl MutDevicePio for I8042Wrapper {
         }
         match offset.try_into() {
             Ok(offset) => {
-                if self.0.write(offset, data[0]).is_err() {
+                if self.write(offset, data[0]).is_err() {
                     debug!("Failed to write to I8042.");
                 }
             }
@@ -40,3 +182,81 @@ impl MutDevicePio for I8042Wrapper {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn i8042() -> I8042Wrapper {
+        I8042Wrapper::new(
+            EventFdTrigger::new(libc::EFD_NONBLOCK).unwrap(),
+            EventFdTrigger::new(libc::EFD_NONBLOCK).unwrap(),
+        )
+    }
+
+    fn read(i8042: &mut I8042Wrapper, offset: u8) -> u8 {
+        let mut data = [0];
+        i8042.pio_read(PioAddress(0x60), offset.into(), &mut data);
+        data[0]
+    }
+
+    fn write(i8042: &mut I8042Wrapper, offset: u8, value: u8) {
+        i8042.pio_write(PioAddress(0x60), offset.into(), &[value]);
+    }
+
+    #[test]
+    fn test_controller_commands() {
+        let mut i8042 = i8042();
+        assert_eq!(read(&mut i8042, OFS_STATUS) & SB_OUT_DATA_AVAIL, 0);
+
+        write(&mut i8042, OFS_STATUS, CMD_READ_CTR);
+        assert_ne!(read(&mut i8042, OFS_STATUS) & SB_OUT_DATA_AVAIL, 0);
+        assert_eq!(read(&mut i8042, OFS_DATA), CB_POST_OK | CB_KBD_INT);
+        assert_eq!(read(&mut i8042, OFS_STATUS) & SB_OUT_DATA_AVAIL, 0);
+
+        write(&mut i8042, OFS_STATUS, CMD_WRITE_CTR);
+        write(&mut i8042, OFS_DATA, CB_POST_OK);
+        write(&mut i8042, OFS_STATUS, CMD_READ_CTR);
+        assert_eq!(read(&mut i8042, OFS_DATA), CB_POST_OK);
+
+        write(&mut i8042, OFS_STATUS, CMD_WRITE_OUTP);
+        write(&mut i8042, OFS_DATA, 0x42);
+        write(&mut i8042, OFS_STATUS, CMD_READ_OUTP);
+        assert_eq!(read(&mut i8042, OFS_DATA), 0x42);
+
+        // Keyboard commands are acknowledged, and raise the interrupt once enabled again.
+        write(&mut i8042, OFS_DATA, 0xF2);
+        assert_eq!(read(&mut i8042, OFS_DATA), KBD_ACK);
+        assert!(i8042.kbd_evt.read().is_err());
+        write(&mut i8042, OFS_STATUS, CMD_WRITE_CTR);
+        write(&mut i8042, OFS_DATA, CB_POST_OK | CB_KBD_INT);
+        write(&mut i8042, OFS_DATA, 0xF4);
+        assert_eq!(i8042.kbd_evt.read().unwrap(), 1);
+        assert_eq!(read(&mut i8042, OFS_DATA), KBD_ACK);
+
+        // The reset goes to its own event.
+        write(&mut i8042, OFS_STATUS, CMD_RESET_CPU);
+        assert!(i8042.kbd_evt.read().is_err());
+    }
+
+    #[test]
+    fn test_ctrl_alt_del() {
+        let mut i8042 = i8042();
+        i8042.trigger_ctrl_alt_del().unwrap();
+        assert_eq!(i8042.kbd_evt.read().unwrap(), 1);
+
+        let mut scancodes = Vec::new();
+        while read(&mut i8042, OFS_STATUS) & SB_OUT_DATA_AVAIL != 0 {
+            scancodes.push(read(&mut i8042, OFS_DATA));
+        }
+        assert_eq!(scancodes, vec![0x14, 0x11, 0xE0, 0x71]);
+        // The interrupt was raised again for each byte left.
+        assert_eq!(i8042.kbd_evt.read().unwrap(), 3);
+
+        // Keystrokes are dropped when the guest does not read them.
+        for _ in 0..BUF_SIZE {
+            i8042.trigger_ctrl_alt_del().unwrap();
+        }
+        assert_eq!(i8042.buf.len(), BUF_SIZE);
+    }
+}
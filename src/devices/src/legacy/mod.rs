@@ -1,16 +1,20 @@
 // Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+mod console;
+mod debug_exit;
 #[cfg(target_arch = "x86_64")]
 mod i8042;
 #[cfg(target_arch = "aarch64")]
 mod rtc;
 mod serial;
+pub use console::Console;
+pub use debug_exit::{DebugExit, GuestExit, DEBUG_EXIT_PORT, DEBUG_EXIT_SIZE};
 #[cfg(target_arch = "x86_64")]
 pub use i8042::I8042Wrapper;
 #[cfg(target_arch = "aarch64")]
 pub use rtc::RtcWrapper;
 pub use serial::Error as SerialError;
-pub use serial::SerialWrapper;
+pub use serial::{SerialState, SerialWrapper};
 use std::io;
 use std::ops::Deref;
 
@@ -2,9 +2,12 @@
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
 use std::convert::TryInto;
-use std::io::{self, stdin, Read, Write};
+use std::io::{self, Write};
+use std::os::unix::net::UnixStream;
 
 use event_manager::{EventOps, Events, MutEventSubscriber};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
 #[cfg(target_arch = "aarch64")]
 use vm_device::{bus::MmioAddress, MutDeviceMmio};
 #[cfg(target_arch = "x86_64")]
@@ -12,46 +15,171 @@ use vm_device::{
     bus::{PioAddress, PioAddressOffset},
     MutDevicePio,
 };
-use vm_superio::serial::{NoEvents, SerialEvents};
+use vm_superio::serial::{self, NoEvents, SerialEvents};
 use vm_superio::{Serial, Trigger};
 use vmm_sys_util::epoll::EventSet;
 
 use utils::debug;
 
+use super::Console;
+
 /// Newtype for implementing `event-manager` functionalities.
 pub struct SerialWrapper<T: Trigger, EV: SerialEvents, W: Write>(pub Serial<T, EV, W>);
 
-impl<T: Trigger, W: Write> MutEventSubscriber for SerialWrapper<T, NoEvents, W> {
+/// Serializable copy of the `vm_superio` serial registers and pending input.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+pub struct SerialState {
+    pub baud_divisor_low: u8,
+    pub baud_divisor_high: u8,
+    pub interrupt_enable: u8,
+    pub interrupt_identification: u8,
+    pub line_control: u8,
+    pub line_status: u8,
+    pub modem_control: u8,
+    pub modem_status: u8,
+    pub scratch: u8,
+    pub in_buffer: Vec<u8>,
+}
+
+impl From<serial::SerialState> for SerialState {
+    fn from(state: serial::SerialState) -> Self {
+        SerialState {
+            baud_divisor_low: state.baud_divisor_low,
+            baud_divisor_high: state.baud_divisor_high,
+            interrupt_enable: state.interrupt_enable,
+            interrupt_identification: state.interrupt_identification,
+            line_control: state.line_control,
+            line_status: state.line_status,
+            modem_control: state.modem_control,
+            modem_status: state.modem_status,
+            scratch: state.scratch,
+            in_buffer: state.in_buffer,
+        }
+    }
+}
+
+impl From<SerialState> for serial::SerialState {
+    fn from(state: SerialState) -> Self {
+        serial::SerialState {
+            baud_divisor_low: state.baud_divisor_low,
+            baud_divisor_high: state.baud_divisor_high,
+            interrupt_enable: state.interrupt_enable,
+            interrupt_identification: state.interrupt_identification,
+            line_control: state.line_control,
+            line_status: state.line_status,
+            modem_control: state.modem_control,
+            modem_status: state.modem_status,
+            scratch: state.scratch,
+            in_buffer: state.in_buffer,
+        }
+    }
+}
+
+impl<T: Trigger, EV: SerialEvents, W: Write> SerialWrapper<T, EV, W> {
+    /// Returns the current state of the serial console.
+    pub fn save_state(&self) -> SerialState {
+        self.0.state().into()
+    }
+}
+
+impl<T: Trigger<E = io::Error>, W: Write> SerialWrapper<T, NoEvents, W> {
+    /// Creates a serial console from a saved `state`.
+    pub fn from_state(state: SerialState, trigger: T, out: W) -> Result<Self, Error> {
+        Serial::from_state(&state.into(), trigger, NoEvents, out)
+            .map(SerialWrapper)
+            .map_err(Error::RestoreState)
+    }
+}
+
+// Replaces the client of `console`, and polls the new one instead of the previous one.
+fn attach_client(console: &mut Console, client: Option<UnixStream>, ops: &mut EventOps) {
+    if let Some(fd) = console.client_fd() {
+        let _ = ops.remove(Events::new_raw(fd, EventSet::IN));
+    }
+    match client {
+        Some(client) => match ops.add(Events::new(&client, EventSet::IN)) {
+            Ok(()) => console.attach(Some(client)),
+            Err(e) => {
+                error!("Failed to register the serial console client: {:?}", e);
+                console.attach(None);
+            }
+        },
+        None => console.attach(None),
+    }
+}
+
+impl<T: Trigger> MutEventSubscriber for SerialWrapper<T, NoEvents, Console> {
     fn process(&mut self, events: Events, ops: &mut EventOps) {
-        // Respond to stdin events.
-        // `EventSet::IN` => send what's coming from stdin to the guest.
+        let console = self.0.writer_mut();
+
+        // A client connected to the console socket, it replaces the attached one.
+        if Some(events.fd()) == console.listener_fd() {
+            match console.accept() {
+                Ok(client) => attach_client(console, Some(client), ops),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => error!("Failed to accept a serial console client: {}", e),
+            }
+            return;
+        }
+
+        // A client was attached or detached through the API.
+        if events.fd() == console.handover_fd() {
+            if let Some(client) = console.take_handover() {
+                attach_client(console, client, ops);
+            }
+            return;
+        }
+
+        // Respond to input events, from the backend or the client.
+        // `EventSet::IN` => send what's coming from the input to the guest.
         // `EventSet::HANG_UP` or `EventSet::ERROR` => deregister the serial input.
+        let from_client = Some(events.fd()) == console.client_fd();
         let mut out = [0u8; 32];
-        match stdin().read(&mut out) {
+        let res = if from_client {
+            console.read_client(&mut out)
+        } else {
+            console.read_input(&mut out)
+        };
+        let count = match res {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
             Err(e) => {
-                eprintln!("Error while reading stdin: {:?}", e);
-            }
-            Ok(count) => {
-                let event_set = events.event_set();
-                let unregister_condition =
-                    event_set.contains(EventSet::ERROR) | event_set.contains(EventSet::HANG_UP);
-                if count > 0 {
-                    if self.0.enqueue_raw_bytes(&out[..count]).is_err() {
-                        eprintln!("Failed to send bytes to the guest via serial input");
-                    }
-                } else if unregister_condition {
-                    // Got 0 bytes from serial input; is it a hang-up or error?
-                    ops.remove(events)
-                        .expect("Failed to unregister serial input");
+                error!("Error while reading the serial input: {:?}", e);
+                // The client is detached, the next one gets the input.
+                if from_client {
+                    attach_client(console, None, ops);
                 }
+                return;
+            }
+            Ok(count) => count,
+        };
+        if count > 0 {
+            if self.0.enqueue_raw_bytes(&out[..count]).is_err() {
+                warn!("Failed to send bytes to the guest via serial input");
             }
+            return;
+        }
+        let event_set = events.event_set();
+        let unregister_condition =
+            event_set.contains(EventSet::ERROR) | event_set.contains(EventSet::HANG_UP);
+        if from_client {
+            // The client disconnected.
+            attach_client(console, None, ops);
+        } else if unregister_condition {
+            // Got 0 bytes from serial input; is it a hang-up or error?
+            ops.remove(events)
+                .expect("Failed to unregister serial input");
         }
     }
 
     fn init(&mut self, ops: &mut EventOps) {
-        // Hook to stdin events.
-        ops.add(Events::new(&stdin(), EventSet::IN))
-            .expect("Failed to register serial input event");
+        let console = self.0.writer_mut();
+        ops.add(Events::new_raw(console.handover_fd(), EventSet::IN))
+            .expect("Failed to register serial console handover event");
+        // Hook to the input events, and wait for clients to bring more.
+        for fd in console.listener_fd().into_iter().chain(console.input_fd()) {
+            ops.add(Events::new_raw(fd, EventSet::IN))
+                .expect("Failed to register serial input event");
+        }
     }
 }
 
@@ -133,6 +261,8 @@ impl<T: Trigger<E = io::Error>, W: Write> MutDeviceMmio for SerialWrapper<T, NoE
 pub enum Error {
     /// Failed to create an event manager for device events.
     EventManager(event_manager::Error),
+    /// Failed to load the serial console state.
+    RestoreState(serial::Error<io::Error>),
 }
 
 #[cfg(test)]
@@ -189,6 +319,32 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_save_state() {
+        let interrupt_evt = EventFdTrigger::new(libc::EFD_NONBLOCK).unwrap();
+        let mut serial_console = SerialWrapper(Serial::new(interrupt_evt, sink()));
+        // Scratch register.
+        let offset = 7;
+
+        #[cfg(target_arch = "x86_64")]
+        serial_console.pio_write(PioAddress(0), offset, &[0x42]);
+        #[cfg(target_arch = "aarch64")]
+        serial_console.mmio_write(MmioAddress(0), offset, &[0x42]);
+        serial_console.0.enqueue_raw_bytes(&[1, 2, 3]).unwrap();
+
+        let state = serial_console.save_state();
+        assert_eq!(state.scratch, 0x42);
+        assert_eq!(state.in_buffer, vec![1, 2, 3]);
+        assert_eq!(
+            SerialState::from(serial::SerialState::from(state.clone())),
+            state
+        );
+
+        let interrupt_evt = EventFdTrigger::new(libc::EFD_NONBLOCK).unwrap();
+        let restored = SerialWrapper::from_state(state.clone(), interrupt_evt, sink()).unwrap();
+        assert_eq!(restored.save_state(), state);
+    }
+
     #[test]
     fn test_valid_write_and_read() {
         let interrupt_evt = EventFdTrigger::new(libc::EFD_NONBLOCK).unwrap();
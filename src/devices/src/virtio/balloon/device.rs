@@ -2,12 +2,16 @@
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
 use std::borrow::{Borrow, BorrowMut};
+use std::collections::BTreeSet;
 use std::fs::OpenOptions;
 use std::ops::DerefMut;
 use std::path::PathBuf;
+use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex};
-use std::sync::atomic::{Ordering};
+use std::time::Duration;
 
+use event_manager::EventManager;
+use serde::Serialize;
 use virtio_blk::stdio_executor::StdIoBackend;
 use virtio_device::{VirtioConfig, VirtioDeviceActions, VirtioDeviceType, VirtioMmioDevice};
 use virtio_queue::Queue;
@@ -15,29 +19,52 @@ use vm_device::bus::MmioAddress;
 use vm_device::device_manager::MmioManager;
 use vm_device::{DeviceMmio, MutDeviceMmio};
 use vm_memory::{GuestAddressSpace, GuestMemoryMmap};
+use vmm_sys_util::timerfd::TimerFd;
 
-use crate::virtio::{CommonConfig, Env, SingleFdSignalQueue, QUEUE_MAX_SIZE};
-use crate::virtio::features::{VIRTIO_F_VERSION_1};
-use crate::virtio::balloon::{BALLOON_DEVICE_ID};
+use crate::virtio::balloon::features::{
+    VIRTIO_BALLOON_F_DEFLATE_ON_OOM, VIRTIO_BALLOON_F_REPORTING, VIRTIO_BALLOON_F_STATS_VQ,
+};
+use crate::virtio::balloon::{StatsSample, BALLOON_DEVICE_ID};
+use crate::virtio::features::VIRTIO_F_VERSION_1;
+use crate::virtio::metrics::BalloonMetrics;
+use crate::virtio::persist::{BalloonState, MmioState, QueueState, VirtioState};
+use crate::virtio::{CommonConfig, Env, SingleFdSignalQueue, Subscriber, QUEUE_MAX_SIZE};
 
-use super::simple_handler::SimpleHandler;
 use super::queue_handler::QueueHandler;
+use super::simple_handler::SimpleHandler;
 use super::{BalloonArgs, Error, Result};
 
-const VIRTIO_MMIO_INT_VRING :u8 = 1 << 0;
-const VIRTIO_MMIO_INT_CONFIG :u8 = 1 << 1;
+const VIRTIO_MMIO_INT_VRING: u8 = 1 << 0;
+const VIRTIO_MMIO_INT_CONFIG: u8 = 1 << 1;
 
+// Offsets of the `virtio_balloon_config` fields in the configuration space.
+const NUM_PAGES_OFFSET: usize = 0;
+const ACTUAL_OFFSET: usize = 4;
+
+/// Size of the balloon, in 4 KiB pages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct BalloonSize {
+    /// Pages the driver is asked to give back.
+    pub target_pages: u32,
+    /// Pages the driver reports having given back.
+    pub actual_pages: u32,
+}
 
 pub struct Balloon<M: GuestAddressSpace> {
     pub cfg: CommonConfig<M>,
     pub guest_memory: GuestMemoryMmap,
+    // Set once the device is activated, and used to retrieve the queue state.
+    handler: Option<Arc<Mutex<QueueHandler<M>>>>,
+    metrics: Arc<BalloonMetrics>,
+    stats_polling_interval: Duration,
+    // Updated by the queue handler whenever the driver sends statistics.
+    stats: Arc<Mutex<Option<StatsSample>>>,
 }
 
 impl<M> Balloon<M>
 where
     M: GuestAddressSpace + Clone + Send + 'static,
 {
-
     pub fn new<B>(env: &mut Env<M, B>, args: &BalloonArgs) -> Result<Arc<Mutex<Self>>>
     where
         // We're using this (more convoluted) bound so we can pass both references and smart
@@ -45,25 +72,36 @@ where
         B: DerefMut,
         B::Target: MmioManager<D = Arc<dyn DeviceMmio + Send + Sync>>,
     {
-        let device_features = (1 << VIRTIO_F_VERSION_1);
-
-
+        let mut device_features = (1 << VIRTIO_F_VERSION_1)
+            | (1 << VIRTIO_BALLOON_F_STATS_VQ)
+            | (1 << VIRTIO_BALLOON_F_REPORTING);
+        if args.deflate_on_oom {
+            device_features |= 1 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM;
+        }
+
+        // The inflate, deflate, stats and reporting queues.
         let queues = vec![
             Queue::new(env.mem.clone(), QUEUE_MAX_SIZE),
             Queue::new(env.mem.clone(), QUEUE_MAX_SIZE),
+            Queue::new(env.mem.clone(), QUEUE_MAX_SIZE),
+            Queue::new(env.mem.clone(), QUEUE_MAX_SIZE),
         ];
 
-
-        let config_data:u64 = 0 ;//virtio_balloon_config  u32 numpages;u32 actual
-        let config_space = config_data.to_le_bytes().to_vec();
+        // virtio_balloon_config: u32 num_pages; u32 actual
+        let mut config_space = vec![0; 8];
+        config_space[NUM_PAGES_OFFSET..NUM_PAGES_OFFSET + 4]
+            .copy_from_slice(&args.num_pages.to_le_bytes());
         let virtio_cfg = VirtioConfig::new(device_features, queues, config_space);
 
         let common_cfg = CommonConfig::new(virtio_cfg, env).map_err(Error::Virtio)?;
 
         let balloon = Arc::new(Mutex::new(Balloon {
             cfg: common_cfg,
-            guest_memory: args.guest_memory.clone()
-
+            guest_memory: args.guest_memory.clone(),
+            handler: None,
+            metrics: Arc::new(BalloonMetrics::default()),
+            stats_polling_interval: args.stats_polling_interval,
+            stats: Arc::new(Mutex::new(None)),
         }));
 
         // Register the device on the MMIO bus.
@@ -72,13 +110,211 @@ where
 
         Ok(balloon)
     }
+
+    // Recreate a `Balloon` device from a saved state. The device is placed at the MMIO range
+    // and interrupt recorded in the state, regardless of `env.mmio_cfg`.
+    pub fn from_state<B>(
+        env: &mut Env<M, B>,
+        args: &BalloonArgs,
+        state: &BalloonState,
+    ) -> Result<Arc<Mutex<Self>>>
+    where
+        B: DerefMut,
+        B::Target: MmioManager<D = Arc<dyn DeviceMmio + Send + Sync>>,
+    {
+        env.mmio_cfg = state.mmio.mmio_config().map_err(Error::Virtio)?;
+
+        let balloon = Self::new(env, args)?;
+        balloon.lock().unwrap().restore(state, env.event_mgr)?;
+        Ok(balloon)
+    }
+
+    fn restore(
+        &mut self,
+        state: &BalloonState,
+        event_mgr: &mut EventManager<Subscriber>,
+    ) -> Result<()> {
+        self.cfg.restore(&state.virtio).map_err(Error::Virtio)?;
+        if let Some(secs) = state.stats_polling_interval_s {
+            self.stats_polling_interval = Duration::from_secs(secs);
+        }
+        if state.virtio.device_activated {
+            let handler = self.create_handler()?;
+            {
+                let mut handler = handler.lock().unwrap();
+                handler.inner.inflate_page_num = state.inflate_page_num;
+                handler.inner.inflated = state.inflated_pages.iter().copied().collect();
+                handler.inner.stats_desc = state.stats_desc_index;
+                // Notifications the driver sent right before the state was saved were lost,
+                // so have a look at the queues straight away.
+                handler
+                    .inflate_io
+                    .write(1)
+                    .and_then(|_| handler.deflate_io.write(1))
+                    .and_then(|_| handler.stats_io.as_ref().map_or(Ok(()), |io| io.write(1)))
+                    .and_then(|_| {
+                        handler
+                            .reporting_io
+                            .as_ref()
+                            .map_or(Ok(()), |io| io.write(1))
+                    })
+                    .map_err(|e| Error::Virtio(crate::virtio::Error::EventFd(e)))?;
+            }
+            self.cfg.finalize_restore(event_mgr, handler);
+        }
+        Ok(())
+    }
+
+    // Sets up the queue handler which takes over the queues once the device is activated.
+    fn create_handler(&mut self) -> Result<Arc<Mutex<QueueHandler<M>>>> {
+        let driver_notify = SingleFdSignalQueue {
+            irqfd: self.cfg.irqfd.clone(),
+            interrupt_status: self.cfg.virtio.interrupt_status.clone(),
+        };
+
+        // The stats and reporting queues only exist if the driver acknowledged the matching
+        // feature. They follow the deflate queue, in this order, without leaving a gap for the
+        // missing ones.
+        let driver_features = self.cfg.virtio.driver_features;
+        let acked = |feature: u64| driver_features & (1 << feature) != 0;
+        let stats_enabled = acked(VIRTIO_BALLOON_F_STATS_VQ);
+        let reporting_enabled = acked(VIRTIO_BALLOON_F_REPORTING);
+        self.cfg
+            .virtio
+            .queues
+            .truncate(2 + stats_enabled as usize + reporting_enabled as usize);
+
+        let mut ioevents = self.cfg.prepare_activate().map_err(Error::Virtio)?;
+
+        let stats_timer = if stats_enabled && !self.stats_polling_interval.is_zero() {
+            let mut timer = TimerFd::new().map_err(Error::Timer)?;
+            timer
+                .reset(
+                    self.stats_polling_interval,
+                    Some(self.stats_polling_interval),
+                )
+                .map_err(Error::Timer)?;
+            Some(timer)
+        } else {
+            None
+        };
+
+        let queues = &mut self.cfg.virtio.queues;
+        let inner = SimpleHandler {
+            driver_notify,
+            inflate: queues.remove(0),
+            deflate: queues.remove(0),
+            stats: stats_enabled.then(|| queues.remove(0)),
+            reporting: reporting_enabled.then(|| queues.remove(0)),
+            guest_mem: self.guest_memory.clone(),
+            inflate_page_num: 0,
+            inflated: BTreeSet::new(),
+            dirty_pages: self.cfg.dirty_pages.clone(),
+            metrics: self.metrics.clone(),
+            stats_desc: None,
+            stats_sample: self.stats.clone(),
+        };
+
+        let handler = Arc::new(Mutex::new(QueueHandler {
+            inner,
+            inflate_io: ioevents.remove(0),
+            deflate_io: ioevents.remove(0),
+            stats_io: stats_enabled.then(|| ioevents.remove(0)),
+            stats_timer,
+            reporting_io: reporting_enabled.then(|| ioevents.remove(0)),
+        }));
+
+        self.handler = Some(handler.clone());
+        Ok(handler)
+    }
+
     /// config: number of pages need to be inflated
     /// 524288: inflate 2G   0: give back all guest's memory
-    pub fn change_config(&mut self,size: u64){
-        self.write(256, &size.to_le_bytes());
-        self.cfg.virtio.interrupt_status.fetch_or(VIRTIO_MMIO_INT_CONFIG, Ordering::SeqCst);
+    pub fn change_config(&mut self, num_pages: u32) {
+        // Only `num_pages` is written, `actual` belongs to the driver.
+        self.cfg.virtio.config_space[NUM_PAGES_OFFSET..NUM_PAGES_OFFSET + 4]
+            .copy_from_slice(&num_pages.to_le_bytes());
+        self.cfg.virtio.config_generation = self.cfg.virtio.config_generation.wrapping_add(1);
+        self.cfg
+            .virtio
+            .interrupt_status
+            .fetch_or(VIRTIO_MMIO_INT_CONFIG, Ordering::SeqCst);
         self.cfg.irqfd.write(1).expect("fail write to eventfd");
     }
+
+    // The target size of the balloon, and the size the driver last reported.
+    pub fn size(&self) -> BalloonSize {
+        let field = |offset: usize| {
+            let mut value = [0u8; 4];
+            value.copy_from_slice(&self.cfg.virtio.config_space[offset..offset + 4]);
+            u32::from_le_bytes(value)
+        };
+        BalloonSize {
+            target_pages: field(NUM_PAGES_OFFSET),
+            actual_pages: field(ACTUAL_OFFSET),
+        }
+    }
+
+    // Counters of the pages moved in and out of the balloon.
+    pub fn metrics(&self) -> Arc<BalloonMetrics> {
+        self.metrics.clone()
+    }
+
+    // The memory statistics last reported by the driver, if any.
+    pub fn stats(&self) -> Option<StatsSample> {
+        self.stats.lock().unwrap().clone()
+    }
+
+    // Reset the device while the event loop is stopped (i.e. when the VM restarts), which the
+    // reset would otherwise wait for to remove the queue handler.
+    pub fn reset_with(&mut self, event_mgr: &mut EventManager<Subscriber>) -> Result<()> {
+        self.cfg
+            .remove_handler_with(event_mgr)
+            .map_err(Error::Virtio)?;
+        VirtioDeviceActions::reset(self)
+    }
+
+    // Returns the current state of the device. The queue handler must not be running
+    // concurrently (i.e. the caller runs on the event manager thread).
+    pub fn save_state(&self) -> BalloonState {
+        let queues = self
+            .cfg
+            .virtio
+            .queues
+            .iter()
+            .map(QueueState::from_queue)
+            .collect();
+        let mut state = BalloonState {
+            mmio: MmioState::from(&self.cfg.mmio),
+            virtio: VirtioState::new(&self.cfg.virtio, queues),
+            inflate_page_num: 0,
+            inflated_pages: Vec::new(),
+            stats_desc_index: None,
+            stats_polling_interval_s: Some(self.stats_polling_interval.as_secs()),
+        };
+
+        if let Some(handler) = self.handler.as_ref() {
+            let handler = handler.lock().unwrap();
+            let inner = &handler.inner;
+            state.virtio.queues = vec![
+                QueueState::from_queue(&inner.inflate),
+                QueueState::from_queue(&inner.deflate),
+            ];
+            state
+                .virtio
+                .queues
+                .extend(inner.stats.as_ref().map(QueueState::from_queue));
+            state
+                .virtio
+                .queues
+                .extend(inner.reporting.as_ref().map(QueueState::from_queue));
+            state.inflate_page_num = inner.inflate_page_num;
+            state.inflated_pages = inner.inflated.iter().copied().collect();
+            state.stats_desc_index = inner.stats_desc;
+        }
+
+        state
+    }
 }
 
 impl<M: GuestAddressSpace + Clone + Send + 'static> Borrow<VirtioConfig<M>> for Balloon<M> {
@@ -103,32 +339,17 @@ impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioDeviceActions for Ball
     type E = Error;
 
     fn activate(&mut self) -> Result<()> {
-        let driver_notify = SingleFdSignalQueue {
-            irqfd: self.cfg.irqfd.clone(),
-            interrupt_status: self.cfg.virtio.interrupt_status.clone(),
-        };
-
-        let mut ioevents = self.cfg.prepare_activate().map_err(Error::Virtio)?;
-
-        let inner = SimpleHandler{
-            driver_notify,
-            inflate: self.cfg.virtio.queues.remove(0),
-            deflate: self.cfg.virtio.queues.remove(0),
-            guest_mem: self.guest_memory.clone(),
-            inflate_page_num: 0
-        };
-
-        let handler = Arc::new(Mutex::new(QueueHandler {
-            inner,
-            inflate_io: ioevents.remove(0),
-            deflate_io: ioevents.remove(0),
-        }));
-
+        let handler = self.create_handler()?;
         self.cfg.finalize_activate(handler).map_err(Error::Virtio)
     }
 
     fn reset(&mut self) -> Result<()> {
-        // Not implemented for now.
+        self.cfg.reset().map_err(Error::Virtio)?;
+        // Drops the queue handler along with its queues.
+        self.handler = None;
+        // The driver reports the balloon size again once it is set up.
+        self.cfg.virtio.config_space[ACTUAL_OFFSET..ACTUAL_OFFSET + 4].copy_from_slice(&[0; 4]);
+        *self.stats.lock().unwrap() = None;
         Ok(())
     }
 }
@@ -144,4 +365,3 @@ impl<M: GuestAddressSpace + Clone + Send + 'static> MutDeviceMmio for Balloon<M>
         self.write(offset, data);
     }
 }
-
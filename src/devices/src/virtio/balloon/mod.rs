@@ -4,30 +4,45 @@
 mod device;
 mod queue_handler;
 mod simple_handler;
+mod stats;
 
+use std::time::Duration;
+
+pub use device::{Balloon, BalloonSize};
+pub use stats::{BalloonStats, StatsSample};
 use vm_memory::GuestMemoryMmap;
-pub use device::Balloon;
 
 // TODO: Move relevant defines to vm-virtio crate.
 
 // Values taken from the virtio standard (section 5.1.3 of the 1.1 version).
 pub mod features {
     pub const VIRTIO_F_VERSION_2: u64 = 32;
-}
 
+    // Section 5.5.3 of the 1.1 version.
+    pub const VIRTIO_BALLOON_F_STATS_VQ: u64 = 1;
+    pub const VIRTIO_BALLOON_F_DEFLATE_ON_OOM: u64 = 2;
+    pub const VIRTIO_BALLOON_F_REPORTING: u64 = 5;
+}
 
 // Net device ID as defined by the standard.
 pub const BALLOON_DEVICE_ID: u32 = 5;
 
-
-
 #[derive(Debug)]
 pub enum Error {
+    Timer(vmm_sys_util::errno::Error),
     Virtio(crate::virtio::Error),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
 pub struct BalloonArgs {
-    pub guest_memory: GuestMemoryMmap
+    pub guest_memory: GuestMemoryMmap,
+    // Number of 4 KiB pages the driver is asked to give back once it is set up.
+    pub num_pages: u32,
+    // Let the driver take pages back from the balloon on its own when the guest runs out of
+    // memory.
+    pub deflate_on_oom: bool,
+    // Statistics are only reported by the driver when it is asked to, so they are never
+    // updated past the first report when this is zero.
+    pub stats_polling_interval: Duration,
 }
@@ -6,21 +6,29 @@ use log::error;
 use vm_memory::{GuestAddressSpace, GuestMemory};
 use vmm_sys_util::epoll::EventSet;
 use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::timerfd::TimerFd;
 
 use crate::virtio::balloon::simple_handler::SimpleHandler;
 use crate::virtio::SingleFdSignalQueue;
 
 const INFLATE_IOEVENT_DATA: u32 = 0;
 const DEFLATE_IOEVENT_DATA: u32 = 1;
+const STATS_IOEVENT_DATA: u32 = 2;
+const STATS_TIMER_DATA: u32 = 3;
+const REPORTING_IOEVENT_DATA: u32 = 4;
 
 // This object simply combines the more generic `SimpleHandler` with a concrete queue
 // signalling implementation based on `EventFd`s, and then also implements `MutEventSubscriber`
 // to interact with the event manager. `ioeventfd` is the `EventFd` connected to queue
-// notifications coming from the driver.
+// notifications coming from the driver. `stats_timer` fires whenever the driver is due to send
+// new memory statistics.
 pub(crate) struct QueueHandler<M: GuestAddressSpace> {
-    pub inner:SimpleHandler<M, SingleFdSignalQueue>,
+    pub inner: SimpleHandler<M, SingleFdSignalQueue>,
     pub inflate_io: EventFd,
     pub deflate_io: EventFd,
+    pub stats_io: Option<EventFd>,
+    pub stats_timer: Option<TimerFd>,
+    pub reporting_io: Option<EventFd>,
 }
 
 impl<M: GuestAddressSpace> MutEventSubscriber for QueueHandler<M> {
@@ -31,7 +39,7 @@ impl<M: GuestAddressSpace> MutEventSubscriber for QueueHandler<M> {
         // just to be sure.
         if events.event_set() != EventSet::IN {
             error!("unexpected event_set");
-        } 
+        }
         match events.data() {
             INFLATE_IOEVENT_DATA => {
                 if self.inflate_io.read().is_err() {
@@ -53,6 +61,45 @@ impl<M: GuestAddressSpace> MutEventSubscriber for QueueHandler<M> {
                 }
             }
 
+            STATS_IOEVENT_DATA => {
+                if self.stats_io.as_ref().map_or(true, |io| io.read().is_err()) {
+                    error!("ioeventfd read error")
+                } else if let Err(e) = self.inner.process_stats() {
+                    error!("error processing stats queue {:?}", e);
+                } else {
+                    error = false;
+                }
+            }
+
+            STATS_TIMER_DATA => {
+                // Only clears the expirations, a single request covers the missed ones.
+                if self
+                    .stats_timer
+                    .as_mut()
+                    .map_or(true, |timer| timer.wait().is_err())
+                {
+                    error!("timerfd read error")
+                } else if let Err(e) = self.inner.request_stats() {
+                    error!("error requesting balloon statistics {:?}", e);
+                } else {
+                    error = false;
+                }
+            }
+
+            REPORTING_IOEVENT_DATA => {
+                if self
+                    .reporting_io
+                    .as_ref()
+                    .map_or(true, |io| io.read().is_err())
+                {
+                    error!("ioeventfd read error")
+                } else if let Err(e) = self.inner.process_reporting() {
+                    error!("error processing reporting queue {:?}", e);
+                } else {
+                    error = false;
+                }
+            }
+
             data => {
                 error!("unexpected events data {}", data);
             }
@@ -77,6 +124,30 @@ impl<M: GuestAddressSpace> MutEventSubscriber for QueueHandler<M> {
             EventSet::IN,
         ))
         .expect("Failed to init deflate queue handler");
+        if let Some(stats_io) = self.stats_io.as_ref() {
+            ops.add(Events::with_data(
+                stats_io,
+                STATS_IOEVENT_DATA,
+                EventSet::IN,
+            ))
+            .expect("Failed to init stats queue handler");
+        }
+        if let Some(stats_timer) = self.stats_timer.as_ref() {
+            ops.add(Events::with_data(
+                stats_timer,
+                STATS_TIMER_DATA,
+                EventSet::IN,
+            ))
+            .expect("Failed to init stats timer");
+        }
+        if let Some(reporting_io) = self.reporting_io.as_ref() {
+            ops.add(Events::with_data(
+                reporting_io,
+                REPORTING_IOEVENT_DATA,
+                EventSet::IN,
+            ))
+            .expect("Failed to init reporting queue handler");
+        }
     }
 }
 
@@ -1,20 +1,34 @@
 // Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
+use std::cmp::min;
+use std::collections::BTreeSet;
 use std::fs::File;
+use std::io;
 use std::result;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 use log::warn;
 use virtio_blk::request::Request;
 use virtio_blk::stdio_executor::{self, StdIoBackend};
 use virtio_queue::{DescriptorChain, Queue};
-use vm_memory::{self, Bytes, GuestAddress,GuestAddressSpace, Address, GuestMemoryMmap, GuestMemory};
+use vm_memory::{
+    self, Address, Bytes, GuestAddress, GuestAddressSpace, GuestMemory, GuestMemoryMmap,
+};
 
+use utils::debug;
+
+use crate::virtio::balloon::stats::{BalloonStats, StatsSample, STAT_SIZE};
+use crate::virtio::dirty::DirtyPages;
+use crate::virtio::metrics::{self, BalloonMetrics};
 use crate::virtio::SignalUsedQueue;
 
-const BALLOON_PAGE_SIZE:u32 = 4096;
-const BALLOON_PAGE_OFFSET:u32 = 12;
-const BALLOON_PFN_SIZE_BYTES:u32 = 4;
+const BALLOON_PAGE_SIZE: u32 = 4096;
+const BALLOON_PAGE_OFFSET: u32 = 12;
+const BALLOON_PFN_SIZE_BYTES: u32 = 4;
+// The driver reports fewer than a dozen statistics, anything past this is ignored.
+const MAX_STATS_LEN: usize = 64 * STAT_SIZE;
 
 #[derive(Debug)]
 pub enum Error {
@@ -51,7 +65,19 @@ pub struct SimpleHandler<M: GuestAddressSpace, S: SignalUsedQueue> {
     pub inflate: Queue<M>,
     pub deflate: Queue<M>,
     pub guest_mem: GuestMemoryMmap,
-    pub inflate_page_num: u64
+    pub inflate_page_num: u64,
+    // Frame numbers of the pages in the balloon.
+    pub inflated: BTreeSet<u64>,
+    pub dirty_pages: Arc<DirtyPages>,
+    pub metrics: Arc<BalloonMetrics>,
+    // Only there if the driver acknowledged `VIRTIO_BALLOON_F_STATS_VQ`.
+    pub stats: Option<Queue<M>>,
+    // Head of the statistics buffer of the driver. The device holds on to it until it wants
+    // new statistics, which the driver sends once the buffer is used.
+    pub stats_desc: Option<u16>,
+    pub stats_sample: Arc<Mutex<Option<StatsSample>>>,
+    // Only there if the driver acknowledged `VIRTIO_BALLOON_F_REPORTING`.
+    pub reporting: Option<Queue<M>>,
 }
 
 impl<M, S> SimpleHandler<M, S>
@@ -59,52 +85,77 @@ where
     M: GuestAddressSpace,
     S: SignalUsedQueue,
 {
-    fn inflate_page(&mut self, pfn:u32) -> result::Result<(), Error> {
-        let gva = GuestAddress((pfn << BALLOON_PAGE_OFFSET).into());
-        //TODO 
-        //if let Some(region) = self.guest_mem.find_region(gva) {
-            let hva = self.guest_mem.get_host_address(gva)
-                .expect("get hva failed");
-            let ret = unsafe{
-                libc::madvise(hva.cast(), BALLOON_PAGE_SIZE as usize, libc::MADV_DONTNEED)
-            };
-            if ret < 0 {
-                println!("madvise failed");
-            } else {
-                self.inflate_page_num += 1;
-            }
-        //}
-        Ok(())
+    // Host address of the page with frame number `pfn`, if the whole page is in guest memory.
+    // The guest physical address is computed in 64 bits, as memory past the MMIO gap starts at
+    // 4 GiB, while the frame numbers which fall in the gap are rejected.
+    fn page_address(&self, pfn: u32) -> Option<(GuestAddress, *mut u8)> {
+        let gpa = GuestAddress(u64::from(pfn) << BALLOON_PAGE_OFFSET);
+        self.guest_mem
+            .get_slice(gpa, BALLOON_PAGE_SIZE as usize)
+            .ok()
+            .map(|slice| (gpa, slice.as_ptr()))
     }
-    
-    fn deflate_page(&mut self, pfn:u32) -> result::Result<(), Error> {
-        let gva = GuestAddress((pfn << BALLOON_PAGE_OFFSET).into());
-        //TODO 
-        //if let Some(region) = self.guest_mem.find_region(gva) {
-            let hva = self.guest_mem.get_host_address(gva)
-                .expect("get hva failed");
-            let ret = unsafe{
-                libc::madvise(hva.cast(), BALLOON_PAGE_SIZE as usize, libc::MADV_WILLNEED)
-            };
-            if ret < 0 {
-                println!("madvise failed");
-            } else {
-                self.inflate_page_num -= 1;
+
+    fn inflate_page(&mut self, pfn: u32) {
+        if self.inflated.contains(&u64::from(pfn)) {
+            debug!("Inflated page {:#x} is already in the balloon", pfn);
+            return;
+        }
+        let (gpa, hva) = match self.page_address(pfn) {
+            Some(page) => page,
+            None => {
+                warn!("Inflated page {:#x} is not in guest memory", pfn);
+                metrics::add(&self.metrics.errors, 1);
+                return;
             }
-        //}
-        Ok(())
+        };
+        let ret =
+            unsafe { libc::madvise(hva.cast(), BALLOON_PAGE_SIZE as usize, libc::MADV_DONTNEED) };
+        if ret < 0 {
+            warn!("madvise failed: {}", io::Error::last_os_error());
+            metrics::add(&self.metrics.errors, 1);
+            return;
+        }
+        self.inflated.insert(pfn.into());
+        self.inflate_page_num += 1;
+        metrics::add(&self.metrics.inflate_pages, 1);
+        // The page reads as zeroes from now on.
+        self.dirty_pages.mark(gpa, BALLOON_PAGE_SIZE.into());
+    }
+
+    // The memory of a deflated page is faulted back in when the guest touches it, so only the
+    // accounting is left. With `VIRTIO_BALLOON_F_DEFLATE_ON_OOM`, the driver also deflates pages
+    // the host did not ask for when the guest runs out of memory, and may already use them,
+    // so they must not be touched here.
+    fn deflate_page(&mut self, pfn: u32) {
+        if self.inflated.remove(&u64::from(pfn)) {
+            self.inflate_page_num -= 1;
+        } else {
+            debug!("Deflated page {:#x} was not in the balloon", pfn);
+        }
+        metrics::add(&self.metrics.deflate_pages, 1);
     }
-        
-    fn process_chain(&mut self, chain: &mut DescriptorChain<M::T>, is_inflate:bool) -> result::Result<(), Error> {
-        let mut buf:[u8;BALLOON_PFN_SIZE_BYTES as usize] = [0;BALLOON_PFN_SIZE_BYTES as usize];
+
+    // The chain holds an array of 32 bit frame numbers. Descriptors the device cannot read are
+    // counted as errors and skipped, and a trailing partial frame number is ignored.
+    fn process_chain(&mut self, chain: &mut DescriptorChain<M::T>, is_inflate: bool) {
+        let mut buf: [u8; BALLOON_PFN_SIZE_BYTES as usize] = [0; BALLOON_PFN_SIZE_BYTES as usize];
         while let Some(desc) = chain.next() {
-            let mut offset:u64 = 0;
-            let len = desc.len() as u64;
-            while offset < len {
-                let addr = desc.addr().checked_add(offset).expect("address overflow");
-                chain.memory()
-                    .read_slice(&mut buf, addr)
-                    .map_err(Error::GuestMemory)?;
+            let len = u64::from(desc.len());
+            let end = len - len % u64::from(BALLOON_PFN_SIZE_BYTES);
+            for offset in (0..end).step_by(BALLOON_PFN_SIZE_BYTES as usize) {
+                let read = desc
+                    .addr()
+                    .checked_add(offset)
+                    .ok_or(vm_memory::GuestMemoryError::InvalidGuestAddress(
+                        desc.addr(),
+                    ))
+                    .and_then(|addr| chain.memory().read_slice(&mut buf, addr));
+                if let Err(e) = read {
+                    warn!("Failed to read the balloon page frame numbers: {:?}", e);
+                    metrics::add(&self.metrics.errors, 1);
+                    break;
+                }
 
                 let pfn = u32::from_le_bytes(buf);
                 if is_inflate {
@@ -112,14 +163,8 @@ where
                 } else {
                     self.deflate_page(pfn);
                 }
-
-                offset += BALLOON_PFN_SIZE_BYTES as u64;
-
             }
-            
         }
-
-        Ok(())
     }
 
     pub fn process_inflate(&mut self) -> result::Result<(), Error> {
@@ -129,8 +174,9 @@ where
             self.inflate.disable_notification()?;
 
             while let Some(mut chain) = self.inflate.iter()?.next() {
-                self.process_chain(&mut chain, true)?;
+                self.process_chain(&mut chain, true);
                 self.inflate.add_used(chain.head_index(), 0)?;
+                self.dirty_pages.mark_used_ring(&self.inflate);
 
                 if self.inflate.needs_notification()? {
                     self.driver_notify.signal_used_queue(0);
@@ -152,8 +198,9 @@ where
             self.deflate.disable_notification()?;
 
             while let Some(mut chain) = self.deflate.iter()?.next() {
-                self.process_chain(&mut chain, false)?;
+                self.process_chain(&mut chain, false);
                 self.deflate.add_used(chain.head_index(), 0)?;
+                self.dirty_pages.mark_used_ring(&self.deflate);
 
                 if self.deflate.needs_notification()? {
                     self.driver_notify.signal_used_queue(0);
@@ -167,7 +214,224 @@ where
 
         Ok(())
     }
+
+    // Reads the statistics in the device readable descriptors of the chain.
+    fn read_stats_chain(
+        chain: &mut DescriptorChain<M::T>,
+    ) -> result::Result<Vec<u8>, vm_memory::GuestMemoryError> {
+        let mut buf = Vec::new();
+        while let Some(desc) = chain.next() {
+            if desc.is_write_only() {
+                continue;
+            }
+            let start = buf.len();
+            buf.resize(start + min(desc.len() as usize, MAX_STATS_LEN - start), 0);
+            chain.memory().read_slice(&mut buf[start..], desc.addr())?;
+        }
+        Ok(buf)
+    }
+
+    pub fn process_stats(&mut self) -> result::Result<(), Error> {
+        let stats = match self.stats.as_mut() {
+            Some(stats) => stats,
+            None => return Ok(()),
+        };
+
+        // To see why this is done in a loop, please look at the `Queue::enable_notification`
+        // comments in `virtio_queue`.
+        loop {
+            stats.disable_notification()?;
+
+            while let Some(mut chain) = stats.iter()?.next() {
+                let buf = match Self::read_stats_chain(&mut chain) {
+                    Ok(buf) => buf,
+                    Err(e) => {
+                        // Give the buffer back, so the driver can send the statistics again.
+                        warn!("Failed to read the balloon statistics: {:?}", e);
+                        metrics::add(&self.metrics.errors, 1);
+                        stats.add_used(chain.head_index(), 0)?;
+                        self.dirty_pages.mark_used_ring(stats);
+                        if stats.needs_notification()? {
+                            self.driver_notify.signal_used_queue(2);
+                        }
+                        continue;
+                    }
+                };
+
+                *self.stats_sample.lock().unwrap() = Some(StatsSample {
+                    stats: BalloonStats::parse(&buf),
+                    time: Instant::now(),
+                });
+
+                // The driver only has one buffer in flight. Give back the previous one if it
+                // sent another anyway, so it does not leak.
+                if let Some(head) = self.stats_desc.replace(chain.head_index()) {
+                    warn!("The balloon driver sent more than one statistics buffer");
+                    stats.add_used(head, 0)?;
+                    self.dirty_pages.mark_used_ring(stats);
+                    if stats.needs_notification()? {
+                        self.driver_notify.signal_used_queue(2);
+                    }
+                }
+            }
+
+            if !stats.enable_notification()? {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Gives the free page ranges reported by the driver back to the host. Each descriptor
+    // holds one range, and the driver does not use the pages until the chain is returned.
+    fn process_report_chain(&mut self, chain: &mut DescriptorChain<M::T>) {
+        while let Some(desc) = chain.next() {
+            let len = desc.len() as usize;
+            let hva = match self.guest_mem.get_slice(desc.addr(), len) {
+                Ok(slice) => slice.as_ptr(),
+                Err(e) => {
+                    warn!("Reported free page range is not in guest memory: {:?}", e);
+                    metrics::add(&self.metrics.errors, 1);
+                    continue;
+                }
+            };
+            let ret = unsafe { libc::madvise(hva.cast(), len, libc::MADV_DONTNEED) };
+            if ret < 0 {
+                warn!("madvise failed: {}", io::Error::last_os_error());
+                metrics::add(&self.metrics.errors, 1);
+            } else {
+                metrics::add(&self.metrics.reported_bytes, len as u64);
+                // The pages read as zeroes from now on.
+                self.dirty_pages.mark(desc.addr(), len as u64);
+            }
+        }
+    }
+
+    pub fn process_reporting(&mut self) -> result::Result<(), Error> {
+        // Taken out so the chains can be processed while the queue is borrowed.
+        let mut reporting = match self.reporting.take() {
+            Some(reporting) => reporting,
+            None => return Ok(()),
+        };
+        let result = self.process_reporting_queue(&mut reporting);
+        self.reporting = Some(reporting);
+        result
+    }
+
+    fn process_reporting_queue(&mut self, reporting: &mut Queue<M>) -> result::Result<(), Error> {
+        // The queues have no gaps, so the reporting queue comes right after the optional
+        // statistics queue.
+        let index = if self.stats.is_some() { 3 } else { 2 };
+
+        // To see why this is done in a loop, please look at the `Queue::enable_notification`
+        // comments in `virtio_queue`.
+        loop {
+            reporting.disable_notification()?;
+
+            while let Some(mut chain) = reporting.iter()?.next() {
+                self.process_report_chain(&mut chain);
+                reporting.add_used(chain.head_index(), 0)?;
+                self.dirty_pages.mark_used_ring(reporting);
+
+                if reporting.needs_notification()? {
+                    self.driver_notify.signal_used_queue(index);
+                }
+            }
+
+            if !reporting.enable_notification()? {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Asks the driver for new statistics, by giving back the buffer it sent them in.
+    pub fn request_stats(&mut self) -> result::Result<(), Error> {
+        if let (Some(stats), Some(head)) = (self.stats.as_mut(), self.stats_desc.take()) {
+            stats.add_used(head, 0)?;
+            self.dirty_pages.mark_used_ring(stats);
+            if stats.needs_notification()? {
+                self.driver_notify.signal_used_queue(2);
+            }
+        }
+        Ok(())
+    }
 }
 
-// TODO: Figure out which unit tests make sense to add after implementing a generic backend
-// abstraction for `InOrderHandler`.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::Ordering;
+
+    struct NoSignal;
+
+    impl SignalUsedQueue for NoSignal {
+        fn signal_used_queue(&self, _index: u16) {}
+    }
+
+    fn handler(guest_mem: GuestMemoryMmap) -> SimpleHandler<Arc<GuestMemoryMmap>, NoSignal> {
+        let mem = Arc::new(guest_mem.clone());
+        SimpleHandler {
+            driver_notify: NoSignal,
+            inflate: Queue::new(mem.clone(), 16),
+            deflate: Queue::new(mem, 16),
+            guest_mem,
+            inflate_page_num: 0,
+            inflated: BTreeSet::new(),
+            dirty_pages: Arc::new(DirtyPages::default()),
+            metrics: Arc::new(BalloonMetrics::default()),
+            stats: None,
+            stats_desc: None,
+            stats_sample: Arc::new(Mutex::new(None)),
+            reporting: None,
+        }
+    }
+
+    #[test]
+    fn test_inflate_deflate_pages() {
+        // 1 MiB at 0, and 1 MiB at 4 GiB as past the MMIO gap.
+        let guest_mem = GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), 0x10_0000),
+            (GuestAddress(1 << 32), 0x10_0000),
+        ])
+        .unwrap();
+        let mut handler = handler(guest_mem.clone());
+        guest_mem.write_obj(0xffu8, GuestAddress(0x1000)).unwrap();
+        guest_mem
+            .write_obj(0xffu8, GuestAddress((1 << 32) + 0x1000))
+            .unwrap();
+
+        handler.inflate_page(1);
+        handler.inflate_page(0x10_0001);
+        // Already in the balloon.
+        handler.inflate_page(1);
+        assert_eq!(handler.inflate_page_num, 2);
+        assert_eq!(handler.metrics.inflate_pages.load(Ordering::Relaxed), 2);
+        assert_eq!(guest_mem.read_obj::<u8>(GuestAddress(0x1000)).unwrap(), 0);
+        assert_eq!(
+            guest_mem
+                .read_obj::<u8>(GuestAddress((1 << 32) + 0x1000))
+                .unwrap(),
+            0
+        );
+
+        // In the gap, and past the end of guest memory.
+        handler.inflate_page(0x100);
+        handler.inflate_page(u32::MAX);
+        assert_eq!(handler.inflate_page_num, 2);
+        assert_eq!(handler.metrics.errors.load(Ordering::Relaxed), 2);
+
+        handler.deflate_page(1);
+        // Not in the balloon.
+        handler.deflate_page(1);
+        handler.deflate_page(2);
+        assert_eq!(handler.inflate_page_num, 1);
+        assert_eq!(
+            handler.inflated.iter().copied().collect::<Vec<_>>(),
+            vec![0x10_0001]
+        );
+    }
+}
@@ -0,0 +1,135 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+// Memory statistics the driver reports on the stats queue (section 5.5.6.3 of the virtio
+// standard, version 1.1).
+
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+// Each entry is a packed `struct virtio_balloon_stat { le16 tag; le64 val; }`.
+pub(crate) const STAT_SIZE: usize = 10;
+
+const VIRTIO_BALLOON_S_SWAP_IN: u16 = 0;
+const VIRTIO_BALLOON_S_SWAP_OUT: u16 = 1;
+const VIRTIO_BALLOON_S_MAJFLT: u16 = 2;
+const VIRTIO_BALLOON_S_MINFLT: u16 = 3;
+const VIRTIO_BALLOON_S_MEMFREE: u16 = 4;
+const VIRTIO_BALLOON_S_MEMTOT: u16 = 5;
+const VIRTIO_BALLOON_S_AVAIL: u16 = 6;
+const VIRTIO_BALLOON_S_CACHES: u16 = 7;
+const VIRTIO_BALLOON_S_HTLB_PGALLOC: u16 = 8;
+const VIRTIO_BALLOON_S_HTLB_PGFAIL: u16 = 9;
+
+/// Memory statistics of the guest. The amounts of memory are in bytes. Drivers only report
+/// the statistics they know about, so the others are missing.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct BalloonStats {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub swap_in: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub swap_out: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub major_faults: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub minor_faults: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub free_memory: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_memory: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub available_memory: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disk_caches: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hugetlb_allocations: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hugetlb_failures: Option<u64>,
+}
+
+impl BalloonStats {
+    // Parses the entries in `buf`. Unknown tags and a trailing partial entry are ignored.
+    pub(crate) fn parse(buf: &[u8]) -> Self {
+        let mut stats = BalloonStats::default();
+        for entry in buf.chunks_exact(STAT_SIZE) {
+            let tag = u16::from_le_bytes([entry[0], entry[1]]);
+            let mut val = [0u8; 8];
+            val.copy_from_slice(&entry[2..]);
+            let val = Some(u64::from_le_bytes(val));
+            match tag {
+                VIRTIO_BALLOON_S_SWAP_IN => stats.swap_in = val,
+                VIRTIO_BALLOON_S_SWAP_OUT => stats.swap_out = val,
+                VIRTIO_BALLOON_S_MAJFLT => stats.major_faults = val,
+                VIRTIO_BALLOON_S_MINFLT => stats.minor_faults = val,
+                VIRTIO_BALLOON_S_MEMFREE => stats.free_memory = val,
+                VIRTIO_BALLOON_S_MEMTOT => stats.total_memory = val,
+                VIRTIO_BALLOON_S_AVAIL => stats.available_memory = val,
+                VIRTIO_BALLOON_S_CACHES => stats.disk_caches = val,
+                VIRTIO_BALLOON_S_HTLB_PGALLOC => stats.hugetlb_allocations = val,
+                VIRTIO_BALLOON_S_HTLB_PGFAIL => stats.hugetlb_failures = val,
+                _ => (),
+            }
+        }
+        stats
+    }
+}
+
+/// The statistics last reported by the driver.
+#[derive(Clone, Debug)]
+pub struct StatsSample {
+    pub stats: BalloonStats,
+    /// When the statistics were received.
+    pub time: Instant,
+}
+
+impl StatsSample {
+    /// Time elapsed since the statistics were received.
+    pub fn age(&self) -> Duration {
+        self.time.elapsed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stat(tag: u16, val: u64) -> Vec<u8> {
+        let mut entry = tag.to_le_bytes().to_vec();
+        entry.extend_from_slice(&val.to_le_bytes());
+        entry
+    }
+
+    #[test]
+    fn test_parse() {
+        let mut buf = Vec::new();
+        buf.extend(stat(VIRTIO_BALLOON_S_MEMFREE, 1 << 30));
+        buf.extend(stat(VIRTIO_BALLOON_S_MAJFLT, 42));
+        // Unknown tag.
+        buf.extend(stat(100, 7));
+        buf.extend(stat(VIRTIO_BALLOON_S_AVAIL, 3 << 29));
+        // Partial entry.
+        buf.extend(&[VIRTIO_BALLOON_S_SWAP_IN as u8, 0, 1]);
+
+        let stats = BalloonStats::parse(&buf);
+        assert_eq!(
+            stats,
+            BalloonStats {
+                major_faults: Some(42),
+                free_memory: Some(1 << 30),
+                available_memory: Some(3 << 29),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            serde_json::to_value(&stats).unwrap(),
+            serde_json::json!({
+                "major_faults": 42,
+                "free_memory": 1u64 << 30,
+                "available_memory": 3u64 << 29,
+            })
+        );
+
+        assert_eq!(BalloonStats::parse(&[]), BalloonStats::default());
+    }
+}
@@ -2,11 +2,12 @@
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
 use std::borrow::{Borrow, BorrowMut};
-use std::fs::OpenOptions;
+use std::fs::{File, OpenOptions};
 use std::ops::DerefMut;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 
+use event_manager::EventManager;
 use virtio_blk::stdio_executor::StdIoBackend;
 use virtio_device::{VirtioConfig, VirtioDeviceActions, VirtioDeviceType, VirtioMmioDevice};
 use virtio_queue::Queue;
@@ -15,8 +16,10 @@ use vm_device::device_manager::MmioManager;
 use vm_device::{DeviceMmio, MutDeviceMmio};
 use vm_memory::GuestAddressSpace;
 
-use crate::virtio::block::{BLOCK_DEVICE_ID, VIRTIO_BLK_F_RO};
-use crate::virtio::{CommonConfig, Env, SingleFdSignalQueue, QUEUE_MAX_SIZE};
+use crate::virtio::block::{BLOCK_DEVICE_ID, VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_RO};
+use crate::virtio::metrics::BlockMetrics;
+use crate::virtio::persist::{BlockState, MmioState, QueueState, VirtioState};
+use crate::virtio::{CommonConfig, Env, SingleFdSignalQueue, Subscriber, QUEUE_MAX_SIZE};
 
 use super::inorder_handler::InOrderQueueHandler;
 use super::queue_handler::QueueHandler;
@@ -29,9 +32,10 @@ pub struct Block<M: GuestAddressSpace> {
     cfg: CommonConfig<M>,
     file_path: PathBuf,
     read_only: bool,
-    // We'll prob need to remember this for state save/restore unless we pass the info from
-    // the outside.
-    _root_device: bool,
+    root_device: bool,
+    // Set once the device is activated, and used to retrieve the queue state.
+    handler: Option<Arc<Mutex<QueueHandler<M>>>>,
+    metrics: Arc<BlockMetrics>,
 }
 
 impl<M> Block<M>
@@ -53,7 +57,9 @@ where
             cfg: common_cfg,
             file_path: args.file_path.clone(),
             read_only: args.read_only,
-            _root_device: args.root_device,
+            root_device: args.root_device,
+            handler: None,
+            metrics: Arc::new(BlockMetrics::default()),
         })
     }
 
@@ -77,30 +83,53 @@ where
 
         Ok(block)
     }
-}
 
-impl<M: GuestAddressSpace + Clone + Send + 'static> Borrow<VirtioConfig<M>> for Block<M> {
-    fn borrow(&self) -> &VirtioConfig<M> {
-        &self.cfg.virtio
-    }
-}
+    // Recreate a `Block` device from a saved state. The device is placed at the MMIO range and
+    // interrupt recorded in the state, regardless of `env.mmio_cfg`.
+    pub fn from_state<B>(env: &mut Env<M, B>, state: &BlockState) -> Result<Arc<Mutex<Self>>>
+    where
+        B: DerefMut,
+        B::Target: MmioManager<D = Arc<dyn DeviceMmio + Send + Sync>>,
+    {
+        env.mmio_cfg = state.mmio.mmio_config().map_err(Error::Virtio)?;
+        let args = BlockArgs {
+            file_path: state.file_path.clone(),
+            read_only: state.read_only,
+            root_device: state.root_device,
+            advertise_flush: state.virtio.device_features & (1 << VIRTIO_BLK_F_FLUSH) != 0,
+        };
 
-impl<M: GuestAddressSpace + Clone + Send + 'static> BorrowMut<VirtioConfig<M>> for Block<M> {
-    fn borrow_mut(&mut self) -> &mut VirtioConfig<M> {
-        &mut self.cfg.virtio
+        let block = Self::new(env, &args)?;
+        block
+            .lock()
+            .unwrap()
+            .restore(&state.virtio, env.event_mgr)?;
+        Ok(block)
     }
-}
 
-impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioDeviceType for Block<M> {
-    fn device_type(&self) -> u32 {
-        BLOCK_DEVICE_ID
+    fn restore(
+        &mut self,
+        state: &VirtioState,
+        event_mgr: &mut EventManager<Subscriber>,
+    ) -> Result<()> {
+        self.cfg.restore(state).map_err(Error::Virtio)?;
+        if state.device_activated {
+            let handler = self.create_handler()?;
+            // Notifications the driver sent right before the state was saved were lost, so
+            // have a look at the queue straight away.
+            handler
+                .lock()
+                .unwrap()
+                .ioeventfd
+                .write(1)
+                .map_err(|e| Error::Virtio(crate::virtio::Error::EventFd(e)))?;
+            self.cfg.finalize_restore(event_mgr, handler);
+        }
+        Ok(())
     }
-}
 
-impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioDeviceActions for Block<M> {
-    type E = Error;
-
-    fn activate(&mut self) -> Result<()> {
+    // Sets up the queue handler which takes over the queue once the device is activated.
+    fn create_handler(&mut self) -> Result<Arc<Mutex<QueueHandler<M>>>> {
         let file = OpenOptions::new()
             .read(true)
             .write(!self.read_only)
@@ -128,6 +157,8 @@ impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioDeviceActions for Bloc
             driver_notify,
             queue: self.cfg.virtio.queues.remove(0),
             disk,
+            dirty_pages: self.cfg.dirty_pages.clone(),
+            metrics: self.metrics.clone(),
         };
 
         let handler = Arc::new(Mutex::new(QueueHandler {
@@ -135,11 +166,91 @@ impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioDeviceActions for Bloc
             ioeventfd: ioevents.remove(0),
         }));
 
+        self.handler = Some(handler.clone());
+        Ok(handler)
+    }
+
+    // Flushes what the guest wrote to the disk backing the device.
+    pub fn sync(&self) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        // The backend writes straight to the file, so syncing any descriptor of the file also
+        // syncs the writes of the backend.
+        File::open(&self.file_path)
+            .and_then(|file| file.sync_all())
+            .map_err(Error::Sync)
+    }
+
+    // Counters of the requests processed by the device.
+    pub fn metrics(&self) -> Arc<BlockMetrics> {
+        self.metrics.clone()
+    }
+
+    // Reset the device while the event loop is stopped (i.e. when the VM restarts), which the
+    // reset would otherwise wait for to remove the queue handler.
+    pub fn reset_with(&mut self, event_mgr: &mut EventManager<Subscriber>) -> Result<()> {
+        self.cfg
+            .remove_handler_with(event_mgr)
+            .map_err(Error::Virtio)?;
+        VirtioDeviceActions::reset(self)
+    }
+
+    // Returns the current state of the device. The queue handler must not be running
+    // concurrently (i.e. the caller runs on the event manager thread), otherwise the queue
+    // positions may be stale by the time this returns.
+    pub fn save_state(&self) -> BlockState {
+        let queues = match self.handler.as_ref() {
+            Some(handler) => vec![QueueState::from_queue(&handler.lock().unwrap().inner.queue)],
+            None => self
+                .cfg
+                .virtio
+                .queues
+                .iter()
+                .map(QueueState::from_queue)
+                .collect(),
+        };
+
+        BlockState {
+            mmio: MmioState::from(&self.cfg.mmio),
+            virtio: VirtioState::new(&self.cfg.virtio, queues),
+            file_path: self.file_path.clone(),
+            read_only: self.read_only,
+            root_device: self.root_device,
+        }
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send + 'static> Borrow<VirtioConfig<M>> for Block<M> {
+    fn borrow(&self) -> &VirtioConfig<M> {
+        &self.cfg.virtio
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send + 'static> BorrowMut<VirtioConfig<M>> for Block<M> {
+    fn borrow_mut(&mut self) -> &mut VirtioConfig<M> {
+        &mut self.cfg.virtio
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioDeviceType for Block<M> {
+    fn device_type(&self) -> u32 {
+        BLOCK_DEVICE_ID
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioDeviceActions for Block<M> {
+    type E = Error;
+
+    fn activate(&mut self) -> Result<()> {
+        let handler = self.create_handler()?;
         self.cfg.finalize_activate(handler).map_err(Error::Virtio)
     }
 
     fn reset(&mut self) -> Result<()> {
-        // Not implemented for now.
+        self.cfg.reset().map_err(Error::Virtio)?;
+        // Drops the queue handler along with its queues.
+        self.handler = None;
         Ok(())
     }
 }
@@ -196,5 +307,19 @@ mod tests {
             block.cfg.virtio.device_features & (1 << VIRTIO_BLK_F_FLUSH),
             0
         );
+
+        let state = block.save_state();
+        assert_eq!(state.mmio.base, mock.mmio_cfg.range.base().0);
+        assert_eq!(state.mmio.gsi, mock.mmio_cfg.gsi);
+        assert_eq!(
+            state.virtio.device_features,
+            block.cfg.virtio.device_features
+        );
+        assert!(!state.virtio.device_activated);
+        assert_eq!(state.virtio.queues.len(), 1);
+        assert_eq!(state.virtio.queues[0].max_size, QUEUE_MAX_SIZE);
+        assert_eq!(state.file_path, tmp.as_path());
+        assert!(state.read_only);
+        assert!(state.root_device);
     }
 }
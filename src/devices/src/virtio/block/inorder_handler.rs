@@ -3,13 +3,16 @@
 
 use std::fs::File;
 use std::result;
+use std::sync::Arc;
 
 use log::warn;
-use virtio_blk::request::Request;
+use virtio_blk::request::{Request, RequestType};
 use virtio_blk::stdio_executor::{self, StdIoBackend};
 use virtio_queue::{DescriptorChain, Queue};
 use vm_memory::{self, GuestAddressSpace};
 
+use crate::virtio::dirty::DirtyPages;
+use crate::virtio::metrics::{self, BlockMetrics};
 use crate::virtio::SignalUsedQueue;
 
 #[derive(Debug)]
@@ -46,6 +49,8 @@ pub struct InOrderQueueHandler<M: GuestAddressSpace, S: SignalUsedQueue> {
     pub driver_notify: S,
     pub queue: Queue<M>,
     pub disk: StdIoBackend<File>,
+    pub dirty_pages: Arc<DirtyPages>,
+    pub metrics: Arc<BlockMetrics>,
 }
 
 impl<M, S> InOrderQueueHandler<M, S>
@@ -54,15 +59,35 @@ where
     S: SignalUsedQueue,
 {
     fn process_chain(&mut self, mut chain: DescriptorChain<M::T>) -> result::Result<(), Error> {
+        // Read requests and the status byte end up in the device writable buffers.
+        self.dirty_pages.mark_chain(&chain);
+        metrics::add(&self.metrics.requests, 1);
         let used_len = match Request::parse(&mut chain) {
-            Ok(request) => self.disk.process_request(chain.memory(), &request)?,
+            Ok(request) => {
+                let used_len = self
+                    .disk
+                    .process_request(chain.memory(), &request)
+                    .map_err(|e| {
+                        metrics::add(&self.metrics.errors, 1);
+                        e
+                    })?;
+                let len = request.data().iter().map(|&(_, len)| u64::from(len)).sum();
+                match request.request_type() {
+                    RequestType::In => metrics::add(&self.metrics.read_bytes, len),
+                    RequestType::Out => metrics::add(&self.metrics.write_bytes, len),
+                    _ => (),
+                }
+                used_len
+            }
             Err(e) => {
                 warn!("block request parse error: {:?}", e);
+                metrics::add(&self.metrics.errors, 1);
                 0
             }
         };
 
         self.queue.add_used(chain.head_index(), used_len)?;
+        self.dirty_pages.mark_used_ring(&self.queue);
 
         if self.queue.needs_notification()? {
             self.driver_notify.signal_used_queue(0);
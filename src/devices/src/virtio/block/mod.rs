@@ -34,6 +34,7 @@ pub enum Error {
     Virtio(crate::virtio::Error),
     OpenFile(io::Error),
     Seek(io::Error),
+    Sync(io::Error),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
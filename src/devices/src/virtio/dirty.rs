@@ -0,0 +1,109 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+// KVM only logs the guest memory writes done by vCPUs. The pages the VMM writes on behalf of the
+// virtio devices (buffers the driver made available for the device to fill, and the used rings)
+// are recorded here instead, while tracking is enabled.
+
+use std::collections::BTreeSet;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use virtio_queue::{DescriptorChain, Queue};
+use vm_memory::{GuestAddress, GuestAddressSpace, GuestMemory};
+
+// Granularity of the tracking, which matches the one of the KVM dirty log.
+pub const DIRTY_PAGE_SIZE: u64 = 4096;
+
+#[derive(Debug, Default)]
+pub struct DirtyPages {
+    enabled: AtomicBool,
+    // Guest page frame numbers.
+    pages: Mutex<BTreeSet<u64>>,
+}
+
+impl DirtyPages {
+    // Start recording pages. Pages recorded by a previous tracking session are dropped.
+    pub fn enable(&self) {
+        self.pages.lock().unwrap().clear();
+        self.enabled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn disable(&self) {
+        self.enabled.store(false, Ordering::SeqCst);
+        self.pages.lock().unwrap().clear();
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::SeqCst)
+    }
+
+    // Record the pages spanned by `len` bytes starting at `addr`.
+    pub fn mark(&self, addr: GuestAddress, len: u64) {
+        if !self.is_enabled() || len == 0 {
+            return;
+        }
+        let first = addr.0 / DIRTY_PAGE_SIZE;
+        let last = addr.0.saturating_add(len - 1) / DIRTY_PAGE_SIZE;
+        self.pages.lock().unwrap().extend(first..=last);
+    }
+
+    // Record the buffers of `chain` the device is allowed to write to. This has to be called
+    // before the chain is consumed, and errs on the side of marking whole buffers.
+    pub fn mark_chain<M>(&self, chain: &DescriptorChain<M>)
+    where
+        M: Clone + Deref,
+        M::Target: GuestMemory,
+    {
+        if !self.is_enabled() {
+            return;
+        }
+        for desc in chain.clone() {
+            if desc.is_write_only() {
+                self.mark(desc.addr(), u64::from(desc.len()));
+            }
+        }
+    }
+
+    // Record the used ring of `queue`, which changes every time the device returns a chain.
+    pub fn mark_used_ring<M: GuestAddressSpace>(&self, queue: &Queue<M>) {
+        // flags, idx, the ring itself and avail_event.
+        let len = 4 + 8 * u64::from(queue.state.size) + 2;
+        self.mark(queue.state.used_ring, len);
+    }
+
+    // Return the recorded page frame numbers, in ascending order, and start over.
+    pub fn take(&self) -> Vec<u64> {
+        let mut pages = self.pages.lock().unwrap();
+        let taken = pages.iter().copied().collect();
+        pages.clear();
+        taken
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dirty_pages() {
+        let dirty = DirtyPages::default();
+
+        // Nothing is recorded until tracking is enabled.
+        dirty.mark(GuestAddress(0), 0x1000);
+        dirty.enable();
+        assert!(dirty.take().is_empty());
+
+        dirty.mark(GuestAddress(0x1fff), 2);
+        dirty.mark(GuestAddress(0x5000), 0x1000);
+        dirty.mark(GuestAddress(0x5000), 0);
+        assert_eq!(dirty.take(), vec![1, 2, 5]);
+        assert!(dirty.take().is_empty());
+
+        dirty.mark(GuestAddress(0x5000), 1);
+        dirty.disable();
+        assert!(!dirty.is_enabled());
+        assert!(dirty.take().is_empty());
+    }
+}
@@ -0,0 +1,49 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+// Counters updated by the queue handlers of the virtio devices. Each device owns its counters,
+// which the VMM collects into its metrics registry once the device is created.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::Serialize;
+
+// Adds `value` to `counter`. The counters are only read for reporting, so they do not order
+// any other memory access.
+pub(crate) fn add(counter: &AtomicU64, value: u64) {
+    counter.fetch_add(value, Ordering::Relaxed);
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct BlockMetrics {
+    // Requests taken off the queue, including the invalid ones.
+    pub requests: AtomicU64,
+    pub read_bytes: AtomicU64,
+    pub write_bytes: AtomicU64,
+    // Requests which could not be parsed or processed.
+    pub errors: AtomicU64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct NetMetrics {
+    pub rx_frames: AtomicU64,
+    pub rx_bytes: AtomicU64,
+    // Frames read from the tap which did not fit in the buffers of the driver, and were
+    // truncated.
+    pub rx_drops: AtomicU64,
+    pub tx_frames: AtomicU64,
+    pub tx_bytes: AtomicU64,
+    // Frames which were too large to send whole, or could not be written to the tap.
+    pub tx_drops: AtomicU64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct BalloonMetrics {
+    pub inflate_pages: AtomicU64,
+    pub deflate_pages: AtomicU64,
+    // Memory of the free page ranges reported by the driver, given back to the host.
+    pub reported_bytes: AtomicU64,
+    // Frame numbers outside guest memory or which could not be read, pages which could not be
+    // given back to the host, and statistics buffers which could not be read.
+    pub errors: AtomicU64,
+}
@@ -3,9 +3,12 @@
 
 // We're only providing virtio over MMIO devices for now, but we aim to add PCI support as well.
 
+pub mod balloon;
 pub mod block;
+pub mod dirty;
+pub mod metrics;
 pub mod net;
-pub mod balloon;
+pub mod persist;
 
 use std::convert::TryFrom;
 use std::io;
@@ -20,6 +23,7 @@ use event_manager::{
 use kvm_ioctls::{IoEventAddress, VmFd};
 use linux_loader::cmdline::Cmdline;
 use virtio_device::VirtioConfig;
+use virtio_queue::Queue;
 use vm_device::bus::{self, MmioAddress, MmioRange};
 use vm_device::device_manager::MmioManager;
 use vm_device::DeviceMmio;
@@ -27,6 +31,9 @@ use vm_memory::{GuestAddress, GuestAddressSpace};
 use vmm_sys_util::errno;
 use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
 
+use dirty::DirtyPages;
+use persist::VirtioState;
+
 // TODO: Move virtio-related defines from the local modules to the `vm-virtio` crate upstream.
 
 // TODO: Add MMIO-specific module when we add support for something like PCI as well.
@@ -64,6 +71,7 @@ pub enum Error {
     QueuesNotValid,
     RegisterIoevent(errno::Error),
     RegisterIrqfd(errno::Error),
+    UnregisterIoevent(errno::Error),
 }
 
 type Result<T> = std::result::Result<T, Error>;
@@ -116,6 +124,8 @@ pub struct Env<'a, M, B> {
     // the devices before loading he kernel cmdline into memory, but that's not a significant
     // limitation.
     pub kernel_cmdline: &'a mut Cmdline,
+    // Records the guest pages written by the device emulation code.
+    pub dirty_pages: Arc<DirtyPages>,
 }
 
 impl<'a, M, B> Env<'a, M, B>
@@ -161,10 +171,26 @@ pub struct CommonConfig<M: GuestAddressSpace> {
     pub endpoint: RemoteEndpoint<Subscriber>,
     pub vm_fd: Arc<VmFd>,
     pub irqfd: Arc<EventFd>,
+    pub dirty_pages: Arc<DirtyPages>,
+    // Set while the queue handler is registered with the `EventManager`.
+    sub_id: Option<SubscriberId>,
+    // Copies of the registered ioeventfds, which are needed to unregister them on reset.
+    ioevents: Vec<EventFd>,
+    // Used to build fresh queues on reset, since the handler takes the activated ones away.
+    mem: M,
+    queue_max_sizes: Vec<u16>,
 }
 
 impl<M: GuestAddressSpace> CommonConfig<M> {
-    pub fn new<B>(virtio_cfg: VirtioConfig<M>, env: &Env<M, B>) -> Result<Self> {
+    pub fn new<B>(virtio_cfg: VirtioConfig<M>, env: &Env<M, B>) -> Result<Self>
+    where
+        M: Clone,
+    {
+        let queue_max_sizes = virtio_cfg
+            .queues
+            .iter()
+            .map(|queue| queue.state.max_size)
+            .collect();
         let irqfd = Arc::new(EventFd::new(EFD_NONBLOCK).map_err(Error::EventFd)?);
 
         env.vm_fd
@@ -177,13 +203,29 @@ impl<M: GuestAddressSpace> CommonConfig<M> {
             endpoint: env.event_mgr.remote_endpoint(),
             vm_fd: env.vm_fd.clone(),
             irqfd,
+            dirty_pages: env.dirty_pages.clone(),
+            sub_id: None,
+            ioevents: Vec::new(),
+            mem: env.mem.clone(),
+            queue_max_sizes,
         })
     }
 
+    // Load a saved transport state. Activating the device, if the state says so, is left to
+    // the caller.
+    pub fn restore(&mut self, state: &VirtioState) -> Result<()> {
+        state.apply(&mut self.virtio);
+        // The driver might not have handled the last interrupt before the state was saved.
+        if state.interrupt_status != 0 {
+            self.irqfd.write(1).map_err(Error::EventFd)?;
+        }
+        Ok(())
+    }
+
     // Perform common initial steps for device activation based on the configuration, and return
     // a `Vec` that contains `EventFd`s registered as ioeventfds, which are used to convey queue
     // notifications coming from the driver.
-    pub fn prepare_activate(&self) -> Result<Vec<EventFd>> {
+    pub fn prepare_activate(&mut self) -> Result<Vec<EventFd>> {
         if !self.virtio.queues_valid() {
             return Err(Error::QueuesNotValid);
         }
@@ -218,6 +260,7 @@ impl<M: GuestAddressSpace> CommonConfig<M> {
                 )
                 .map_err(Error::RegisterIoevent)?;
 
+            self.ioevents.push(fd.try_clone().map_err(Error::EventFd)?);
             ioevents.push(fd);
         }
 
@@ -228,20 +271,91 @@ impl<M: GuestAddressSpace> CommonConfig<M> {
     // provided subscriber that's going to handle the device queues. We'll extend this when
     // we start support devices that make use of multiple handlers (i.e. for multiple queues).
     pub fn finalize_activate(&mut self, handler: Subscriber) -> Result<()> {
-        // Register the queue handler with the `EventManager`. We could record the `sub_id`
-        // (and/or keep a handler clone) for further interaction (i.e. to remove the subscriber at
-        // a later time, retrieve state, etc).
-        let _sub_id = self
+        // Register the queue handler with the `EventManager`. We record the `sub_id` so the
+        // handler can be removed later on. Devices keep a handler clone around to retrieve state.
+        let sub_id = self
             .endpoint
             .call_blocking(move |mgr| -> EvmgrResult<SubscriberId> {
                 Ok(mgr.add_subscriber(handler))
             })
             .map_err(Error::Endpoint)?;
 
+        self.sub_id = Some(sub_id);
         self.virtio.device_activated = true;
 
         Ok(())
     }
+
+    // Unregister the queue handler from the `EventManager`, which drops its reference to the
+    // handler. Must not be called from the thread running the event loop.
+    pub fn remove_handler(&mut self) -> Result<()> {
+        if let Some(sub_id) = self.sub_id.take() {
+            self.endpoint
+                .call_blocking(move |mgr| mgr.remove_subscriber(sub_id).map(|_| ()))
+                .map_err(Error::Endpoint)?;
+        }
+        Ok(())
+    }
+
+    // Same as `remove_handler`, for callers which own the event manager (i.e. while the VM is
+    // restarted, with the event loop stopped).
+    pub fn remove_handler_with(&mut self, event_mgr: &mut EventManager<Subscriber>) -> Result<()> {
+        if let Some(sub_id) = self.sub_id.take() {
+            event_mgr
+                .remove_subscriber(sub_id)
+                .map_err(Error::Endpoint)?;
+        }
+        Ok(())
+    }
+
+    // Bring the transport back to the state it had when the device was created, so the driver
+    // can set it up again. Removes the queue handler first if it's still registered.
+    pub fn reset(&mut self) -> Result<()>
+    where
+        M: Clone,
+    {
+        self.remove_handler()?;
+
+        for (i, fd) in self.ioevents.drain(..).enumerate() {
+            self.vm_fd
+                .unregister_ioevent(
+                    &fd,
+                    &IoEventAddress::Mmio(
+                        self.mmio.range.base().0 + VIRTIO_MMIO_QUEUE_NOTIFY_OFFSET,
+                    ),
+                    u32::try_from(i).unwrap(),
+                )
+                .map_err(Error::UnregisterIoevent)?;
+        }
+
+        let queues = self
+            .queue_max_sizes
+            .iter()
+            .map(|size| Queue::new(self.mem.clone(), *size))
+            .collect();
+
+        let virtio = &mut self.virtio;
+        virtio.driver_features_select = 0;
+        virtio.driver_features = 0;
+        virtio.device_features_select = 0;
+        virtio.device_status = 0;
+        virtio.queue_select = 0;
+        virtio.queues = queues;
+        virtio.interrupt_status.store(0, Ordering::SeqCst);
+        virtio.device_activated = false;
+        Ok(())
+    }
+
+    // Same as `finalize_activate`, for callers which own the event manager (i.e. while a saved
+    // state is loaded, before the event loop runs).
+    pub fn finalize_restore(
+        &mut self,
+        event_mgr: &mut EventManager<Subscriber>,
+        handler: Subscriber,
+    ) {
+        self.sub_id = Some(event_mgr.add_subscriber(handler));
+        self.virtio.device_activated = true;
+    }
 }
 
 /// Simple trait to model the operation of signalling the driver about used events
@@ -285,7 +399,6 @@ pub(crate) mod tests {
         kvm_create_device, kvm_device_attr, kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3,
         KVM_DEV_ARM_VGIC_CTRL_INIT, KVM_DEV_ARM_VGIC_GRP_CTRL,
     };
-    use virtio_queue::Queue;
     pub type MockMem = Arc<GuestMemoryMmap>;
 
     // Can be used in other modules to test functionality that requires a `CommonArgs` struct as
@@ -298,6 +411,7 @@ pub(crate) mod tests {
         pub mmio_mgr: IoManager,
         pub mmio_cfg: MmioConfig,
         pub kernel_cmdline: Cmdline,
+        pub dirty_pages: Arc<DirtyPages>,
     }
 
     impl EnvMock {
@@ -326,6 +440,7 @@ pub(crate) mod tests {
                 mmio_cfg,
                 // `4096` seems large enough for testing.
                 kernel_cmdline: Cmdline::new(4096),
+                dirty_pages: Arc::new(DirtyPages::default()),
             }
         }
         pub fn env(&mut self) -> Env<MockMem, &mut IoManager> {
@@ -336,6 +451,7 @@ pub(crate) mod tests {
                 mmio_mgr: &mut self.mmio_mgr,
                 mmio_cfg: self.mmio_cfg,
                 kernel_cmdline: &mut self.kernel_cmdline,
+                dirty_pages: self.dirty_pages.clone(),
             }
         }
         #[cfg(target_arch = "aarch64")]
@@ -448,4 +564,51 @@ pub(crate) mod tests {
 
         t.join().unwrap();
     }
+
+    #[test]
+    fn test_common_config_reset() {
+        let mut mock = EnvMock::new();
+        let mut cfg = {
+            let env = mock.env();
+            let queues = vec![Queue::new(env.mem.clone(), 256)];
+            let virtio_cfg = VirtioConfig::new(1 << VIRTIO_F_VERSION_1, queues, Vec::new());
+            CommonConfig::new(virtio_cfg, &env).unwrap()
+        };
+
+        let activate = |cfg: &mut CommonConfig<MockMem>| {
+            cfg.virtio.queues[0].state.ready = true;
+            cfg.virtio.queues[0].state.size = 256;
+            cfg.virtio.driver_features = 1 << VIRTIO_F_VERSION_1;
+            cfg.prepare_activate()
+        };
+
+        struct Dummy;
+
+        impl MutEventSubscriber for Dummy {
+            fn process(&mut self, _events: Events, _ops: &mut EventOps) {}
+
+            fn init(&mut self, _ops: &mut EventOps) {}
+        }
+
+        let ioevents = activate(&mut cfg).unwrap();
+        // The handler takes the queues away.
+        cfg.virtio.queues.clear();
+        cfg.finalize_restore(&mut mock.event_mgr, Arc::new(Mutex::new(Dummy)));
+        cfg.virtio.interrupt_status.store(1, Ordering::SeqCst);
+
+        // `reset` would otherwise wait for the event loop to remove the handler.
+        cfg.remove_handler_with(&mut mock.event_mgr).unwrap();
+        cfg.reset().unwrap();
+        drop(ioevents);
+
+        assert!(!cfg.virtio.device_activated);
+        assert_eq!(cfg.virtio.driver_features, 0);
+        assert_eq!(cfg.virtio.interrupt_status.load(Ordering::SeqCst), 0);
+        assert_eq!(cfg.virtio.queues.len(), 1);
+        assert!(!cfg.virtio.queues[0].state.ready);
+        assert_eq!(cfg.virtio.queues[0].state.max_size, 256);
+
+        // The ioeventfds were unregistered, so registering them again works.
+        assert_eq!(activate(&mut cfg).unwrap().len(), 1);
+    }
 }
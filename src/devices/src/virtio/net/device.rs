@@ -5,6 +5,7 @@ use std::borrow::{Borrow, BorrowMut};
 use std::ops::DerefMut;
 use std::sync::{Arc, Mutex};
 
+use event_manager::EventManager;
 use virtio_device::{VirtioConfig, VirtioDeviceActions, VirtioDeviceType, VirtioMmioDevice};
 use virtio_queue::Queue;
 use vm_device::bus::MmioAddress;
@@ -13,9 +14,11 @@ use vm_device::{DeviceMmio, MutDeviceMmio};
 use vm_memory::GuestAddressSpace;
 
 use crate::virtio::features::{VIRTIO_F_IN_ORDER, VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_VERSION_1};
+use crate::virtio::metrics::NetMetrics;
 use crate::virtio::net::features::*;
 use crate::virtio::net::{Error, NetArgs, Result, NET_DEVICE_ID, VIRTIO_NET_HDR_SIZE};
-use crate::virtio::{CommonConfig, Env, SingleFdSignalQueue, QUEUE_MAX_SIZE};
+use crate::virtio::persist::{MmioState, NetState, QueueState, VirtioState};
+use crate::virtio::{CommonConfig, Env, SingleFdSignalQueue, Subscriber, QUEUE_MAX_SIZE};
 
 use super::bindings;
 use super::queue_handler::QueueHandler;
@@ -25,6 +28,9 @@ use super::tap::Tap;
 pub struct Net<M: GuestAddressSpace> {
     cfg: CommonConfig<M>,
     tap_name: String,
+    // Set once the device is activated, and used to retrieve the queue state.
+    handler: Option<Arc<Mutex<QueueHandler<M>>>>,
+    metrics: Arc<NetMetrics>,
 }
 
 impl<M> Net<M>
@@ -66,6 +72,8 @@ where
         let net = Arc::new(Mutex::new(Net {
             cfg: common_cfg,
             tap_name: args.tap_name.clone(),
+            handler: None,
+            metrics: Arc::new(NetMetrics::default()),
         }));
 
         env.register_mmio_device(net.clone())
@@ -73,30 +81,49 @@ where
 
         Ok(net)
     }
-}
 
-impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioDeviceType for Net<M> {
-    fn device_type(&self) -> u32 {
-        NET_DEVICE_ID
-    }
-}
+    // Recreate a `Net` device from a saved state. The device is placed at the MMIO range and
+    // interrupt recorded in the state, regardless of `env.mmio_cfg`.
+    pub fn from_state<B>(env: &mut Env<M, B>, state: &NetState) -> Result<Arc<Mutex<Self>>>
+    where
+        B: DerefMut,
+        B::Target: MmioManager<D = Arc<dyn DeviceMmio + Send + Sync>>,
+    {
+        env.mmio_cfg = state.mmio.mmio_config().map_err(Error::Virtio)?;
+        let args = NetArgs {
+            tap_name: state.tap_name.clone(),
+        };
 
-impl<M: GuestAddressSpace + Clone + Send + 'static> Borrow<VirtioConfig<M>> for Net<M> {
-    fn borrow(&self) -> &VirtioConfig<M> {
-        &self.cfg.virtio
+        let net = Self::new(env, &args)?;
+        net.lock().unwrap().restore(&state.virtio, env.event_mgr)?;
+        Ok(net)
     }
-}
 
-impl<M: GuestAddressSpace + Clone + Send + 'static> BorrowMut<VirtioConfig<M>> for Net<M> {
-    fn borrow_mut(&mut self) -> &mut VirtioConfig<M> {
-        &mut self.cfg.virtio
+    fn restore(
+        &mut self,
+        state: &VirtioState,
+        event_mgr: &mut EventManager<Subscriber>,
+    ) -> Result<()> {
+        self.cfg.restore(state).map_err(Error::Virtio)?;
+        if state.device_activated {
+            let handler = self.create_handler()?;
+            {
+                // Notifications the driver sent right before the state was saved were lost,
+                // so have a look at the queues straight away.
+                let handler = handler.lock().unwrap();
+                handler
+                    .rx_ioevent
+                    .write(1)
+                    .and_then(|_| handler.tx_ioevent.write(1))
+                    .map_err(|e| Error::Virtio(crate::virtio::Error::EventFd(e)))?;
+            }
+            self.cfg.finalize_restore(event_mgr, handler);
+        }
+        Ok(())
     }
-}
-
-impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioDeviceActions for Net<M> {
-    type E = Error;
 
-    fn activate(&mut self) -> Result<()> {
+    // Sets up the queue handler which takes over the queues once the device is activated.
+    fn create_handler(&mut self) -> Result<Arc<Mutex<QueueHandler<M>>>> {
         let tap = Tap::open_named(self.tap_name.as_str()).map_err(Error::Tap)?;
 
         // Set offload flags to match the relevant virtio features of the device (for now,
@@ -123,7 +150,14 @@ impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioDeviceActions for Net<
 
         let rxq = self.cfg.virtio.queues.remove(0);
         let txq = self.cfg.virtio.queues.remove(0);
-        let inner = SimpleHandler::new(driver_notify, rxq, txq, tap);
+        let inner = SimpleHandler::new(
+            driver_notify,
+            rxq,
+            txq,
+            tap,
+            self.cfg.dirty_pages.clone(),
+            self.metrics.clone(),
+        );
 
         let handler = Arc::new(Mutex::new(QueueHandler {
             inner,
@@ -131,11 +165,92 @@ impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioDeviceActions for Net<
             tx_ioevent: ioevents.remove(0),
         }));
 
+        self.handler = Some(handler.clone());
+        Ok(handler)
+    }
+
+    // Counters of the frames moved by the device.
+    pub fn metrics(&self) -> Arc<NetMetrics> {
+        self.metrics.clone()
+    }
+
+    // Reset the device while the event loop is stopped (i.e. when the VM restarts), which the
+    // reset would otherwise wait for to remove the queue handler.
+    pub fn reset_with(&mut self, event_mgr: &mut EventManager<Subscriber>) -> Result<()> {
+        self.cfg
+            .remove_handler_with(event_mgr)
+            .map_err(Error::Virtio)?;
+        VirtioDeviceActions::reset(self)
+    }
+
+    // Returns the current state of the device. The queue handler must not be running
+    // concurrently (i.e. the caller runs on the event manager thread).
+    pub fn save_state(&self) -> NetState {
+        let queues = match self.handler.as_ref() {
+            Some(handler) => {
+                let handler = handler.lock().unwrap();
+                vec![
+                    QueueState::from_queue(&handler.inner.rxq),
+                    QueueState::from_queue(&handler.inner.txq),
+                ]
+            }
+            None => self
+                .cfg
+                .virtio
+                .queues
+                .iter()
+                .map(QueueState::from_queue)
+                .collect(),
+        };
+
+        NetState {
+            mmio: MmioState::from(&self.cfg.mmio),
+            virtio: VirtioState::new(&self.cfg.virtio, queues),
+            tap_name: self.tap_name.clone(),
+        }
+    }
+
+    // Stop processing the queues and close the tap, so that another process (i.e. the
+    // destination of a migration) can open it. The device does not work anymore afterwards.
+    // Must not be called from the thread running the event loop.
+    pub fn release_tap(&mut self) -> Result<()> {
+        self.cfg.remove_handler().map_err(Error::Virtio)?;
+        // The queue handler owns the tap, and this was its last reference.
+        self.handler = None;
+        Ok(())
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioDeviceType for Net<M> {
+    fn device_type(&self) -> u32 {
+        NET_DEVICE_ID
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send + 'static> Borrow<VirtioConfig<M>> for Net<M> {
+    fn borrow(&self) -> &VirtioConfig<M> {
+        &self.cfg.virtio
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send + 'static> BorrowMut<VirtioConfig<M>> for Net<M> {
+    fn borrow_mut(&mut self) -> &mut VirtioConfig<M> {
+        &mut self.cfg.virtio
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioDeviceActions for Net<M> {
+    type E = Error;
+
+    fn activate(&mut self) -> Result<()> {
+        let handler = self.create_handler()?;
         self.cfg.finalize_activate(handler).map_err(Error::Virtio)
     }
 
     fn reset(&mut self) -> std::result::Result<(), Error> {
-        // Not implemented for now.
+        self.cfg.reset().map_err(Error::Virtio)?;
+        // Drops the queue handler along with its queues.
+        self.handler = None;
         Ok(())
     }
 }
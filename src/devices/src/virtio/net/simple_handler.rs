@@ -4,11 +4,14 @@
 use std::cmp;
 use std::io::{self, Read, Write};
 use std::result;
+use std::sync::Arc;
 
 use log::warn;
 use virtio_queue::{DescriptorChain, Queue};
 use vm_memory::{Bytes, GuestAddressSpace};
 
+use crate::virtio::dirty::DirtyPages;
+use crate::virtio::metrics::{self, NetMetrics};
 use crate::virtio::net::tap::Tap;
 use crate::virtio::net::{RXQ_INDEX, TXQ_INDEX};
 use crate::virtio::SignalUsedQueue;
@@ -47,10 +50,19 @@ pub struct SimpleHandler<M: GuestAddressSpace, S: SignalUsedQueue> {
     pub txq: Queue<M>,
     pub txbuf: [u8; MAX_BUFFER_SIZE],
     pub tap: Tap,
+    pub dirty_pages: Arc<DirtyPages>,
+    pub metrics: Arc<NetMetrics>,
 }
 
 impl<M: GuestAddressSpace, S: SignalUsedQueue> SimpleHandler<M, S> {
-    pub fn new(driver_notify: S, rxq: Queue<M>, txq: Queue<M>, tap: Tap) -> Self {
+    pub fn new(
+        driver_notify: S,
+        rxq: Queue<M>,
+        txq: Queue<M>,
+        tap: Tap,
+        dirty_pages: Arc<DirtyPages>,
+        metrics: Arc<NetMetrics>,
+    ) -> Self {
         SimpleHandler {
             driver_notify,
             rxq,
@@ -59,6 +71,8 @@ impl<M: GuestAddressSpace, S: SignalUsedQueue> SimpleHandler<M, S> {
             txq,
             txbuf: [0u8; MAX_BUFFER_SIZE],
             tap,
+            dirty_pages,
+            metrics,
         }
     }
 
@@ -74,6 +88,8 @@ impl<M: GuestAddressSpace, S: SignalUsedQueue> SimpleHandler<M, S> {
             _ => return Ok(false),
         };
 
+        self.dirty_pages.mark_chain(&chain);
+
         let mut count = 0;
         let buf = &mut self.rxbuf[..num_bytes];
 
@@ -96,9 +112,14 @@ impl<M: GuestAddressSpace, S: SignalUsedQueue> SimpleHandler<M, S> {
         if count != buf.len() {
             // The frame was too large for the chain.
             warn!("rx frame too large");
+            metrics::add(&self.metrics.rx_drops, 1);
+        } else {
+            metrics::add(&self.metrics.rx_frames, 1);
+            metrics::add(&self.metrics.rx_bytes, count as u64);
         }
 
         self.rxq.add_used(chain.head_index(), count as u32)?;
+        self.dirty_pages.mark_used_ring(&self.rxq);
 
         self.rxbuf_current = 0;
 
@@ -143,6 +164,7 @@ impl<M: GuestAddressSpace, S: SignalUsedQueue> SimpleHandler<M, S> {
 
             if len > left {
                 warn!("tx frame too large");
+                metrics::add(&self.metrics.tx_drops, 1);
                 break;
             }
 
@@ -154,7 +176,12 @@ impl<M: GuestAddressSpace, S: SignalUsedQueue> SimpleHandler<M, S> {
             count += len;
         }
 
-        self.tap.write(&self.txbuf[..count]).map_err(Error::Tap)?;
+        if let Err(e) = self.tap.write(&self.txbuf[..count]) {
+            metrics::add(&self.metrics.tx_drops, 1);
+            return Err(Error::Tap(e));
+        }
+        metrics::add(&self.metrics.tx_frames, 1);
+        metrics::add(&self.metrics.tx_bytes, count as u64);
 
         Ok(count as u32)
     }
@@ -167,6 +194,7 @@ impl<M: GuestAddressSpace, S: SignalUsedQueue> SimpleHandler<M, S> {
                 self.send_frame_from_chain(&mut chain)?;
 
                 self.txq.add_used(chain.head_index(), 0)?;
+                self.dirty_pages.mark_used_ring(&self.txq);
 
                 if self.txq.needs_notification()? {
                     self.driver_notify.signal_used_queue(TXQ_INDEX);
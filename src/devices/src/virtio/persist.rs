@@ -0,0 +1,257 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+// Serializable state of the virtio MMIO devices. It holds the transport registers and queue
+// positions the driver has configured, together with the device arguments needed to recreate
+// the backend (disk file, tap interface) when the state is loaded again.
+
+use std::num::Wrapping;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+
+use serde::{Deserialize, Serialize};
+use virtio_device::VirtioConfig;
+use virtio_queue::Queue;
+use vm_memory::{GuestAddress, GuestAddressSpace};
+
+use super::{MmioConfig, Result};
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct QueueState {
+    pub max_size: u16,
+    pub size: u16,
+    pub ready: bool,
+    pub desc_table: u64,
+    pub avail_ring: u64,
+    pub used_ring: u64,
+    pub next_avail: u16,
+    pub next_used: u16,
+    pub event_idx_enabled: bool,
+}
+
+impl QueueState {
+    pub fn from_queue<M: GuestAddressSpace>(queue: &Queue<M>) -> Self {
+        let state = &queue.state;
+        QueueState {
+            max_size: state.max_size,
+            size: state.size,
+            ready: state.ready,
+            desc_table: state.desc_table.0,
+            avail_ring: state.avail_ring.0,
+            used_ring: state.used_ring.0,
+            next_avail: state.next_avail.0,
+            next_used: state.next_used.0,
+            event_idx_enabled: state.event_idx_enabled,
+        }
+    }
+
+    pub fn apply<M: GuestAddressSpace>(&self, queue: &mut Queue<M>) {
+        let state = &mut queue.state;
+        state.max_size = self.max_size;
+        state.size = self.size;
+        state.ready = self.ready;
+        state.desc_table = GuestAddress(self.desc_table);
+        state.avail_ring = GuestAddress(self.avail_ring);
+        state.used_ring = GuestAddress(self.used_ring);
+        state.next_avail = Wrapping(self.next_avail);
+        state.next_used = Wrapping(self.next_used);
+        state.event_idx_enabled = self.event_idx_enabled;
+    }
+}
+
+// The transport level state of a device, as seen through its MMIO registers.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct VirtioState {
+    pub device_features: u64,
+    pub driver_features: u64,
+    pub device_features_select: u32,
+    pub driver_features_select: u32,
+    pub device_status: u8,
+    pub queue_select: u16,
+    pub config_generation: u8,
+    pub config_space: Vec<u8>,
+    pub interrupt_status: u8,
+    pub device_activated: bool,
+    pub queues: Vec<QueueState>,
+}
+
+impl VirtioState {
+    // Activated devices hand their queues over to the queue handler, so the caller passes the
+    // queue state in explicitly instead of it being read from `cfg`.
+    pub fn new<M: GuestAddressSpace>(cfg: &VirtioConfig<M>, queues: Vec<QueueState>) -> Self {
+        VirtioState {
+            device_features: cfg.device_features,
+            driver_features: cfg.driver_features,
+            device_features_select: cfg.device_features_select,
+            driver_features_select: cfg.driver_features_select,
+            device_status: cfg.device_status,
+            queue_select: cfg.queue_select,
+            config_generation: cfg.config_generation,
+            config_space: cfg.config_space.clone(),
+            interrupt_status: cfg.interrupt_status.load(Ordering::SeqCst),
+            device_activated: cfg.device_activated,
+            queues,
+        }
+    }
+
+    // Loads the state into `cfg`, except for `device_activated`. Activation is left to the
+    // device, as it also has to set up the queue handler.
+    pub fn apply<M: GuestAddressSpace>(&self, cfg: &mut VirtioConfig<M>) {
+        cfg.device_features = self.device_features;
+        cfg.driver_features = self.driver_features;
+        cfg.device_features_select = self.device_features_select;
+        cfg.driver_features_select = self.driver_features_select;
+        cfg.device_status = self.device_status;
+        cfg.queue_select = self.queue_select;
+        cfg.config_generation = self.config_generation;
+        cfg.config_space = self.config_space.clone();
+        cfg.interrupt_status
+            .store(self.interrupt_status, Ordering::SeqCst);
+        for (queue, state) in cfg.queues.iter_mut().zip(self.queues.iter()) {
+            state.apply(queue);
+        }
+    }
+}
+
+// Where the device lives on the MMIO bus, and the interrupt line it uses.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct MmioState {
+    pub base: u64,
+    pub size: u64,
+    pub gsi: u32,
+}
+
+impl From<&MmioConfig> for MmioState {
+    fn from(cfg: &MmioConfig) -> Self {
+        MmioState {
+            base: cfg.range.base().0,
+            size: cfg.range.size(),
+            gsi: cfg.gsi,
+        }
+    }
+}
+
+impl MmioState {
+    pub fn mmio_config(&self) -> Result<MmioConfig> {
+        MmioConfig::new(self.base, self.size, self.gsi)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct BlockState {
+    pub mmio: MmioState,
+    pub virtio: VirtioState,
+    pub file_path: PathBuf,
+    pub read_only: bool,
+    pub root_device: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct NetState {
+    pub mmio: MmioState,
+    pub virtio: VirtioState,
+    pub tap_name: String,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct BalloonState {
+    pub mmio: MmioState,
+    pub virtio: VirtioState,
+    // Number of pages currently given back to the host.
+    pub inflate_page_num: u64,
+    // Frame numbers of those pages, in ascending order. Missing from the states saved before
+    // the pages were tracked.
+    #[serde(default)]
+    pub inflated_pages: Vec<u64>,
+    // Head of the statistics buffer the device holds on to, if any. Missing from the states
+    // saved before the stats queue was supported.
+    #[serde(default)]
+    pub stats_desc_index: Option<u16>,
+    // Seconds between two requests for statistics. Missing from the states saved before it
+    // was, in which case the device keeps the interval it was created with.
+    #[serde(default)]
+    pub stats_polling_interval_s: Option<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use vm_memory::{GuestAddress, GuestMemoryMmap};
+
+    use super::*;
+
+    #[test]
+    fn test_virtio_state() {
+        let mem = Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1_0000)]).unwrap());
+        let mut queue = Queue::new(mem.clone(), 256);
+        queue.state.size = 128;
+        queue.state.ready = true;
+        queue.state.desc_table = GuestAddress(0x1000);
+        queue.state.avail_ring = GuestAddress(0x2000);
+        queue.state.used_ring = GuestAddress(0x3000);
+        queue.state.next_avail.0 = 7;
+        queue.state.next_used.0 = 5;
+
+        let queue_state = QueueState::from_queue(&queue);
+        assert_eq!(
+            queue_state,
+            QueueState {
+                max_size: 256,
+                size: 128,
+                ready: true,
+                desc_table: 0x1000,
+                avail_ring: 0x2000,
+                used_ring: 0x3000,
+                next_avail: 7,
+                next_used: 5,
+                event_idx_enabled: false,
+            }
+        );
+
+        let mut cfg = VirtioConfig::new(1 << 32, vec![queue], vec![1, 2, 3]);
+        cfg.driver_features = 1 << 32;
+        cfg.device_status = 0xf;
+        cfg.interrupt_status.store(1, Ordering::SeqCst);
+
+        let state = VirtioState::new(&cfg, vec![queue_state]);
+        assert_eq!(state.device_features, 1 << 32);
+        assert_eq!(state.driver_features, 1 << 32);
+        assert_eq!(state.device_status, 0xf);
+        assert_eq!(state.interrupt_status, 1);
+        assert_eq!(state.config_space, vec![1, 2, 3]);
+
+        let json = serde_json::to_string(&state).unwrap();
+        assert_eq!(serde_json::from_str::<VirtioState>(&json).unwrap(), state);
+
+        // Load the state into a fresh configuration.
+        let mut restored = VirtioConfig::new(1 << 32, vec![Queue::new(mem, 256)], Vec::new());
+        state.apply(&mut restored);
+        assert!(!restored.device_activated);
+        assert_eq!(restored.device_status, 0xf);
+        assert_eq!(restored.interrupt_status.load(Ordering::SeqCst), 1);
+        assert_eq!(restored.config_space, vec![1, 2, 3]);
+        assert_eq!(QueueState::from_queue(&restored.queues[0]), state.queues[0]);
+    }
+
+    #[test]
+    fn test_balloon_state_defaults() {
+        let mem = Arc::new(GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1_0000)]).unwrap());
+        let cfg = VirtioConfig::new(0, vec![Queue::new(mem, 256)], vec![0; 8]);
+        // A state saved before the statistics were supported.
+        let json = serde_json::json!({
+            "mmio": {"base": 0x1000, "size": 0x1000, "gsi": 5},
+            "virtio": VirtioState::new(&cfg, Vec::new()),
+            "inflate_page_num": 2,
+        });
+
+        let mut state = serde_json::from_value::<BalloonState>(json).unwrap();
+        assert_eq!(state.inflate_page_num, 2);
+        assert_eq!(state.stats_desc_index, None);
+        assert_eq!(state.stats_polling_interval_s, None);
+
+        state.stats_polling_interval_s = Some(10);
+        let json = serde_json::to_value(&state).unwrap();
+        assert_eq!(serde_json::from_value::<BalloonState>(json).unwrap(), state);
+    }
+}
@@ -1,71 +1,16 @@
 // Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 use std::env;
-use std::thread;
-use std::io::{Read,Result};
-use std::os::unix::net::{UnixListener,UnixStream};
-
+use std::process;
 use std::sync::{Arc, Mutex};
-use api::Cli;
-use vmm::{TryFrom1,Vmm, WrappedExitHandler};
-use event_manager::{EventManager,MutEventSubscriber, SubscriberOps};
 
+use api::control::{remove_socket, ControlServer};
+use api::Cli;
+use event_manager::{EventManager, MutEventSubscriber, SubscriberOps};
+use log::error;
+use utils::logger;
+use vmm::{TryFrom1, Vmm, WrappedExitHandler};
 
-const sock_path:&str = "/tmp/rust-vmm.sock";
-fn start_unix_socket_server(vmm: Arc<Mutex<Vmm>>) -> Result<()> {
-
-    let listener = UnixListener::bind(sock_path).expect("create sock fail");
-    thread::spawn(move || {
-        for stream in listener.incoming() {
-            match stream {
-                Ok(mut stream) => {
-                    let vmm = vmm.clone();
-                    thread::spawn(move||{
-                    let mut buffer = [0;1024];
-                    match stream.read(&mut buffer) {
-                        Ok(size) => {
-                            let recv = String::from_utf8_lossy(&buffer[..size]);
-                            let mut parts = recv.split_whitespace();
-                            let (command, number_str) = match (parts.next(), parts.next()) {
-                                (Some(cmd), Some(num_str)) => (cmd, num_str),
-                                _ => {
-                                    eprintln!("Failed to parse command and/or number");
-                                    return;
-                                }
-                            };
-                            let number: u64 = number_str.parse().unwrap();
-                            match command {
-                                "balloon" => {
-                                    let mut vmm = vmm.lock().unwrap();
-                                    let success = vmm.change_balloon_config(number);
-                                    if !success {
-                                        eprintln!("Failed to balloon, please add balloon device");
-                                    }
-                                }
-                                "shutdown" => {
-                                    vmm.lock().unwrap().vm.shutdown();
-                                    std::fs::remove_file(sock_path);
-                                    std::process::exit(0);
-                                }
-                                cmd => {
-                                    eprintln!("unkown {}", cmd);
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            eprintln!("fail {}", e);
-                        }
-                    }
-                    });
-                }
-                Err(e) => {
-                    eprintln!("fail {}", e);
-                }
-            }
-        }
-    });
-    Ok(())
-}
 fn main() {
     match Cli::launch(
         env::args()
@@ -75,26 +20,71 @@ fn main() {
             .collect(),
     ) {
         Ok(vmm_config) => {
+            let log_config = &vmm_config.log_config;
+            if let Err(e) = logger::init(log_config.filter.clone(), log_config.path.as_deref()) {
+                eprintln!("Failed to install the logger. {}", e);
+                return;
+            }
+            let api_config = vmm_config.api_config.clone();
             let wrapped_exit_handler = WrappedExitHandler::new().expect("exit create failed");
-            let mut event_manager = EventManager::<Arc<Mutex<dyn MutEventSubscriber + Send>>>::new()
-                .expect("event create failed");
+            let mut event_manager =
+                EventManager::<Arc<Mutex<dyn MutEventSubscriber + Send>>>::new()
+                    .expect("event create failed");
             event_manager.add_subscriber(wrapped_exit_handler.0.clone());
-            let mut vmm =
-                Arc::new(Mutex::new(Vmm::try_from1(vmm_config, &wrapped_exit_handler, &mut event_manager).expect("Failed to create VMM from configurations")));
-            start_unix_socket_server(vmm.clone());
+            let vmm = if vmm_config.preboot {
+                let server = ControlServer::bind(&api_config.socket_path)
+                    .expect("Failed to bind the control socket");
+                let vmm = server
+                    .configure(vmm_config, |config| {
+                        Vmm::try_from1(config, &wrapped_exit_handler, &mut event_manager).map_err(
+                            |e| format!("Failed to create VMM from configurations: {:?}", e),
+                        )
+                    })
+                    .expect("Failed to configure the VM");
+                server
+                    .start(vmm.clone())
+                    .expect("Failed to start the control socket");
+                vmm
+            } else {
+                let vmm = Arc::new(Mutex::new(
+                    Vmm::try_from1(vmm_config, &wrapped_exit_handler, &mut event_manager)
+                        .expect("Failed to create VMM from configurations"),
+                ));
+                // Bound only now, a migrated VM takes over the socket path of the source VMM.
+                if api_config.enabled {
+                    ControlServer::bind(&api_config.socket_path)
+                        .and_then(|server| server.start(vmm.clone()))
+                        .expect("Failed to start the control socket");
+                }
+                vmm
+            };
+            vmm.lock()
+                .unwrap()
+                .handle_signals()
+                .expect("Failed to handle the termination signals");
             // For now we are just unwrapping here, in the future we might use a nicer way of
             // handling errors such as pretty printing them.
             vmm.lock().unwrap().run().unwrap();
             loop {
                 match event_manager.run() {
-                   Ok(_) => (),
-                   Err(e) => eprintln!("Failed to handle events: {:?}", e),
-               }
-               if !wrapped_exit_handler.keep_running() {
-                   break;
-               }
+                    Ok(_) => (),
+                    Err(e) => error!("Failed to handle events: {:?}", e),
+                }
+                if !wrapped_exit_handler.keep_running() {
+                    // The guest may have rebooted, in which case the VM boots again.
+                    match vmm.lock().unwrap().restart(&mut event_manager) {
+                        Ok(true) => continue,
+                        Ok(false) => (),
+                        Err(e) => error!("Failed to restart the VM: {:?}", e),
+                    }
+                    break;
+                }
+            }
+            vmm.lock().unwrap().shutdown();
+            if api_config.enabled {
+                remove_socket(&api_config.socket_path);
             }
-            vmm.lock().unwrap().vm.shutdown();
+            process::exit(wrapped_exit_handler.exit_code());
         }
         Err(e) => {
             eprintln!("Failed to parse command line options. {}", e);
@@ -1,20 +1,15 @@
 // Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
+pub mod logger;
 pub mod resource_download;
 
-/// A macro for printing errors only in debug mode.
-#[macro_export]
-#[cfg(debug_assertions)]
-macro_rules! debug {
-    ($( $args:expr ),*) => { eprintln!( $( $args ),* ); }
-}
+pub use log;
 
-/// A macro that allows printing to be ignored in release mode.
+/// A macro for logging debug messages, through the logger installed by the VMM.
 #[macro_export]
-#[cfg(not(debug_assertions))]
 macro_rules! debug {
-    ($( $args:expr ),*) => {
-        ()
+    ($($args:tt)*) => {
+        $crate::log::debug!($($args)*)
     };
 }
@@ -0,0 +1,254 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! A logger for the `log` facade.
+//!
+//! Each record is written on its own line, as
+//! `<UTC timestamp> [<thread name>] <LEVEL> <module>: <message>`, to stderr or appended to a file.
+
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+/// Errors of the logger.
+#[derive(Debug)]
+pub enum Error {
+    /// The filter is not `<level>[,<module>=<level>...]`.
+    InvalidFilter(String),
+    /// The log file could not be opened.
+    OpenFile(io::Error),
+    /// A logger is already installed.
+    AlreadyInitialized,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidFilter(directive) => write!(f, "invalid log filter: {}", directive),
+            Error::OpenFile(e) => write!(f, "cannot open the log file: {}", e),
+            Error::AlreadyInitialized => write!(f, "a logger is already installed"),
+        }
+    }
+}
+
+/// Levels of the records that are logged, by module.
+///
+/// It is parsed from `<level>[,<module>=<level>...]`, where the levels are `off`, `error`,
+/// `warn`, `info`, `debug` or `trace`, and the modules are paths such as `devices::virtio`.
+/// A record is logged at the level of the longest module which contains it, or at the default
+/// level when there is none.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Filter {
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter {
+            default: LevelFilter::Info,
+            modules: Vec::new(),
+        }
+    }
+}
+
+impl Filter {
+    /// Returns the most verbose level logged for records of `target`.
+    pub fn level(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .filter(|(module, _)| {
+                target.starts_with(module.as_str())
+                    && (target.len() == module.len() || target[module.len()..].starts_with("::"))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    /// Returns the most verbose level logged for any module.
+    pub fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, LevelFilter::max)
+    }
+}
+
+impl FromStr for Filter {
+    type Err = Error;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let parse_level = |level: &str| {
+            LevelFilter::from_str(level.trim()).map_err(|_| Error::InvalidFilter(level.to_string()))
+        };
+
+        let mut filter = Filter::default();
+        for directive in spec.split(',').map(str::trim) {
+            let mut parts = directive.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(level), None) => filter.default = parse_level(level)?,
+                (Some(module), Some(level)) if !module.trim().is_empty() => filter
+                    .modules
+                    .push((module.trim().to_string(), parse_level(level)?)),
+                _ => return Err(Error::InvalidFilter(directive.to_string())),
+            }
+        }
+        Ok(filter)
+    }
+}
+
+struct Logger {
+    filter: Filter,
+    output: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.filter.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format!(
+            "{} [{}] {} {}: {}\n",
+            timestamp(SystemTime::now()),
+            thread::current().name().unwrap_or("unnamed"),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+        // A single write, so that the lines of different threads do not interleave.
+        if let Ok(mut output) = self.output.lock() {
+            let _ = output.write_all(line.as_bytes());
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(mut output) = self.output.lock() {
+            let _ = output.flush();
+        }
+    }
+}
+
+/// Installs the logger for the whole process.
+///
+/// The records are appended to the file at `path`, which is created if needed, or written to
+/// stderr when there is no `path`.
+pub fn init(filter: Filter, path: Option<&Path>) -> Result<(), Error> {
+    let output: Box<dyn Write + Send> = match path {
+        Some(path) => Box::new(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(Error::OpenFile)?,
+        ),
+        None => Box::new(io::stderr()),
+    };
+    let max_level = filter.max_level();
+    log::set_boxed_logger(Box::new(Logger {
+        filter,
+        output: Mutex::new(output),
+    }))
+    .map_err(|_| Error::AlreadyInitialized)?;
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+// Formats `time` as an ISO 8601 UTC timestamp, with milliseconds.
+fn timestamp(time: SystemTime) -> String {
+    let elapsed = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = elapsed.as_secs();
+    let (year, month, day) = civil_from_days(seconds / 86_400);
+    let seconds = seconds % 86_400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        elapsed.subsec_millis()
+    )
+}
+
+// Converts a number of days since 1970-01-01 to a (year, month, day) date of the Gregorian
+// calendar. The days are counted from 0000-03-01 instead, so that the leap day is the last
+// day of the year, and grouped by eras of 400 years, which all have the same number of days.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    // Months start in March.
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn test_filter() {
+        let filter = Filter::from_str("warn").unwrap();
+        assert_eq!(filter.level("devices::legacy::serial"), LevelFilter::Warn);
+        assert_eq!(filter.max_level(), LevelFilter::Warn);
+
+        let filter =
+            Filter::from_str("error, devices=debug,devices::virtio::net=trace,vm_vcpu=off")
+                .unwrap();
+        assert_eq!(filter.level("vmm"), LevelFilter::Error);
+        assert_eq!(filter.level("devices"), LevelFilter::Debug);
+        assert_eq!(filter.level("devices::virtio::block"), LevelFilter::Debug);
+        assert_eq!(filter.level("devices::virtio::net"), LevelFilter::Trace);
+        assert_eq!(
+            filter.level("devices::virtio::net::tap"),
+            LevelFilter::Trace
+        );
+        assert_eq!(filter.level("devices_extra"), LevelFilter::Error);
+        assert_eq!(filter.level("vm_vcpu::vcpu"), LevelFilter::Off);
+        assert_eq!(filter.max_level(), LevelFilter::Trace);
+
+        // Only the modules are filtered, at the default level.
+        let filter = Filter::from_str("devices=DEBUG").unwrap();
+        assert_eq!(filter.level("vmm"), LevelFilter::Info);
+        assert_eq!(filter.level("devices::legacy"), LevelFilter::Debug);
+
+        assert!(Filter::from_str("").is_err());
+        assert!(Filter::from_str("verbose").is_err());
+        assert!(Filter::from_str("info,devices").is_err());
+        assert!(Filter::from_str("info,=debug").is_err());
+        assert!(Filter::from_str("info,devices=").is_err());
+    }
+
+    #[test]
+    fn test_timestamp() {
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            timestamp(UNIX_EPOCH + Duration::from_millis(951_782_400_042)),
+            "2000-02-29T00:00:00.042Z"
+        );
+        assert_eq!(
+            timestamp(UNIX_EPOCH + Duration::from_secs(1_672_531_199)),
+            "2022-12-31T23:59:59.000Z"
+        );
+    }
+}
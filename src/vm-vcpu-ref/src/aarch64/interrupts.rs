@@ -131,6 +131,7 @@ impl Default for GicConfig {
 
 /// Structure used for serializing the state of the GIC registers
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct GicState {
     dist: Vec<GicRegState<u32>>,
     gic_vcpu_states: Vec<GicVcpuState>,
@@ -138,6 +139,7 @@ pub struct GicState {
 
 /// Structure used for serializing the state of the GIC registers for a specific vCPU
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct GicVcpuState {
     redist: Vec<GicRegState<u32>>,
     icc: GicSysRegsState,
@@ -18,6 +18,7 @@ mod redist;
 
 /// Generic GIC register state,
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct GicRegState<T> {
     pub(crate) chunks: Vec<T>,
 }
@@ -0,0 +1,244 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Helpers for serializing the KVM structures that make up the VM and vCPU state.
+//!
+//! `kvm-bindings` does not implement `serde` traits for its types, so they are stored as hex
+//! encoded blobs of their in-memory representation. This is only done for plain structures
+//! made of integers (and arrays or unions of integers), for which any byte pattern is valid.
+//! The resulting state is only meant to be loaded on a host with the same architecture.
+
+use std::any::type_name;
+use std::mem::size_of;
+use std::ptr;
+use std::slice;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Marker for KVM structures that can be saved as raw bytes.
+///
+/// # Safety
+///
+/// Implementors must be `repr(C)` types made only of integers, such that any byte pattern
+/// of `size_of::<Self>()` bytes is a valid value.
+pub(crate) unsafe trait Pod: Copy + Default {}
+
+#[cfg(target_arch = "x86_64")]
+mod x86_64 {
+    use super::Pod;
+    use kvm_bindings::{
+        kvm_clock_data, kvm_cpuid_entry2, kvm_debugregs, kvm_irqchip, kvm_lapic_state,
+        kvm_mp_state, kvm_msr_entry, kvm_pit_state2, kvm_regs, kvm_sregs, kvm_vcpu_events,
+        kvm_xcrs, kvm_xsave,
+    };
+
+    // Safe because these are all plain integer structures generated by bindgen.
+    unsafe impl Pod for kvm_clock_data {}
+    unsafe impl Pod for kvm_cpuid_entry2 {}
+    unsafe impl Pod for kvm_debugregs {}
+    unsafe impl Pod for kvm_irqchip {}
+    unsafe impl Pod for kvm_lapic_state {}
+    unsafe impl Pod for kvm_mp_state {}
+    unsafe impl Pod for kvm_msr_entry {}
+    unsafe impl Pod for kvm_pit_state2 {}
+    unsafe impl Pod for kvm_regs {}
+    unsafe impl Pod for kvm_sregs {}
+    unsafe impl Pod for kvm_vcpu_events {}
+    unsafe impl Pod for kvm_xcrs {}
+    unsafe impl Pod for kvm_xsave {}
+}
+
+#[cfg(target_arch = "aarch64")]
+mod aarch64 {
+    use super::Pod;
+    use kvm_bindings::{kvm_mp_state, kvm_one_reg};
+
+    // Safe because these are all plain integer structures generated by bindgen.
+    unsafe impl Pod for kvm_mp_state {}
+    unsafe impl Pod for kvm_one_reg {}
+}
+
+fn as_bytes<T: Pod>(value: &T) -> &[u8] {
+    // Safe because `T: Pod` is plain data and the slice covers exactly `value`.
+    unsafe { slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+}
+
+fn from_bytes<T: Pod>(bytes: &[u8]) -> Option<T> {
+    if bytes.len() != size_of::<T>() {
+        return None;
+    }
+    let mut value = T::default();
+    // Safe because the sizes match, and any byte pattern is a valid `T: Pod`.
+    unsafe {
+        ptr::copy_nonoverlapping(bytes.as_ptr(), &mut value as *mut T as *mut u8, bytes.len())
+    };
+    Some(value)
+}
+
+fn encode<T: Pod>(value: &T) -> String {
+    as_bytes(value)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn decode<T: Pod, E: serde::de::Error>(hex: &str) -> Result<T, E> {
+    let invalid = || E::custom(format!("invalid {}", type_name::<T>()));
+    if !hex.is_ascii() || hex.len() % 2 != 0 {
+        return Err(invalid());
+    }
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| invalid())?;
+    from_bytes(&bytes).ok_or_else(invalid)
+}
+
+/// `serde(with)` module for a single KVM structure.
+pub(crate) mod pod {
+    use super::*;
+
+    pub fn serialize<T: Pod, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&encode(value))
+    }
+
+    pub fn deserialize<'de, T: Pod, D: Deserializer<'de>>(deserializer: D) -> Result<T, D::Error> {
+        decode(&String::deserialize(deserializer)?)
+    }
+}
+
+/// `serde(with)` module for a vector of KVM structures.
+pub(crate) mod pod_vec {
+    use super::*;
+
+    pub fn serialize<T: Pod, S: Serializer>(
+        values: &[T],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        values
+            .iter()
+            .map(encode)
+            .collect::<Vec<String>>()
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, T: Pod, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<T>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|hex| decode(hex))
+            .collect()
+    }
+}
+
+/// `serde(with)` module for KVM structures with a flexible array member (i.e. `CpuId`, `Msrs`).
+#[cfg(target_arch = "x86_64")]
+pub(crate) mod fam {
+    use super::*;
+    use serde::de::Error as _;
+    use vmm_sys_util::fam::{FamStruct, FamStructWrapper};
+
+    pub fn serialize<T, S>(value: &FamStructWrapper<T>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        T: Default + FamStruct,
+        T::Entry: Pod,
+        S: Serializer,
+    {
+        pod_vec::serialize(value.as_slice(), serializer)
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<FamStructWrapper<T>, D::Error>
+    where
+        T: Default + FamStruct,
+        T::Entry: Pod,
+        D: Deserializer<'de>,
+    {
+        let entries = pod_vec::deserialize::<T::Entry, D>(deserializer)?;
+        FamStructWrapper::from_entries(&entries)
+            .map_err(|e| D::Error::custom(format!("invalid {}: {:?}", type_name::<T>(), e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Clone, Copy, Debug, Default, PartialEq)]
+    #[repr(C)]
+    struct Foo {
+        a: u32,
+        b: [u8; 4],
+    }
+
+    unsafe impl Pod for Foo {}
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct Bar {
+        #[serde(with = "pod")]
+        foo: Foo,
+        #[serde(with = "pod_vec")]
+        foos: Vec<Foo>,
+    }
+
+    #[test]
+    fn test_pod_roundtrip() {
+        let bar = Bar {
+            foo: Foo {
+                a: 0x0403_0201,
+                b: [5, 6, 7, 8],
+            },
+            foos: vec![Foo::default(), Foo { a: 1, b: [0xff; 4] }],
+        };
+        let json = serde_json::to_string(&bar).unwrap();
+        #[cfg(target_endian = "little")]
+        assert_eq!(
+            json,
+            r#"{"foo":"0102030405060708","foos":["0000000000000000","01000000ffffffff"]}"#
+        );
+        assert_eq!(serde_json::from_str::<Bar>(&json).unwrap(), bar);
+
+        // Wrong size.
+        assert!(serde_json::from_str::<Bar>(r#"{"foo":"01","foos":[]}"#).is_err());
+        // Not hex.
+        assert!(serde_json::from_str::<Bar>(r#"{"foo":"zz02030405060708","foos":[]}"#).is_err());
+        // Not ASCII.
+        assert!(serde_json::from_str::<Bar>(r#"{"foo":"é2030405060708","foos":[]}"#).is_err());
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_fam_roundtrip() {
+        use kvm_bindings::{kvm_msr_entry, Msrs};
+
+        #[derive(Deserialize, Serialize)]
+        struct Baz {
+            #[serde(with = "fam")]
+            msrs: Msrs,
+        }
+
+        let entries = [
+            kvm_msr_entry {
+                index: 0x10,
+                data: 42,
+                ..Default::default()
+            },
+            kvm_msr_entry {
+                index: 0x11,
+                data: 43,
+                ..Default::default()
+            },
+        ];
+        let baz = Baz {
+            msrs: Msrs::from_entries(&entries).unwrap(),
+        };
+        let json = serde_json::to_string(&baz).unwrap();
+        let restored = serde_json::from_str::<Baz>(&json).unwrap();
+        assert_eq!(restored.msrs.as_fam_struct_ref().nmsrs, 2);
+        assert_eq!(restored.msrs.as_slice()[1].index, 0x11);
+        assert_eq!(restored.msrs.as_slice()[1].data, 43);
+    }
+}
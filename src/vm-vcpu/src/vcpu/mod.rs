@@ -4,11 +4,16 @@
 //
 use libc::siginfo_t;
 use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::ffi::c_void;
 use std::io::{self, stdin};
 use std::os::raw::c_int;
 use std::result;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Barrier, Condvar, Mutex};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
 
 #[cfg(target_arch = "x86_64")]
 use kvm_bindings::{
@@ -21,6 +26,7 @@ use kvm_bindings::{
     KVM_SYSTEM_EVENT_CRASH, KVM_SYSTEM_EVENT_RESET, KVM_SYSTEM_EVENT_SHUTDOWN,
 };
 use kvm_ioctls::{Kvm, VcpuExit, VcpuFd, VmFd};
+use log::{info, warn};
 use vm_device::bus::{MmioAddress, PioAddress};
 use vm_device::device_manager::{IoManager, MmioManager, PioManager};
 #[cfg(target_arch = "aarch64")]
@@ -49,6 +55,11 @@ mod regs;
 #[cfg(target_arch = "aarch64")]
 use regs::*;
 
+#[cfg(target_arch = "x86_64")]
+use crate::persist::fam;
+use crate::persist::pod;
+#[cfg(target_arch = "aarch64")]
+use crate::persist::pod_vec;
 use crate::vm::VmRunState;
 #[cfg(target_arch = "aarch64")]
 use arch::{AARCH64_FDT_MAX_SIZE, AARCH64_PHYS_MEM_START};
@@ -211,18 +222,20 @@ pub enum Error {
 /// Dedicated Result type.
 pub type Result<T> = result::Result<T, Error>;
 
-#[derive(Clone)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct VcpuConfig {
     pub id: u8,
     #[cfg(target_arch = "x86_64")]
+    #[serde(with = "fam")]
     pub cpuid: CpuId,
     #[cfg(target_arch = "x86_64")]
     // This is just a workaround so that we can get a list of MSRS.
     // Just getting all the MSRS on a vcpu is not possible with KVM.
+    #[serde(with = "fam")]
     pub msrs: Msrs,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct VcpuConfigList {
     pub configs: Vec<VcpuConfig>,
 }
@@ -268,25 +281,37 @@ impl VcpuConfigList {
 
 /// Structure holding the kvm state for an x86_64 VCPU.
 #[cfg(target_arch = "x86_64")]
-#[derive(Clone)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct VcpuState {
+    #[serde(with = "fam")]
     pub cpuid: CpuId,
+    #[serde(with = "fam")]
     pub msrs: Msrs,
+    #[serde(with = "pod")]
     pub debug_regs: kvm_debugregs,
+    #[serde(with = "pod")]
     pub lapic: kvm_lapic_state,
+    #[serde(with = "pod")]
     pub mp_state: kvm_mp_state,
+    #[serde(with = "pod")]
     pub regs: kvm_regs,
+    #[serde(with = "pod")]
     pub sregs: kvm_sregs,
+    #[serde(with = "pod")]
     pub vcpu_events: kvm_vcpu_events,
+    #[serde(with = "pod")]
     pub xcrs: kvm_xcrs,
+    #[serde(with = "pod")]
     pub xsave: kvm_xsave,
     pub config: VcpuConfig,
 }
 
 #[cfg(target_arch = "aarch64")]
-#[derive(Clone)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct VcpuState {
+    #[serde(with = "pod")]
     pub mp_state: kvm_mp_state,
+    #[serde(with = "pod_vec")]
     pub regs: Vec<kvm_one_reg>,
     /// Cached value of MPIDR register. Even though it's stored
     /// in `regs`, searching for it is an expensive linear scan.
@@ -294,11 +319,62 @@ pub struct VcpuState {
     pub config: VcpuConfig,
 }
 
+/// Number of times a vCPU exited to the VMM, by exit reason.
+#[derive(Debug, Default, Serialize)]
+pub struct VcpuExitMetrics {
+    /// Port I/O reads.
+    pub io_in: AtomicU64,
+    /// Port I/O writes.
+    pub io_out: AtomicU64,
+    /// MMIO reads.
+    pub mmio_read: AtomicU64,
+    /// MMIO writes.
+    pub mmio_write: AtomicU64,
+    /// `HLT` instructions.
+    pub hlt: AtomicU64,
+    /// Triple faults and other shutdowns.
+    pub shutdown: AtomicU64,
+    /// System events, such as PSCI shutdown or reset requests.
+    pub system_event: AtomicU64,
+    /// Exits the VMM does not handle.
+    pub other: AtomicU64,
+}
+
+impl VcpuExitMetrics {
+    fn count(&self, exit_reason: &VcpuExit) {
+        let counter = match exit_reason {
+            VcpuExit::IoIn(..) => &self.io_in,
+            VcpuExit::IoOut(..) => &self.io_out,
+            VcpuExit::MmioRead(..) => &self.mmio_read,
+            VcpuExit::MmioWrite(..) => &self.mmio_write,
+            VcpuExit::Hlt => &self.hlt,
+            VcpuExit::Shutdown => &self.shutdown,
+            VcpuExit::SystemEvent(..) => &self.system_event,
+            _ => &self.other,
+        };
+        // The counters are only read for reporting.
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Why a vCPU stopped running the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VcpuStop {
+    /// The guest powered off or crashed, or the VM is exiting.
+    PowerOff,
+    /// The guest reset the machine, to boot again.
+    Reset,
+}
+
 /// Represents the current run state of the VCPUs.
 #[derive(Default)]
 pub struct VcpuRunState {
     pub(crate) vm_state: Mutex<VmRunState>,
     condvar: Condvar,
+    // The vCPUs currently parked in `KvmVcpu::pause`, by id, along with the state they were
+    // parked with (if saving it succeeded).
+    parked_vcpus: Mutex<BTreeMap<u8, Option<VcpuState>>>,
+    parked_condvar: Condvar,
 }
 
 impl VcpuRunState {
@@ -306,6 +382,38 @@ impl VcpuRunState {
         *self.vm_state.lock().unwrap() = state;
         self.condvar.notify_all();
     }
+
+    /// Waits up to `timeout` for at least `num_vcpus` vCPUs to be parked.
+    ///
+    /// Returns whether the vCPUs got parked in time.
+    pub(crate) fn wait_parked(&self, num_vcpus: usize, timeout: Duration) -> bool {
+        let parked = self.parked_vcpus.lock().unwrap();
+        let (_parked, result) = self
+            .parked_condvar
+            .wait_timeout_while(parked, timeout, |parked| parked.len() < num_vcpus)
+            .unwrap();
+        !result.timed_out()
+    }
+
+    /// Returns the state saved by each parked vCPU, ordered by vCPU id.
+    pub(crate) fn parked_vcpus_state(&self) -> Vec<(u8, Option<VcpuState>)> {
+        self.parked_vcpus
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, state)| (*id, state.clone()))
+            .collect()
+    }
+
+    fn park(&self, id: u8, state: Option<VcpuState>) {
+        self.parked_vcpus.lock().unwrap().insert(id, state);
+        self.parked_condvar.notify_all();
+    }
+
+    fn unpark(&self, id: u8) {
+        self.parked_vcpus.lock().unwrap().remove(&id);
+        self.parked_condvar.notify_all();
+    }
 }
 
 /// Struct for interacting with vCPUs.
@@ -320,6 +428,7 @@ pub struct KvmVcpu {
     config: VcpuConfig,
     run_barrier: Arc<Barrier>,
     pub(crate) run_state: Arc<VcpuRunState>,
+    pub(crate) exit_metrics: Arc<VcpuExitMetrics>,
 }
 
 impl KvmVcpu {
@@ -350,6 +459,7 @@ impl KvmVcpu {
             config,
             run_barrier,
             run_state,
+            exit_metrics: Arc::new(VcpuExitMetrics::default()),
         };
 
         #[cfg(target_arch = "x86_64")]
@@ -423,6 +533,22 @@ impl KvmVcpu {
         Ok(())
     }
 
+    /// Bring the vCPU back to `state`, the one it had right after it was created, for the
+    /// guest to boot again. The boot tables are written to guest memory again as well.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) fn reset<M: GuestMemory>(&mut self, state: VcpuState, memory: &M) -> Result<()> {
+        self.set_state(state)?;
+        self.configure_sregs(memory)
+    }
+
+    /// Bring the vCPU back to its power-on state, for the guest to boot again.
+    #[cfg(target_arch = "aarch64")]
+    pub(crate) fn reset<M: GuestMemory>(&mut self, vm_fd: &VmFd, memory: &M) -> Result<()> {
+        // `KVM_ARM_VCPU_INIT` resets the registers when called again.
+        self.init(vm_fd)?;
+        self.configure_regs(memory)
+    }
+
     /// Create a vCPU from a previously saved state.
     pub fn from_state<M: GuestMemory>(
         vm_fd: &VmFd,
@@ -439,6 +565,7 @@ impl KvmVcpu {
             config: state.config.clone(),
             run_barrier,
             run_state,
+            exit_metrics: Arc::new(VcpuExitMetrics::default()),
         };
 
         #[cfg(target_arch = "aarch64")]
@@ -672,8 +799,10 @@ impl KvmVcpu {
     ///
     /// * `instruction_pointer`: Represents the start address of the vcpu. This can be None
     /// when the IP is specified using the platform dependent registers.
+    ///
+    /// Returns why the vcpu stopped.
     #[allow(clippy::if_same_then_else)]
-    pub fn run(&mut self, instruction_pointer: Option<GuestAddress>) -> Result<()> {
+    pub fn run(&mut self, instruction_pointer: Option<GuestAddress>) -> Result<VcpuStop> {
         if let Some(ip) = instruction_pointer {
             #[cfg(target_arch = "x86_64")]
             self.configure_regs(ip)?;
@@ -689,19 +818,27 @@ impl KvmVcpu {
         self.init_tls()?;
 
         self.run_barrier.wait();
-        'vcpu_run: loop {
+        let stop = 'vcpu_run: loop {
             let mut interrupted_by_signal = false;
             match self.vcpu_fd.run() {
                 Ok(exit_reason) => {
                     // println!("{:#?}", exit_reason);
+                    self.exit_metrics.count(&exit_reason);
                     match exit_reason {
-                        VcpuExit::Shutdown | VcpuExit::Hlt => {
-                            println!("Guest shutdown: {:?}. Bye!", exit_reason);
+                        // A triple fault, which resets the machine. This is how the guest
+                        // reboots with `reboot=t`.
+                        VcpuExit::Shutdown => {
+                            info!("Guest reset.");
+                            self.run_state.set_and_notify(VmRunState::Exiting);
+                            break VcpuStop::Reset;
+                        }
+                        VcpuExit::Hlt => {
+                            info!("Guest shutdown: {:?}. Bye!", exit_reason);
                             if stdin().lock().set_canon_mode().is_err() {
-                                eprintln!("Failed to set canon mode. Stdin will not echo.");
+                                warn!("Failed to set canon mode. Stdin will not echo.");
                             }
                             self.run_state.set_and_notify(VmRunState::Exiting);
-                            break;
+                            break VcpuStop::PowerOff;
                         }
                         VcpuExit::IoOut(addr, data) => {
                             if (0x3f8..(0x3f8 + 8)).contains(&addr) {
@@ -747,6 +884,18 @@ impl KvmVcpu {
                                 {
                                     debug!("Failed to read from serial port");
                                 }
+                            } else if addr == 0x060 || addr == 0x061 || addr == 0x064 {
+                                // Read from the i8042 port.
+                                #[cfg(target_arch = "x86_64")]
+                                if self
+                                    .device_mgr
+                                    .lock()
+                                    .unwrap()
+                                    .pio_read(PioAddress(addr), data)
+                                    .is_err()
+                                {
+                                    debug!("Failed to read from i8042 port")
+                                }
                             } else {
                                 // Read from some other port.
                             }
@@ -775,15 +924,18 @@ impl KvmVcpu {
                         }
                         #[cfg(target_arch = "aarch64")]
                         VcpuExit::SystemEvent(type_, flags) => match type_ {
-                            KVM_SYSTEM_EVENT_SHUTDOWN
-                            | KVM_SYSTEM_EVENT_RESET
-                            | KVM_SYSTEM_EVENT_CRASH => {
-                                println!("Exit reason: {:#?}", VcpuExit::SystemEvent(type_, flags));
+                            KVM_SYSTEM_EVENT_RESET => {
+                                info!("Exit reason: {:#?}", VcpuExit::SystemEvent(type_, flags));
+                                self.run_state.set_and_notify(VmRunState::Exiting);
+                                break VcpuStop::Reset;
+                            }
+                            KVM_SYSTEM_EVENT_SHUTDOWN | KVM_SYSTEM_EVENT_CRASH => {
+                                info!("Exit reason: {:#?}", VcpuExit::SystemEvent(type_, flags));
                                 if stdin().lock().set_canon_mode().is_err() {
-                                    eprintln!("Failed to set canon mode. Stdin will not echo.");
+                                    warn!("Failed to set canon mode. Stdin will not echo.");
                                 }
                                 self.run_state.set_and_notify(VmRunState::Exiting);
-                                break;
+                                break VcpuStop::PowerOff;
                             }
                             _ => {
                                 // Unknown system event type
@@ -806,7 +958,7 @@ impl KvmVcpu {
                         }
                         _ => {
                             debug!("Emulation error: {}", e);
-                            break;
+                            break VcpuStop::PowerOff;
                         }
                     }
                 }
@@ -814,37 +966,72 @@ impl KvmVcpu {
 
             if interrupted_by_signal {
                 self.vcpu_fd.set_kvm_immediate_exit(0);
-                let mut run_state_lock = self.run_state.vm_state.lock().unwrap();
-                loop {
-                    match *run_state_lock {
-                        VmRunState::Running => {
-                            // The VM state is running, so we need to exit from this loop,
-                            // and enter the kvm run loop.
-                            break;
-                        }
-                        VmRunState::Suspending => {
-                            // The VM is suspending. We run this loop until we get a different
-                            // state.
-                        }
-                        VmRunState::Exiting => {
-                            // The VM is exiting. We also exit from this VCPU thread.
-                            break 'vcpu_run;
-                        }
-                    }
-                    // Give ownership of our exclusive lock to the condition variable that will
-                    // block. When the condition variable is notified, `wait` will unblock and
-                    // return a new exclusive lock.
-                    run_state_lock = self.run_state.condvar.wait(run_state_lock).unwrap();
+                if self.park() == VmRunState::Exiting {
+                    // The VM is exiting. We also exit from this VCPU thread.
+                    break 'vcpu_run VcpuStop::PowerOff;
                 }
             }
-        }
+        };
 
-        Ok(())
+        // The vcpu is handed back to the VM once its thread is done, so the signal handler
+        // must not use this address anymore.
+        Self::TLS_VCPU_PTR.with(|vcpu| *vcpu.borrow_mut() = None);
+        Ok(stop)
     }
 
     /// Pause the vcpu. If the vcpu is already paused, this is a no-op.
+    ///
+    /// This is called from the vCPU thread once `KVM_RUN` was interrupted, and returns once
+    /// the VM is no longer `Suspending`.
     pub fn pause(&mut self) -> Result<()> {
-        todo!()
+        self.park();
+        Ok(())
+    }
+
+    /// Parks the vCPU thread for as long as the VM is `Suspending`. While parked, the vCPU is
+    /// accounted for in [`VcpuRunState`] so that the VM can tell when all of its vCPUs are
+    /// quiesced.
+    ///
+    /// The vCPU saves its state right before parking. The VM cannot do it on its own since the
+    /// vCPUs are owned by their threads once running, and the state cannot change until the
+    /// vCPU is unparked.
+    ///
+    /// Returns the run state that ended the pause.
+    fn park(&mut self) -> VmRunState {
+        // Clone the `Arc` so that the lock does not borrow `self`, which is needed for saving
+        // the state.
+        let run_state = self.run_state.clone();
+        let mut run_state_lock = run_state.vm_state.lock().unwrap();
+        let mut parked = false;
+        loop {
+            match *run_state_lock {
+                VmRunState::Suspending => {
+                    // The VM is suspending. We run this loop until we get a different
+                    // state.
+                    if !parked {
+                        parked = true;
+                        let state = self
+                            .save_state()
+                            .map_err(|_e| debug!("Failed to save the vcpu state: {}", _e))
+                            .ok();
+                        run_state.park(self.config.id, state);
+                    }
+                }
+                // The VM state is running, so we need to exit from this loop,
+                // and enter the kvm run loop. Or the VM is exiting, which is up to
+                // the caller to handle.
+                state => {
+                    if parked {
+                        run_state.unpark(self.config.id);
+                    }
+                    return state;
+                }
+            }
+            // Give ownership of our exclusive lock to the condition variable that will
+            // block. When the condition variable is notified, `wait` will unblock and
+            // return a new exclusive lock.
+            run_state_lock = run_state.condvar.wait(run_state_lock).unwrap();
+        }
     }
 
     #[cfg(target_arch = "x86_64")]
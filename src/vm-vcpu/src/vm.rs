@@ -7,22 +7,28 @@ use std::convert::TryInto;
 use std::io::{self, ErrorKind};
 use std::sync::{Arc, Barrier, Mutex};
 use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-use kvm_bindings::kvm_userspace_memory_region;
 #[cfg(target_arch = "x86_64")]
 use kvm_bindings::{
     kvm_clock_data, kvm_irqchip, kvm_pit_config, kvm_pit_state2, KVM_CLOCK_TSC_STABLE,
     KVM_IRQCHIP_IOAPIC, KVM_IRQCHIP_PIC_MASTER, KVM_IRQCHIP_PIC_SLAVE, KVM_PIT_SPEAKER_DUMMY,
 };
+use kvm_bindings::{kvm_userspace_memory_region, KVM_MEM_LOG_DIRTY_PAGES};
 
 use kvm_ioctls::{Kvm, VmFd};
+use serde::{Deserialize, Serialize};
 use vm_device::device_manager::IoManager;
 use vm_memory::{Address, GuestAddress, GuestMemory, GuestMemoryRegion};
 use vmm_sys_util::errno::Error as Errno;
 use vmm_sys_util::eventfd::EventFd;
 use vmm_sys_util::signal::{Killable, SIGRTMIN};
 
-use crate::vcpu::{self, KvmVcpu, VcpuConfigList, VcpuRunState, VcpuState};
+#[cfg(target_arch = "x86_64")]
+use crate::persist::pod;
+use crate::vcpu::{
+    self, KvmVcpu, VcpuConfigList, VcpuExitMetrics, VcpuRunState, VcpuState, VcpuStop,
+};
 
 #[cfg(target_arch = "aarch64")]
 use vm_vcpu_ref::aarch64::interrupts::{self, Gic, GicConfig, GicState};
@@ -34,8 +40,13 @@ pub const MAX_IRQ: u32 = interrupts::MIN_NR_IRQS;
 #[cfg(target_arch = "x86_64")]
 pub const MAX_IRQ: u32 = mptable::IRQ_MAX as u32;
 
+/// How long `pause` waits for the vcpus to leave `KVM_RUN`.
+const PAUSE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often `pause` kicks the vcpus that are not parked yet.
+const PAUSE_KICK_INTERVAL: Duration = Duration::from_millis(10);
+
 /// Defines the configuration of this VM.
-#[derive(Clone)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct VmConfig {
     pub num_vcpus: u8,
     pub vcpus_config: VcpuConfigList,
@@ -54,19 +65,24 @@ impl VmConfig {
 }
 
 #[cfg(target_arch = "x86_64")]
-#[derive(Clone)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct VmState {
+    #[serde(with = "pod")]
     pub pitstate: kvm_pit_state2,
+    #[serde(with = "pod")]
     pub clock: kvm_clock_data,
+    #[serde(with = "pod")]
     pub pic_master: kvm_irqchip,
+    #[serde(with = "pod")]
     pub pic_slave: kvm_irqchip,
+    #[serde(with = "pod")]
     pub ioapic: kvm_irqchip,
     pub config: VmConfig,
     pub vcpus_state: Vec<VcpuState>,
 }
 
 #[cfg(target_arch = "aarch64")]
-#[derive(Clone)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct VmState {
     pub config: VmConfig,
     pub vcpus_state: Vec<VcpuState>,
@@ -84,15 +100,61 @@ pub struct KvmVm<EH: ExitHandler + Send> {
     // To create the `vcpu_handles` the `vcpu` vector is drained.
     // A better abstraction should be used to represent this behavior.
     vcpus: Vec<KvmVcpu>,
-    vcpu_handles: Vec<JoinHandle<()>>,
+    // The threads hand their vcpu back once they are done with it.
+    vcpu_handles: Vec<JoinHandle<KvmVcpu>>,
     exit_handler: EH,
     vcpu_barrier: Arc<Barrier>,
     vcpu_run_state: Arc<VcpuRunState>,
+    // The memory slots registered with KVM, indexed by slot number.
+    memory_regions: Vec<kvm_userspace_memory_region>,
+    // The state of the VM right after it was created, which `reset` brings it back to. It is
+    // not known for a VM restored from a saved state.
+    boot_state: Option<VmState>,
 
     #[cfg(target_arch = "aarch64")]
     gic: Option<Gic>,
 }
 
+/// Guest memory pages of a memory slot written by the vcpus.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct DirtyLog {
+    /// Memory slot number.
+    pub slot: u32,
+    /// Guest physical address of the memory slot.
+    pub guest_phys_addr: u64,
+    /// Size of the memory slot in bytes.
+    pub memory_size: u64,
+    /// One bit per 4 KiB page of the slot, set when the page was written.
+    pub bitmap: Vec<u64>,
+}
+
+impl DirtyLog {
+    /// Marks the page holding `addr` as written. Returns `false` if `addr` is outside of the
+    /// memory slot.
+    pub fn mark(&mut self, addr: u64) -> bool {
+        if addr < self.guest_phys_addr || addr - self.guest_phys_addr >= self.memory_size {
+            return false;
+        }
+        let page = (addr - self.guest_phys_addr) / 4096;
+        // The bitmap is sized by KVM for the whole slot.
+        self.bitmap[(page / 64) as usize] |= 1 << (page % 64);
+        true
+    }
+
+    /// Returns the guest physical addresses of the written pages.
+    pub fn dirty_pages(&self) -> impl Iterator<Item = u64> + '_ {
+        let base = self.guest_phys_addr;
+        self.bitmap
+            .iter()
+            .enumerate()
+            .flat_map(move |(index, word)| {
+                (0..64)
+                    .filter(move |bit| word & (1 << bit) != 0)
+                    .map(move |bit| base + (index as u64 * 64 + bit) * 4096)
+            })
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     /// Failed to create the VM Configuration.
@@ -133,6 +195,18 @@ pub enum Error {
     /// Failed to resume vcpus.
     #[error("Failed to resume vcpus: {0}")]
     ResumeVcpus(Errno),
+    /// The vcpus did not pause in time.
+    #[error("Timed out waiting for the vcpus to pause.")]
+    PauseTimeout,
+    /// The VM is exiting, so its run state cannot change anymore.
+    #[error("The VM is exiting.")]
+    VmExiting,
+    /// The operation requires the VM to be paused.
+    #[error("The VM is not paused.")]
+    VmNotPaused,
+    /// A vcpu failed to save its state when it was paused.
+    #[error("The state of vcpu {0} is not available.")]
+    VcpuStateUnavailable(u8),
     /// Failed to get KVM vm pit state.
     #[error("Failed to get KVM vm pit state: {0}")]
     VmGetPit2(kvm_ioctls::Error),
@@ -157,6 +231,24 @@ pub enum Error {
     /// Failed to save the state of vCPUs.
     #[error("Failed to save the state of vCPUs: {0}")]
     SaveVcpuState(vcpu::Error),
+    /// Failed to reset a vCPU.
+    #[error("Failed to reset a vCPU: {0}")]
+    ResetVcpu(vcpu::Error),
+    /// A vCPU thread panicked, so its vCPU is lost.
+    #[error("A vCPU thread panicked.")]
+    VcpuPanicked,
+    /// The VM was restored from a saved state, so the state it boots with is not known.
+    #[error("The VM has no boot state to be reset to.")]
+    NoBootState,
+    /// Failed to change dirty page logging on a memory slot.
+    #[error("Failed to change dirty page logging: {0}")]
+    SetDirtyLogging(kvm_ioctls::Error),
+    /// Failed to get the dirty page log of a memory slot.
+    #[error("Failed to get the dirty page log: {0}")]
+    GetDirtyLog(kvm_ioctls::Error),
+    /// The memory slot does not exist.
+    #[error("Invalid memory slot: {0}")]
+    InvalidMemorySlot(u32),
     #[cfg(target_arch = "x86_64")]
     /// Invalid max IRQ value.
     #[error("Invalid maximum number of IRQ: {0}")]
@@ -184,10 +276,16 @@ pub type Result<T> = std::result::Result<T, Error>;
 // This trait needs Clone because each VCPU needs to be able to call the `kick` function.
 pub trait ExitHandler: Clone {
     fn kick(&self) -> io::Result<()>;
+
+    /// Called instead of `kick` when the guest reset the VM, which `KvmVm::reset` can then
+    /// prepare to boot again.
+    fn reset(&self) -> io::Result<()> {
+        self.kick()
+    }
 }
 
 /// Represents the current state of the VM.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum VmRunState {
     Running,
     Suspending,
@@ -213,7 +311,7 @@ impl<EH: 'static + ExitHandler + Send> KvmVm<EH> {
         let vm_fd = Arc::new(kvm.create_vm().map_err(Error::CreateVm)?);
         let vcpu_run_state = Arc::new(VcpuRunState::default());
 
-        let vm = KvmVm {
+        let mut vm = KvmVm {
             vcpu_barrier: Arc::new(Barrier::new(config.num_vcpus as usize)),
             config,
             fd: vm_fd,
@@ -221,6 +319,8 @@ impl<EH: 'static + ExitHandler + Send> KvmVm<EH> {
             vcpu_handles: Vec::new(),
             exit_handler,
             vcpu_run_state,
+            memory_regions: Vec::new(),
+            boot_state: None,
 
             #[cfg(target_arch = "aarch64")]
             gic: None,
@@ -242,22 +342,28 @@ impl<EH: 'static + ExitHandler + Send> KvmVm<EH> {
         let mut vm = Self::create_vm(kvm, vm_config, exit_handler, guest_memory)?;
 
         #[cfg(target_arch = "x86_64")]
-        {
-            let max_irq: u8 = vm
-                .config
-                .max_irq
-                .try_into()
-                .map_err(|_| Error::IRQMaxValue(vm.config.max_irq))?;
-            MpTable::new(vm.config.num_vcpus, max_irq)?.write(guest_memory)?;
-        }
+        vm.setup_mptable(guest_memory)?;
         #[cfg(target_arch = "x86_64")]
         vm.setup_irq_controller()?;
 
         vm.create_vcpus(bus, vcpus_config, guest_memory)?;
+        vm.boot_state = Some(vm.save_state()?);
 
         Ok(vm)
     }
 
+    // Write the MP table, which describes the vcpus and the interrupt controllers to the guest.
+    #[cfg(target_arch = "x86_64")]
+    fn setup_mptable<M: GuestMemory>(&self, guest_memory: &M) -> Result<()> {
+        let max_irq: u8 = self
+            .config
+            .max_irq
+            .try_into()
+            .map_err(|_| Error::IRQMaxValue(self.config.max_irq))?;
+        MpTable::new(self.config.num_vcpus, max_irq)?.write(guest_memory)?;
+        Ok(())
+    }
+
     #[cfg(target_arch = "x86_64")]
     // Set the state of this `KvmVm`. Errors returned from this function
     // MUST not be ignored because they can lead to undefined behavior when
@@ -338,8 +444,31 @@ impl<EH: 'static + ExitHandler + Send> KvmVm<EH> {
         self.config.max_irq
     }
 
+    /// Returns the number of vcpus of the VM.
+    pub fn num_vcpus(&self) -> u8 {
+        self.config.num_vcpus
+    }
+
+    /// Returns the exit counters of the vcpus. The vcpus are handed to their threads once the
+    /// VM runs, so this has to be called before.
+    pub fn vcpu_exit_metrics(&self) -> Vec<Arc<VcpuExitMetrics>> {
+        self.vcpus
+            .iter()
+            .map(|vcpu| vcpu.exit_metrics.clone())
+            .collect()
+    }
+
+    /// Returns whether the vcpu threads are started.
+    pub fn vcpus_started(&self) -> bool {
+        !self.vcpu_handles.is_empty()
+    }
+
     // Create the kvm memory regions based on the configuration passed as `guest_memory`.
-    fn configure_memory_regions<M: GuestMemory>(&self, guest_memory: &M, kvm: &Kvm) -> Result<()> {
+    fn configure_memory_regions<M: GuestMemory>(
+        &mut self,
+        guest_memory: &M,
+        kvm: &Kvm,
+    ) -> Result<()> {
         if guest_memory.num_regions() > kvm.get_nr_memslots() {
             return Err(Error::NotEnoughMemorySlots);
         }
@@ -362,11 +491,82 @@ impl<EH: 'static + ExitHandler + Send> KvmVm<EH> {
             //   the whole guest memory, or 2 regions with the MMIO gap in between.
             unsafe { self.fd.set_user_memory_region(memory_region) }
                 .map_err(Error::SetupMemoryRegion)?;
+            self.memory_regions.push(memory_region);
+        }
+
+        Ok(())
+    }
+
+    /// Number of memory slots registered with KVM. Slots are numbered from 0.
+    pub fn memory_slots(&self) -> u32 {
+        self.memory_regions.len() as u32
+    }
+
+    /// Enable or disable the logging of guest memory pages written by the vcpus, on memory
+    /// `slot`.
+    ///
+    /// The log of the slot is empty right after logging is enabled. Enabling logging on a slot
+    /// which already logs keeps its log.
+    pub fn set_slot_dirty_logging(&mut self, slot: u32, enabled: bool) -> Result<()> {
+        let memory_region = self
+            .memory_regions
+            .get_mut(slot as usize)
+            .ok_or(Error::InvalidMemorySlot(slot))?;
+        let flags = if enabled { KVM_MEM_LOG_DIRTY_PAGES } else { 0 };
+        if memory_region.flags == flags {
+            return Ok(());
         }
 
+        memory_region.flags = flags;
+        // Safe because the region is the one registered by `configure_memory_regions`,
+        // only the flags are different.
+        unsafe { self.fd.set_user_memory_region(*memory_region) }.map_err(Error::SetDirtyLogging)
+    }
+
+    /// Enable or disable the logging of guest memory pages written by the vcpus, on all the
+    /// memory slots.
+    pub fn set_dirty_logging(&mut self, enabled: bool) -> Result<()> {
+        for slot in 0..self.memory_slots() {
+            self.set_slot_dirty_logging(slot, enabled)?;
+        }
         Ok(())
     }
 
+    /// Returns whether dirty page logging is enabled on memory `slot`.
+    pub fn slot_dirty_logging(&self, slot: u32) -> bool {
+        self.memory_regions
+            .get(slot as usize)
+            .map_or(false, |region| region.flags & KVM_MEM_LOG_DIRTY_PAGES != 0)
+    }
+
+    /// Returns whether dirty page logging is enabled on at least one memory slot.
+    pub fn dirty_logging(&self) -> bool {
+        self.memory_regions
+            .iter()
+            .any(|region| region.flags & KVM_MEM_LOG_DIRTY_PAGES != 0)
+    }
+
+    /// Return the pages written by the vcpus since dirty logging was enabled or since the
+    /// last call, and clear the log. Only the slots with logging enabled are reported.
+    pub fn dirty_log(&self) -> Result<Vec<DirtyLog>> {
+        self.memory_regions
+            .iter()
+            .filter(|region| region.flags & KVM_MEM_LOG_DIRTY_PAGES != 0)
+            .map(|region| {
+                let bitmap = self
+                    .fd
+                    .get_dirty_log(region.slot, region.memory_size as usize)
+                    .map_err(Error::GetDirtyLog)?;
+                Ok(DirtyLog {
+                    slot: region.slot,
+                    guest_phys_addr: region.guest_phys_addr,
+                    memory_size: region.memory_size,
+                    bitmap,
+                })
+            })
+            .collect()
+    }
+
     // Configures the in kernel interrupt controller.
     // This function should be reused to configure the aarch64 interrupt controller (GIC).
     #[cfg(target_arch = "x86_64")]
@@ -492,9 +692,12 @@ impl<EH: 'static + ExitHandler + Send> KvmVm<EH> {
                 .name(format!("vcpu_{}", id))
                 .spawn(move || {
                     // TODO: Check the result of both vcpu run & kick.
-                    vcpu.run(vcpu_run_addr).unwrap();
-                    let _ = vcpu_exit_handler.kick();
+                    let _ = match vcpu.run(vcpu_run_addr).unwrap() {
+                        VcpuStop::Reset => vcpu_exit_handler.reset(),
+                        VcpuStop::PowerOff => vcpu_exit_handler.kick(),
+                    };
                     vcpu.run_state.set_and_notify(VmRunState::Exiting);
+                    vcpu
                 })
                 .map_err(Error::RunVcpus)?;
             self.vcpu_handles.push(vcpu_handle);
@@ -513,21 +716,139 @@ impl<EH: 'static + ExitHandler + Send> KvmVm<EH> {
         })
     }
 
+    /// Stop the vcpus, and bring the VM back to the state it had right after it was created,
+    /// for the guest to boot again with `run`. Guest memory is left as is, except for the boot
+    /// tables, which are written again.
+    ///
+    /// Returns an error for a VM restored from a saved state.
+    pub fn reset<M: GuestMemory>(&mut self, guest_memory: &M) -> Result<()> {
+        let state = self.boot_state.clone().ok_or(Error::NoBootState)?;
+
+        self.vcpu_run_state.set_and_notify(VmRunState::Exiting);
+        for handle in self.vcpu_handles.drain(..) {
+            #[allow(clippy::identity_op)]
+            let _ = handle.kill(SIGRTMIN() + 0);
+            let vcpu = handle.join().map_err(|_| Error::VcpuPanicked)?;
+            self.vcpus.push(vcpu);
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            self.setup_mptable(guest_memory)?;
+            for (vcpu, vcpu_state) in self.vcpus.iter_mut().zip(state.vcpus_state.iter()) {
+                vcpu.reset(vcpu_state.clone(), guest_memory)
+                    .map_err(Error::ResetVcpu)?;
+            }
+        }
+        #[cfg(target_arch = "aarch64")]
+        for vcpu in self.vcpus.iter_mut() {
+            vcpu.reset(&self.fd, guest_memory)
+                .map_err(Error::ResetVcpu)?;
+        }
+        self.set_state(state)?;
+
+        self.vcpu_run_state.set_and_notify(VmRunState::Running);
+        Ok(())
+    }
+
     /// Pause a running VM.
     ///
-    /// If the VM is already paused, this is a no-op.
+    /// If the VM is already paused, this is a no-op. When this function returns successfully,
+    /// all vcpus are out of `KVM_RUN` and parked, so their state (and the guest memory) no
+    /// longer changes until the VM is resumed.
     pub fn pause(&mut self) -> Result<()> {
-        todo!();
+        {
+            let mut vm_state = self.vcpu_run_state.vm_state.lock().unwrap();
+            match *vm_state {
+                VmRunState::Exiting => return Err(Error::VmExiting),
+                VmRunState::Running => *vm_state = VmRunState::Suspending,
+                VmRunState::Suspending => (),
+            }
+        }
+
+        // The vcpus did not start running yet, so there is nothing to park.
+        if self.vcpu_handles.is_empty() {
+            return Ok(());
+        }
+
+        // A vcpu only checks the run state after `KVM_RUN` returns. Keep kicking until all of
+        // them are parked, because a signal that lands before a vcpu thread registers itself
+        // for immediate exit is lost. Kicking an already parked vcpu is harmless.
+        let deadline = Instant::now() + PAUSE_TIMEOUT;
+        loop {
+            for handle in self.vcpu_handles.iter() {
+                #[allow(clippy::identity_op)]
+                handle.kill(SIGRTMIN() + 0).map_err(Error::PauseVcpus)?;
+            }
+            if self
+                .vcpu_run_state
+                .wait_parked(self.vcpu_handles.len(), PAUSE_KICK_INTERVAL)
+            {
+                return Ok(());
+            }
+            if *self.vcpu_run_state.vm_state.lock().unwrap() == VmRunState::Exiting {
+                // The guest shut down while we were waiting.
+                return Err(Error::VmExiting);
+            }
+            if Instant::now() >= deadline {
+                return Err(Error::PauseTimeout);
+            }
+        }
     }
 
+    /// Resume a paused VM.
+    ///
+    /// If the VM is already running, this is a no-op.
+    pub fn resume(&mut self) -> Result<()> {
+        let mut vm_state = self.vcpu_run_state.vm_state.lock().unwrap();
+        match *vm_state {
+            VmRunState::Exiting => Err(Error::VmExiting),
+            VmRunState::Running => Ok(()),
+            VmRunState::Suspending => {
+                drop(vm_state);
+                self.vcpu_run_state.set_and_notify(VmRunState::Running);
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns the current run state of the VM.
+    pub fn run_state(&self) -> VmRunState {
+        *self.vcpu_run_state.vm_state.lock().unwrap()
+    }
+
+    // Retrieve the state of the vcpus.
+    //
+    // Once the VM runs, the vcpus are owned by their threads, and they save their own state
+    // when they get paused.
+    fn vcpus_state(&mut self) -> Result<Vec<VcpuState>> {
+        if self.vcpu_handles.is_empty() {
+            return self
+                .vcpus
+                .iter_mut()
+                .map(|vcpu| vcpu.save_state())
+                .collect::<vcpu::Result<Vec<VcpuState>>>()
+                .map_err(Error::SaveVcpuState);
+        }
+
+        let parked_vcpus = self.vcpu_run_state.parked_vcpus_state();
+        if self.run_state() != VmRunState::Suspending
+            || parked_vcpus.len() != self.config.num_vcpus as usize
+        {
+            return Err(Error::VmNotPaused);
+        }
+        parked_vcpus
+            .into_iter()
+            .map(|(id, state)| state.ok_or(Error::VcpuStateUnavailable(id)))
+            .collect()
+    }
+
+    /// Retrieve the state of a `paused` VM.
+    ///
+    /// Returns an error when the VM is running.
     #[cfg(target_arch = "aarch64")]
     pub fn save_state(&mut self) -> Result<VmState> {
-        let vcpus_state = self
-            .vcpus
-            .iter_mut()
-            .map(|vcpu| vcpu.save_state())
-            .collect::<vcpu::Result<Vec<VcpuState>>>()
-            .map_err(Error::SaveVcpuState)?;
+        let vcpus_state = self.vcpus_state()?;
 
         let mpidrs = vcpus_state.iter().map(|state| state.mpidr).collect();
         let gic_state = self.gic().save_state(mpidrs)?;
@@ -541,9 +862,10 @@ impl<EH: 'static + ExitHandler + Send> KvmVm<EH> {
 
     /// Retrieve the state of a `paused` VM.
     ///
-    /// Returns an error when the VM is not paused.
+    /// Returns an error when the VM is running.
     #[cfg(target_arch = "x86_64")]
     pub fn save_state(&mut self) -> Result<VmState> {
+        let vcpus_state = self.vcpus_state()?;
         let pitstate = self.fd.get_pit2().map_err(Error::VmGetPit2)?;
 
         let mut clock = self.fd.get_clock().map_err(Error::VmGetClock)?;
@@ -574,13 +896,6 @@ impl<EH: 'static + ExitHandler + Send> KvmVm<EH> {
             .get_irqchip(&mut ioapic)
             .map_err(Error::VmGetIrqChip)?;
 
-        let vcpus_state = self
-            .vcpus
-            .iter_mut()
-            .map(|vcpu| vcpu.save_state())
-            .collect::<vcpu::Result<Vec<VcpuState>>>()
-            .map_err(Error::SaveVcpuState)?;
-
         Ok(VmState {
             pitstate,
             clock,
@@ -618,6 +933,7 @@ mod tests {
     #[derive(Default)]
     struct DummyExitHandler {
         kicked: AtomicBool,
+        reset: AtomicBool,
     }
 
     impl ExitHandler for WrappedExitHandler {
@@ -625,6 +941,11 @@ mod tests {
             self.0.kicked.store(true, Ordering::Release);
             Ok(())
         }
+
+        fn reset(&self) -> io::Result<()> {
+            self.0.reset.store(true, Ordering::Release);
+            Ok(())
+        }
     }
 
     fn default_memory() -> GuestMemoryMmap {
@@ -710,6 +1031,8 @@ mod tests {
             fd: Arc::new(kvm.create_vm().unwrap()),
             exit_handler: WrappedExitHandler::default(),
             vcpu_run_state: Arc::new(VcpuRunState::default()),
+            memory_regions: Vec::new(),
+            boot_state: None,
             #[cfg(target_arch = "aarch64")]
             gic: None,
         };
@@ -720,6 +1043,42 @@ mod tests {
         assert!(matches!(res, Err(Error::SetupInterruptController(_))));
     }
 
+    #[test]
+    fn test_dirty_log() {
+        let num_vcpus = 1;
+        let mut guest_memory = default_memory();
+        let mut vm = create_vm_and_vcpus(num_vcpus, &mut guest_memory);
+
+        assert!(vm.dirty_log().unwrap().is_empty());
+        vm.set_slot_dirty_logging(0, true).unwrap();
+        assert!(vm.dirty_logging());
+        assert!(vm.slot_dirty_logging(0));
+        assert!(!vm.slot_dirty_logging(vm.memory_slots()));
+        let log = vm.dirty_log().unwrap();
+        assert_eq!(log.len(), guest_memory.num_regions());
+        assert!(log.iter().all(|slot| slot.dirty_pages().next().is_none()));
+        vm.set_dirty_logging(false).unwrap();
+        assert!(!vm.dirty_logging());
+        assert!(matches!(
+            vm.set_slot_dirty_logging(vm.memory_slots(), true),
+            Err(Error::InvalidMemorySlot(_))
+        ));
+
+        let mut log = DirtyLog {
+            slot: 0,
+            guest_phys_addr: 0x10_0000,
+            memory_size: 128 * 4096,
+            bitmap: vec![0b101, 0],
+        };
+        assert!(log.mark(0x10_0000 + 127 * 4096 + 42));
+        assert!(!log.mark(0x10_0000 + 128 * 4096));
+        assert!(!log.mark(0xfffff));
+        assert_eq!(
+            log.dirty_pages().collect::<Vec<_>>(),
+            vec![0x10_0000, 0x10_2000, 0x10_0000 + 127 * 4096]
+        );
+    }
+
     #[test]
     fn test_shutdown() {
         let num_vcpus = 4;
@@ -744,6 +1103,134 @@ mod tests {
         );
     }
 
+    // Runs the vcpus of `vm` in an endless loop.
+    fn run_spinning_vm(vm: &mut KvmVm<WrappedExitHandler>, guest_memory: &GuestMemoryMmap) {
+        let load_addr = GuestAddress(0x100_0000);
+        #[cfg(target_arch = "x86_64")]
+        let asm_code = &[0xeb, 0xfe /* jmp $ */];
+        #[cfg(target_arch = "aarch64")]
+        let asm_code = &[0x00, 0x00, 0x00, 0x14 /* b . */];
+        guest_memory.write_slice(asm_code, load_addr).unwrap();
+        vm.run(Some(load_addr)).unwrap();
+    }
+
+    #[test]
+    fn test_pause_resume() {
+        let num_vcpus = 4;
+        let mut guest_memory = default_memory();
+
+        let mut vm = create_vm_and_vcpus(num_vcpus, &mut guest_memory);
+
+        // Pausing a VM that is not running yet only changes its run state.
+        vm.pause().unwrap();
+        assert_eq!(vm.run_state(), VmRunState::Suspending);
+        vm.resume().unwrap();
+        assert_eq!(vm.run_state(), VmRunState::Running);
+
+        run_spinning_vm(&mut vm, &guest_memory);
+
+        vm.pause().unwrap();
+        assert_eq!(vm.run_state(), VmRunState::Suspending);
+        assert!(vm
+            .vcpu_run_state
+            .wait_parked(num_vcpus as usize, Duration::from_secs(0)));
+        // Pausing a paused VM is a no-op.
+        vm.pause().unwrap();
+
+        vm.resume().unwrap();
+        assert_eq!(vm.run_state(), VmRunState::Running);
+        // Resuming a running VM is a no-op.
+        vm.resume().unwrap();
+
+        // The parked vcpus exit when shutting down a paused VM.
+        vm.pause().unwrap();
+        vm.shutdown();
+        assert!(vm.exit_handler.0.kicked.load(Ordering::Relaxed));
+        assert!(matches!(vm.pause(), Err(Error::VmExiting)));
+        assert!(matches!(vm.resume(), Err(Error::VmExiting)));
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_reset() {
+        let num_vcpus = 2;
+        let mut guest_memory = default_memory();
+
+        let mut vm = create_vm_and_vcpus(num_vcpus, &mut guest_memory);
+        // An invalid opcode, with no IDT to handle it: the boot vcpu triple faults, which
+        // resets the machine.
+        let load_addr = GuestAddress(0x100_0000);
+        guest_memory
+            .write_slice(&[0x0f, 0x0b /* ud2 */], load_addr)
+            .unwrap();
+        vm.run(Some(load_addr)).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !vm.exit_handler.0.reset.load(Ordering::Acquire) {
+            assert!(Instant::now() < deadline);
+            sleep(Duration::from_millis(10));
+        }
+        assert!(!vm.exit_handler.0.kicked.load(Ordering::Acquire));
+
+        vm.reset(&guest_memory).unwrap();
+        assert!(!vm.vcpus_started());
+        assert_eq!(vm.vcpus.len(), num_vcpus as usize);
+        assert_eq!(vm.run_state(), VmRunState::Running);
+        // The vcpus are back to their state at creation, the application processor waiting
+        // to be started by the boot one.
+        let boot_state = vm.boot_state.clone().unwrap();
+        for (vcpu, state) in vm.vcpus.iter().zip(boot_state.vcpus_state.iter()) {
+            assert_eq!(vcpu.vcpu_fd.get_regs().unwrap(), state.regs);
+            assert_eq!(
+                vcpu.vcpu_fd.get_mp_state().unwrap().mp_state,
+                state.mp_state.mp_state
+            );
+        }
+
+        // The VM can boot again.
+        run_spinning_vm(&mut vm, &guest_memory);
+        assert_eq!(vm.run_state(), VmRunState::Running);
+        vm.shutdown();
+        assert!(vm.exit_handler.0.kicked.load(Ordering::Acquire));
+    }
+
+    #[test]
+    fn test_save_state_running_vm() {
+        let num_vcpus = 2;
+        let mut guest_memory = default_memory();
+
+        let mut vm = create_vm_and_vcpus(num_vcpus, &mut guest_memory);
+        run_spinning_vm(&mut vm, &guest_memory);
+
+        // The vcpus can only provide their state once paused.
+        assert!(matches!(vm.save_state(), Err(Error::VmNotPaused)));
+
+        vm.pause().unwrap();
+        let vm_state = vm.save_state().unwrap();
+        assert_eq!(vm_state.vcpus_state.len(), num_vcpus as usize);
+        for (id, vcpu_state) in vm_state.vcpus_state.iter().enumerate() {
+            assert_eq!(vcpu_state.config.id as usize, id);
+        }
+
+        // The state can be saved to and loaded from a file.
+        let json = serde_json::to_string(&vm_state).unwrap();
+        let restored: VmState = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.config.num_vcpus, num_vcpus);
+        #[cfg(target_arch = "x86_64")]
+        {
+            assert_eq!(restored.vcpus_state[1].regs, vm_state.vcpus_state[1].regs);
+            assert_eq!(restored.ioapic.chip_id, KVM_IRQCHIP_IOAPIC);
+        }
+        #[cfg(target_arch = "aarch64")]
+        assert_eq!(restored.vcpus_state[1].mpidr, vm_state.vcpus_state[1].mpidr);
+
+        // Resuming the VM drops the saved state of the vcpus.
+        vm.resume().unwrap();
+        assert!(matches!(vm.save_state(), Err(Error::VmNotPaused)));
+
+        vm.shutdown();
+    }
+
     #[cfg(target_arch = "x86_64")]
     #[test]
     fn test_vm_save_state() {
@@ -783,7 +1270,10 @@ mod tests {
         let kvm = Kvm::new().unwrap();
         let io_manager = Arc::new(Mutex::new(IoManager::new()));
         let exit_handler = WrappedExitHandler::default();
-        assert!(KvmVm::from_state(&kvm, vm_state, &guest_memory, exit_handler, io_manager).is_ok());
+        let mut vm =
+            KvmVm::from_state(&kvm, vm_state, &guest_memory, exit_handler, io_manager).unwrap();
+        // What the restored VM boots with is not known.
+        assert!(matches!(vm.reset(&guest_memory), Err(Error::NoBootState)));
     }
 
     #[cfg(target_arch = "aarch64")]
@@ -3,9 +3,12 @@
 
 //! Config builder
 use std::convert::TryFrom;
+use std::path::PathBuf;
 
 use super::{
-    BalloonConfig, BlockConfig, ConversionError, KernelConfig, MemoryConfig, NetConfig, VMMConfig, VcpuConfig,
+    ApiConfig, BalloonConfig, BlockConfig, ConversionError, KernelConfig, MemoryConfig,
+    MetricsConfig, MigrationAddress, NetConfig, RebootPolicy, RestoreConfig, SerialConfig,
+    VMMConfig, VcpuConfig,
 };
 
 /// Builder structure for VMMConfig
@@ -53,12 +56,23 @@ impl Builder {
         // Check if there are any errors
         match &self.inner {
             Ok(vc) => {
-                // Empty kernel image path.
-                if vc.kernel_config.path.to_str().unwrap().is_empty() {
+                // Empty kernel image path. Restored and migrated VMs do not boot a kernel, and
+                // the kernel of a pre-boot VM comes later from the control API.
+                if vc.restore_config.is_none()
+                    && vc.incoming_config.is_none()
+                    && !vc.preboot
+                    && vc.kernel_config.path.to_str().unwrap().is_empty()
+                {
                     return Err(ConversionError::ParseKernel(
                         "Kernel Image Path is Empty.".to_string(),
                     ));
                 }
+                // A pre-boot VM only gets its configuration from the control API.
+                if vc.preboot && !vc.api_config.enabled {
+                    return Err(ConversionError::ParseApi(
+                        "Pre-boot mode needs the control API".to_string(),
+                    ));
+                }
             }
             Err(_) => {}
         }
@@ -130,6 +144,8 @@ impl Builder {
 
     /// Configure Builder with Network Configuration for the VMM.
     ///
+    /// Every call adds one more device.
+    ///
     /// # Example
     ///
     /// You can see example of how to use this function in [`Example` section from
@@ -141,7 +157,9 @@ impl Builder {
     {
         match net {
             Some(n) => self.and_then(|mut config| {
-                config.net_config = Some(TryFrom::try_from(n).map_err(Into::into)?);
+                config
+                    .net_config
+                    .push(TryFrom::try_from(n).map_err(Into::into)?);
                 Ok(config)
             }),
             None => self,
@@ -150,6 +168,8 @@ impl Builder {
 
     /// Configure Builder with Block Device Configuration for the VMM.
     ///
+    /// Every call adds one more device.
+    ///
     /// # Example
     ///
     /// You can see example of how to use this function in [`Example` section from
@@ -161,7 +181,9 @@ impl Builder {
     {
         match block {
             Some(b) => self.and_then(|mut config| {
-                config.block_config = Some(TryFrom::try_from(b).map_err(Into::into)?);
+                config
+                    .block_config
+                    .push(TryFrom::try_from(b).map_err(Into::into)?);
                 Ok(config)
             }),
             None => self,
@@ -170,6 +192,8 @@ impl Builder {
 
     /// Configure Builder with Balloon Device Configuration for the VMM.
     ///
+    /// Every call adds one more device.
+    ///
     /// # Example
     ///
     /// You can see example of how to use this function in [`Example` section from
@@ -181,13 +205,160 @@ impl Builder {
     {
         match balloon {
             Some(b) => self.and_then(|mut config| {
-                config.balloon_config = Some(TryFrom::try_from(b).map_err(Into::into)?);
+                config
+                    .balloon_config
+                    .push(TryFrom::try_from(b).map_err(Into::into)?);
+                Ok(config)
+            }),
+            None => self,
+        }
+    }
+
+    /// Configure Builder with the snapshot to restore the VM from.
+    ///
+    /// When set, the kernel configuration is not required.
+    pub fn restore_config<T>(self, restore: Option<T>) -> Self
+    where
+        RestoreConfig: TryFrom<T>,
+        <RestoreConfig as TryFrom<T>>::Error: Into<ConversionError>,
+    {
+        match restore {
+            Some(r) => self.and_then(|mut config| {
+                config.restore_config = Some(TryFrom::try_from(r).map_err(Into::into)?);
+                Ok(config)
+            }),
+            None => self,
+        }
+    }
+
+    /// Configure Builder with the address to receive a migrated VM on.
+    ///
+    /// When set, the kernel configuration is not required.
+    pub fn incoming_config<T>(self, incoming: Option<T>) -> Self
+    where
+        MigrationAddress: TryFrom<T>,
+        <MigrationAddress as TryFrom<T>>::Error: Into<ConversionError>,
+    {
+        match incoming {
+            Some(i) => self.and_then(|mut config| {
+                config.incoming_config = Some(TryFrom::try_from(i).map_err(Into::into)?);
+                Ok(config)
+            }),
+            None => self,
+        }
+    }
+
+    /// Configure Builder with the control API configuration.
+    pub fn api_config<T>(self, api: Option<T>) -> Self
+    where
+        ApiConfig: TryFrom<T>,
+        <ApiConfig as TryFrom<T>>::Error: Into<ConversionError>,
+    {
+        match api {
+            Some(a) => self.and_then(|mut config| {
+                config.api_config = TryFrom::try_from(a).map_err(Into::into)?;
+                Ok(config)
+            }),
+            None => self,
+        }
+    }
+
+    /// Configure Builder with the file the metrics are written to.
+    pub fn metrics_config<T>(self, metrics: Option<T>) -> Self
+    where
+        MetricsConfig: TryFrom<T>,
+        <MetricsConfig as TryFrom<T>>::Error: Into<ConversionError>,
+    {
+        match metrics {
+            Some(m) => self.and_then(|mut config| {
+                config.metrics_config = Some(TryFrom::try_from(m).map_err(Into::into)?);
+                Ok(config)
+            }),
+            None => self,
+        }
+    }
+
+    /// Configure Builder with the backend of the serial console.
+    pub fn serial_config<T>(self, serial: Option<T>) -> Self
+    where
+        SerialConfig: TryFrom<T>,
+        <SerialConfig as TryFrom<T>>::Error: Into<ConversionError>,
+    {
+        match serial {
+            Some(s) => self.and_then(|mut config| {
+                config.serial_config = TryFrom::try_from(s).map_err(Into::into)?;
+                Ok(config)
+            }),
+            None => self,
+        }
+    }
+
+    /// Configure Builder with what to do when the guest reboots.
+    pub fn reboot_policy<T>(self, policy: Option<T>) -> Self
+    where
+        RebootPolicy: TryFrom<T>,
+        <RebootPolicy as TryFrom<T>>::Error: Into<ConversionError>,
+    {
+        match policy {
+            Some(p) => self.and_then(|mut config| {
+                config.reboot_policy = TryFrom::try_from(p).map_err(Into::into)?;
                 Ok(config)
             }),
             None => self,
         }
     }
 
+    /// Configure Builder with the file the logs are appended to.
+    pub fn log_path<T: Into<PathBuf>>(self, path: Option<T>) -> Self {
+        match path {
+            Some(p) => self.and_then(|mut config| {
+                config.log_config.path = Some(p.into());
+                Ok(config)
+            }),
+            None => self,
+        }
+    }
+
+    /// Configure Builder with the levels of the logs, as `<level>[,<module>=<level>...]`.
+    pub fn log_level(self, level: Option<&str>) -> Self {
+        match level {
+            Some(l) => self.and_then(|mut config| {
+                config.log_config.filter = l.parse().map_err(ConversionError::new_log)?;
+                Ok(config)
+            }),
+            None => self,
+        }
+    }
+
+    /// Configure Builder to run the VMM without the control API.
+    pub fn disable_api(self, disable: bool) -> Self {
+        self.and_then(|mut config| {
+            if disable {
+                config.api_config.enabled = false;
+            }
+            Ok(config)
+        })
+    }
+
+    /// Configure Builder to give the guest a device it ends the VMM with, choosing the exit
+    /// code.
+    pub fn debug_exit(self, enable: bool) -> Self {
+        self.and_then(|mut config| {
+            config.debug_exit = enable;
+            Ok(config)
+        })
+    }
+
+    /// Configure Builder to start the VMM with only the control API.
+    ///
+    /// When set, the kernel configuration is not required.
+    pub fn preboot(self, preboot: bool) -> Self {
+        self.and_then(|mut config| {
+            config.preboot = preboot;
+            Ok(config)
+        })
+    }
+
     fn and_then<F>(self, func: F) -> Self
     where
         F: FnOnce(VMMConfig) -> Result<VMMConfig, ConversionError>,
@@ -204,7 +375,7 @@ mod tests {
     use std::path::PathBuf;
 
     use super::*;
-    use crate::DEFAULT_KERNEL_LOAD_ADDR;
+    use crate::{LogConfig, DEFAULT_KERNEL_LOAD_ADDR};
 
     #[test]
     fn test_builder_default_err() {
@@ -290,7 +461,7 @@ mod tests {
             .kernel_config(Some("path=bzImage"))
             .build();
         assert!(vmm_config.is_ok());
-        assert!(vmm_config.unwrap().net_config.is_none());
+        assert!(vmm_config.unwrap().net_config.is_empty());
     }
 
     #[test]
@@ -302,9 +473,9 @@ mod tests {
         assert!(vmm_config.is_ok());
         assert_eq!(
             vmm_config.unwrap().net_config,
-            Some(NetConfig {
+            vec![NetConfig {
                 tap_name: "tap0".to_string()
-            })
+            }]
         );
     }
 
@@ -315,7 +486,7 @@ mod tests {
             .kernel_config(Some("path=bzImage"))
             .build();
         assert!(vmm_config.is_ok());
-        assert!(vmm_config.unwrap().block_config.is_none());
+        assert!(vmm_config.unwrap().block_config.is_empty());
     }
 
     #[test]
@@ -327,12 +498,70 @@ mod tests {
         assert!(vmm_config.is_ok());
         assert_eq!(
             vmm_config.unwrap().block_config,
-            Some(BlockConfig {
+            vec![BlockConfig {
                 path: PathBuf::from("/dev/loop0")
-            })
+            }]
         );
     }
 
+    #[test]
+    fn test_builder_balloon_config() {
+        let vmm_config = Builder::default()
+            .balloon_config(Some("amount_mib=512,stats_polling_interval_s=5"))
+            .kernel_config(Some("path=bzImage"))
+            .build();
+        assert_eq!(
+            vmm_config.unwrap().balloon_config,
+            vec![BalloonConfig {
+                amount_mib: 512,
+                deflate_on_oom: false,
+                stats_polling_interval_s: 5,
+            }]
+        );
+
+        let vmm_config = Builder::default()
+            .balloon_config(Some("amount_mib=foo"))
+            .kernel_config(Some("path=bzImage"))
+            .build();
+        assert!(matches!(
+            vmm_config.unwrap_err(),
+            ConversionError::ParseBalloon(_)
+        ));
+    }
+
+    #[test]
+    fn test_builder_multiple_devices() {
+        let vmm_config = Builder::default()
+            .kernel_config(Some("path=bzImage"))
+            .block_config(Some("path=/dev/loop0"))
+            .block_config(Some("path=/dev/loop1"))
+            .net_config(Some("tap=tap0"))
+            .net_config(Some("tap=tap1"))
+            .build()
+            .unwrap();
+        assert_eq!(
+            vmm_config.block_config,
+            vec![
+                BlockConfig {
+                    path: PathBuf::from("/dev/loop0")
+                },
+                BlockConfig {
+                    path: PathBuf::from("/dev/loop1")
+                }
+            ]
+        );
+        assert_eq!(vmm_config.net_config.len(), 2);
+        assert_eq!(vmm_config.net_config[1].tap_name, "tap1");
+
+        // A bad device config fails the build, even if the others are valid.
+        let vmm_config = Builder::default()
+            .kernel_config(Some("path=bzImage"))
+            .block_config(Some("path=/dev/loop0"))
+            .block_config(Some("foo=bar"))
+            .build();
+        assert!(vmm_config.is_err());
+    }
+
     #[test]
     fn test_builder_vmm_config_success() {
         let vmm_config = Builder::default()
@@ -353,13 +582,169 @@ mod tests {
                     load_addr: DEFAULT_KERNEL_LOAD_ADDR,
                     path: PathBuf::from("bzImage")
                 },
-                net_config: Some(NetConfig {
+                net_config: vec![NetConfig {
                     tap_name: "tap0".to_string()
-                }),
-                block_config: Some(BlockConfig {
+                }],
+                block_config: vec![BlockConfig {
                     path: PathBuf::from("/dev/loop0")
-                })
+                }],
+                balloon_config: vec![],
+                restore_config: None,
+                incoming_config: None,
+                api_config: ApiConfig::default(),
+                metrics_config: None,
+                log_config: LogConfig::default(),
+                serial_config: SerialConfig::Stdio,
+                reboot_policy: RebootPolicy::Exit,
+                debug_exit: false,
+                preboot: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_builder_restore_config_success() {
+        // The kernel is not needed when restoring a snapshot.
+        let vmm_config = Builder::default()
+            .restore_config(Some("state_path=/foo/state,mem_path=/foo/mem"))
+            .build();
+        assert!(vmm_config.is_ok());
+        assert_eq!(
+            vmm_config.unwrap().restore_config,
+            Some(RestoreConfig {
+                state_path: PathBuf::from("/foo/state"),
+                mem_path: PathBuf::from("/foo/mem"),
+                lazy: false,
+            })
+        );
+
+        let vmm_config = Builder::default()
+            .restore_config(Some("state_path=/foo/state"))
+            .build();
+        assert!(vmm_config.is_err());
+    }
+
+    #[test]
+    fn test_builder_api_config() {
+        let vmm_config = Builder::default()
+            .kernel_config(Some("path=bzImage"))
+            .api_config(Some("/tmp/foo.sock"))
+            .build();
+        assert_eq!(
+            vmm_config.unwrap().api_config,
+            ApiConfig {
+                socket_path: PathBuf::from("/tmp/foo.sock"),
+                enabled: true,
             }
         );
+
+        let vmm_config = Builder::default()
+            .kernel_config(Some("path=bzImage"))
+            .disable_api(true)
+            .build();
+        assert!(!vmm_config.unwrap().api_config.enabled);
+
+        let vmm_config = Builder::default()
+            .kernel_config(Some("path=bzImage"))
+            .api_config(Some(""))
+            .build();
+        assert!(vmm_config.is_err());
+
+        // Nothing could configure a pre-boot VM without the API.
+        let vmm_config = Builder::default().preboot(true).disable_api(true).build();
+        assert!(vmm_config.is_err());
+    }
+
+    #[test]
+    fn test_builder_serial_config() {
+        // The serial console can be configured for a restored VM as well.
+        let vmm_config = Builder::default()
+            .restore_config(Some("state_path=/foo/state,mem_path=/foo/mem"))
+            .serial_config(Some("unix:/tmp/console.sock"))
+            .build();
+        assert_eq!(
+            vmm_config.unwrap().serial_config,
+            SerialConfig::Socket(PathBuf::from("/tmp/console.sock"))
+        );
+
+        let vmm_config = Builder::default()
+            .kernel_config(Some("path=bzImage"))
+            .serial_config(Some("tty"))
+            .build();
+        assert!(vmm_config.is_err());
+    }
+
+    #[test]
+    fn test_builder_reboot_policy() {
+        let vmm_config = Builder::default()
+            .kernel_config(Some("path=bzImage"))
+            .reboot_policy(Some("restart"))
+            .build();
+        assert_eq!(vmm_config.unwrap().reboot_policy, RebootPolicy::Restart);
+
+        let vmm_config = Builder::default()
+            .kernel_config(Some("path=bzImage"))
+            .reboot_policy(Some("reset"))
+            .build();
+        assert!(vmm_config.is_err());
+    }
+
+    #[test]
+    fn test_builder_debug_exit() {
+        let vmm_config = Builder::default()
+            .kernel_config(Some("path=bzImage"))
+            .debug_exit(true)
+            .build();
+        assert!(vmm_config.unwrap().debug_exit);
+    }
+
+    #[test]
+    fn test_builder_log_config() {
+        let vmm_config = Builder::default()
+            .kernel_config(Some("path=bzImage"))
+            .log_path(Some("/tmp/vmm.log"))
+            .log_level(Some("warn,devices::virtio=debug"))
+            .build()
+            .unwrap();
+        assert_eq!(
+            vmm_config.log_config,
+            LogConfig {
+                path: Some(PathBuf::from("/tmp/vmm.log")),
+                filter: "warn,devices::virtio=debug".parse().unwrap(),
+            }
+        );
+
+        let vmm_config = Builder::default()
+            .kernel_config(Some("path=bzImage"))
+            .log_level(Some("verbose"))
+            .build();
+        assert!(vmm_config.is_err());
+    }
+
+    #[test]
+    fn test_builder_preboot() {
+        // The kernel is not needed before the VM is configured over the control API.
+        let vmm_config = Builder::default().preboot(true).build();
+        assert!(vmm_config.unwrap().preboot);
+
+        let vmm_config = Builder::default().preboot(false).build();
+        assert!(vmm_config.is_err());
+    }
+
+    #[test]
+    fn test_builder_incoming_config_success() {
+        // The kernel is not needed when receiving a migrated VM.
+        let vmm_config = Builder::default()
+            .incoming_config(Some("unix:/tmp/migrate.sock"))
+            .build();
+        assert_eq!(
+            vmm_config.unwrap().incoming_config,
+            Some(MigrationAddress::Unix(PathBuf::from("/tmp/migrate.sock")))
+        );
+
+        let vmm_config = Builder::default()
+            .incoming_config(Some("tcp:192.168.0.1:4444"))
+            .build();
+        assert!(vmm_config.is_err());
     }
 }
@@ -3,16 +3,21 @@
 
 use std::convert::TryFrom;
 use std::fmt;
+use std::net::SocketAddr;
 use std::num;
 use std::path::PathBuf;
 use std::result;
 
 use linux_loader::cmdline::Cmdline;
+use utils::logger::Filter;
 
 use arg_parser::CfgArgParser;
 use builder::Builder;
 
-use super::{DEFAULT_KERNEL_CMDLINE, DEFAULT_KERNEL_LOAD_ADDR};
+use super::{
+    DEFAULT_API_SOCKET_PATH, DEFAULT_BALLOON_STATS_POLLING_INTERVAL_S, DEFAULT_KERNEL_CMDLINE,
+    DEFAULT_KERNEL_LOAD_ADDR, DEFAULT_METRICS_FLUSH_INTERVAL_S,
+};
 
 mod arg_parser;
 mod builder;
@@ -32,6 +37,22 @@ pub enum ConversionError {
     ParseNet(String),
     /// Failed to parse the string representation for the block.
     ParseBlock(String),
+    /// Failed to parse the string representation for the balloon.
+    ParseBalloon(String),
+    /// Failed to parse the string representation for the snapshot to restore.
+    ParseRestore(String),
+    /// Failed to parse the string representation for the migration address.
+    ParseMigration(String),
+    /// Failed to parse the control API configuration.
+    ParseApi(String),
+    /// Failed to parse the metrics configuration.
+    ParseMetrics(String),
+    /// Failed to parse the logger configuration.
+    ParseLog(String),
+    /// Failed to parse the serial console configuration.
+    ParseSerial(String),
+    /// Failed to parse the reboot policy.
+    ParseReboot(String),
 }
 
 impl ConversionError {
@@ -50,6 +71,30 @@ impl ConversionError {
     fn new_net<T: fmt::Display>(err: T) -> Self {
         Self::ParseNet(err.to_string())
     }
+    fn new_balloon<T: fmt::Display>(err: T) -> Self {
+        Self::ParseBalloon(err.to_string())
+    }
+    fn new_restore<T: fmt::Display>(err: T) -> Self {
+        Self::ParseRestore(err.to_string())
+    }
+    fn new_migration<T: fmt::Display>(err: T) -> Self {
+        Self::ParseMigration(err.to_string())
+    }
+    fn new_api<T: fmt::Display>(err: T) -> Self {
+        Self::ParseApi(err.to_string())
+    }
+    fn new_metrics<T: fmt::Display>(err: T) -> Self {
+        Self::ParseMetrics(err.to_string())
+    }
+    fn new_log<T: fmt::Display>(err: T) -> Self {
+        Self::ParseLog(err.to_string())
+    }
+    fn new_serial<T: fmt::Display>(err: T) -> Self {
+        Self::ParseSerial(err.to_string())
+    }
+    fn new_reboot<T: fmt::Display>(err: T) -> Self {
+        Self::ParseReboot(err.to_string())
+    }
 }
 
 impl VMMConfig {
@@ -68,6 +113,14 @@ impl fmt::Display for ConversionError {
             ParseVcpus(ref s) => write!(f, "Invalid input for vCPUs: {}", s),
             ParseNet(ref s) => write!(f, "Invalid input for network: {}", s),
             ParseBlock(ref s) => write!(f, "Invalid input for block: {}", s),
+            ParseBalloon(ref s) => write!(f, "Invalid input for balloon: {}", s),
+            ParseRestore(ref s) => write!(f, "Invalid input for restore: {}", s),
+            ParseMigration(ref s) => write!(f, "Invalid input for migration: {}", s),
+            ParseApi(ref s) => write!(f, "Invalid input for API: {}", s),
+            ParseMetrics(ref s) => write!(f, "Invalid input for metrics: {}", s),
+            ParseLog(ref s) => write!(f, "Invalid input for logger: {}", s),
+            ParseSerial(ref s) => write!(f, "Invalid input for serial console: {}", s),
+            ParseReboot(ref s) => write!(f, "Invalid input for reboot policy: {}", s),
         }
     }
 }
@@ -155,6 +208,17 @@ impl KernelConfig {
 
         cmdline
     }
+
+    /// Build a kernel command line out of `cmdline_str`.
+    ///
+    /// Fails if the string does not fit in the command line capacity.
+    pub fn parse_cmdline(cmdline_str: &str) -> result::Result<Cmdline, ConversionError> {
+        let mut cmdline = Cmdline::new(KERNEL_CMDLINE_CAPACITY);
+        cmdline
+            .insert_str(cmdline_str)
+            .map_err(|_| ConversionError::new_kernel("Kernel cmdline capacity error"))?;
+        Ok(cmdline)
+    }
 }
 
 impl Default for KernelConfig {
@@ -181,10 +245,7 @@ impl TryFrom<&str> for KernelConfig {
             .map_err(ConversionError::new_kernel)?
             .unwrap_or_else(|| DEFAULT_KERNEL_CMDLINE.to_string());
 
-        let mut cmdline = Cmdline::new(KERNEL_CMDLINE_CAPACITY);
-        cmdline
-            .insert_str(cmdline_str)
-            .map_err(|_| ConversionError::new_kernel("Kernel cmdline capacity error"))?;
+        let cmdline = KernelConfig::parse_cmdline(&cmdline_str)?;
 
         let path = arg_parser
             .value_of("path")
@@ -261,16 +322,323 @@ impl TryFrom<&str> for BlockConfig {
 /// Balloon device configuration
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct BalloonConfig {
+    /// Memory the guest is asked to give back to the host once it boots, in MiB.
+    pub amount_mib: u32,
+    /// Let the guest take memory back from the balloon when it runs out of it.
+    pub deflate_on_oom: bool,
+    /// Seconds between two requests for the memory statistics of the guest. The guest only
+    /// reports them once when 0.
+    pub stats_polling_interval_s: u64,
+}
+
+impl BalloonConfig {
+    /// Size of the balloon in 4 KiB pages, as the guest gets it.
+    pub fn num_pages(&self) -> u32 {
+        self.amount_mib * (1 << 8)
+    }
+
+    /// Fails if `amount_mib` does not fit in the 32 bit page count of the device.
+    pub fn check_amount(amount_mib: u32) -> Result<(), ConversionError> {
+        amount_mib
+            .checked_mul(1 << 8)
+            .map(|_| ())
+            .ok_or_else(|| ConversionError::new_balloon("Param 'amount_mib' is too large"))
+    }
+}
+
+impl Default for BalloonConfig {
+    fn default() -> Self {
+        BalloonConfig {
+            amount_mib: 0,
+            deflate_on_oom: false,
+            stats_polling_interval_s: DEFAULT_BALLOON_STATS_POLLING_INTERVAL_S,
+        }
+    }
 }
 
 impl TryFrom<&str> for BalloonConfig {
     type Error = ConversionError;
 
     fn try_from(balloon_cfg_str: &str) -> Result<Self, Self::Error> {
-        Ok(BalloonConfig {})
+        // Supported options: `amount_mib=u32,deflate_on_oom=bool,stats_polling_interval_s=u64`
+        let mut arg_parser = CfgArgParser::new(balloon_cfg_str);
+
+        let amount_mib = arg_parser
+            .value_of("amount_mib")
+            .map_err(ConversionError::new_balloon)?
+            .unwrap_or(0);
+        BalloonConfig::check_amount(amount_mib)?;
+        let deflate_on_oom = arg_parser
+            .value_of("deflate_on_oom")
+            .map_err(ConversionError::new_balloon)?
+            .unwrap_or(false);
+        let stats_polling_interval_s = arg_parser
+            .value_of("stats_polling_interval_s")
+            .map_err(ConversionError::new_balloon)?
+            .unwrap_or(DEFAULT_BALLOON_STATS_POLLING_INTERVAL_S);
+
+        arg_parser
+            .all_consumed()
+            .map_err(ConversionError::new_balloon)?;
+        Ok(BalloonConfig {
+            amount_mib,
+            deflate_on_oom,
+            stats_polling_interval_s,
+        })
+    }
+}
+
+/// Snapshot to restore the VM from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RestoreConfig {
+    /// Path to the VM and device state file.
+    pub state_path: PathBuf,
+    /// Path to the guest memory file.
+    pub mem_path: PathBuf,
+    /// Load guest memory on demand instead of reading the whole file before resuming.
+    pub lazy: bool,
+}
+
+impl TryFrom<&str> for RestoreConfig {
+    type Error = ConversionError;
+
+    fn try_from(restore_cfg_str: &str) -> Result<Self, Self::Error> {
+        // Supported options: `state_path=PathBuf,mem_path=PathBuf,lazy=bool`
+        let mut arg_parser = CfgArgParser::new(restore_cfg_str);
+
+        let state_path = arg_parser
+            .value_of("state_path")
+            .map_err(ConversionError::new_restore)?
+            .ok_or_else(|| ConversionError::new_restore("Missing required argument: state_path"))?;
+        let mem_path = arg_parser
+            .value_of("mem_path")
+            .map_err(ConversionError::new_restore)?
+            .ok_or_else(|| ConversionError::new_restore("Missing required argument: mem_path"))?;
+        let lazy = arg_parser
+            .value_of("lazy")
+            .map_err(ConversionError::new_restore)?
+            .unwrap_or(false);
+
+        arg_parser
+            .all_consumed()
+            .map_err(ConversionError::new_restore)?;
+        Ok(RestoreConfig {
+            state_path,
+            mem_path,
+            lazy,
+        })
+    }
+}
+
+/// Address of the socket a VM is migrated over.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MigrationAddress {
+    /// Unix domain socket at the given path.
+    Unix(PathBuf),
+    /// TCP socket on the loopback interface.
+    Tcp(SocketAddr),
+}
+
+impl TryFrom<&str> for MigrationAddress {
+    type Error = ConversionError;
+
+    fn try_from(addr_str: &str) -> Result<Self, Self::Error> {
+        // Supported formats: `unix:<path>` and `tcp:<ip>:<port>`.
+        if let Some(path) = addr_str.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(ConversionError::new_migration("Empty socket path"));
+            }
+            return Ok(MigrationAddress::Unix(PathBuf::from(path)));
+        }
+        if let Some(addr) = addr_str.strip_prefix("tcp:") {
+            let addr = addr
+                .parse::<SocketAddr>()
+                .map_err(ConversionError::new_migration)?;
+            // Guest memory is sent in the clear, so it must not leave the host.
+            if !addr.ip().is_loopback() {
+                return Err(ConversionError::new_migration(
+                    "Only loopback TCP addresses are supported",
+                ));
+            }
+            return Ok(MigrationAddress::Tcp(addr));
+        }
+        Err(ConversionError::new_migration(format!(
+            "Expected unix:<path> or tcp:<ip>:<port>, got {}",
+            addr_str
+        )))
+    }
+}
+
+impl fmt::Display for MigrationAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+            MigrationAddress::Tcp(addr) => write!(f, "tcp:{}", addr),
+        }
+    }
+}
+
+/// Backend of the serial console.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SerialConfig {
+    /// The terminal of the VMM: output to stdout, input from stdin.
+    Stdio,
+    /// Output appended to the file at the given path, no input.
+    File(PathBuf),
+    /// Unix socket listening at the given path, which one client at a time can attach to.
+    Socket(PathBuf),
+    /// Newly allocated PTY.
+    Pty,
+}
+
+impl Default for SerialConfig {
+    fn default() -> Self {
+        SerialConfig::Stdio
+    }
+}
+
+impl TryFrom<&str> for SerialConfig {
+    type Error = ConversionError;
+
+    fn try_from(serial_str: &str) -> Result<Self, Self::Error> {
+        // Supported formats: `stdio`, `file:<path>`, `unix:<path>` and `pty`.
+        let with_path = |path: &str, config: fn(PathBuf) -> SerialConfig| {
+            if path.is_empty() {
+                return Err(ConversionError::new_serial("Empty path"));
+            }
+            Ok(config(PathBuf::from(path)))
+        };
+        match serial_str {
+            "stdio" => Ok(SerialConfig::Stdio),
+            "pty" => Ok(SerialConfig::Pty),
+            _ => {
+                if let Some(path) = serial_str.strip_prefix("file:") {
+                    return with_path(path, SerialConfig::File);
+                }
+                if let Some(path) = serial_str.strip_prefix("unix:") {
+                    return with_path(path, SerialConfig::Socket);
+                }
+                Err(ConversionError::new_serial(format!(
+                    "Expected stdio, file:<path>, unix:<path> or pty, got {}",
+                    serial_str
+                )))
+            }
+        }
+    }
+}
+
+/// What the VMM does when the guest reboots.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RebootPolicy {
+    /// Exit, as when the guest shuts down.
+    Exit,
+    /// Boot the kernel again, in the same process and with the same devices.
+    Restart,
+}
+
+impl Default for RebootPolicy {
+    fn default() -> Self {
+        RebootPolicy::Exit
+    }
+}
+
+impl TryFrom<&str> for RebootPolicy {
+    type Error = ConversionError;
+
+    fn try_from(policy_str: &str) -> Result<Self, Self::Error> {
+        match policy_str {
+            "exit" => Ok(RebootPolicy::Exit),
+            "restart" => Ok(RebootPolicy::Restart),
+            _ => Err(ConversionError::new_reboot(format!(
+                "Expected exit or restart, got {}",
+                policy_str
+            ))),
+        }
+    }
+}
+
+/// Control API configuration.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ApiConfig {
+    /// Path of the Unix socket the control server listens on.
+    pub socket_path: PathBuf,
+    /// Whether the control server runs at all.
+    pub enabled: bool,
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        ApiConfig {
+            socket_path: PathBuf::from(DEFAULT_API_SOCKET_PATH),
+            enabled: true,
+        }
+    }
+}
+
+impl TryFrom<&str> for ApiConfig {
+    type Error = ConversionError;
+
+    fn try_from(socket_path: &str) -> Result<Self, Self::Error> {
+        // The whole string is the socket path.
+        if socket_path.is_empty() {
+            return Err(ConversionError::new_api("Empty socket path"));
+        }
+        Ok(ApiConfig {
+            socket_path: PathBuf::from(socket_path),
+            enabled: true,
+        })
+    }
+}
+
+/// Metrics configuration.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MetricsConfig {
+    /// Path of the file the metrics are appended to, one JSON object per line.
+    pub path: PathBuf,
+    /// Seconds between two writes of the metrics.
+    pub flush_interval_s: u64,
+}
+
+impl TryFrom<&str> for MetricsConfig {
+    type Error = ConversionError;
+
+    fn try_from(metrics_cfg_str: &str) -> Result<Self, Self::Error> {
+        // Supported options: `path=PathBuf,flush_interval_s=u64`
+        let mut arg_parser = CfgArgParser::new(metrics_cfg_str);
+
+        let path = arg_parser
+            .value_of("path")
+            .map_err(ConversionError::new_metrics)?
+            .ok_or_else(|| ConversionError::new_metrics("Missing required argument: path"))?;
+        let flush_interval_s = arg_parser
+            .value_of("flush_interval_s")
+            .map_err(ConversionError::new_metrics)?
+            .unwrap_or(DEFAULT_METRICS_FLUSH_INTERVAL_S);
+        if flush_interval_s == 0 {
+            return Err(ConversionError::new_metrics(
+                "Param 'flush_interval_s' must be greater than 0",
+            ));
+        }
+
+        arg_parser
+            .all_consumed()
+            .map_err(ConversionError::new_metrics)?;
+        Ok(MetricsConfig {
+            path,
+            flush_interval_s,
+        })
     }
 }
 
+/// Logger configuration.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LogConfig {
+    /// Path of the file the logs are appended to. They are written to stderr when missing.
+    pub path: Option<PathBuf>,
+    /// Levels of the logs, by module. Defaults to `info` for all of them.
+    pub filter: Filter,
+}
+
 /// VMM configuration.
 #[derive(Clone, Debug, Default, PartialEq)]
 pub struct VMMConfig {
@@ -280,12 +648,33 @@ pub struct VMMConfig {
     pub vcpu_config: VcpuConfig,
     /// Guest kernel configuration.
     pub kernel_config: KernelConfig,
-    /// Network device configuration.
-    pub net_config: Option<NetConfig>,
-    /// Block device configuration.
-    pub block_config: Option<BlockConfig>,
-    /// Balloon device configuration.
-    pub balloon_config: Option<BalloonConfig>,
+    /// Network device configurations.
+    pub net_config: Vec<NetConfig>,
+    /// Block device configurations. The first block device is the root device.
+    pub block_config: Vec<BlockConfig>,
+    /// Balloon device configurations.
+    pub balloon_config: Vec<BalloonConfig>,
+    /// Snapshot to restore the VM from. When set, the VM is not built from the other
+    /// configurations, but from the saved state.
+    pub restore_config: Option<RestoreConfig>,
+    /// Address to receive a migrated VM on. When set, the VM is not built from the other
+    /// configurations, but from the state sent by the source VMM.
+    pub incoming_config: Option<MigrationAddress>,
+    /// Control API configuration.
+    pub api_config: ApiConfig,
+    /// Where to write the metrics. They are only available over the control API when missing.
+    pub metrics_config: Option<MetricsConfig>,
+    /// Logger configuration.
+    pub log_config: LogConfig,
+    /// Serial console backend.
+    pub serial_config: SerialConfig,
+    /// What to do when the guest reboots.
+    pub reboot_policy: RebootPolicy,
+    /// Give the guest a device it ends the VMM with, choosing the exit code.
+    pub debug_exit: bool,
+    /// Start with only the control API, which then provides the VM configuration and starts
+    /// the VM.
+    pub preboot: bool,
 }
 
 #[cfg(test)]
@@ -402,6 +791,153 @@ mod tests {
         assert!(BlockConfig::try_from(block_str).is_err());
     }
 
+    #[test]
+    fn test_balloon_config() {
+        assert_eq!(
+            BalloonConfig::try_from("").unwrap(),
+            BalloonConfig::default()
+        );
+        let balloon_cfg = BalloonConfig::try_from(
+            "amount_mib=1024,deflate_on_oom=true,stats_polling_interval_s=0",
+        )
+        .unwrap();
+        assert_eq!(
+            balloon_cfg,
+            BalloonConfig {
+                amount_mib: 1024,
+                deflate_on_oom: true,
+                stats_polling_interval_s: 0,
+            }
+        );
+        assert_eq!(balloon_cfg.num_pages(), 262144);
+
+        // Test case: the page count does not fit in 32 bits.
+        assert_eq!(
+            BalloonConfig::try_from("amount_mib=16777216").unwrap_err(),
+            ConversionError::ParseBalloon("Param 'amount_mib' is too large".to_string())
+        );
+
+        // Test case: invalid inputs.
+        assert!(BalloonConfig::try_from("amount_mib=-1").is_err());
+        assert!(BalloonConfig::try_from("deflate_on_oom=yes").is_err());
+        // The former `--balloon <anything>` form.
+        assert!(BalloonConfig::try_from("0").is_err());
+    }
+
+    #[test]
+    fn test_restore_config() {
+        let restore_str = "state_path=/foo/state,mem_path=/foo/mem";
+        let restore_cfg = RestoreConfig::try_from(restore_str).unwrap();
+        let expected_cfg = RestoreConfig {
+            state_path: PathBuf::from("/foo/state"),
+            mem_path: PathBuf::from("/foo/mem"),
+            lazy: false,
+        };
+        assert_eq!(restore_cfg, expected_cfg);
+
+        let restore_str = "state_path=/foo/state,mem_path=/foo/mem,lazy=true";
+        assert!(RestoreConfig::try_from(restore_str).unwrap().lazy);
+        let restore_str = "state_path=/foo/state,mem_path=/foo/mem,lazy=foo";
+        assert!(RestoreConfig::try_from(restore_str).is_err());
+
+        // Test case: empty string error.
+        assert!(RestoreConfig::try_from("").is_err());
+
+        // Test case: missing memory file.
+        assert_eq!(
+            RestoreConfig::try_from("state_path=/foo/state").unwrap_err(),
+            ConversionError::ParseRestore("Missing required argument: mem_path".to_string())
+        );
+
+        // Test case: unused parameters
+        let restore_str = "state_path=/foo/state,mem_path=/foo/mem,blah=blah";
+        assert!(RestoreConfig::try_from(restore_str).is_err());
+    }
+
+    #[test]
+    fn test_migration_address() {
+        assert_eq!(
+            MigrationAddress::try_from("unix:/tmp/migrate.sock").unwrap(),
+            MigrationAddress::Unix(PathBuf::from("/tmp/migrate.sock"))
+        );
+        assert_eq!(
+            MigrationAddress::try_from("tcp:127.0.0.1:4444").unwrap(),
+            MigrationAddress::Tcp("127.0.0.1:4444".parse().unwrap())
+        );
+        assert_eq!(
+            MigrationAddress::try_from("tcp:[::1]:4444")
+                .unwrap()
+                .to_string(),
+            "tcp:[::1]:4444"
+        );
+
+        assert!(MigrationAddress::try_from("unix:").is_err());
+        assert!(MigrationAddress::try_from("tcp:127.0.0.1").is_err());
+        assert!(MigrationAddress::try_from("tcp:10.0.0.1:4444").is_err());
+        assert!(MigrationAddress::try_from("/tmp/migrate.sock").is_err());
+    }
+
+    #[test]
+    fn test_serial_config() {
+        assert_eq!(
+            SerialConfig::try_from("stdio").unwrap(),
+            SerialConfig::Stdio
+        );
+        assert_eq!(SerialConfig::try_from("pty").unwrap(), SerialConfig::Pty);
+        assert_eq!(
+            SerialConfig::try_from("file:/tmp/console.log").unwrap(),
+            SerialConfig::File(PathBuf::from("/tmp/console.log"))
+        );
+        assert_eq!(
+            SerialConfig::try_from("unix:/tmp/console.sock").unwrap(),
+            SerialConfig::Socket(PathBuf::from("/tmp/console.sock"))
+        );
+        assert_eq!(
+            SerialConfig::try_from("file:").unwrap_err(),
+            ConversionError::ParseSerial("Empty path".to_string())
+        );
+        assert!(SerialConfig::try_from("tcp:127.0.0.1:4444").is_err());
+        assert!(SerialConfig::try_from("").is_err());
+    }
+
+    #[test]
+    fn test_reboot_policy() {
+        assert_eq!(RebootPolicy::default(), RebootPolicy::Exit);
+        assert_eq!(RebootPolicy::try_from("exit").unwrap(), RebootPolicy::Exit);
+        assert_eq!(
+            RebootPolicy::try_from("restart").unwrap(),
+            RebootPolicy::Restart
+        );
+        assert_eq!(
+            RebootPolicy::try_from("reboot").unwrap_err(),
+            ConversionError::ParseReboot("Expected exit or restart, got reboot".to_string())
+        );
+    }
+
+    #[test]
+    fn test_metrics_config() {
+        assert_eq!(
+            MetricsConfig::try_from("path=/tmp/metrics.json").unwrap(),
+            MetricsConfig {
+                path: PathBuf::from("/tmp/metrics.json"),
+                flush_interval_s: DEFAULT_METRICS_FLUSH_INTERVAL_S,
+            }
+        );
+        assert_eq!(
+            MetricsConfig::try_from("path=/tmp/metrics.json,flush_interval_s=5")
+                .unwrap()
+                .flush_interval_s,
+            5
+        );
+
+        assert_eq!(
+            MetricsConfig::try_from("flush_interval_s=5").unwrap_err(),
+            ConversionError::ParseMetrics("Missing required argument: path".to_string())
+        );
+        assert!(MetricsConfig::try_from("path=/tmp/metrics.json,flush_interval_s=0").is_err());
+        assert!(MetricsConfig::try_from("path=/tmp/metrics.json,blah=blah").is_err());
+    }
+
     #[test]
     fn test_memory_config() {
         let default = MemoryConfig { size_mib: 256 };
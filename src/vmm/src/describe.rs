@@ -0,0 +1,104 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Description of the resolved layout of a VM.
+//!
+//! This is what the guest gets to see: the guest memory regions, and where each device sits
+//! on the MMIO or PIO bus along with its interrupt line, as handed out by the allocators.
+
+use serde::Serialize;
+
+use devices::virtio::MmioConfig;
+
+/// Run state of a VM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunState {
+    /// The vCPUs did not start running yet.
+    NotStarted,
+    /// The vCPUs run guest code.
+    Running,
+    /// The vCPUs are paused.
+    Paused,
+    /// The VM is shutting down.
+    Exiting,
+}
+
+/// Range of guest physical or port I/O addresses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct AddressRange {
+    /// First address of the range.
+    pub base: u64,
+    /// Size of the range in bytes.
+    pub size: u64,
+}
+
+/// Bus a device sits on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Bus {
+    /// Memory mapped I/O.
+    Mmio,
+    /// Port I/O.
+    Pio,
+}
+
+/// Device of a VM.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct DeviceDescription {
+    /// Kind of device, such as `serial` or `block`.
+    pub kind: String,
+    /// Bus the device sits on.
+    pub bus: Bus,
+    /// Addresses of the device on its bus.
+    pub range: AddressRange,
+    /// Interrupt line of the device, if it has one.
+    pub gsi: Option<u32>,
+    /// Backing file of a block device, tap device of a network device, or backend of the serial
+    /// console in the format of the `--serial` option.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backend: Option<String>,
+}
+
+impl DeviceDescription {
+    /// Describes a virtio device placed at `mmio_cfg`.
+    pub(crate) fn virtio(kind: &str, mmio_cfg: &MmioConfig, backend: Option<String>) -> Self {
+        DeviceDescription {
+            kind: kind.to_string(),
+            bus: Bus::Mmio,
+            range: AddressRange {
+                base: mmio_cfg.range.base().0,
+                size: mmio_cfg.range.size(),
+            },
+            gsi: Some(mmio_cfg.gsi),
+            backend,
+        }
+    }
+
+    /// Describes a legacy device at `range` of `bus`.
+    pub(crate) fn legacy(kind: &str, bus: Bus, range: AddressRange, gsi: Option<u32>) -> Self {
+        DeviceDescription {
+            kind: kind.to_string(),
+            bus,
+            range,
+            gsi,
+            backend: None,
+        }
+    }
+}
+
+/// Resolved layout and run state of a VM.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct VmDescription {
+    /// Run state of the VM.
+    pub state: RunState,
+    /// Number of vCPUs.
+    pub vcpus: u8,
+    /// Guest memory regions.
+    pub memory: Vec<AddressRange>,
+    /// Devices, in the order they were added.
+    pub devices: Vec<DeviceDescription>,
+    /// Kernel command line, with the entries added for the devices. Missing when the VM was
+    /// restored from a snapshot or migrated, as its kernel booted elsewhere.
+    pub kernel_cmdline: Option<String>,
+}
@@ -3,17 +3,22 @@
 //! Reference VMM built with rust-vmm components and minimal glue.
 #![deny(missing_docs)]
 
+use std::collections::BTreeSet;
 use std::convert::TryFrom;
 #[cfg(target_arch = "aarch64")]
 use std::convert::TryInto;
-use std::fs::File;
-use std::io::{self, stdin, stdout};
+use std::fs::{self, File};
+use std::io::{self, stdin};
 use std::ops::DerefMut;
-use std::path::PathBuf;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use event_manager::{EventManager, EventOps, Events, MutEventSubscriber, SubscriberOps};
+use event_manager::{
+    EventManager, EventOps, Events, MutEventSubscriber, RemoteEndpoint, SubscriberOps,
+};
 use irq_allocator::IrqAllocator;
 use kvm_bindings::KVM_API_VERSION;
 use kvm_ioctls::{
@@ -35,6 +40,7 @@ use linux_loader::loader::{
     elf::{self, Elf},
     load_cmdline,
 };
+use log::{error, info, warn};
 use vm_device::bus::{MmioAddress, MmioRange};
 #[cfg(target_arch = "x86_64")]
 use vm_device::bus::{PioAddress, PioRange};
@@ -43,11 +49,8 @@ use vm_device::device_manager::IoManager;
 use vm_device::device_manager::MmioManager;
 #[cfg(target_arch = "x86_64")]
 use vm_device::device_manager::PioManager;
-#[cfg(target_arch = "aarch64")]
-use vm_memory::GuestMemoryRegion;
-use vm_memory::{GuestAddress, GuestMemory, GuestMemoryMmap};
-#[cfg(target_arch = "x86_64")]
-use vm_superio::I8042Device;
+use vm_memory::{GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+use vm_superio::serial::NoEvents;
 #[cfg(target_arch = "aarch64")]
 use vm_superio::Rtc;
 use vm_superio::Serial;
@@ -56,15 +59,25 @@ use vmm_sys_util::{epoll::EventSet, eventfd::EventFd, terminal::Terminal};
 #[cfg(target_arch = "x86_64")]
 use boot::build_bootparams;
 pub use config::*;
+use devices::virtio::balloon::{self, BalloonArgs, BalloonSize, StatsSample};
 use devices::virtio::block::{self, BlockArgs};
+use devices::virtio::dirty::DirtyPages;
+pub use devices::virtio::dirty::DIRTY_PAGE_SIZE;
 use devices::virtio::net::{self, NetArgs};
-use devices::virtio::balloon::{self, BalloonArgs};
+use devices::virtio::persist::MmioState;
 use devices::virtio::{Env, MmioConfig};
 
+use describe::{AddressRange, Bus, DeviceDescription, RunState, VmDescription};
 #[cfg(target_arch = "x86_64")]
 use devices::legacy::I8042Wrapper;
-use devices::legacy::{EventFdTrigger, SerialWrapper};
-use vm_vcpu::vm::{self, ExitHandler, KvmVm, VmConfig};
+use devices::legacy::{Console, DebugExit, EventFdTrigger, GuestExit, SerialState, SerialWrapper};
+#[cfg(target_arch = "x86_64")]
+use devices::legacy::{DEBUG_EXIT_PORT, DEBUG_EXIT_SIZE};
+use metrics::{Metrics, MetricsFlusher};
+use shutdown::ShutdownHandler;
+use snapshot::{DevicesState, ParentSnapshot, Snapshot, SNAPSHOT_VERSION};
+use uffd::PageFaultMetrics;
+use vm_vcpu::vm::{self, DirtyLog, ExitHandler, KvmVm, VmConfig, VmRunState};
 
 #[cfg(target_arch = "aarch64")]
 use devices::legacy::RtcWrapper;
@@ -78,7 +91,13 @@ use vm_vcpu::vm::MAX_IRQ;
 
 mod boot;
 mod config;
+pub mod describe;
 mod irq_allocator;
+pub mod metrics;
+pub mod migration;
+pub mod shutdown;
+pub mod snapshot;
+pub mod uffd;
 
 /// First address past 32 bits is where the MMIO gap ends.
 #[cfg(target_arch = "x86_64")]
@@ -113,10 +132,24 @@ pub const DEFAULT_KERNEL_CMDLINE: &str = "panic=1 pci=off";
 #[cfg(target_arch = "aarch64")]
 /// Default kernel command line.
 pub const DEFAULT_KERNEL_CMDLINE: &str = "reboot=t panic=1 pci=off";
+/// Parameters added to the kernel command line for the i8042 keyboard.
+#[cfg(target_arch = "x86_64")]
+pub const I8042_CMDLINE: &str = "i8042.noaux i8042.nomux i8042.nopnp i8042.dumbkbd";
 /// Default address allocator alignment. It needs to be a power of 2.
 pub const DEFAULT_ADDRESSS_ALIGNEMNT: u64 = 4;
 /// Default allocation policy for address allocator.
 pub const DEFAULT_ALLOC_POLICY: AllocPolicy = AllocPolicy::FirstMatch;
+/// Default path of the control API socket.
+pub const DEFAULT_API_SOCKET_PATH: &str = "/tmp/rust-vmm.sock";
+/// Default number of seconds between two writes of the metrics to their file.
+pub const DEFAULT_METRICS_FLUSH_INTERVAL_S: u64 = 60;
+/// Default number of seconds between two requests for the memory statistics of the guest.
+pub const DEFAULT_BALLOON_STATS_POLLING_INTERVAL_S: u64 = 1;
+
+/// Maximum number of rounds sending the pages written by a migrating VM while it runs.
+const MAX_PRECOPY_ROUNDS: usize = 16;
+/// Number of written pages under which a migrating VM is paused to send the remaining ones.
+const STOP_COPY_PAGES: usize = 1024;
 
 /// IRQ line 4 is typically used for serial port 1.
 // See more IRQ assignments & info: https://tldp.org/HOWTO/Serial-HOWTO-8.html
@@ -150,6 +183,8 @@ pub enum Error {
     Cmdline(cmdline::Error),
     /// Error setting up the serial device.
     SerialDevice(devices::legacy::SerialError),
+    /// Failed to open the backend of the serial console.
+    SerialConsole(io::Error),
     /// Event management error.
     EventManager(event_manager::Error),
     /// I/O error.
@@ -180,6 +215,28 @@ pub enum Error {
     SetupFdt(arch::Error),
     /// IrqAllocator error
     IrqAllocator(irq_allocator::Error),
+    /// Failed to save or restore a snapshot.
+    Snapshot(snapshot::Error),
+    /// Failed to set up lazy loading of guest memory.
+    LazyRestore(uffd::Error),
+    /// Failed to migrate the VM.
+    Migration(migration::Error),
+    /// The memory slot does not exist.
+    InvalidMemorySlot(u32),
+    /// Dirty page logging is already used, i.e. by a client of the control socket.
+    DirtyLogBusy,
+    /// A diff snapshot was requested, but dirty page logging was not enabled on all memory
+    /// slots since the previous snapshot.
+    NoDiffBase,
+    /// Diff snapshots cannot be loaded lazily.
+    LazyDiffRestore,
+    /// Failed to set up the metrics file.
+    Metrics(io::Error),
+    /// Failed to set up the handling of termination signals, or to ask the guest to shut
+    /// down.
+    Signals(io::Error),
+    /// The VMM was not built by `try_from1`, so it does not handle shutdown requests.
+    NoShutdownHandler,
 }
 
 impl std::convert::From<vm::Error> for Error {
@@ -200,12 +257,28 @@ impl From<irq_allocator::Error> for crate::Error {
     }
 }
 
+// Needed for running closures on the event manager thread through `RemoteEndpoint`.
+impl From<event_manager::Error> for Error {
+    fn from(error: event_manager::Error) -> Self {
+        Error::EventManager(error)
+    }
+}
+
 /// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
 pub type Result<T> = std::result::Result<T, Error>;
 
 type Block = block::Block<Arc<GuestMemoryMmap>>;
 type Net = net::Net<Arc<GuestMemoryMmap>>;
 type Balloon = balloon::Balloon<Arc<GuestMemoryMmap>>;
+type SerialDevice = SerialWrapper<EventFdTrigger, NoEvents, Console>;
+type Subscriber = Arc<Mutex<dyn MutEventSubscriber + Send>>;
+
+// Snapshot the next diff snapshot applies on top of.
+struct DiffBase {
+    parent: ParentSnapshot,
+    // Pages written since the parent was taken, which are no longer in the dirty log.
+    pages: BTreeSet<u64>,
+}
 
 /// A live VMM.
 pub struct Vmm {
@@ -218,12 +291,42 @@ pub struct Vmm {
     // The `device_mgr` is an Arc<Mutex> so that it can be shared between
     // the Vcpu threads, and modified when new devices are added.
     device_mgr: Arc<Mutex<IoManager>>,
+    // Used to run code on the thread which processes device events.
+    event_endpoint: RemoteEndpoint<Subscriber>,
+    // Stops the event manager loop, which then tears the VMM down.
+    exit_handler: WrappedExitHandler,
+    serial: Option<Arc<Mutex<SerialDevice>>>,
+    // Used to ask the guest to shut down.
+    #[cfg(target_arch = "x86_64")]
+    i8042: Option<Arc<Mutex<I8042Wrapper>>>,
     // Arc<Mutex<>> because the same device (a dyn DevicePio/DeviceMmio from IoManager's
     // perspective, and a dyn MutEventSubscriber from EventManager's) is managed by the 2 entities,
     // and isn't Copy-able; so once one of them gets ownership, the other one can't anymore.
     block_devices: Vec<Arc<Mutex<Block>>>,
     net_devices: Vec<Arc<Mutex<Net>>>,
     balloon_devices: Vec<Arc<Mutex<Balloon>>>,
+    // Where each device was placed, in the order they were added.
+    devices: Vec<DeviceDescription>,
+    // Set when the VM is restored from a snapshot, in which case the vCPUs resume from their
+    // saved registers instead of booting the kernel.
+    restored: bool,
+    // What happens when the guest resets the VM.
+    reboot_policy: RebootPolicy,
+    // Counters of the vCPUs and of the devices, which also covers the page faults when guest
+    // memory is loaded lazily from a snapshot.
+    metrics: Metrics,
+    // Set when the metrics are written to a file. It is also registered with the event manager,
+    // which runs it periodically.
+    metrics_flusher: Option<Arc<Mutex<MetricsFlusher>>>,
+    // Asks the guest to shut down on termination signals and on request of the control socket.
+    shutdown_handler: Option<Arc<Mutex<ShutdownHandler>>>,
+    // Guest pages written by the virtio devices, shared with all of them.
+    dirty_pages: Arc<DirtyPages>,
+    // Set while dirty page logging is enabled on all memory slots, once a snapshot was taken.
+    diff_base: Option<DiffBase>,
+    // Connection to the destination of a completed migration. It is closed when the process
+    // exits, which lets the destination know it can take over.
+    migration_stream: Option<migration::Stream>,
     // TODO: fetch the vcpu number from the `vm` object.
     // TODO-continued: this is needed to make the arm POC work as we need to create the FDT
     // TODO-continued: after the other resources are created.
@@ -241,6 +344,12 @@ pub struct Vmm {
 pub struct VmmExitHandler {
     exit_event: EventFd,
     keep_running: AtomicBool,
+    // Exit code of the process once the loop exits.
+    exit_code: i32,
+    // Set when the loop stopped because the guest reset the VM.
+    reset: bool,
+    // Set when the VMM was asked to exit, in which case a reset does not restart the VM.
+    exiting: bool,
 }
 
 // The wrapped exit handler is needed because the ownership of the inner `VmmExitHandler` is
@@ -250,7 +359,8 @@ pub struct VmmExitHandler {
 #[derive(Clone)]
 pub struct WrappedExitHandler(
     ///
-    pub Arc<Mutex<VmmExitHandler>>);
+    pub Arc<Mutex<VmmExitHandler>>,
+);
 
 impl WrappedExitHandler {
     ///
@@ -258,6 +368,9 @@ impl WrappedExitHandler {
         Ok(WrappedExitHandler(Arc::new(Mutex::new(VmmExitHandler {
             exit_event: EventFd::new(libc::EFD_NONBLOCK).map_err(Error::ExitEvent)?,
             keep_running: AtomicBool::new(true),
+            exit_code: 0,
+            reset: false,
+            exiting: false,
         }))))
     }
 
@@ -265,12 +378,60 @@ impl WrappedExitHandler {
     pub fn keep_running(&self) -> bool {
         self.0.lock().unwrap().keep_running.load(Ordering::Acquire)
     }
+
+    /// Stop the event manager loop, for the process to exit with `code`.
+    pub fn exit(&self, code: i32) -> io::Result<()> {
+        let mut handler = self.0.lock().unwrap();
+        handler.exit_code = code;
+        handler.exiting = true;
+        handler.exit_event.write(1)
+    }
+
+    /// Exit once the guest resets the VM, regardless of the reboot policy (i.e. after the
+    /// guest was asked to shut down, which it does by rebooting).
+    pub fn exit_on_reset(&self) {
+        self.0.lock().unwrap().exiting = true;
+    }
+
+    /// Whether the loop stopped because the guest reset the VM, and nothing asked the VMM to
+    /// exit.
+    pub fn restart_requested(&self) -> bool {
+        let handler = self.0.lock().unwrap();
+        handler.reset && !handler.exiting
+    }
+
+    // Get the loop running again once the VM restarted. The vCPUs stopped for the restart
+    // kicked the exit event, so it is drained.
+    fn restarted(&self) {
+        let mut handler = self.0.lock().unwrap();
+        // Fails with `WouldBlock` when there was nothing to drain.
+        let _ = handler.exit_event.read();
+        handler.reset = false;
+        handler.keep_running.store(true, Ordering::Release);
+    }
+
+    /// The code the process exits with: 0 unless another one was given to `exit`.
+    pub fn exit_code(&self) -> i32 {
+        self.0.lock().unwrap().exit_code
+    }
 }
 
 impl ExitHandler for WrappedExitHandler {
     fn kick(&self) -> io::Result<()> {
         self.0.lock().unwrap().exit_event.write(1)
     }
+
+    fn reset(&self) -> io::Result<()> {
+        let mut handler = self.0.lock().unwrap();
+        handler.reset = true;
+        handler.exit_event.write(1)
+    }
+}
+
+impl GuestExit for WrappedExitHandler {
+    fn exit(&self, code: i32) -> io::Result<()> {
+        WrappedExitHandler::exit(self, code)
+    }
 }
 
 impl MutEventSubscriber for VmmExitHandler {
@@ -292,20 +453,22 @@ impl MutEventSubscriber for VmmExitHandler {
 }
 
 ///
-pub trait TryFrom1{
-///
-    fn try_from1(config: VMMConfig, 
+pub trait TryFrom1 {
+    ///
+    fn try_from1(
+        config: VMMConfig,
         exit_handler: &WrappedExitHandler,
         event_mgr: &mut EventManager<Arc<Mutex<dyn MutEventSubscriber + Send>>>,
-        ) -> Result<Vmm>;
+    ) -> Result<Vmm>;
 }
 impl TryFrom1 for Vmm {
     //type Error = Error;
 
-    fn try_from1(config: VMMConfig, 
+    fn try_from1(
+        config: VMMConfig,
         exit_handler: &WrappedExitHandler,
         event_mgr: &mut EventManager<Arc<Mutex<dyn MutEventSubscriber + Send>>>,
-        ) -> Result<Vmm> {
+    ) -> Result<Vmm> {
         let kvm = Kvm::new().map_err(Error::KvmIoctl)?;
 
         // Check that the KVM on the host is supported.
@@ -315,14 +478,49 @@ impl TryFrom1 for Vmm {
         }
         Vmm::check_kvm_capabilities(&kvm)?;
 
+        let metrics_config = config.metrics_config.clone();
+        let debug_exit = config.debug_exit;
+        let mut vmm = if let Some(restore_cfg) = config.restore_config.as_ref() {
+            Vmm::restore(
+                &kvm,
+                restore_cfg,
+                &config.serial_config,
+                exit_handler,
+                event_mgr,
+            )?
+        } else if let Some(addr) = config.incoming_config.as_ref() {
+            Vmm::incoming(&kvm, addr, &config.serial_config, exit_handler, event_mgr)?
+        } else {
+            Vmm::create(&kvm, config, exit_handler, event_mgr)?
+        };
+        if debug_exit {
+            vmm.add_debug_exit_device()?;
+        }
+        if let Some(metrics_cfg) = metrics_config.as_ref() {
+            vmm.add_metrics_flusher(metrics_cfg, event_mgr)?;
+        }
+        vmm.add_shutdown_handler(event_mgr)?;
+
+        Ok(vmm)
+    }
+}
+
+impl Vmm {
+    // Build a new VM from `config`.
+    fn create(
+        kvm: &Kvm,
+        config: VMMConfig,
+        exit_handler: &WrappedExitHandler,
+        event_mgr: &mut EventManager<Subscriber>,
+    ) -> Result<Vmm> {
         let guest_memory = Vmm::create_guest_memory(&config.memory_config)?;
         let address_allocator = Vmm::create_address_allocator(&config.memory_config)?;
         let device_mgr = Arc::new(Mutex::new(IoManager::new()));
 
         // Create the KvmVm.
-        let vm_config = VmConfig::new(&kvm, config.vcpu_config.num, MAX_IRQ)?;
+        let vm_config = VmConfig::new(kvm, config.vcpu_config.num, MAX_IRQ)?;
         let vm = KvmVm::new(
-            &kvm,
+            kvm,
             vm_config,
             &guest_memory,
             exit_handler.clone(),
@@ -333,6 +531,10 @@ impl TryFrom1 for Vmm {
         let fdt_builder = FdtBuilder::new();
 
         let irq_allocator = IrqAllocator::new(SERIAL_IRQ, vm.max_irq())?;
+        let metrics = Metrics {
+            vcpu_exits: vm.vcpu_exit_metrics(),
+            ..Default::default()
+        };
 
         let mut vmm = Vmm {
             vm,
@@ -340,31 +542,45 @@ impl TryFrom1 for Vmm {
             address_allocator,
             irq_allocator,
             device_mgr,
+            event_endpoint: event_mgr.remote_endpoint(),
+            exit_handler: exit_handler.clone(),
+            serial: None,
+            #[cfg(target_arch = "x86_64")]
+            i8042: None,
             kernel_cfg: config.kernel_config,
             block_devices: Vec::new(),
             net_devices: Vec::new(),
             balloon_devices: Vec::new(),
+            devices: Vec::new(),
+            restored: false,
+            reboot_policy: config.reboot_policy,
+            metrics,
+            metrics_flusher: None,
+            shutdown_handler: None,
+            dirty_pages: Arc::new(DirtyPages::default()),
+            diff_base: None,
+            migration_stream: None,
             #[cfg(target_arch = "aarch64")]
             num_vcpus: config.vcpu_config.num as u64,
             #[cfg(target_arch = "aarch64")]
             fdt_builder,
         };
-        vmm.add_serial_console(event_mgr)?;
+        vmm.add_serial_console(event_mgr, &config.serial_config, None)?;
         #[cfg(target_arch = "x86_64")]
         vmm.add_i8042_device()?;
         #[cfg(target_arch = "aarch64")]
         vmm.add_rtc_device()?;
 
         // Adding the virtio devices. We'll come up with a cleaner abstraction for `Env`.
-        if let Some(cfg) = config.block_config.as_ref() {
+        for cfg in config.block_config.iter() {
             vmm.add_block_device(cfg, event_mgr)?;
         }
 
-        if let Some(cfg) = config.net_config.as_ref() {
+        for cfg in config.net_config.iter() {
             vmm.add_net_device(cfg, event_mgr)?;
         }
-        if let Some(cfg) = config.balloon_config.as_ref() {
-            vmm.add_balloon_device(event_mgr)?;
+        for cfg in config.balloon_config.iter() {
+            vmm.add_balloon_device(cfg, event_mgr)?;
         }
 
         Ok(vmm)
@@ -373,7 +589,134 @@ impl TryFrom1 for Vmm {
 
 impl Vmm {
     /// Run the VMM.
+    ///
+    /// A VM restored from a snapshot resumes where it was saved; otherwise the kernel is booted.
     pub fn run(&mut self) -> Result<()> {
+        let vcpu_run_addr = if self.restored {
+            None
+        } else {
+            Some(self.prepare_boot()?)
+        };
+
+        if self.on_terminal() && stdin().lock().set_raw_mode().is_err() {
+            warn!("Failed to set raw mode on terminal. Stdin will echo.");
+        }
+
+        self.vm.run(vcpu_run_addr).map_err(Error::Vm)?;
+        Ok(())
+    }
+
+    /// Boot the guest again once it reset the VM, if the reboot policy says so: the vCPUs
+    /// and the virtio devices are reset, and the kernel is loaded again. Runs while the event
+    /// manager loop is stopped. Returns false when the VMM exits instead.
+    pub fn restart(&mut self, event_mgr: &mut EventManager<Subscriber>) -> Result<bool> {
+        if !self.exit_handler.restart_requested() || self.reboot_policy != RebootPolicy::Restart {
+            return Ok(false);
+        }
+        info!("The guest rebooted, restarting the VM.");
+
+        self.vm.reset(&self.guest_memory)?;
+        for block in self.block_devices.iter() {
+            block
+                .lock()
+                .unwrap()
+                .reset_with(event_mgr)
+                .map_err(Error::Block)?;
+        }
+        for net in self.net_devices.iter() {
+            net.lock()
+                .unwrap()
+                .reset_with(event_mgr)
+                .map_err(Error::Net)?;
+        }
+        for balloon in self.balloon_devices.iter() {
+            balloon
+                .lock()
+                .unwrap()
+                .reset_with(event_mgr)
+                .map_err(Error::Balloon)?;
+        }
+
+        self.exit_handler.restarted();
+        self.run()?;
+        Ok(true)
+    }
+
+    /// Stop the event manager loop, for the VMM to be torn down and exit with `code`.
+    pub fn request_exit(&self, code: i32) -> Result<()> {
+        self.exit_handler.exit(code).map_err(Error::ExitEvent)
+    }
+
+    /// Tear the VMM down once the event manager loop exited: stop the vCPUs, sync the block
+    /// devices to their backends, give the terminal back its canonical mode and write the
+    /// metrics one last time.
+    pub fn shutdown(&mut self) {
+        self.vm.shutdown();
+        for block in self.block_devices.iter() {
+            if let Err(e) = block.lock().unwrap().sync() {
+                error!("Failed to sync a block device: {:?}", e);
+            }
+        }
+        if self.on_terminal() && stdin().lock().set_canon_mode().is_err() {
+            warn!("Failed to set canon mode. Stdin will not echo.");
+        }
+        self.flush_metrics();
+    }
+
+    /// Shut down on SIGTERM and SIGINT instead of dying: the guest is asked to shut down, and
+    /// stopped if it did not within [`SHUTDOWN_TIMEOUT`](shutdown::SHUTDOWN_TIMEOUT), or on
+    /// the next signal. The VMM then exits with 128 plus the number of the signal.
+    pub fn handle_signals(&self) -> Result<()> {
+        self.shutdown_handler()?
+            .lock()
+            .unwrap()
+            .register_signal_handlers()
+            .map_err(Error::Signals)
+    }
+
+    /// Ask the guest to shut down by pressing Ctrl-Alt-Del, which is only possible on x86_64.
+    /// The VMM exits once the guest shut down or rebooted, whatever the reboot policy. With a
+    /// `timeout`, the vCPUs are stopped if the guest did not shut down by then, and the VMM
+    /// exits with 0 as well. Returns false when the VM has no way of asking the guest.
+    pub fn send_ctrl_alt_del(&self, timeout: Option<Duration>) -> Result<bool> {
+        self.shutdown_handler()?
+            .lock()
+            .unwrap()
+            .request(timeout)
+            .map_err(Error::Signals)
+    }
+
+    // Create the handler of the shutdown requests, and register it with the event manager.
+    fn add_shutdown_handler(&mut self, event_mgr: &mut EventManager<Subscriber>) -> Result<()> {
+        #[allow(unused_mut)]
+        let mut handler =
+            ShutdownHandler::new(self.exit_handler.clone()).map_err(Error::Signals)?;
+        #[cfg(target_arch = "x86_64")]
+        {
+            handler.i8042 = self.i8042.clone();
+        }
+        let handler = Arc::new(Mutex::new(handler));
+        event_mgr.add_subscriber(handler.clone());
+        self.shutdown_handler = Some(handler);
+        Ok(())
+    }
+
+    fn shutdown_handler(&self) -> Result<&Arc<Mutex<ShutdownHandler>>> {
+        self.shutdown_handler
+            .as_ref()
+            .ok_or(Error::NoShutdownHandler)
+    }
+
+    // Whether the serial console is the terminal of the VMM.
+    fn on_terminal(&self) -> bool {
+        self.serial
+            .as_ref()
+            .map_or(false, |serial| serial.lock().unwrap().0.writer().is_stdio())
+    }
+
+    // Load the kernel and set up everything it needs to boot. Returns the address at which
+    // the vCPUs start running.
+    fn prepare_boot(&mut self) -> Result<GuestAddress> {
         let load_result = self.load_kernel()?;
         #[cfg(target_arch = "x86_64")]
         let kernel_load_addr = self.compute_kernel_load_addr(&load_result)?;
@@ -381,17 +724,653 @@ impl Vmm {
         let kernel_load_addr = load_result.kernel_load;
         #[cfg(target_arch = "aarch64")]
         self.setup_fdt()?;
+        Ok(kernel_load_addr)
+    }
+
+    // Recreate the VM saved in the snapshot described by `restore_cfg`. Guest memory is loaded
+    // from the memory file, either upfront or on demand. Diff snapshots are loaded upfront,
+    // along with their parents.
+    fn restore(
+        kvm: &Kvm,
+        restore_cfg: &RestoreConfig,
+        serial_cfg: &SerialConfig,
+        exit_handler: &WrappedExitHandler,
+        event_mgr: &mut EventManager<Subscriber>,
+    ) -> Result<Vmm> {
+        let snapshot = Snapshot::load(&restore_cfg.state_path).map_err(Error::Snapshot)?;
+        let mut mem_file = File::open(&restore_cfg.mem_path).map_err(Error::IO)?;
+        let (guest_memory, page_fault_metrics) = if restore_cfg.lazy {
+            // Pages would have to be looked up along the chain of memory files.
+            if snapshot.parent.is_some() {
+                return Err(Error::LazyDiffRestore);
+            }
+            let guest_memory =
+                snapshot::create_memory(&snapshot.memory).map_err(Error::Snapshot)?;
+            let metrics = uffd::load_lazily(
+                &guest_memory,
+                &snapshot.memory,
+                mem_file,
+                exit_handler.clone(),
+            )
+            .map_err(Error::LazyRestore)?;
+            (guest_memory, Some(metrics))
+        } else {
+            let guest_memory = snapshot::restore_memory_chain(&snapshot, &mut mem_file)
+                .map_err(Error::Snapshot)?;
+            (guest_memory, None)
+        };
+
+        Vmm::from_snapshot(
+            kvm,
+            snapshot,
+            guest_memory,
+            page_fault_metrics,
+            serial_cfg,
+            exit_handler,
+            event_mgr,
+        )
+    }
+
+    // Receive a VM migrated from another VMM on `addr`. Guest memory is received while the VM
+    // still runs on the source, then the VM is built from the state sent once it is paused.
+    fn incoming(
+        kvm: &Kvm,
+        addr: &MigrationAddress,
+        serial_cfg: &SerialConfig,
+        exit_handler: &WrappedExitHandler,
+        event_mgr: &mut EventManager<Subscriber>,
+    ) -> Result<Vmm> {
+        let mut stream = migration::accept(addr).map_err(Error::Migration)?;
+        let header = migration::recv_header(&mut stream).map_err(Error::Migration)?;
+        let guest_memory = snapshot::create_memory(&header.memory).map_err(Error::Snapshot)?;
+        let snapshot =
+            migration::recv_memory(&mut stream, &guest_memory).map_err(Error::Migration)?;
+
+        // The source stops the VM for good once it is told we are ready, and lets go of the
+        // tap devices we are about to open.
+        migration::send_tag(&mut stream, migration::READY).map_err(Error::Migration)?;
+        migration::expect_tag(&mut stream, migration::RELEASED).map_err(Error::Migration)?;
+        // Resources such as the control socket are only released when the source exits.
+        migration::wait_for_close(&mut stream).map_err(Error::Migration)?;
+
+        Vmm::from_snapshot(
+            kvm,
+            snapshot,
+            guest_memory,
+            None,
+            serial_cfg,
+            exit_handler,
+            event_mgr,
+        )
+    }
+
+    // Build the VM saved in `snapshot`, on top of `guest_memory` which already holds (or lazily
+    // loads) its contents. The devices are placed at the same MMIO ranges and interrupts they
+    // had when the state was saved, so the guest does not notice the difference.
+    fn from_snapshot(
+        kvm: &Kvm,
+        snapshot: Snapshot,
+        guest_memory: GuestMemoryMmap,
+        page_fault_metrics: Option<Arc<PageFaultMetrics>>,
+        serial_cfg: &SerialConfig,
+        exit_handler: &WrappedExitHandler,
+        event_mgr: &mut EventManager<Subscriber>,
+    ) -> Result<Vmm> {
+        let mem_size: u64 = snapshot.memory.iter().map(|region| region.size).sum();
+        let address_allocator = Vmm::create_address_allocator(&MemoryConfig {
+            size_mib: (mem_size >> 20) as u32,
+        })?;
+        let device_mgr = Arc::new(Mutex::new(IoManager::new()));
+
+        #[cfg(target_arch = "aarch64")]
+        let num_vcpus = snapshot.vm.config.num_vcpus as u64;
+        let vm = KvmVm::from_state(
+            kvm,
+            snapshot.vm,
+            &guest_memory,
+            exit_handler.clone(),
+            device_mgr.clone(),
+        )?;
+
+        let irq_allocator = IrqAllocator::new(SERIAL_IRQ, vm.max_irq())?;
+        let metrics = Metrics {
+            vcpu_exits: vm.vcpu_exit_metrics(),
+            lazy_restore: page_fault_metrics,
+            ..Default::default()
+        };
+
+        let mut vmm = Vmm {
+            vm,
+            guest_memory,
+            address_allocator,
+            irq_allocator,
+            device_mgr,
+            event_endpoint: event_mgr.remote_endpoint(),
+            exit_handler: exit_handler.clone(),
+            serial: None,
+            #[cfg(target_arch = "x86_64")]
+            i8042: None,
+            kernel_cfg: KernelConfig::default(),
+            block_devices: Vec::new(),
+            net_devices: Vec::new(),
+            balloon_devices: Vec::new(),
+            devices: Vec::new(),
+            restored: true,
+            // The kernel of a restored VM is unknown, so it cannot boot again.
+            reboot_policy: RebootPolicy::Exit,
+            metrics,
+            metrics_flusher: None,
+            shutdown_handler: None,
+            dirty_pages: Arc::new(DirtyPages::default()),
+            diff_base: None,
+            migration_stream: None,
+            #[cfg(target_arch = "aarch64")]
+            num_vcpus,
+            #[cfg(target_arch = "aarch64")]
+            fdt_builder: FdtBuilder::new(),
+        };
+        let devices = snapshot.devices;
+        vmm.add_serial_console(event_mgr, serial_cfg, devices.serial)?;
+        #[cfg(target_arch = "x86_64")]
+        vmm.add_i8042_device()?;
+        #[cfg(target_arch = "aarch64")]
+        vmm.add_rtc_device()?;
+
+        for state in devices.block.iter() {
+            let mmio_cfg = vmm.reserve_mmio(&state.mmio)?;
+            let backend = Some(state.file_path.display().to_string());
+            let description = DeviceDescription::virtio("block", &mmio_cfg, backend);
+            let mut guard = vmm.device_mgr.lock().unwrap();
+            let mut env = Env {
+                mem: Arc::new(vmm.guest_memory.clone()),
+                vm_fd: vmm.vm.vm_fd(),
+                event_mgr,
+                mmio_mgr: guard.deref_mut(),
+                mmio_cfg,
+                kernel_cmdline: &mut vmm.kernel_cfg.cmdline,
+                dirty_pages: vmm.dirty_pages.clone(),
+            };
+            let block = Block::from_state(&mut env, state).map_err(Error::Block)?;
+            drop(guard);
+            vmm.metrics.block.push(block.lock().unwrap().metrics());
+            vmm.block_devices.push(block);
+            vmm.devices.push(description);
+        }
+
+        for state in devices.net.iter() {
+            let mmio_cfg = vmm.reserve_mmio(&state.mmio)?;
+            let description =
+                DeviceDescription::virtio("net", &mmio_cfg, Some(state.tap_name.clone()));
+            let mut guard = vmm.device_mgr.lock().unwrap();
+            let mut env = Env {
+                mem: Arc::new(vmm.guest_memory.clone()),
+                vm_fd: vmm.vm.vm_fd(),
+                event_mgr,
+                mmio_mgr: guard.deref_mut(),
+                mmio_cfg,
+                kernel_cmdline: &mut vmm.kernel_cfg.cmdline,
+                dirty_pages: vmm.dirty_pages.clone(),
+            };
+            let net = Net::from_state(&mut env, state).map_err(Error::Net)?;
+            drop(guard);
+            vmm.metrics.net.push(net.lock().unwrap().metrics());
+            vmm.net_devices.push(net);
+            vmm.devices.push(description);
+        }
+
+        for state in devices.balloon.iter() {
+            let mmio_cfg = vmm.reserve_mmio(&state.mmio)?;
+            let description = DeviceDescription::virtio("balloon", &mmio_cfg, None);
+            let mut guard = vmm.device_mgr.lock().unwrap();
+            let mut env = Env {
+                mem: Arc::new(vmm.guest_memory.clone()),
+                vm_fd: vmm.vm.vm_fd(),
+                event_mgr,
+                mmio_mgr: guard.deref_mut(),
+                mmio_cfg,
+                kernel_cmdline: &mut vmm.kernel_cfg.cmdline,
+                dirty_pages: vmm.dirty_pages.clone(),
+            };
+            // The balloon size, features and polling interval are part of the saved state. The
+            // default interval is for the states saved before it was.
+            let args = BalloonArgs {
+                guest_memory: vmm.guest_memory.clone(),
+                num_pages: 0,
+                deflate_on_oom: false,
+                stats_polling_interval: Duration::from_secs(
+                    DEFAULT_BALLOON_STATS_POLLING_INTERVAL_S,
+                ),
+            };
+            let balloon = Balloon::from_state(&mut env, &args, state).map_err(Error::Balloon)?;
+            drop(guard);
+            vmm.metrics.balloon.push(balloon.lock().unwrap().metrics());
+            vmm.balloon_devices.push(balloon);
+            vmm.devices.push(description);
+        }
+
+        Ok(vmm)
+    }
+
+    // Reserve the MMIO range a restored virtio device used, so that it is not handed out again.
+    fn reserve_mmio(&mut self, mmio: &MmioState) -> Result<MmioConfig> {
+        let range = self.address_allocator.allocate(
+            mmio.size,
+            DEFAULT_ADDRESSS_ALIGNEMNT,
+            AllocPolicy::ExactMatch(mmio.base),
+        )?;
+        Ok(MmioConfig {
+            range: mmio_from_range(&range),
+            gsi: mmio.gsi,
+        })
+    }
+
+    /// Pause the vCPUs of the VM.
+    ///
+    /// Returns once all vCPUs are parked outside of `KVM_RUN`. Pausing a paused VM is a no-op.
+    pub fn pause(&mut self) -> Result<()> {
+        self.vm.pause().map_err(Error::Vm)
+    }
+
+    /// Resume the vCPUs of a paused VM. Resuming a running VM is a no-op.
+    pub fn resume(&mut self) -> Result<()> {
+        self.vm.resume().map_err(Error::Vm)
+    }
+
+    /// Save the state of the VM to `state_path`, and the contents of guest memory to `mem_path`.
+    ///
+    /// With `diff`, only the guest pages written since the previous snapshot are saved, to a
+    /// sparse memory file. This needs dirty page logging to have been enabled on all memory
+    /// slots since then. Any snapshot taken while it is enabled can be the parent of a diff.
+    ///
+    /// The VM is paused first, and is left paused so that the caller can either resume it or
+    /// shut it down. The event loop must be running, as devices are saved from its thread.
+    pub fn snapshot<P: AsRef<Path>>(
+        &mut self,
+        state_path: P,
+        mem_path: P,
+        diff: bool,
+    ) -> Result<()> {
+        if diff && self.diff_base.is_none() {
+            return Err(Error::NoDiffBase);
+        }
+        self.pause()?;
+        let vm_state = self.vm.save_state()?;
+        let mut mem_file = File::create(&mem_path).map_err(Error::IO)?;
+
+        // Drain the dirty log even for full snapshots, so that the next diff starts from here.
+        // The pages go back to the diff base if the snapshot fails, so the next diff still
+        // saves them.
+        let tracking = self.dirty_logging_all();
+        let mut pages = if tracking {
+            self.vm_dirty_pages()?
+        } else {
+            BTreeSet::new()
+        };
+        let parent = self.diff_base.as_mut().map(|base| {
+            pages.append(&mut base.pages);
+            base.parent.clone()
+        });
+        let parent = if diff { parent } else { None };
+
+        let guest_memory = self.guest_memory.clone();
+        let save_devices = self.save_devices();
+        let dirty_pages = self.dirty_pages.clone();
+
+        // The queue handlers run on the event manager thread and can still write to guest
+        // memory while the vCPUs are paused (i.e. when a packet arrives on the tap). Saving the
+        // devices and memory from that thread keeps them consistent with each other.
+        let (pages, saved) = self.event_endpoint.call_blocking(move |_| {
+            let devices = save_devices();
+            if tracking {
+                pages.extend(dirty_pages.take());
+            }
+            let dirty_ranges = if diff {
+                snapshot::page_ranges(&pages.iter().copied().collect::<Vec<_>>())
+            } else {
+                Vec::new()
+            };
+            let memory = if diff {
+                snapshot::save_memory_ranges(&guest_memory, &mut mem_file, &dirty_ranges)
+            } else {
+                snapshot::save_memory(&guest_memory, &mut mem_file)
+            };
+            Ok::<_, Error>((pages, memory.map(|memory| (devices, memory, dirty_ranges))))
+        })?;
+
+        let result = saved
+            .map_err(Error::Snapshot)
+            .and_then(|(devices, memory, dirty_ranges)| {
+                Snapshot {
+                    version: SNAPSHOT_VERSION,
+                    vm: vm_state,
+                    memory,
+                    devices,
+                    parent,
+                    dirty_ranges,
+                }
+                .save(&state_path)
+                .map_err(Error::Snapshot)?;
+                if !tracking {
+                    return Ok(None);
+                }
+                // Diffs record where their parent is, independently of the working directory.
+                Ok(Some(ParentSnapshot {
+                    state_path: fs::canonicalize(&state_path).map_err(Error::IO)?,
+                    mem_path: fs::canonicalize(&mem_path).map_err(Error::IO)?,
+                }))
+            });
+
+        match result {
+            Ok(parent) => {
+                self.diff_base = parent.map(|parent| DiffBase {
+                    parent,
+                    pages: BTreeSet::new(),
+                });
+                Ok(())
+            }
+            Err(e) => {
+                if let Some(base) = self.diff_base.as_mut() {
+                    base.pages.extend(pages);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    // Whether dirty page logging is enabled on all memory slots.
+    fn dirty_logging_all(&self) -> bool {
+        (0..self.vm.memory_slots()).all(|slot| self.vm.slot_dirty_logging(slot))
+    }
+
+    // Returns a closure which saves the state of the devices, to be run on the event manager
+    // thread.
+    fn save_devices(&self) -> impl FnOnce() -> DevicesState + Send + 'static {
+        let serial = self.serial.clone();
+        let block_devices = self.block_devices.clone();
+        let net_devices = self.net_devices.clone();
+        let balloon_devices = self.balloon_devices.clone();
+
+        move || DevicesState {
+            serial: serial.map(|serial| serial.lock().unwrap().save_state()),
+            block: block_devices
+                .iter()
+                .map(|block| block.lock().unwrap().save_state())
+                .collect(),
+            net: net_devices
+                .iter()
+                .map(|net| net.lock().unwrap().save_state())
+                .collect(),
+            balloon: balloon_devices
+                .iter()
+                .map(|balloon| balloon.lock().unwrap().save_state())
+                .collect(),
+        }
+    }
+
+    /// Migrate the VM to the VMM waiting on `addr` (see `--incoming`).
+    ///
+    /// Guest memory is copied while the VM keeps running, then the VM is paused and sent along
+    /// with the pages written in the meantime. On success, the VM is left paused without its
+    /// tap devices, and the process must exit for the destination to take over. If the
+    /// transfer fails, the VM resumes running here. The event loop must be running.
+    pub fn migrate(&mut self, addr: &MigrationAddress) -> Result<()> {
+        // The migration relies on seeing every written page, and stops logging when done.
+        // Check before connecting, so the destination isn't left with a truncated stream.
+        if self.vm.dirty_logging() {
+            return Err(Error::DirtyLogBusy);
+        }
+        let mut stream = migration::connect(addr).map_err(Error::Migration)?;
+        let layout = migration::memory_layout(&self.guest_memory);
+        migration::send_header(&mut stream, layout).map_err(Error::Migration)?;
+
+        self.vm.set_dirty_logging(true)?;
+        self.dirty_pages.enable();
+        let result = self.send_vm(&mut stream);
+        self.dirty_pages.disable();
+        if let Err(e) = result {
+            let _ = self.vm.set_dirty_logging(false);
+            let _ = self.resume();
+            return Err(e);
+        }
+        // Logging only slows the vCPUs down from now on, so failing to stop it is harmless.
+        let _ = self.vm.set_dirty_logging(false);
+
+        // The destination holds the whole VM, and waits for us to close the taps before
+        // opening them. There is no going back once they are closed.
+        for net in self.net_devices.iter() {
+            net.lock().unwrap().release_tap().map_err(Error::Net)?;
+        }
+        migration::send_tag(&mut stream, migration::RELEASED).map_err(Error::Migration)?;
+        self.migration_stream = Some(stream);
+        Ok(())
+    }
+
+    // Send guest memory and the state of the VM, pre-copy style. Returns once the destination
+    // acknowledged the state, with the VM paused.
+    fn send_vm(&mut self, stream: &mut migration::Stream) -> Result<()> {
+        // Send all of guest memory, then the pages written while the previous batch was in
+        // flight, until they are few enough to be sent with the VM paused.
+        migration::send_memory(stream, &self.guest_memory).map_err(Error::Migration)?;
+        for _ in 0..MAX_PRECOPY_ROUNDS {
+            let pages = self.take_dirty_pages()?;
+            migration::send_pages(stream, &self.guest_memory, &pages).map_err(Error::Migration)?;
+            if pages.len() <= STOP_COPY_PAGES {
+                break;
+            }
+        }
+
+        self.pause()?;
+        let vm = self.vm.save_state()?;
+        let mut pages = self.vm_dirty_pages()?;
+        let memory = migration::memory_layout(&self.guest_memory);
+        let guest_memory = self.guest_memory.clone();
+        let dirty_pages = self.dirty_pages.clone();
+        let save_devices = self.save_devices();
+        let mut transfer = stream.try_clone().map_err(Error::IO)?;
+
+        // As for snapshots, the devices are saved from the event manager thread, along with
+        // the last pages they wrote.
+        self.event_endpoint.call_blocking(move |_| {
+            let devices = save_devices();
+            pages.extend(dirty_pages.take());
+            let pages = pages.into_iter().collect::<Vec<_>>();
+            migration::send_pages(&mut transfer, &guest_memory, &pages)
+                .and_then(|_| {
+                    let snapshot = Snapshot {
+                        version: SNAPSHOT_VERSION,
+                        vm,
+                        memory,
+                        devices,
+                        parent: None,
+                        dirty_ranges: Vec::new(),
+                    };
+                    migration::send_state(&mut transfer, &snapshot)
+                })
+                .map_err(Error::Migration)
+        })?;
+
+        migration::expect_tag(stream, migration::READY).map_err(Error::Migration)
+    }
+
+    // Page frame numbers of the guest pages written since the previous call, by the vCPUs and
+    // by the devices, in ascending order.
+    fn take_dirty_pages(&mut self) -> Result<Vec<u64>> {
+        // Slots are sorted by address, and their pages too.
+        Ok(self
+            .dirty_log()?
+            .iter()
+            .flat_map(|slot| slot.dirty_pages())
+            .map(|addr| addr / DIRTY_PAGE_SIZE)
+            .collect())
+    }
+
+    // Page frame numbers of the guest pages written by the vCPUs since the previous call.
+    fn vm_dirty_pages(&self) -> Result<BTreeSet<u64>> {
+        Ok(self
+            .vm
+            .dirty_log()?
+            .iter()
+            .flat_map(|slot| slot.dirty_pages())
+            .map(|addr| addr / DIRTY_PAGE_SIZE)
+            .collect())
+    }
+
+    /// Start logging the guest pages written by the vCPUs and by the devices, on the memory
+    /// `slots`, or on all of them when `None`.
+    pub fn start_dirty_log(&mut self, slots: Option<&[u32]>) -> Result<()> {
+        match slots {
+            Some(slots) => {
+                // Check all the slots before enabling logging on any of them.
+                let num_slots = self.vm.memory_slots();
+                if let Some(&slot) = slots.iter().find(|&&slot| slot >= num_slots) {
+                    return Err(Error::InvalidMemorySlot(slot));
+                }
+                for &slot in slots {
+                    self.vm.set_slot_dirty_logging(slot, true)?;
+                }
+            }
+            None => self.vm.set_dirty_logging(true)?,
+        }
+        // Adding slots does not drop the pages the devices recorded so far.
+        if !self.dirty_pages.is_enabled() {
+            self.dirty_pages.enable();
+        }
+        Ok(())
+    }
+
+    /// Stop logging written guest pages, on all memory slots.
+    pub fn stop_dirty_log(&mut self) -> Result<()> {
+        // Pages written from now on would be missed.
+        self.diff_base = None;
+        self.dirty_pages.disable();
+        self.vm.set_dirty_logging(false).map_err(Error::Vm)
+    }
 
-        if stdin().lock().set_raw_mode().is_err() {
-            eprintln!("Failed to set raw mode on terminal. Stdin will echo.");
+    /// Return the guest pages written by the vCPUs and by the devices since logging started
+    /// or since the previous call, and clear them. Only the slots with logging enabled are
+    /// reported. The event loop must be running.
+    pub fn dirty_log(&mut self) -> Result<Vec<DirtyLog>> {
+        let mut log = self.vm.dirty_log()?;
+
+        // Devices record the buffers they are about to write. Taking the pages while they are
+        // not processing their queues ensures none is taken before it is actually written.
+        let dirty_pages = self.dirty_pages.clone();
+        let device_pages = self
+            .event_endpoint
+            .call_blocking(move |_| Ok::<_, Error>(dirty_pages.take()))?;
+        for pfn in device_pages {
+            // Pages in slots which do not log are dropped.
+            for slot in log.iter_mut() {
+                if slot.mark(pfn * DIRTY_PAGE_SIZE) {
+                    break;
+                }
+            }
+        }
+
+        // The pages are gone from the log, but the next diff snapshot still has to save them.
+        if let Some(base) = self.diff_base.as_mut() {
+            base.pages.extend(
+                log.iter()
+                    .flat_map(|slot| slot.dirty_pages())
+                    .map(|addr| addr / DIRTY_PAGE_SIZE),
+            );
+        }
+        Ok(log)
+    }
+
+    /// Counters of the vCPUs and of the devices.
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.clone()
+    }
+
+    /// Write the metrics to their file, if there is one.
+    pub fn flush_metrics(&self) {
+        if let Some(flusher) = self.metrics_flusher.as_ref() {
+            if let Err(e) = flusher.lock().unwrap().flush() {
+                error!("Failed to write the metrics: {}", e);
+            }
         }
+    }
 
-        self.vm.run(Some(kernel_load_addr)).map_err(Error::Vm)?;
+    // Start writing the metrics to the file in `metrics_cfg`. The devices are all added by now,
+    // so the flusher sees their counters.
+    fn add_metrics_flusher(
+        &mut self,
+        metrics_cfg: &MetricsConfig,
+        event_mgr: &mut EventManager<Subscriber>,
+    ) -> Result<()> {
+        let flusher = MetricsFlusher::new(
+            self.metrics.clone(),
+            &metrics_cfg.path,
+            Duration::from_secs(metrics_cfg.flush_interval_s),
+        )
+        .map_err(Error::Metrics)?;
+        let flusher = Arc::new(Mutex::new(flusher));
+        event_mgr.add_subscriber(flusher.clone());
+        self.metrics_flusher = Some(flusher);
         Ok(())
     }
 
+    /// Describe the resolved layout of the VM and its run state.
+    pub fn describe(&self) -> VmDescription {
+        let state = match self.vm.run_state() {
+            VmRunState::Exiting => RunState::Exiting,
+            _ if !self.vm.vcpus_started() => RunState::NotStarted,
+            VmRunState::Running => RunState::Running,
+            VmRunState::Suspending => RunState::Paused,
+        };
+        VmDescription {
+            state,
+            vcpus: self.vm.num_vcpus(),
+            memory: self
+                .guest_memory
+                .iter()
+                .map(|region| AddressRange {
+                    base: region.start_addr().0,
+                    size: region.len(),
+                })
+                .collect(),
+            devices: self.devices.clone(),
+            kernel_cmdline: if self.restored {
+                None
+            } else {
+                Some(self.kernel_cfg.cmdline.as_str().to_string())
+            },
+        }
+    }
+
+    /// Attach `stream` to the serial console, in place of the current client. It first gets the
+    /// recent output of the guest, then sends the input and gets the output until it
+    /// disconnects or another client is attached.
+    pub fn attach_console(&self, stream: UnixStream) -> Result<()> {
+        self.with_console(|console| console.hand_over(stream))
+    }
+
+    /// Detach the current client of the serial console, if any.
+    pub fn detach_console(&self) -> Result<()> {
+        self.with_console(|console| console.detach())
+    }
+
+    /// The most recent output of the guest on the serial console.
+    pub fn console_log(&self) -> Vec<u8> {
+        self.serial.as_ref().map_or_else(Vec::new, |serial| {
+            serial.lock().unwrap().0.writer().scrollback()
+        })
+    }
+
+    fn with_console<F>(&self, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut Console) -> io::Result<()>,
+    {
+        let serial = self
+            .serial
+            .as_ref()
+            .ok_or_else(|| Error::SerialConsole(io::Error::from(io::ErrorKind::NotFound)))?;
+        f(serial.lock().unwrap().0.writer_mut()).map_err(Error::SerialConsole)
+    }
+
     /// change balloon config
-    pub fn change_balloon_config(&mut self, size:u64) -> bool {
+    pub fn change_balloon_config(&mut self, size: u32) -> bool {
         if self.balloon_devices.is_empty() {
             return false;
         }
@@ -399,6 +1378,22 @@ impl Vmm {
         return true;
     }
 
+    /// The target and actual sizes of the balloon. Returns `None` when the VM has no balloon
+    /// device.
+    pub fn balloon_size(&self) -> Option<BalloonSize> {
+        self.balloon_devices
+            .first()
+            .map(|balloon| balloon.lock().unwrap().size())
+    }
+
+    /// The memory statistics the guest last reported through the balloon device, if any.
+    /// Returns `None` when the VM has no balloon device.
+    pub fn balloon_stats(&self) -> Option<Option<StatsSample>> {
+        self.balloon_devices
+            .first()
+            .map(|balloon| balloon.lock().unwrap().stats())
+    }
+
     // Create guest memory regions.
     fn create_guest_memory(memory_config: &MemoryConfig) -> Result<GuestMemoryMmap> {
         let mem_size = ((memory_config.size_mib as u64) << 20) as usize;
@@ -513,16 +1508,33 @@ impl Vmm {
         .map_err(Error::KernelLoad)
     }
 
-    // Create and add a serial console to the VMM.
-    fn add_serial_console(&mut self,
+    // Create and add a serial console to the VMM, on the backend described by `serial_cfg`.
+    // When `state` is provided, the console starts from that state (i.e. when restoring a
+    // snapshot).
+    fn add_serial_console(
+        &mut self,
         event_mgr: &mut EventManager<Arc<Mutex<dyn MutEventSubscriber + Send>>>,
-        ) -> Result<()> {
+        serial_cfg: &SerialConfig,
+        state: Option<SerialState>,
+    ) -> Result<()> {
+        let console = match serial_cfg {
+            SerialConfig::Stdio => Console::stdio().map_err(Error::SerialConsole)?,
+            SerialConfig::File(path) => Console::file(path).map_err(Error::SerialConsole)?,
+            SerialConfig::Socket(path) => Console::socket(path).map_err(Error::SerialConsole)?,
+            SerialConfig::Pty => Console::pty().map_err(Error::SerialConsole)?,
+        };
+        let backend = console.to_string();
+
         // Create the serial console.
         let interrupt_evt = EventFdTrigger::new(libc::EFD_NONBLOCK).map_err(Error::IO)?;
-        let serial = Arc::new(Mutex::new(SerialWrapper(Serial::new(
-            interrupt_evt.try_clone().map_err(Error::IO)?,
-            stdout(),
-        ))));
+        let trigger = interrupt_evt.try_clone().map_err(Error::IO)?;
+        let serial = match state {
+            Some(state) => {
+                SerialWrapper::from_state(state, trigger, console).map_err(Error::SerialDevice)?
+            }
+            None => SerialWrapper(Serial::new(trigger, console)),
+        };
+        let serial = Arc::new(Mutex::new(serial));
 
         // Register its interrupt fd with KVM.
         self.vm.register_irqfd(&interrupt_evt, SERIAL_IRQ)?;
@@ -549,6 +1561,18 @@ impl Vmm {
                 .unwrap()
                 .register_pio(range, serial.clone())
                 .unwrap();
+            self.devices.push(DeviceDescription {
+                backend: Some(backend),
+                ..DeviceDescription::legacy(
+                    "serial",
+                    Bus::Pio,
+                    AddressRange {
+                        base: 0x3f8,
+                        size: 0x8,
+                    },
+                    Some(SERIAL_IRQ),
+                )
+            });
         }
 
         #[cfg(target_arch = "aarch64")]
@@ -560,6 +1584,18 @@ impl Vmm {
             )?;
             self.fdt_builder
                 .with_serial_console(range.start(), range.len());
+            self.devices.push(DeviceDescription {
+                backend: Some(backend),
+                ..DeviceDescription::legacy(
+                    "serial",
+                    Bus::Mmio,
+                    AddressRange {
+                        base: range.start(),
+                        size: range.len(),
+                    },
+                    Some(SERIAL_IRQ),
+                )
+            });
             let range = mmio_from_range(&range);
             self.device_mgr
                 .lock()
@@ -569,7 +1605,8 @@ impl Vmm {
         }
 
         // Hook it to event management.
-        event_mgr.add_subscriber(serial);
+        event_mgr.add_subscriber(serial.clone());
+        self.serial = Some(serial);
 
         Ok(())
     }
@@ -578,17 +1615,83 @@ impl Vmm {
     #[cfg(target_arch = "x86_64")]
     fn add_i8042_device(&mut self) -> Result<()> {
         let reset_evt = EventFdTrigger::new(libc::EFD_NONBLOCK).map_err(Error::IO)?;
-        let i8042_device = Arc::new(Mutex::new(I8042Wrapper(I8042Device::new(
+        let kbd_evt = EventFdTrigger::new(libc::EFD_NONBLOCK).map_err(Error::IO)?;
+        let i8042_device = Arc::new(Mutex::new(I8042Wrapper::new(
             reset_evt.try_clone().map_err(Error::IO)?,
-        ))));
+            kbd_evt.try_clone().map_err(Error::IO)?,
+        )));
         self.vm.register_irqfd(&reset_evt, 1)?;
+        self.vm.register_irqfd(&kbd_evt, 1)?;
+        // The guest only finds the keyboard when it does not probe what is not emulated.
+        self.kernel_cfg
+            .cmdline
+            .insert_str(I8042_CMDLINE)
+            .map_err(Error::Cmdline)?;
         let range = PioRange::new(PioAddress(0x060), 0x5).unwrap();
 
         self.device_mgr
             .lock()
             .unwrap()
-            .register_pio(range, i8042_device)
+            .register_pio(range, i8042_device.clone())
             .unwrap();
+        self.i8042 = Some(i8042_device);
+        self.devices.push(DeviceDescription::legacy(
+            "i8042",
+            Bus::Pio,
+            AddressRange {
+                base: 0x060,
+                size: 0x5,
+            },
+            Some(1),
+        ));
+        Ok(())
+    }
+
+    // Create and add the device the guest exits the VMM with. It goes after the virtio devices
+    // on aarch64, so their addresses do not depend on it.
+    fn add_debug_exit_device(&mut self) -> Result<()> {
+        let device = Arc::new(Mutex::new(DebugExit::new(self.exit_handler.clone())));
+        #[cfg(target_arch = "x86_64")]
+        {
+            let range = PioRange::new(PioAddress(DEBUG_EXIT_PORT), DEBUG_EXIT_SIZE).unwrap();
+            self.device_mgr
+                .lock()
+                .unwrap()
+                .register_pio(range, device)
+                .unwrap();
+            self.devices.push(DeviceDescription::legacy(
+                "debug_exit",
+                Bus::Pio,
+                AddressRange {
+                    base: DEBUG_EXIT_PORT.into(),
+                    size: DEBUG_EXIT_SIZE.into(),
+                },
+                None,
+            ));
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            let range = self.address_allocator.allocate(
+                0x1000,
+                DEFAULT_ADDRESSS_ALIGNEMNT,
+                DEFAULT_ALLOC_POLICY,
+            )?;
+            self.fdt_builder.with_debug_exit(range.start(), range.len());
+            self.devices.push(DeviceDescription::legacy(
+                "debug_exit",
+                Bus::Mmio,
+                AddressRange {
+                    base: range.start(),
+                    size: range.len(),
+                },
+                None,
+            ));
+            self.device_mgr
+                .lock()
+                .unwrap()
+                .register_mmio(mmio_from_range(&range), device)
+                .unwrap();
+        }
         Ok(())
     }
 
@@ -601,6 +1704,15 @@ impl Vmm {
             DEFAULT_ALLOC_POLICY,
         )?;
         self.fdt_builder.with_rtc(range.start(), range.len());
+        self.devices.push(DeviceDescription::legacy(
+            "rtc",
+            Bus::Mmio,
+            AddressRange {
+                base: range.start(),
+                size: range.len(),
+            },
+            None,
+        ));
         let range = mmio_from_range(&range);
         self.device_mgr
             .lock()
@@ -614,9 +1726,11 @@ impl Vmm {
     // only support a single device. We need to expand this, but it looks like a good match if we
     // can do it after figuring out how to better separate concerns and make the VMM agnostic of
     // the actual device types.
-    fn add_block_device(&mut self, cfg: &BlockConfig,
+    fn add_block_device(
+        &mut self,
+        cfg: &BlockConfig,
         event_mgr: &mut EventManager<Arc<Mutex<dyn MutEventSubscriber + Send>>>,
-        ) -> Result<()> {
+    ) -> Result<()> {
         let mem = Arc::new(self.guest_memory.clone());
         let range = self.address_allocator.allocate(
             0x1000,
@@ -629,6 +1743,8 @@ impl Vmm {
             range: mmio_range,
             gsi: irq,
         };
+        let description =
+            DeviceDescription::virtio("block", &mmio_cfg, Some(cfg.path.display().to_string()));
 
         let mut guard = self.device_mgr.lock().unwrap();
 
@@ -639,12 +1755,14 @@ impl Vmm {
             mmio_mgr: guard.deref_mut(),
             mmio_cfg,
             kernel_cmdline: &mut self.kernel_cfg.cmdline,
+            dirty_pages: self.dirty_pages.clone(),
         };
 
         let args = BlockArgs {
             file_path: PathBuf::from(&cfg.path),
             read_only: false,
-            root_device: true,
+            // The guest boots from the first block device.
+            root_device: self.block_devices.is_empty(),
             advertise_flush: true,
         };
 
@@ -653,14 +1771,18 @@ impl Vmm {
         #[cfg(target_arch = "aarch64")]
         self.fdt_builder
             .add_virtio_device(range.start(), range.len(), irq);
+        self.metrics.block.push(block.lock().unwrap().metrics());
         self.block_devices.push(block);
+        self.devices.push(description);
 
         Ok(())
     }
 
-    fn add_balloon_device(&mut self,
+    fn add_balloon_device(
+        &mut self,
+        cfg: &BalloonConfig,
         event_mgr: &mut EventManager<Arc<Mutex<dyn MutEventSubscriber + Send>>>,
-        ) -> Result<()> {
+    ) -> Result<()> {
         let mem = Arc::new(self.guest_memory.clone());
         let range = self.address_allocator.allocate(
             0x1000,
@@ -673,6 +1795,7 @@ impl Vmm {
             range: mmio_range,
             gsi: irq,
         };
+        let description = DeviceDescription::virtio("balloon", &mmio_cfg, None);
 
         let mut guard = self.device_mgr.lock().unwrap();
 
@@ -683,22 +1806,30 @@ impl Vmm {
             mmio_mgr: guard.deref_mut(),
             mmio_cfg,
             kernel_cmdline: &mut self.kernel_cfg.cmdline,
+            dirty_pages: self.dirty_pages.clone(),
         };
 
         let args = BalloonArgs {
-            guest_memory:self.guest_memory.clone()
+            guest_memory: self.guest_memory.clone(),
+            num_pages: cfg.num_pages(),
+            deflate_on_oom: cfg.deflate_on_oom,
+            stats_polling_interval: Duration::from_secs(cfg.stats_polling_interval_s),
         };
 
         // We can also hold this somewhere if we need to keep the handle for later.
         let balloon = Balloon::new(&mut env, &args).map_err(Error::Balloon)?;
+        self.metrics.balloon.push(balloon.lock().unwrap().metrics());
         self.balloon_devices.push(balloon);
+        self.devices.push(description);
 
         Ok(())
     }
 
-    fn add_net_device(&mut self, cfg: &NetConfig,
+    fn add_net_device(
+        &mut self,
+        cfg: &NetConfig,
         event_mgr: &mut EventManager<Arc<Mutex<dyn MutEventSubscriber + Send>>>,
-        ) -> Result<()> {
+    ) -> Result<()> {
         let mem = Arc::new(self.guest_memory.clone());
         let range = self.address_allocator.allocate(
             0x1000,
@@ -711,6 +1842,7 @@ impl Vmm {
             range: mmio_range,
             gsi: irq,
         };
+        let description = DeviceDescription::virtio("net", &mmio_cfg, Some(cfg.tap_name.clone()));
 
         let mut guard = self.device_mgr.lock().unwrap();
 
@@ -721,6 +1853,7 @@ impl Vmm {
             mmio_mgr: guard.deref_mut(),
             mmio_cfg,
             kernel_cmdline: &mut self.kernel_cfg.cmdline,
+            dirty_pages: self.dirty_pages.clone(),
         };
 
         let args = NetArgs {
@@ -729,7 +1862,9 @@ impl Vmm {
 
         // We can also hold this somewhere if we need to keep the handle for later.
         let net = Net::new(&mut env, &args).map_err(Error::Net)?;
+        self.metrics.net.push(net.lock().unwrap().metrics());
         self.net_devices.push(net);
+        self.devices.push(description);
         #[cfg(target_arch = "aarch64")]
         self.fdt_builder
             .add_virtio_device(range.start(), range.len(), irq);
@@ -873,8 +2008,18 @@ mod tests {
                 size_mib: MEM_SIZE_MIB,
             },
             vcpu_config: VcpuConfig { num: NUM_VCPUS },
-            block_config: None,
-            net_config: None,
+            block_config: vec![],
+            net_config: vec![],
+            balloon_config: vec![],
+            restore_config: None,
+            incoming_config: None,
+            api_config: ApiConfig::default(),
+            metrics_config: None,
+            log_config: LogConfig::default(),
+            serial_config: SerialConfig::Stdio,
+            reboot_policy: RebootPolicy::Exit,
+            debug_exit: false,
+            preboot: false,
         }
     }
 
@@ -882,6 +2027,9 @@ mod tests {
         WrappedExitHandler(Arc::new(Mutex::new(VmmExitHandler {
             keep_running: AtomicBool::default(),
             exit_event: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+            exit_code: 0,
+            reset: false,
+            exiting: false,
         })))
     }
 
@@ -914,10 +2062,24 @@ mod tests {
             address_allocator,
             irq_allocator,
             device_mgr,
+            event_endpoint: EventManager::<Subscriber>::new().unwrap().remote_endpoint(),
+            exit_handler,
+            serial: None,
+            #[cfg(target_arch = "x86_64")]
+            i8042: None,
             kernel_cfg: vmm_config.kernel_config,
             block_devices: Vec::new(),
             net_devices: Vec::new(),
             balloon_devices: Vec::new(),
+            devices: Vec::new(),
+            restored: false,
+            reboot_policy: vmm_config.reboot_policy,
+            metrics: Metrics::default(),
+            metrics_flusher: None,
+            shutdown_handler: None,
+            dirty_pages: Arc::new(DirtyPages::default()),
+            diff_base: None,
+            migration_stream: None,
             #[cfg(target_arch = "aarch64")]
             num_vcpus: vmm_config.vcpu_config.num as u64,
             #[cfg(target_arch = "aarch64")]
@@ -1072,7 +2234,9 @@ mod tests {
         vmm_config.kernel_config.path = default_elf_path();
         let mut vmm = mock_vmm(vmm_config);
         assert_eq!(vmm.kernel_cfg.cmdline.as_str(), DEFAULT_KERNEL_CMDLINE);
-        vmm.add_serial_console().unwrap();
+        let mut event_mgr = EventManager::<Subscriber>::new().unwrap();
+        vmm.add_serial_console(&mut event_mgr, &SerialConfig::Stdio, None)
+            .unwrap();
         #[cfg(target_arch = "x86_64")]
         assert!(vmm.kernel_cfg.cmdline.as_str().contains("console=ttyS0"));
         #[cfg(target_arch = "aarch64")]
@@ -1081,6 +2245,12 @@ mod tests {
             .cmdline
             .as_str()
             .contains("earlycon=uart,mmio"));
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            vmm.add_i8042_device().unwrap();
+            assert!(vmm.kernel_cfg.cmdline.as_str().contains(I8042_CMDLINE));
+        }
     }
 
     #[test]
@@ -1289,4 +2459,90 @@ mod tests {
             .unwrap_err();
         assert_eq!(alloc_err, vm_allocator::Error::ResourceNotAvailable);
     }
+
+    #[test]
+    fn test_exit_handler_reset() {
+        let exit_handler = WrappedExitHandler::new().unwrap();
+        ExitHandler::reset(&exit_handler).unwrap();
+        assert!(exit_handler.restart_requested());
+
+        exit_handler.restarted();
+        assert!(!exit_handler.restart_requested());
+        assert!(exit_handler.keep_running());
+        // The exit event was drained.
+        {
+            let handler = exit_handler.0.lock().unwrap();
+            let err = handler.exit_event.read().unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        }
+
+        // Asking the VMM to exit wins over the reset.
+        ExitHandler::reset(&exit_handler).unwrap();
+        exit_handler.exit(0).unwrap();
+        assert!(!exit_handler.restart_requested());
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_debug_exit() {
+        let mut vmm = mock_vmm(default_vmm_config());
+        vmm.add_debug_exit_device().unwrap();
+        vmm.device_mgr
+            .lock()
+            .unwrap()
+            .pio_write(PioAddress(DEBUG_EXIT_PORT), &[3])
+            .unwrap();
+        assert_eq!(vmm.exit_handler.exit_code(), 7);
+        assert!(!vmm.exit_handler.restart_requested());
+    }
+
+    #[test]
+    fn test_restart_policy() {
+        let mut event_mgr = EventManager::<Subscriber>::new().unwrap();
+        let mut vmm = mock_vmm(default_vmm_config());
+        ExitHandler::reset(&vmm.exit_handler).unwrap();
+        // The default policy is to exit.
+        assert!(!vmm.restart(&mut event_mgr).unwrap());
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_failed_snapshot_keeps_diff_base() {
+        let mut vmm_config = default_vmm_config();
+        vmm_config.memory_config.size_mib = 16;
+        let mut vmm = mock_vmm(vmm_config);
+        // Devices are saved from the event loop.
+        let mut event_mgr = EventManager::<Subscriber>::new().unwrap();
+        vmm.event_endpoint = event_mgr.remote_endpoint();
+        std::thread::spawn(move || loop {
+            event_mgr.run().unwrap();
+        });
+
+        let dir = TempDir::new().unwrap();
+        let path = |name: &str| dir.as_path().join(name);
+        vmm.start_dirty_log(None).unwrap();
+        vmm.snapshot(path("full.state"), path("full.mem"), false)
+            .unwrap();
+
+        // The pages are drained from the log, and the state file cannot be written.
+        vmm.dirty_pages.mark(GuestAddress(0x1000), 1);
+        assert!(vmm
+            .snapshot(path("missing/diff.state"), path("diff.mem"), true)
+            .is_err());
+
+        vmm.snapshot(path("diff.state"), path("diff.mem"), true)
+            .unwrap();
+        let snapshot = Snapshot::load(path("diff.state")).unwrap();
+        assert_eq!(
+            snapshot.parent.unwrap().state_path,
+            fs::canonicalize(path("full.state")).unwrap()
+        );
+        assert_eq!(
+            snapshot.dirty_ranges,
+            vec![snapshot::MemoryRange {
+                base_address: 0x1000,
+                size: DIRTY_PAGE_SIZE,
+            }]
+        );
+    }
 }
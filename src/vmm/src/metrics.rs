@@ -0,0 +1,173 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Metrics of a VM.
+//!
+//! The vCPUs and the virtio devices own their counters and update them as they run. The
+//! [`Metrics`] registry collects the counters as the VM is built, and is reported over the
+//! control API. It can also be appended to a file at a fixed interval, one JSON object per line.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use event_manager::{EventOps, Events, MutEventSubscriber};
+use log::error;
+use serde::ser::{SerializeStruct, Serializer};
+use serde::Serialize;
+use vmm_sys_util::epoll::EventSet;
+use vmm_sys_util::timerfd::TimerFd;
+
+use devices::virtio::metrics::{BalloonMetrics, BlockMetrics, NetMetrics};
+use vm_vcpu::vcpu::VcpuExitMetrics;
+
+use crate::uffd::PageFaultMetrics;
+
+/// Counters of a VM, by vCPU and by device.
+///
+/// Cloning the registry shares the counters.
+#[derive(Clone, Debug, Default)]
+pub struct Metrics {
+    /// Exits of each vCPU.
+    pub vcpu_exits: Vec<Arc<VcpuExitMetrics>>,
+    /// Requests of each block device, in the order of the devices.
+    pub block: Vec<Arc<BlockMetrics>>,
+    /// Frames of each network device, in the order of the devices.
+    pub net: Vec<Arc<NetMetrics>>,
+    /// Pages of each balloon device.
+    pub balloon: Vec<Arc<BalloonMetrics>>,
+    /// Page faults, when guest memory is loaded lazily from a snapshot.
+    pub lazy_restore: Option<Arc<PageFaultMetrics>>,
+}
+
+// Serializes the counters behind the `Arc`s, which serde only supports with its `rc` feature.
+struct Shared<'a, T>(&'a [Arc<T>]);
+
+impl<T: Serialize> Serialize for Shared<'_, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.iter().map(Deref::deref))
+    }
+}
+
+impl Serialize for Metrics {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("Metrics", 5)?;
+        state.serialize_field("vcpu_exits", &Shared(&self.vcpu_exits))?;
+        state.serialize_field("block", &Shared(&self.block))?;
+        state.serialize_field("net", &Shared(&self.net))?;
+        state.serialize_field("balloon", &Shared(&self.balloon))?;
+        state.serialize_field("lazy_restore", &self.lazy_restore.as_deref())?;
+        state.end()
+    }
+}
+
+// Line of the metrics file.
+#[derive(Serialize)]
+struct Record<'a> {
+    // Milliseconds since the Unix epoch.
+    timestamp_ms: u64,
+    #[serde(flatten)]
+    metrics: &'a Metrics,
+}
+
+/// Appends the metrics to a file every time its timer fires.
+pub(crate) struct MetricsFlusher {
+    metrics: Metrics,
+    file: File,
+    timer: TimerFd,
+}
+
+impl MetricsFlusher {
+    /// Opens the file at `path`, and arms the timer to fire every `interval`.
+    pub(crate) fn new(metrics: Metrics, path: &Path, interval: Duration) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let to_io_error = |e: vmm_sys_util::errno::Error| io::Error::from_raw_os_error(e.errno());
+        let mut timer = TimerFd::new().map_err(to_io_error)?;
+        timer.reset(interval, Some(interval)).map_err(to_io_error)?;
+        Ok(MetricsFlusher {
+            metrics,
+            file,
+            timer,
+        })
+    }
+
+    /// Appends the current values of the counters to the file.
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64);
+        let mut line = serde_json::to_vec(&Record {
+            timestamp_ms,
+            metrics: &self.metrics,
+        })?;
+        line.push(b'\n');
+        // A single write, so that readers never see part of a line.
+        self.file.write_all(&line)
+    }
+}
+
+impl MutEventSubscriber for MetricsFlusher {
+    fn process(&mut self, events: Events, ops: &mut EventOps) {
+        if events.event_set().contains(EventSet::IN) {
+            // Only clears the expirations, a late flush does not make up for the missed ones.
+            let _ = self.timer.wait();
+            if let Err(e) = self.flush() {
+                error!("Failed to write the metrics: {}", e);
+            }
+        }
+        if events.event_set().contains(EventSet::ERROR) {
+            let _ = ops.remove(Events::new(&self.timer, EventSet::IN));
+        }
+    }
+
+    fn init(&mut self, ops: &mut EventOps) {
+        ops.add(Events::new(&self.timer, EventSet::IN))
+            .expect("Cannot initialize the metrics timer.");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn test_flush_metrics() {
+        let metrics = Metrics {
+            vcpu_exits: vec![Arc::default(), Arc::default()],
+            block: vec![Arc::default()],
+            ..Default::default()
+        };
+        metrics.vcpu_exits[1].io_out.store(3, Ordering::Relaxed);
+        metrics.block[0].read_bytes.store(512, Ordering::Relaxed);
+
+        let path = env::temp_dir().join(format!("vmm_metrics_{}.json", process::id()));
+        let _ = fs::remove_file(&path);
+        let mut flusher =
+            MetricsFlusher::new(metrics.clone(), &path, Duration::from_secs(60)).unwrap();
+        flusher.flush().unwrap();
+        metrics.block[0].read_bytes.store(1024, Ordering::Relaxed);
+        flusher.flush().unwrap();
+
+        let content = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let lines = content
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0]["timestamp_ms"].as_u64().unwrap() > 0);
+        assert_eq!(lines[0]["vcpu_exits"][1]["io_out"], 3);
+        assert_eq!(lines[0]["block"][0]["read_bytes"], 512);
+        assert_eq!(lines[1]["block"][0]["read_bytes"], 1024);
+        assert_eq!(lines[1]["net"], serde_json::json!([]));
+        assert!(lines[1]["lazy_restore"].is_null());
+    }
+}
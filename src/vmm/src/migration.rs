@@ -0,0 +1,386 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Live migration wire format.
+//!
+//! The source VMM connects to a destination VMM started with `--incoming`, and sends a header
+//! line with the JSON encoded [`Header`]. It then sends frames, each starting with a tag byte:
+//!
+//! - [`PAGES`]: guest physical address and length (both `u64`, little endian), followed by
+//!   the contents of that guest memory range. Pages are sent while the VM keeps running, and
+//!   sent again whenever they are written in the meantime; the last copy wins.
+//! - [`STATE`]: length (`u64`, little endian) followed by the JSON encoded [`Snapshot`] of the
+//!   paused VM. No more pages follow.
+//! - [`RELEASED`]: the source has closed the host resources the destination needs to open,
+//!   such as tap devices.
+//!
+//! The destination answers [`STATE`] with a single [`READY`] byte, once it holds all of guest
+//! memory and the state. The source closes the connection when it exits, which is when the
+//! destination resumes the VM.
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+
+use serde::{Deserialize, Serialize};
+use vm_memory::{Bytes, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+
+use crate::config::MigrationAddress;
+use crate::snapshot::{self, MemoryRegionState, Snapshot};
+
+/// Version of the migration protocol spoken by this VMM.
+pub const MIGRATION_VERSION: u32 = 1;
+
+/// Frame holding a range of guest memory.
+pub const PAGES: u8 = 1;
+/// Frame holding the VM and device state.
+pub const STATE: u8 = 2;
+/// Sent by the destination once it received the state.
+pub const READY: u8 = 3;
+/// Sent by the source once it released the host resources of the VM.
+pub const RELEASED: u8 = 4;
+
+/// Errors encountered while migrating a VM.
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to connect to the destination.
+    Connect(io::Error),
+    /// Failed to listen for the source.
+    Listen(io::Error),
+    /// Failed to accept the connection from the source.
+    Accept(io::Error),
+    /// Failed to send or receive data.
+    IO(io::Error),
+    /// Failed to access guest memory.
+    Memory(vm_memory::GuestMemoryError),
+    /// Failed to serialize the header or the state.
+    Serialize(serde_json::Error),
+    /// Failed to parse the header or the state.
+    Deserialize(serde_json::Error),
+    /// The peer speaks an incompatible protocol version.
+    UnsupportedVersion(u32),
+    /// The peer sent an unexpected tag.
+    UnexpectedTag(u8),
+    /// The source sent pages outside of the guest memory layout.
+    InvalidRange(u64, u64),
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// First line sent by the source.
+#[derive(Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct Header {
+    /// Version of the migration protocol.
+    pub version: u32,
+    /// Layout of guest memory, which the destination allocates upfront.
+    pub memory: Vec<MemoryRegionState>,
+}
+
+/// Connection between the source and the destination VMMs.
+#[derive(Debug)]
+pub enum Stream {
+    /// Unix domain socket connection.
+    Unix(UnixStream),
+    /// TCP connection.
+    Tcp(TcpStream),
+}
+
+impl Stream {
+    /// Returns a new handle to the same connection.
+    pub fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Unix(stream) => stream.try_clone().map(Stream::Unix),
+            Stream::Tcp(stream) => stream.try_clone().map(Stream::Tcp),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Unix(stream) => stream.read(buf),
+            Stream::Tcp(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Unix(stream) => stream.write(buf),
+            Stream::Tcp(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Unix(stream) => stream.flush(),
+            Stream::Tcp(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Connects to the destination VMM listening on `addr`.
+pub fn connect(addr: &MigrationAddress) -> Result<Stream> {
+    match addr {
+        MigrationAddress::Unix(path) => UnixStream::connect(path).map(Stream::Unix),
+        MigrationAddress::Tcp(addr) => TcpStream::connect(addr).map(|stream| {
+            // Do not hold back the single byte frames.
+            let _ = stream.set_nodelay(true);
+            Stream::Tcp(stream)
+        }),
+    }
+    .map_err(Error::Connect)
+}
+
+/// Waits for a single source VMM to connect on `addr`.
+pub fn accept(addr: &MigrationAddress) -> Result<Stream> {
+    match addr {
+        MigrationAddress::Unix(path) => {
+            let listener = UnixListener::bind(path).map_err(Error::Listen)?;
+            let stream = listener.accept().map_err(Error::Accept)?.0;
+            // Nobody else is going to connect.
+            let _ = fs::remove_file(path);
+            Ok(Stream::Unix(stream))
+        }
+        MigrationAddress::Tcp(addr) => {
+            let listener = TcpListener::bind(addr).map_err(Error::Listen)?;
+            let stream = listener.accept().map_err(Error::Accept)?.0;
+            let _ = stream.set_nodelay(true);
+            Ok(Stream::Tcp(stream))
+        }
+    }
+}
+
+/// Returns the layout of `guest_memory`, as sent in the header.
+pub fn memory_layout(guest_memory: &GuestMemoryMmap) -> Vec<MemoryRegionState> {
+    let mut offset = 0;
+    guest_memory
+        .iter()
+        .map(|region| {
+            let state = MemoryRegionState {
+                base_address: region.start_addr().0,
+                size: region.len(),
+                offset,
+            };
+            offset += region.len();
+            state
+        })
+        .collect()
+}
+
+/// Sends the header describing `memory`.
+pub fn send_header<W: Write>(stream: &mut W, memory: Vec<MemoryRegionState>) -> Result<()> {
+    let header = Header {
+        version: MIGRATION_VERSION,
+        memory,
+    };
+    let mut line = serde_json::to_vec(&header).map_err(Error::Serialize)?;
+    line.push(b'\n');
+    stream.write_all(&line).map_err(Error::IO)
+}
+
+/// Receives the header, and checks that the protocol version is supported.
+///
+/// The header is read byte by byte, so that nothing past it is consumed from `stream`.
+pub fn recv_header<R: Read>(stream: &mut R) -> Result<Header> {
+    let mut line = Vec::new();
+    BufReader::with_capacity(1, stream)
+        .read_until(b'\n', &mut line)
+        .map_err(Error::IO)?;
+    let header: Header = serde_json::from_slice(&line).map_err(Error::Deserialize)?;
+    if header.version != MIGRATION_VERSION {
+        return Err(Error::UnsupportedVersion(header.version));
+    }
+    Ok(header)
+}
+
+/// Sends a frame without payload, or an acknowledgement.
+pub fn send_tag<W: Write>(stream: &mut W, tag: u8) -> Result<()> {
+    stream.write_all(&[tag]).map_err(Error::IO)
+}
+
+/// Waits for the peer to send `tag`.
+pub fn expect_tag<R: Read>(stream: &mut R, tag: u8) -> Result<()> {
+    match read_tag(stream)? {
+        t if t == tag => Ok(()),
+        t => Err(Error::UnexpectedTag(t)),
+    }
+}
+
+/// Waits for the peer to close the connection.
+pub fn wait_for_close<R: Read>(stream: &mut R) -> Result<()> {
+    let mut tag = [0u8; 1];
+    match stream.read(&mut tag).map_err(Error::IO)? {
+        0 => Ok(()),
+        _ => Err(Error::UnexpectedTag(tag[0])),
+    }
+}
+
+fn read_tag<R: Read>(stream: &mut R) -> Result<u8> {
+    let mut tag = [0u8; 1];
+    stream.read_exact(&mut tag).map_err(Error::IO)?;
+    Ok(tag[0])
+}
+
+fn read_u64<R: Read>(stream: &mut R) -> Result<u64> {
+    let mut bytes = [0u8; 8];
+    stream.read_exact(&mut bytes).map_err(Error::IO)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Sends `len` bytes of guest memory starting at `addr`.
+pub fn send_range<W: Write>(
+    stream: &mut W,
+    guest_memory: &GuestMemoryMmap,
+    addr: u64,
+    len: u64,
+) -> Result<()> {
+    let mut frame = [0u8; 17];
+    frame[0] = PAGES;
+    frame[1..9].copy_from_slice(&addr.to_le_bytes());
+    frame[9..17].copy_from_slice(&len.to_le_bytes());
+    stream.write_all(&frame).map_err(Error::IO)?;
+    guest_memory
+        .write_all_to(GuestAddress(addr), stream, len as usize)
+        .map_err(Error::Memory)
+}
+
+/// Sends all of guest memory.
+pub fn send_memory<W: Write>(stream: &mut W, guest_memory: &GuestMemoryMmap) -> Result<()> {
+    for region in guest_memory.iter() {
+        send_range(stream, guest_memory, region.start_addr().0, region.len())?;
+    }
+    Ok(())
+}
+
+/// Sends the pages with the page frame numbers in `pfns`, which must be sorted.
+///
+/// Contiguous pages are sent in a single frame.
+pub fn send_pages<W: Write>(
+    stream: &mut W,
+    guest_memory: &GuestMemoryMmap,
+    pfns: &[u64],
+) -> Result<()> {
+    for range in snapshot::page_ranges(pfns) {
+        send_range(stream, guest_memory, range.base_address, range.size)?;
+    }
+    Ok(())
+}
+
+/// Sends the state of the paused VM. This is the last frame holding guest data.
+pub fn send_state<W: Write>(stream: &mut W, snapshot: &Snapshot) -> Result<()> {
+    let state = serde_json::to_vec(snapshot).map_err(Error::Serialize)?;
+    stream.write_all(&[STATE]).map_err(Error::IO)?;
+    stream
+        .write_all(&(state.len() as u64).to_le_bytes())
+        .map_err(Error::IO)?;
+    stream.write_all(&state).map_err(Error::IO)?;
+    stream.flush().map_err(Error::IO)
+}
+
+/// Receives guest memory into `guest_memory`, until the source sends the state of the VM.
+pub fn recv_memory<R: Read>(stream: &mut R, guest_memory: &GuestMemoryMmap) -> Result<Snapshot> {
+    loop {
+        match read_tag(stream)? {
+            PAGES => {
+                let addr = read_u64(stream)?;
+                let len = read_u64(stream)?;
+                // Check the whole range upfront, so that a bogus frame does not leave the
+                // stream in the middle of the pages.
+                if !guest_memory.check_range(GuestAddress(addr), len as usize) {
+                    return Err(Error::InvalidRange(addr, len));
+                }
+                guest_memory
+                    .read_exact_from(GuestAddress(addr), stream, len as usize)
+                    .map_err(Error::Memory)?;
+            }
+            STATE => {
+                let len = read_u64(stream)?;
+                let mut state = Vec::new();
+                stream
+                    .by_ref()
+                    .take(len)
+                    .read_to_end(&mut state)
+                    .map_err(Error::IO)?;
+                return serde_json::from_slice(&state).map_err(Error::Deserialize);
+            }
+            tag => return Err(Error::UnexpectedTag(tag)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    #[test]
+    fn test_header() {
+        let memory = vec![MemoryRegionState {
+            base_address: 0,
+            size: 0x2000,
+            offset: 0,
+        }];
+        let mut buf = Vec::new();
+        send_header(&mut buf, memory.clone()).unwrap();
+        send_tag(&mut buf, RELEASED).unwrap();
+
+        let mut stream = Cursor::new(buf);
+        assert_eq!(recv_header(&mut stream).unwrap().memory, memory);
+        // Only the header line is consumed.
+        expect_tag(&mut stream, RELEASED).unwrap();
+        wait_for_close(&mut stream).unwrap();
+
+        let mut stream = Cursor::new(b"{\"version\":2,\"memory\":[]}\n".to_vec());
+        assert!(matches!(
+            recv_header(&mut stream),
+            Err(Error::UnsupportedVersion(2))
+        ));
+    }
+
+    #[test]
+    fn test_memory() {
+        let ranges = [(GuestAddress(0), 0x4000), (GuestAddress(0x10_0000), 0x2000)];
+        let source = GuestMemoryMmap::from_ranges(&ranges).unwrap();
+        let destination = GuestMemoryMmap::from_ranges(&ranges).unwrap();
+
+        let mut buf = Vec::new();
+        source.write_obj(0xaa_u8, GuestAddress(0x10_1fff)).unwrap();
+        send_memory(&mut buf, &source).unwrap();
+        // Pages written after the first copy are sent again.
+        source.write_obj(0xbb_u8, GuestAddress(0x1000)).unwrap();
+        source.write_obj(0xcc_u8, GuestAddress(0x2fff)).unwrap();
+        send_pages(&mut buf, &source, &[1, 2]).unwrap();
+        buf.push(STATE);
+
+        let mut stream = Cursor::new(buf);
+        // The state frame is truncated, but guest memory is complete by then.
+        assert!(recv_memory(&mut stream, &destination).is_err());
+        assert_eq!(
+            destination.read_obj::<u8>(GuestAddress(0x10_1fff)).unwrap(),
+            0xaa
+        );
+        assert_eq!(
+            destination.read_obj::<u8>(GuestAddress(0x1000)).unwrap(),
+            0xbb
+        );
+        assert_eq!(
+            destination.read_obj::<u8>(GuestAddress(0x2fff)).unwrap(),
+            0xcc
+        );
+
+        // Pages outside of guest memory are rejected.
+        let mut buf = Vec::new();
+        buf.push(PAGES);
+        buf.extend_from_slice(&0x4000_u64.to_le_bytes());
+        buf.extend_from_slice(&0x1000_u64.to_le_bytes());
+        assert!(matches!(
+            recv_memory(&mut Cursor::new(buf), &destination),
+            Err(Error::InvalidRange(0x4000, 0x1000))
+        ));
+    }
+}
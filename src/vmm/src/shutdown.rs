@@ -0,0 +1,271 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Graceful shutdown on SIGTERM and SIGINT, and on request of the control socket.
+//!
+//! The signal handler only wakes the event manager loop up. There, the guest is asked to shut
+//! down, and gets [`SHUTDOWN_TIMEOUT`] to do so before the VMM stops its vCPUs. A second signal
+//! stops them right away. Either way, the loop then exits and the VMM is torn down by
+//! [`Vmm::shutdown`](crate::Vmm::shutdown). A client asking for the shutdown chooses how long
+//! the guest gets, if it is to be stopped at all.
+
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+#[cfg(target_arch = "x86_64")]
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use event_manager::{EventOps, Events, MutEventSubscriber};
+use libc::{c_int, c_void, siginfo_t};
+use log::{error, info, warn};
+use vmm_sys_util::epoll::EventSet;
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::signal::register_signal_handler;
+use vmm_sys_util::timerfd::TimerFd;
+
+#[cfg(target_arch = "x86_64")]
+use devices::legacy::I8042Wrapper;
+
+use crate::WrappedExitHandler;
+
+/// Time the guest gets to shut down after a signal.
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Signals which shut the VMM down.
+pub const SIGNALS: [c_int; 2] = [libc::SIGTERM, libc::SIGINT];
+
+// Used by the signal handler, which can only run async-signal-safe code.
+static SIGNAL_EVENT_FD: AtomicI32 = AtomicI32::new(-1);
+static LAST_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn handle_signal(num: c_int, _: *mut siginfo_t, _: *mut c_void) {
+    LAST_SIGNAL.store(num, Ordering::SeqCst);
+    let value = 1u64;
+    // Safe because `value` outlives the call, which reads 8 bytes from it. The result is
+    // ignored: a write only fails if the event counter is about to overflow, in which case the
+    // loop is woken up anyway.
+    unsafe {
+        libc::write(
+            SIGNAL_EVENT_FD.load(Ordering::SeqCst),
+            &value as *const u64 as *const c_void,
+            8,
+        )
+    };
+}
+
+fn to_io_error(e: vmm_sys_util::errno::Error) -> io::Error {
+    io::Error::from_raw_os_error(e.errno())
+}
+
+/// Turns the termination signals into a shutdown of the guest, on the event manager loop.
+pub(crate) struct ShutdownHandler {
+    signal_evt: EventFd,
+    timer: TimerFd,
+    exit_handler: WrappedExitHandler,
+    // Gets Ctrl-Alt-Del, when the VM has one.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) i8042: Option<Arc<Mutex<I8042Wrapper>>>,
+    // First signal received.
+    signal: Option<c_int>,
+}
+
+impl ShutdownHandler {
+    /// Creates a handler which stops the loop with `exit_handler`.
+    pub(crate) fn new(exit_handler: WrappedExitHandler) -> io::Result<Self> {
+        Ok(ShutdownHandler {
+            signal_evt: EventFd::new(libc::EFD_NONBLOCK)?,
+            timer: TimerFd::new().map_err(to_io_error)?,
+            exit_handler,
+            #[cfg(target_arch = "x86_64")]
+            i8042: None,
+            signal: None,
+        })
+    }
+
+    /// Installs the handler of [`SIGNALS`] for the whole process, which wakes this handler up.
+    pub(crate) fn register_signal_handlers(&self) -> io::Result<()> {
+        SIGNAL_EVENT_FD.store(self.signal_evt.as_raw_fd(), Ordering::SeqCst);
+        for signal in SIGNALS.iter() {
+            register_signal_handler(*signal, handle_signal).map_err(to_io_error)?;
+        }
+        Ok(())
+    }
+
+    /// Asks the guest to shut down, and stops it if it did not within `timeout`, when given.
+    /// Returns false when the VM has no way of asking the guest.
+    pub(crate) fn request(&mut self, timeout: Option<Duration>) -> io::Result<bool> {
+        if !self.ask_guest() {
+            return Ok(false);
+        }
+        if let Some(timeout) = timeout {
+            self.timer.reset(timeout, None).map_err(to_io_error)?;
+        }
+        Ok(true)
+    }
+
+    // Asks the guest to shut down. Returns false when the VM has no way of asking it.
+    fn ask_guest(&self) -> bool {
+        #[cfg(target_arch = "x86_64")]
+        if let Some(i8042) = self.i8042.as_ref() {
+            match i8042.lock().unwrap().trigger_ctrl_alt_del() {
+                Ok(()) => {
+                    // The guest may well shut down by rebooting, which must not restart it.
+                    self.exit_handler.exit_on_reset();
+                    return true;
+                }
+                Err(e) => error!("Failed to press Ctrl-Alt-Del: {}", e),
+            }
+        }
+        false
+    }
+
+    // Stops the loop, for the process to exit with `code`.
+    fn stop(&self, code: i32) {
+        if let Err(e) = self.exit_handler.exit(code) {
+            error!("Failed to stop the event loop: {}", e);
+        }
+    }
+}
+
+impl MutEventSubscriber for ShutdownHandler {
+    fn process(&mut self, events: Events, ops: &mut EventOps) {
+        if events.event_set().contains(EventSet::ERROR) {
+            let _ = ops.remove(events);
+            return;
+        }
+
+        if events.fd() == self.timer.as_raw_fd() {
+            let _ = self.timer.wait();
+            warn!("The guest did not shut down in time, stopping it.");
+            // As if killed by the signal, if there was one.
+            self.stop(self.signal.map_or(0, |signal| 128 + signal));
+            return;
+        }
+
+        let _ = self.signal_evt.read();
+        let signal = LAST_SIGNAL.load(Ordering::SeqCst);
+        if self.signal.is_some() {
+            info!("Received signal {} again, stopping the guest.", signal);
+            self.stop(128 + signal);
+            return;
+        }
+        self.signal = Some(signal);
+        if !self.ask_guest() {
+            info!("Received signal {}, stopping the guest.", signal);
+            self.stop(128 + signal);
+            return;
+        }
+        info!("Received signal {}, asking the guest to shut down.", signal);
+        if let Err(e) = self.timer.reset(SHUTDOWN_TIMEOUT, None) {
+            error!("Failed to arm the shutdown timer: {}", e);
+            self.stop(128 + signal);
+        }
+    }
+
+    fn init(&mut self, ops: &mut EventOps) {
+        ops.add(Events::new(&self.signal_evt, EventSet::IN))
+            .expect("Cannot initialize the signal event.");
+        ops.add(Events::new(&self.timer, EventSet::IN))
+            .expect("Cannot initialize the shutdown timer.");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    use event_manager::{EventManager, SubscriberOps};
+    use vm_vcpu::vm::ExitHandler;
+
+    type Subscriber = Arc<Mutex<dyn MutEventSubscriber + Send>>;
+
+    // Tests run in parallel, and all see `LAST_SIGNAL`: they only use SIGTERM.
+    fn signal(event_mgr: &mut EventManager<Subscriber>, signal_evt: &EventFd, num: c_int) {
+        LAST_SIGNAL.store(num, Ordering::SeqCst);
+        signal_evt.write(1).unwrap();
+        // Once for the signal, once for the exit event.
+        event_mgr.run_with_timeout(100).unwrap();
+        event_mgr.run_with_timeout(100).unwrap();
+    }
+
+    #[test]
+    fn test_stop_guest() {
+        let exit_handler = WrappedExitHandler::new().unwrap();
+        let mut event_mgr = EventManager::<Subscriber>::new().unwrap();
+        event_mgr.add_subscriber(exit_handler.0.clone());
+        let handler = ShutdownHandler::new(exit_handler.clone()).unwrap();
+        let signal_evt = handler.signal_evt.try_clone().unwrap();
+        event_mgr.add_subscriber(Arc::new(Mutex::new(handler)));
+
+        // Nothing asks the guest to shut down, so it is stopped right away.
+        signal(&mut event_mgr, &signal_evt, libc::SIGTERM);
+        assert!(!exit_handler.keep_running());
+        assert_eq!(exit_handler.exit_code(), 128 + libc::SIGTERM);
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_ask_guest() {
+        use devices::legacy::EventFdTrigger;
+
+        let exit_handler = WrappedExitHandler::new().unwrap();
+        let mut event_mgr = EventManager::<Subscriber>::new().unwrap();
+        event_mgr.add_subscriber(exit_handler.0.clone());
+        let kbd_evt = EventFdTrigger::new(libc::EFD_NONBLOCK).unwrap();
+        let mut handler = ShutdownHandler::new(exit_handler.clone()).unwrap();
+        handler.i8042 = Some(Arc::new(Mutex::new(I8042Wrapper::new(
+            EventFdTrigger::new(libc::EFD_NONBLOCK).unwrap(),
+            kbd_evt.try_clone().unwrap(),
+        ))));
+        let signal_evt = handler.signal_evt.try_clone().unwrap();
+        event_mgr.add_subscriber(Arc::new(Mutex::new(handler)));
+
+        // The guest gets Ctrl-Alt-Del, and time to shut down.
+        signal(&mut event_mgr, &signal_evt, libc::SIGTERM);
+        assert_eq!(kbd_evt.read().unwrap(), 1);
+        assert!(exit_handler.keep_running());
+        // Rebooting does not restart it.
+        ExitHandler::reset(&exit_handler).unwrap();
+        assert!(!exit_handler.restart_requested());
+
+        // Unless it is asked again.
+        signal(&mut event_mgr, &signal_evt, libc::SIGTERM);
+        assert!(!exit_handler.keep_running());
+        assert_eq!(exit_handler.exit_code(), 128 + libc::SIGTERM);
+    }
+
+    #[test]
+    fn test_request() {
+        let exit_handler = WrappedExitHandler::new().unwrap();
+        let mut event_mgr = EventManager::<Subscriber>::new().unwrap();
+        event_mgr.add_subscriber(exit_handler.0.clone());
+
+        // Nothing asks the guest to shut down.
+        #[allow(unused_mut)]
+        let mut handler = ShutdownHandler::new(exit_handler.clone()).unwrap();
+        assert!(!handler.request(None).unwrap());
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            use devices::legacy::EventFdTrigger;
+
+            let kbd_evt = EventFdTrigger::new(libc::EFD_NONBLOCK).unwrap();
+            handler.i8042 = Some(Arc::new(Mutex::new(I8042Wrapper::new(
+                EventFdTrigger::new(libc::EFD_NONBLOCK).unwrap(),
+                kbd_evt.try_clone().unwrap(),
+            ))));
+            assert!(handler.request(Some(Duration::from_millis(10))).unwrap());
+            assert_eq!(kbd_evt.read().unwrap(), 1);
+            event_mgr.add_subscriber(Arc::new(Mutex::new(handler)));
+
+            // The guest does not shut down, so it is stopped once the timer fires.
+            event_mgr.run_with_timeout(1000).unwrap();
+            event_mgr.run_with_timeout(100).unwrap();
+            assert!(!exit_handler.keep_running());
+            assert_eq!(exit_handler.exit_code(), 0);
+        }
+    }
+}
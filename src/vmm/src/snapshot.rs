@@ -0,0 +1,469 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Snapshot file format.
+//!
+//! A snapshot is made of two files. The state file is a JSON document holding the vCPU, VM and
+//! device state, along with the layout of guest memory. The memory file holds the contents of
+//! all guest memory regions, one after the other, in the order they are listed in the state file.
+//!
+//! A diff snapshot only saves the guest pages written since its parent snapshot was taken. Its
+//! memory file has the same layout as a full one, but is sparse: only the ranges listed in the
+//! state file hold data. Restoring a diff snapshot loads the full snapshot at the root of the
+//! chain, then applies the diffs down to the requested one.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use vm_memory::{Bytes, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+
+use devices::legacy::SerialState;
+use devices::virtio::dirty::DIRTY_PAGE_SIZE;
+use devices::virtio::persist::{BalloonState, BlockState, NetState};
+use vm_vcpu::vm::VmState;
+
+/// Version of the snapshot format written by this VMM.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// Errors encountered while saving or loading a snapshot.
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to create guest memory.
+    CreateMemory(vm_memory::Error),
+    /// Failed to parse the state file.
+    Deserialize(serde_json::Error),
+    /// Failed to access the state or memory file.
+    IO(io::Error),
+    /// Failed to save or load guest memory.
+    Memory(vm_memory::GuestMemoryError),
+    /// Failed to serialize the state.
+    Serialize(serde_json::Error),
+    /// The state file was written by an incompatible VMM.
+    UnsupportedVersion(u64),
+    /// A snapshot of the chain has a different guest memory layout than its child.
+    ParentMismatch(PathBuf),
+    /// The chain of diff snapshots loops back on itself.
+    ParentCycle(PathBuf),
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Guest memory region saved in the memory file.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct MemoryRegionState {
+    /// Guest physical address of the region.
+    pub base_address: u64,
+    /// Size of the region in bytes.
+    pub size: u64,
+    /// Offset of the region contents in the memory file.
+    pub offset: u64,
+}
+
+/// Range of guest memory saved in the memory file of a diff snapshot.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct MemoryRange {
+    /// Guest physical address of the range.
+    pub base_address: u64,
+    /// Size of the range in bytes.
+    pub size: u64,
+}
+
+/// Snapshot a diff snapshot applies on top of.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct ParentSnapshot {
+    /// Path to the state file of the parent.
+    pub state_path: PathBuf,
+    /// Path to the memory file of the parent.
+    pub mem_path: PathBuf,
+}
+
+/// State of the devices attached to the VM.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct DevicesState {
+    /// Serial console state.
+    pub serial: Option<SerialState>,
+    /// Virtio block devices state.
+    pub block: Vec<BlockState>,
+    /// Virtio net devices state.
+    pub net: Vec<NetState>,
+    /// Virtio balloon devices state.
+    pub balloon: Vec<BalloonState>,
+}
+
+/// Contents of the snapshot state file.
+#[derive(Deserialize, Serialize)]
+pub struct Snapshot {
+    /// Version of the snapshot format.
+    pub version: u32,
+    /// VM and vCPU state.
+    pub vm: VmState,
+    /// Layout of the memory file.
+    pub memory: Vec<MemoryRegionState>,
+    /// Device state.
+    pub devices: DevicesState,
+    /// Parent of a diff snapshot. Full snapshots have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<ParentSnapshot>,
+    /// Ranges saved in the memory file of a diff snapshot.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dirty_ranges: Vec<MemoryRange>,
+}
+
+impl Snapshot {
+    /// Writes the snapshot to the state file at `path`.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path).map_err(Error::IO)?);
+        serde_json::to_writer(&mut writer, self).map_err(Error::Serialize)?;
+        writer.flush().map_err(Error::IO)?;
+        writer.get_ref().sync_all().map_err(Error::IO)
+    }
+
+    /// Reads a snapshot from the state file at `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let reader = BufReader::new(File::open(path).map_err(Error::IO)?);
+        let value: Value = serde_json::from_reader(reader).map_err(Error::Deserialize)?;
+
+        // Check the version first, so that newer formats are reported as such instead of
+        // failing on whichever field changed.
+        let version = value.get("version").and_then(Value::as_u64).unwrap_or(0);
+        if version != u64::from(SNAPSHOT_VERSION) {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        serde_json::from_value(value).map_err(Error::Deserialize)
+    }
+}
+
+/// Writes the contents of `guest_memory` to `file`, and returns the resulting layout.
+pub fn save_memory(
+    guest_memory: &GuestMemoryMmap,
+    file: &mut File,
+) -> Result<Vec<MemoryRegionState>> {
+    let mut offset = 0;
+    let mut regions = Vec::new();
+    for region in guest_memory.iter() {
+        let size = region.len();
+        guest_memory
+            .write_all_to(region.start_addr(), file, size as usize)
+            .map_err(Error::Memory)?;
+        regions.push(MemoryRegionState {
+            base_address: region.start_addr().0,
+            size,
+            offset,
+        });
+        offset += size;
+    }
+    file.sync_all().map_err(Error::IO)?;
+    Ok(regions)
+}
+
+/// Coalesces sorted page frame numbers into contiguous ranges of guest memory.
+pub fn page_ranges(pfns: &[u64]) -> Vec<MemoryRange> {
+    let mut ranges: Vec<MemoryRange> = Vec::new();
+    for &pfn in pfns {
+        let base_address = pfn * DIRTY_PAGE_SIZE;
+        match ranges.last_mut() {
+            Some(range) if range.base_address + range.size == base_address => {
+                range.size += DIRTY_PAGE_SIZE
+            }
+            _ => ranges.push(MemoryRange {
+                base_address,
+                size: DIRTY_PAGE_SIZE,
+            }),
+        }
+    }
+    ranges
+}
+
+// Offset of the guest memory at `addr` in a memory file with the layout in `regions`.
+fn file_offset(regions: &[MemoryRegionState], addr: u64) -> Option<u64> {
+    regions
+        .iter()
+        .find(|region| addr >= region.base_address && addr - region.base_address < region.size)
+        .map(|region| region.offset + addr - region.base_address)
+}
+
+/// Writes the `ranges` of `guest_memory` to `file`, at the same offsets as `save_memory`
+/// would. The rest of the file is left as a hole. Returns the layout of the file.
+pub fn save_memory_ranges(
+    guest_memory: &GuestMemoryMmap,
+    file: &mut File,
+    ranges: &[MemoryRange],
+) -> Result<Vec<MemoryRegionState>> {
+    let mut offset = 0;
+    let mut regions = Vec::new();
+    for region in guest_memory.iter() {
+        regions.push(MemoryRegionState {
+            base_address: region.start_addr().0,
+            size: region.len(),
+            offset,
+        });
+        offset += region.len();
+    }
+    file.set_len(offset).map_err(Error::IO)?;
+
+    for range in ranges {
+        let offset =
+            file_offset(&regions, range.base_address).ok_or_else(|| invalid_range(range))?;
+        file.seek(SeekFrom::Start(offset)).map_err(Error::IO)?;
+        guest_memory
+            .write_all_to(GuestAddress(range.base_address), file, range.size as usize)
+            .map_err(Error::Memory)?;
+    }
+    file.sync_all().map_err(Error::IO)?;
+    Ok(regions)
+}
+
+/// Creates empty guest memory with the layout in `regions`.
+pub fn create_memory(regions: &[MemoryRegionState]) -> Result<GuestMemoryMmap> {
+    let ranges = regions
+        .iter()
+        .map(|region| (GuestAddress(region.base_address), region.size as usize))
+        .collect::<Vec<_>>();
+    GuestMemoryMmap::from_ranges(&ranges).map_err(Error::CreateMemory)
+}
+
+/// Creates guest memory with the layout in `regions`, and fills it from `file`.
+pub fn restore_memory(regions: &[MemoryRegionState], file: &mut File) -> Result<GuestMemoryMmap> {
+    let guest_memory = create_memory(regions)?;
+
+    for region in regions {
+        file.seek(SeekFrom::Start(region.offset))
+            .map_err(Error::IO)?;
+        guest_memory
+            .read_exact_from(
+                GuestAddress(region.base_address),
+                file,
+                region.size as usize,
+            )
+            .map_err(Error::Memory)?;
+    }
+    Ok(guest_memory)
+}
+
+/// Creates guest memory with the layout of `snapshot`, and fills it from `file`, its memory
+/// file.
+///
+/// For a diff snapshot, the full snapshot at the root of the chain is loaded first, then the
+/// ranges saved by each diff are applied, from the oldest to `snapshot`.
+pub fn restore_memory_chain(snapshot: &Snapshot, file: &mut File) -> Result<GuestMemoryMmap> {
+    // Walk up to the full snapshot at the root of the chain. Each entry holds the ranges saved
+    // by a snapshot, and the path of its memory file (none for `file`).
+    let mut chain = vec![(snapshot.dirty_ranges.clone(), None)];
+    let mut visited = HashSet::new();
+    let mut parent = snapshot.parent.clone();
+    while let Some(p) = parent {
+        if !visited.insert(p.state_path.clone()) {
+            return Err(Error::ParentCycle(p.state_path));
+        }
+        let parent_snapshot = Snapshot::load(&p.state_path)?;
+        if parent_snapshot.memory != snapshot.memory {
+            return Err(Error::ParentMismatch(p.state_path));
+        }
+        chain.push((parent_snapshot.dirty_ranges, Some(p.mem_path)));
+        parent = parent_snapshot.parent;
+    }
+
+    let guest_memory = match chain.pop() {
+        Some((_, Some(root_path))) => {
+            let mut root = File::open(root_path).map_err(Error::IO)?;
+            restore_memory(&snapshot.memory, &mut root)?
+        }
+        // Not a diff snapshot.
+        _ => return restore_memory(&snapshot.memory, file),
+    };
+    for (ranges, mem_path) in chain.iter().rev() {
+        let mut diff_file = match mem_path {
+            Some(path) => File::open(path),
+            None => file.try_clone(),
+        }
+        .map_err(Error::IO)?;
+        apply_ranges(&guest_memory, &snapshot.memory, ranges, &mut diff_file)?;
+    }
+    Ok(guest_memory)
+}
+
+// Loads the `ranges` saved in the memory file of a diff snapshot into `guest_memory`.
+fn apply_ranges(
+    guest_memory: &GuestMemoryMmap,
+    regions: &[MemoryRegionState],
+    ranges: &[MemoryRange],
+    file: &mut File,
+) -> Result<()> {
+    for range in ranges {
+        let offset =
+            file_offset(regions, range.base_address).ok_or_else(|| invalid_range(range))?;
+        file.seek(SeekFrom::Start(offset)).map_err(Error::IO)?;
+        guest_memory
+            .read_exact_from(GuestAddress(range.base_address), file, range.size as usize)
+            .map_err(Error::Memory)?;
+    }
+    Ok(())
+}
+
+fn invalid_range(range: &MemoryRange) -> Error {
+    Error::Memory(vm_memory::GuestMemoryError::InvalidGuestAddress(
+        GuestAddress(range.base_address),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use vmm_sys_util::tempfile::TempFile;
+
+    use super::*;
+
+    #[test]
+    fn test_save_memory() {
+        let guest_memory = GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), 0x1000),
+            (GuestAddress(0x10_0000), 0x2000),
+        ])
+        .unwrap();
+        guest_memory
+            .write_obj(0xaa_u8, GuestAddress(0xfff))
+            .unwrap();
+        guest_memory
+            .write_obj(0xbb_u8, GuestAddress(0x10_0000))
+            .unwrap();
+
+        let tmp = TempFile::new().unwrap();
+        let mut file = tmp.as_file().try_clone().unwrap();
+        let regions = save_memory(&guest_memory, &mut file).unwrap();
+        assert_eq!(
+            regions,
+            vec![
+                MemoryRegionState {
+                    base_address: 0,
+                    size: 0x1000,
+                    offset: 0,
+                },
+                MemoryRegionState {
+                    base_address: 0x10_0000,
+                    size: 0x2000,
+                    offset: 0x1000,
+                },
+            ]
+        );
+
+        let mut contents = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents.len(), 0x3000);
+        assert_eq!(contents[0xfff], 0xaa);
+        assert_eq!(contents[0x1000], 0xbb);
+
+        let restored = restore_memory(&regions, &mut file).unwrap();
+        assert_eq!(restored.num_regions(), 2);
+        assert_eq!(restored.read_obj::<u8>(GuestAddress(0xfff)).unwrap(), 0xaa);
+        assert_eq!(
+            restored.read_obj::<u8>(GuestAddress(0x10_0000)).unwrap(),
+            0xbb
+        );
+        assert_eq!(restored.read_obj::<u8>(GuestAddress(0x10_1fff)).unwrap(), 0);
+
+        // The memory file is too short for the layout.
+        let regions = vec![MemoryRegionState {
+            base_address: 0,
+            size: 0x4000,
+            offset: 0,
+        }];
+        assert!(matches!(
+            restore_memory(&regions, &mut file),
+            Err(Error::Memory(_))
+        ));
+    }
+
+    #[test]
+    fn test_page_ranges() {
+        assert!(page_ranges(&[]).is_empty());
+        assert_eq!(
+            page_ranges(&[1, 2, 3, 5]),
+            vec![
+                MemoryRange {
+                    base_address: 0x1000,
+                    size: 0x3000,
+                },
+                MemoryRange {
+                    base_address: 0x5000,
+                    size: 0x1000,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff_memory() {
+        let guest_memory = GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), 0x2000),
+            (GuestAddress(0x10_0000), 0x2000),
+        ])
+        .unwrap();
+        guest_memory.write_obj(0xaa_u8, GuestAddress(0)).unwrap();
+        guest_memory
+            .write_obj(0xaa_u8, GuestAddress(0x10_1000))
+            .unwrap();
+
+        let full = TempFile::new().unwrap();
+        let regions = save_memory(&guest_memory, &mut full.as_file().try_clone().unwrap()).unwrap();
+
+        // Only the second page of each region is written to the diff.
+        guest_memory.write_obj(0xbb_u8, GuestAddress(0)).unwrap();
+        guest_memory
+            .write_obj(0xbb_u8, GuestAddress(0x1000))
+            .unwrap();
+        guest_memory
+            .write_obj(0xbb_u8, GuestAddress(0x10_1000))
+            .unwrap();
+        let ranges = page_ranges(&[1, 0x101]);
+        let diff = TempFile::new().unwrap();
+        let mut diff_file = diff.as_file().try_clone().unwrap();
+        assert_eq!(
+            save_memory_ranges(&guest_memory, &mut diff_file, &ranges).unwrap(),
+            regions
+        );
+        assert_eq!(diff_file.metadata().unwrap().len(), 0x4000);
+
+        let restored = restore_memory(&regions, &mut full.as_file().try_clone().unwrap()).unwrap();
+        apply_ranges(&restored, &regions, &ranges, &mut diff_file).unwrap();
+        // Written after the base snapshot, but not part of the diff.
+        assert_eq!(restored.read_obj::<u8>(GuestAddress(0)).unwrap(), 0xaa);
+        assert_eq!(restored.read_obj::<u8>(GuestAddress(0x1000)).unwrap(), 0xbb);
+        assert_eq!(
+            restored.read_obj::<u8>(GuestAddress(0x10_1000)).unwrap(),
+            0xbb
+        );
+
+        let outside = page_ranges(&[0x10]);
+        assert!(save_memory_ranges(&guest_memory, &mut diff_file, &outside).is_err());
+    }
+
+    #[test]
+    fn test_load_version() {
+        let tmp = TempFile::new().unwrap();
+        std::fs::write(tmp.as_path(), r#"{"version": 2, "foo": "bar"}"#).unwrap();
+        assert!(matches!(
+            Snapshot::load(tmp.as_path()),
+            Err(Error::UnsupportedVersion(2))
+        ));
+
+        std::fs::write(tmp.as_path(), r#"{"version": 1, "foo": "bar"}"#).unwrap();
+        assert!(matches!(
+            Snapshot::load(tmp.as_path()),
+            Err(Error::Deserialize(_))
+        ));
+
+        std::fs::write(tmp.as_path(), "foo").unwrap();
+        assert!(matches!(
+            Snapshot::load(tmp.as_path()),
+            Err(Error::Deserialize(_))
+        ));
+    }
+}
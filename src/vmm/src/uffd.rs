@@ -0,0 +1,453 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Lazy loading of guest memory from a snapshot memory file.
+//!
+//! Instead of reading the whole memory file before the VM resumes, guest memory is registered
+//! with [userfaultfd](https://www.kernel.org/doc/html/latest/admin-guide/mm/userfaultfd.html).
+//! The first access to a page, be it from a vCPU or from a device emulated by the VMM, blocks
+//! the faulting thread until a dedicated handler thread has copied the page in from the file.
+//!
+//! Pages the VMM gives back to the kernel with `MADV_DONTNEED`, such as balloon pages, stay
+//! registered. The handler is told about them, and zeroes them instead of loading them from the
+//! file on their next access, as if guest memory was not registered.
+//!
+//! When the handler fails to serve a fault, it asks the VMM to exit. Closing the userfaultfd
+//! then wakes up the faulting threads, so the VMM does not hang on its way out.
+
+use std::cmp::{max, min};
+use std::fs::File;
+use std::io::{self, Read};
+use std::mem;
+use std::os::raw::c_uint;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::FromRawFd;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use log::error;
+use serde::Serialize;
+use vm_memory::{GuestAddress, GuestMemory, GuestMemoryMmap};
+use vmm_sys_util::ioctl::ioctl_with_mut_ref;
+use vmm_sys_util::{ioctl_expr, ioctl_ioc_nr, ioctl_iowr_nr};
+
+use crate::snapshot::MemoryRegionState;
+use vm_vcpu::vm::ExitHandler;
+
+/// Errors encountered while setting up lazy loading of guest memory.
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to create the userfaultfd.
+    Create(io::Error),
+    /// The kernel does not implement the userfaultfd API version we need.
+    Api(io::Error),
+    /// The memory file is smaller than the guest memory layout requires.
+    MemoryFileTooShort,
+    /// Failed to access the memory file.
+    MemoryFile(io::Error),
+    /// Failed to find the host address of a guest memory region.
+    HostAddress(vm_memory::GuestMemoryError),
+    /// Failed to register guest memory with the userfaultfd.
+    Register(io::Error),
+    /// The kernel cannot copy pages in the registered memory.
+    CopyUnsupported,
+    /// The kernel cannot zero pages in the registered memory.
+    ZeroPageUnsupported,
+    /// Failed to spawn the page fault handler thread.
+    SpawnThread(io::Error),
+}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Counters updated by the page fault handler.
+#[derive(Debug, Default, Serialize)]
+pub struct PageFaultMetrics {
+    /// Number of pages loaded from the memory file.
+    pub page_faults: AtomicU64,
+    /// Number of pages given back to the kernel, then zeroed on their next access.
+    pub zeroed_pages: AtomicU64,
+    /// Number of faults which could not be served.
+    pub failed_faults: AtomicU64,
+}
+
+// Definitions from `include/uapi/linux/userfaultfd.h`.
+const UFFD_API: u64 = 0xAA;
+const UFFD_EVENT_PAGEFAULT: u8 = 0x12;
+const UFFD_EVENT_REMOVE: u8 = 0x15;
+const UFFD_FEATURE_EVENT_REMOVE: u64 = 1 << 3;
+const UFFDIO_REGISTER_MODE_MISSING: u64 = 1 << 0;
+const _UFFDIO_COPY: u64 = 0x03;
+const _UFFDIO_ZEROPAGE: u64 = 0x04;
+
+// Kernel ABI structures, some fields of which are only there for the layout.
+#[allow(dead_code)]
+mod bindings {
+    #[repr(C)]
+    #[derive(Default)]
+    pub struct UffdioApi {
+        pub api: u64,
+        pub features: u64,
+        pub ioctls: u64,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    pub struct UffdioRange {
+        pub start: u64,
+        pub len: u64,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    pub struct UffdioRegister {
+        pub range: UffdioRange,
+        pub mode: u64,
+        pub ioctls: u64,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    pub struct UffdioCopy {
+        pub dst: u64,
+        pub src: u64,
+        pub len: u64,
+        pub mode: u64,
+        pub copy: i64,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    pub struct UffdioZeropage {
+        pub range: UffdioRange,
+        pub mode: u64,
+        pub zeropage: i64,
+    }
+
+    // `struct uffd_msg`, with the union narrowed down to the page fault event. The remove
+    // event stores the `start` and `end` of the range where this one has `flags` and `address`.
+    #[repr(C)]
+    #[derive(Default)]
+    pub struct UffdMsg {
+        pub event: u8,
+        pub reserved1: u8,
+        pub reserved2: u16,
+        pub reserved3: u32,
+        pub flags: u64,
+        pub address: u64,
+        pub ptid: u32,
+        pub reserved4: u32,
+    }
+}
+
+use bindings::{UffdMsg, UffdioApi, UffdioCopy, UffdioRange, UffdioRegister, UffdioZeropage};
+
+const UFFDIO: c_uint = 0xAA;
+ioctl_iowr_nr!(UFFDIO_API, UFFDIO, 0x3F, UffdioApi);
+ioctl_iowr_nr!(UFFDIO_REGISTER, UFFDIO, 0x00, UffdioRegister);
+ioctl_iowr_nr!(UFFDIO_COPY, UFFDIO, 0x03, UffdioCopy);
+ioctl_iowr_nr!(UFFDIO_ZEROPAGE, UFFDIO, 0x04, UffdioZeropage);
+
+// Guest memory region registered with the userfaultfd, and where its contents live in the
+// memory file.
+struct Region {
+    host_addr: u64,
+    size: u64,
+    offset: u64,
+    // Pages given back to the kernel since the restore, one bit per page. Their contents in
+    // the memory file are stale.
+    removed: Vec<u64>,
+}
+
+impl Region {
+    fn contains(&self, addr: u64) -> bool {
+        addr >= self.host_addr && addr - self.host_addr < self.size
+    }
+
+    // Index and mask of the bit of the page at `addr` in `removed`.
+    fn removed_bit(&self, addr: u64, page_size: u64) -> (usize, u64) {
+        let page = (addr - self.host_addr) / page_size;
+        ((page / 64) as usize, 1 << (page % 64))
+    }
+
+    fn is_removed(&self, addr: u64, page_size: u64) -> bool {
+        let (index, mask) = self.removed_bit(addr, page_size);
+        self.removed[index] & mask != 0
+    }
+
+    // Marks the pages of `[start, end)` which are in the region as removed.
+    fn remove(&mut self, start: u64, end: u64, page_size: u64) {
+        let start = max(start, self.host_addr);
+        let end = min(end, self.host_addr + self.size);
+        for addr in (start..end).step_by(page_size as usize) {
+            let (index, mask) = self.removed_bit(addr, page_size);
+            self.removed[index] |= mask;
+        }
+    }
+}
+
+/// Registers `guest_memory` with a userfaultfd, and starts a thread which loads the pages
+/// from `mem_file` on first access.
+///
+/// `regions` describes where each guest memory region is saved in `mem_file`, and must match
+/// the layout of `guest_memory`. The returned metrics are updated by the handler thread.
+pub fn load_lazily<EH: ExitHandler + Send + 'static>(
+    guest_memory: &GuestMemoryMmap,
+    regions: &[MemoryRegionState],
+    mem_file: File,
+    exit_handler: EH,
+) -> Result<Arc<PageFaultMetrics>> {
+    // Faults past the end of the file could not be served, so check it upfront.
+    let file_len = mem_file.metadata().map_err(Error::MemoryFile)?.len();
+    if regions
+        .iter()
+        .any(|region| region.offset + region.size > file_len)
+    {
+        return Err(Error::MemoryFileTooShort);
+    }
+
+    let uffd = create_uffd()?;
+    let page_size = page_size();
+    let mut registered = Vec::with_capacity(regions.len());
+    for region in regions {
+        let guest_addr = GuestAddress(region.base_address);
+        let host_addr = guest_memory
+            .get_host_address(guest_addr)
+            .map_err(Error::HostAddress)? as u64;
+        register(&uffd, host_addr, region.size)?;
+        let pages = (region.size + page_size - 1) / page_size;
+        registered.push(Region {
+            host_addr,
+            size: region.size,
+            offset: region.offset,
+            removed: vec![0; ((pages + 63) / 64) as usize],
+        });
+    }
+
+    let metrics = Arc::new(PageFaultMetrics::default());
+    let handler_metrics = metrics.clone();
+    thread::Builder::new()
+        .name("uffd_handler".to_string())
+        .spawn(move || {
+            if let Err(e) = serve_faults(uffd, &mut registered, &mem_file, &handler_metrics) {
+                error!("Failed to load guest memory: {:?}", e);
+                handler_metrics
+                    .failed_faults
+                    .fetch_add(1, Ordering::Relaxed);
+                // The userfaultfd is closed when the thread returns, which lets the faulting
+                // threads go on while the VMM exits.
+                let _ = exit_handler.kick();
+            }
+        })
+        .map_err(Error::SpawnThread)?;
+
+    Ok(metrics)
+}
+
+fn create_uffd() -> Result<File> {
+    // Safe because the syscall does not touch memory, and we check the return value.
+    let fd = unsafe { libc::syscall(libc::SYS_userfaultfd, libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(Error::Create(io::Error::last_os_error()));
+    }
+    // Safe because we just created the fd, and nothing else owns it.
+    let uffd = unsafe { File::from_raw_fd(fd as i32) };
+
+    let mut api = UffdioApi {
+        api: UFFD_API,
+        features: UFFD_FEATURE_EVENT_REMOVE,
+        ..Default::default()
+    };
+    // Safe because `api` is a valid `UffdioApi` which outlives the call.
+    let ret = unsafe { ioctl_with_mut_ref(&uffd, UFFDIO_API(), &mut api) };
+    if ret < 0 {
+        return Err(Error::Api(io::Error::last_os_error()));
+    }
+    Ok(uffd)
+}
+
+fn register(uffd: &File, host_addr: u64, size: u64) -> Result<()> {
+    let mut reg = UffdioRegister {
+        range: UffdioRange {
+            start: host_addr,
+            len: size,
+        },
+        mode: UFFDIO_REGISTER_MODE_MISSING,
+        ..Default::default()
+    };
+    // Safe because `reg` is a valid `UffdioRegister` which outlives the call, and the range
+    // is guest memory mapped by this process.
+    let ret = unsafe { ioctl_with_mut_ref(uffd, UFFDIO_REGISTER(), &mut reg) };
+    if ret < 0 {
+        return Err(Error::Register(io::Error::last_os_error()));
+    }
+    if reg.ioctls & (1 << _UFFDIO_COPY) == 0 {
+        return Err(Error::CopyUnsupported);
+    }
+    if reg.ioctls & (1 << _UFFDIO_ZEROPAGE) == 0 {
+        return Err(Error::ZeroPageUnsupported);
+    }
+    Ok(())
+}
+
+fn page_size() -> u64 {
+    // Safe because sysconf does not touch memory.
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 }
+}
+
+// Serves page faults until reading the userfaultfd or the memory file fails.
+fn serve_faults(
+    mut uffd: File,
+    regions: &mut [Region],
+    mem_file: &File,
+    metrics: &PageFaultMetrics,
+) -> io::Result<()> {
+    let page_size = page_size();
+    let mut page = vec![0u8; page_size as usize];
+    let mut msg = UffdMsg::default();
+
+    loop {
+        // Safe because `UffdMsg` is plain data, so any byte pattern is valid.
+        let buf = unsafe {
+            std::slice::from_raw_parts_mut(
+                &mut msg as *mut UffdMsg as *mut u8,
+                mem::size_of::<UffdMsg>(),
+            )
+        };
+        uffd.read_exact(buf)?;
+        if msg.event == UFFD_EVENT_REMOVE {
+            // The thread giving the range back waits until the event is read, so the pages
+            // are marked before they can fault again.
+            for region in regions.iter_mut() {
+                region.remove(msg.flags, msg.address, page_size);
+            }
+            continue;
+        }
+        if msg.event != UFFD_EVENT_PAGEFAULT {
+            continue;
+        }
+
+        let addr = msg.address & !(page_size - 1);
+        let region = regions
+            .iter()
+            .find(|r| r.contains(addr))
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        if region.is_removed(addr, page_size) {
+            zero_page(&uffd, addr, page_size, metrics)?;
+            continue;
+        }
+        mem_file.read_exact_at(&mut page, region.offset + (addr - region.host_addr))?;
+
+        let mut copy = UffdioCopy {
+            dst: addr,
+            src: page.as_ptr() as u64,
+            len: page_size,
+            ..Default::default()
+        };
+        // Safe because `copy` is a valid `UffdioCopy`, `src` points to `page_size` bytes,
+        // and `dst` is a page of registered guest memory.
+        let ret = unsafe { ioctl_with_mut_ref(&uffd, UFFDIO_COPY(), &mut copy) };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            // Another fault on the same page was served in the meantime.
+            if e.raw_os_error() != Some(libc::EEXIST) {
+                return Err(e);
+            }
+        } else {
+            metrics.page_faults.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// Fills the page at `addr` with zeroes, as the kernel does for memory which is not registered.
+fn zero_page(uffd: &File, addr: u64, page_size: u64, metrics: &PageFaultMetrics) -> io::Result<()> {
+    let mut zero = UffdioZeropage {
+        range: UffdioRange {
+            start: addr,
+            len: page_size,
+        },
+        ..Default::default()
+    };
+    // Safe because `zero` is a valid `UffdioZeropage`, and the range is a page of registered
+    // guest memory.
+    let ret = unsafe { ioctl_with_mut_ref(uffd, UFFDIO_ZEROPAGE(), &mut zero) };
+    if ret < 0 {
+        let e = io::Error::last_os_error();
+        // Another fault on the same page was served in the meantime.
+        if e.raw_os_error() != Some(libc::EEXIST) {
+            return Err(e);
+        }
+    } else {
+        metrics.zeroed_pages.fetch_add(1, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uffd_abi() {
+        // Sizes from `include/uapi/linux/userfaultfd.h`.
+        assert_eq!(mem::size_of::<UffdMsg>(), 32);
+        assert_eq!(mem::size_of::<UffdioApi>(), 24);
+        assert_eq!(mem::size_of::<UffdioRegister>(), 32);
+        assert_eq!(mem::size_of::<UffdioCopy>(), 40);
+        assert_eq!(mem::size_of::<UffdioZeropage>(), 32);
+    }
+
+    #[test]
+    fn test_region_remove() {
+        let mut region = Region {
+            host_addr: 0x10000,
+            size: 0x100000,
+            offset: 0,
+            removed: vec![0; 4],
+        };
+        // Partly before the region.
+        region.remove(0x8000, 0x12000, 0x1000);
+        // Partly after the region.
+        region.remove(0x10f000, 0x200000, 0x1000);
+
+        assert!(region.is_removed(0x10000, 0x1000));
+        assert!(region.is_removed(0x11fff, 0x1000));
+        assert!(!region.is_removed(0x12000, 0x1000));
+        assert!(!region.is_removed(0x10e000, 0x1000));
+        assert!(region.is_removed(0x10f000, 0x1000));
+        assert!(!region.contains(0x110000));
+    }
+
+    #[test]
+    fn test_memory_file_too_short() {
+        use vmm_sys_util::tempfile::TempFile;
+
+        let guest_memory = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x2000)]).unwrap();
+        let regions = vec![MemoryRegionState {
+            base_address: 0,
+            size: 0x2000,
+            offset: 0,
+        }];
+        let tmp = TempFile::new().unwrap();
+        tmp.as_file().set_len(0x1000).unwrap();
+
+        #[derive(Clone)]
+        struct DummyExitHandler;
+        impl ExitHandler for DummyExitHandler {
+            fn kick(&self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        assert!(matches!(
+            load_lazily(
+                &guest_memory,
+                &regions,
+                tmp.as_file().try_clone().unwrap(),
+                DummyExitHandler
+            ),
+            Err(Error::MemoryFileTooShort)
+        ));
+    }
+}
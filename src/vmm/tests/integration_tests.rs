@@ -1,10 +1,14 @@
 // Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
-use std::convert::TryFrom;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 
+use event_manager::{EventManager, MutEventSubscriber, SubscriberOps};
 use utils::resource_download::s3_download;
-use vmm::{KernelConfig, MemoryConfig, VMMConfig, VcpuConfig, Vmm, DEFAULT_KERNEL_LOAD_ADDR};
+use vmm::{
+    KernelConfig, MemoryConfig, TryFrom1, VMMConfig, VcpuConfig, Vmm, WrappedExitHandler,
+    DEFAULT_KERNEL_LOAD_ADDR,
+};
 
 fn default_memory_config() -> MemoryConfig {
     MemoryConfig { size_mib: 1024 }
@@ -27,12 +31,20 @@ fn run_vmm(kernel_path: PathBuf) {
         kernel_config: default_kernel_config(kernel_path),
         memory_config: default_memory_config(),
         vcpu_config: default_vcpu_config(),
-        block_config: None,
-        net_config: None,
+        ..Default::default()
     };
 
-    let mut vmm = Vmm::try_from(vmm_config).unwrap();
+    let exit_handler = WrappedExitHandler::new().unwrap();
+    let mut event_manager =
+        EventManager::<Arc<Mutex<dyn MutEventSubscriber + Send>>>::new().unwrap();
+    event_manager.add_subscriber(exit_handler.0.clone());
+    let mut vmm = Vmm::try_from1(vmm_config, &exit_handler, &mut event_manager).unwrap();
     vmm.run().unwrap();
+    // Run until the guest, which halts right after booting, ends the VMM.
+    while exit_handler.keep_running() {
+        event_manager.run().unwrap();
+    }
+    vmm.shutdown();
 }
 
 #[test]